@@ -0,0 +1,134 @@
+use std::{
+    env, fs, io,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+/// Directory whose contents are packed into the initial ramdisk.
+const INITRD_DIR: &str = "initrd";
+
+/// Directory whose contents are copied onto the FAT32 test image.
+const FAT32_FIXTURE_DIR: &str = "tests/fixtures/fat32";
+
+/// Size of the FAT32 test image in KiB. This is below the cluster count
+/// FAT32 officially requires, which `mkfs.fat -F 32` accepts with a warning.
+const FAT32_IMAGE_KIB: &str = "1024";
+
+fn main() {
+    let out_dir = PathBuf::from(env::var_os("OUT_DIR").expect("OUT_DIR not set"));
+
+    println!("cargo:rerun-if-changed={}", INITRD_DIR);
+
+    let mut archive = Vec::new();
+    let root = Path::new(INITRD_DIR);
+    if root.is_dir() {
+        append_dir(&mut archive, root, root).expect("failed to pack initrd");
+    }
+    // Two zeroed blocks mark the end of a tar archive
+    archive.extend_from_slice(&[0; 1024]);
+
+    fs::write(out_dir.join("initrd.tar"), archive).expect("failed to write initrd.tar");
+
+    println!("cargo:rerun-if-changed={}", FAT32_FIXTURE_DIR);
+    build_fat32_image(&out_dir.join("fat32.img"));
+}
+
+/// Formats a FAT32 image with `mkfs.fat` and copies the fixtures onto it
+/// with `mcopy`, so the driver is tested against images made by other tools.
+///
+/// If dosfstools or mtools are missing an empty file is written instead and
+/// the tests that need the image skip themselves.
+fn build_fat32_image(image: &Path) {
+    let _ = fs::remove_file(image);
+
+    let fixtures = fs::read_dir(FAT32_FIXTURE_DIR)
+        .map(|entries| {
+            let mut paths: Vec<_> = entries.filter_map(|e| e.ok()).map(|e| e.path()).collect();
+            paths.sort();
+            paths
+        })
+        .unwrap_or_default();
+
+    let formatted = Command::new("mkfs.fat")
+        .args(["-F", "32", "-n", "BLIGHT", "-C"])
+        .arg(image)
+        .arg(FAT32_IMAGE_KIB)
+        .output()
+        .map_or(false, |output| output.status.success());
+
+    let copied = formatted
+        && (fixtures.is_empty()
+            || Command::new("mcopy")
+                .env("MTOOLS_SKIP_CHECK", "1")
+                .arg("-s")
+                .arg("-i")
+                .arg(image)
+                .args(&fixtures)
+                .arg("::/")
+                .output()
+                .map_or(false, |output| output.status.success()));
+
+    if !copied {
+        println!("cargo:warning=mkfs.fat or mcopy unavailable, FAT32 image tests will be skipped");
+        fs::write(image, []).expect("failed to write fat32.img");
+    }
+}
+
+fn append_dir(archive: &mut Vec<u8>, root: &Path, dir: &Path) -> io::Result<()> {
+    let mut entries = fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+    // Sort so the archive is reproducible between builds
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let path = entry.path();
+        let name = path
+            .strip_prefix(root)
+            .unwrap()
+            .to_str()
+            .expect("initrd paths must be valid UTF-8")
+            .replace('\\', "/");
+
+        if entry.file_type()?.is_dir() {
+            append_header(archive, &(name + "/"), b'5', 0o755, 0);
+            append_dir(archive, root, &path)?;
+        } else {
+            let data = fs::read(&path)?;
+            append_header(archive, &name, b'0', 0o644, data.len());
+            archive.extend_from_slice(&data);
+            archive.resize(archive.len() + (512 - data.len() % 512) % 512, 0);
+        }
+    }
+
+    Ok(())
+}
+
+fn append_header(archive: &mut Vec<u8>, name: &str, typeflag: u8, mode: u32, size: usize) {
+    assert!(name.len() < 100, "initrd path too long: {}", name);
+
+    let mut header = [0u8; 512];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    write_octal(&mut header[100..108], mode as u64);
+    write_octal(&mut header[108..116], 0);
+    write_octal(&mut header[116..124], 0);
+    write_octal(&mut header[124..136], size as u64);
+    write_octal(&mut header[136..148], 0);
+    header[156] = typeflag;
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+
+    // The checksum is calculated with the checksum field filled with spaces
+    header[148..156].copy_from_slice(b"        ");
+    let checksum: u32 = header.iter().map(|&b| b as u32).sum();
+    write_octal(&mut header[148..155], checksum as u64);
+    header[155] = b' ';
+
+    archive.extend_from_slice(&header);
+}
+
+/// Writes `value` as zero padded octal followed by a NUL terminator.
+fn write_octal(field: &mut [u8], value: u64) {
+    let digits = field.len() - 1;
+    let text = format!("{:0width$o}", value, width = digits);
+    field[..digits].copy_from_slice(text.as_bytes());
+    field[digits] = 0;
+}
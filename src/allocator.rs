@@ -11,7 +11,7 @@ use x86_64::{
 };
 
 pub const HEAP_START: usize = 0x_4444_4444_0000;
-pub const HEAP_SIZE: usize = 100 * 1024; // 100 KiB
+pub const HEAP_SIZE: usize = 1024 * 1024; // 1 MiB
 
 #[global_allocator]
 static ALLOCATOR: LockedHeap = LockedHeap::empty();
@@ -56,3 +56,21 @@ pub fn init_heap(
 
     Ok(())
 }
+
+/// How much of the kernel heap is in use, in bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapStats {
+    pub size: usize,
+    pub used: usize,
+    pub free: usize,
+}
+
+/// All 0 until [`init_heap`] ran.
+pub fn stats() -> HeapStats {
+    let heap = ALLOCATOR.lock();
+    HeapStats {
+        size: heap.size(),
+        used: heap.used(),
+        free: heap.free(),
+    }
+}
@@ -0,0 +1,258 @@
+use super::{check_request, BlockDevice, BlockError, BlockFuture};
+use alloc::{boxed::Box, collections::BTreeMap, sync::Arc, vec::Vec};
+use spin::Mutex;
+
+/// Number of sectors kept by [`BufferCache::with_default_capacity`].
+pub const DEFAULT_CAPACITY: usize = 128;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Dirty sectors written to the device, on eviction or flush.
+    pub write_backs: u64,
+    pub cached: usize,
+    pub dirty: usize,
+}
+
+struct Buffer {
+    data: Box<[u8]>,
+    dirty: bool,
+    last_used: u64,
+}
+
+struct CacheState {
+    buffers: BTreeMap<u64, Buffer>,
+    /// Sector numbers by the time they were last used, oldest first.
+    lru: BTreeMap<u64, u64>,
+    clock: u64,
+    stats: CacheStats,
+}
+
+impl CacheState {
+    /// Marks `lba` as the most recently used sector, returning whether it
+    /// is cached at all.
+    fn touch(&mut self, lba: u64) -> bool {
+        self.clock += 1;
+        match self.buffers.get_mut(&lba) {
+            Some(buffer) => {
+                self.lru.remove(&buffer.last_used);
+                buffer.last_used = self.clock;
+                self.lru.insert(self.clock, lba);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// A write-back LRU cache of sectors in front of another block device.
+///
+/// Writes only reach the device when a dirty sector is evicted or the cache
+/// is flushed, so filesystems can update the same FAT or directory sector
+/// over and over without touching the disk each time.
+pub struct BufferCache {
+    device: Arc<dyn BlockDevice>,
+    capacity: usize,
+    state: Mutex<CacheState>,
+}
+
+impl BufferCache {
+    pub fn new(device: Arc<dyn BlockDevice>, capacity: usize) -> BufferCache {
+        BufferCache {
+            device,
+            capacity: capacity.max(1),
+            state: Mutex::new(CacheState {
+                buffers: BTreeMap::new(),
+                lru: BTreeMap::new(),
+                clock: 0,
+                stats: CacheStats::default(),
+            }),
+        }
+    }
+
+    pub fn with_default_capacity(device: Arc<dyn BlockDevice>) -> BufferCache {
+        BufferCache::new(device, DEFAULT_CAPACITY)
+    }
+
+    pub fn device(&self) -> &Arc<dyn BlockDevice> {
+        &self.device
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let state = self.state.lock();
+        CacheStats {
+            cached: state.buffers.len(),
+            dirty: state.buffers.values().filter(|buffer| buffer.dirty).count(),
+            ..state.stats
+        }
+    }
+
+    /// Copies `lba` into `buf` if it is cached.
+    fn lookup(&self, lba: u64, buf: &mut [u8]) -> bool {
+        let mut state = self.state.lock();
+        if state.touch(lba) {
+            buf.copy_from_slice(&state.buffers[&lba].data);
+            state.stats.hits += 1;
+            true
+        } else {
+            state.stats.misses += 1;
+            false
+        }
+    }
+
+    /// Caches `data` for `lba`. Data read from the device never replaces a
+    /// cached copy, which may be newer.
+    fn insert(&self, lba: u64, data: &[u8], dirty: bool) -> Result<(), BlockError> {
+        let mut state = self.state.lock();
+        if state.touch(lba) {
+            if dirty {
+                let buffer = state.buffers.get_mut(&lba).unwrap();
+                buffer.data.copy_from_slice(data);
+                buffer.dirty = true;
+            }
+            return Ok(());
+        }
+
+        if state.buffers.len() >= self.capacity {
+            self.evict(&mut state)?;
+        }
+        let last_used = state.clock;
+        state.buffers.insert(
+            lba,
+            Buffer {
+                data: data.into(),
+                dirty,
+                last_used,
+            },
+        );
+        state.lru.insert(last_used, lba);
+        Ok(())
+    }
+
+    /// Drops the least recently used sector, writing it back if it is dirty.
+    fn evict(&self, state: &mut CacheState) -> Result<(), BlockError> {
+        let Some((last_used, lba)) = state.lru.pop_first() else {
+            return Ok(());
+        };
+        let buffer = state.buffers.remove(&lba).unwrap();
+
+        if buffer.dirty {
+            if let Err(err) = self.device.write_sectors(lba, &buffer.data) {
+                // Keep it so the write isn't lost
+                state.lru.insert(last_used, lba);
+                state.buffers.insert(lba, buffer);
+                return Err(err);
+            }
+            state.stats.write_backs += 1;
+        }
+        Ok(())
+    }
+
+    /// Marks every dirty sector clean and returns copies of them, in order.
+    fn take_dirty(&self) -> Vec<(u64, Box<[u8]>)> {
+        let mut state = self.state.lock();
+        state
+            .buffers
+            .iter_mut()
+            .filter(|(_, buffer)| buffer.dirty)
+            .map(|(&lba, buffer)| {
+                buffer.dirty = false;
+                (lba, buffer.data.clone())
+            })
+            .collect()
+    }
+
+    /// Marks sectors dirty again after writing them back failed.
+    fn restore_dirty(&self, sectors: &[(u64, Box<[u8]>)]) {
+        let mut state = self.state.lock();
+        for (lba, data) in sectors {
+            match state.buffers.get_mut(lba) {
+                Some(buffer) => buffer.dirty = true,
+                None => {
+                    state.clock += 1;
+                    let last_used = state.clock;
+                    state.buffers.insert(
+                        *lba,
+                        Buffer {
+                            data: data.clone(),
+                            dirty: true,
+                            last_used,
+                        },
+                    );
+                    state.lru.insert(last_used, *lba);
+                }
+            }
+        }
+    }
+}
+
+impl BlockDevice for BufferCache {
+    fn sector_size(&self) -> usize {
+        self.device.sector_size()
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.device.sector_count()
+    }
+
+    fn read_sectors(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        check_request(self, lba, buf.len())?;
+        for (i, sector) in buf.chunks_exact_mut(self.sector_size()).enumerate() {
+            let lba = lba + i as u64;
+            if !self.lookup(lba, sector) {
+                self.device.read_sectors(lba, sector)?;
+                self.insert(lba, sector, false)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        check_request(self, lba, buf.len())?;
+        for (i, sector) in buf.chunks_exact(self.sector_size()).enumerate() {
+            self.insert(lba + i as u64, sector, true)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        let dirty = self.take_dirty();
+        for (i, (lba, data)) in dirty.iter().enumerate() {
+            if let Err(err) = self.device.write_sectors(*lba, data) {
+                self.restore_dirty(&dirty[i..]);
+                return Err(err);
+            }
+        }
+        self.state.lock().stats.write_backs += dirty.len() as u64;
+        self.device.flush()
+    }
+
+    fn read_sectors_async<'a>(&'a self, lba: u64, buf: &'a mut [u8]) -> BlockFuture<'a, ()> {
+        Box::pin(async move {
+            check_request(self, lba, buf.len())?;
+            for (i, sector) in buf.chunks_exact_mut(self.sector_size()).enumerate() {
+                let lba = lba + i as u64;
+                if !self.lookup(lba, sector) {
+                    self.device.read_sectors_async(lba, sector).await?;
+                    self.insert(lba, sector, false)?;
+                }
+            }
+            Ok(())
+        })
+    }
+
+    fn flush_async(&self) -> BlockFuture<'_, ()> {
+        Box::pin(async move {
+            let dirty = self.take_dirty();
+            for (i, (lba, data)) in dirty.iter().enumerate() {
+                if let Err(err) = self.device.write_sectors_async(*lba, data).await {
+                    self.restore_dirty(&dirty[i..]);
+                    return Err(err);
+                }
+            }
+            self.state.lock().stats.write_backs += dirty.len() as u64;
+            self.device.flush_async().await
+        })
+    }
+}
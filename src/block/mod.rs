@@ -0,0 +1,77 @@
+pub mod cache;
+pub mod ramdisk;
+
+use crate::fs::vfs::FsError;
+use alloc::boxed::Box;
+use core::{future::Future, pin::Pin};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    /// The request reaches past the end of the device.
+    OutOfRange,
+    /// The buffer isn't a whole number of sectors.
+    UnalignedBuffer,
+    ReadOnly,
+    Timeout,
+    /// The device reported an error.
+    Io,
+}
+
+impl From<BlockError> for FsError {
+    fn from(err: BlockError) -> FsError {
+        match err {
+            BlockError::ReadOnly => FsError::ReadOnly,
+            _ => FsError::Io,
+        }
+    }
+}
+
+pub type BlockFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, BlockError>> + 'a>>;
+
+/// Storage that is read and written in whole sectors.
+///
+/// The async variants default to the blocking calls. Drivers that complete
+/// requests from an interrupt override them so the executor can run other
+/// tasks in the meantime.
+pub trait BlockDevice: Send + Sync {
+    fn sector_size(&self) -> usize;
+
+    fn sector_count(&self) -> u64;
+
+    /// Reads `buf.len() / sector_size()` sectors starting at `lba`.
+    fn read_sectors(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError>;
+
+    /// Writes `buf.len() / sector_size()` sectors starting at `lba`.
+    fn write_sectors(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError>;
+
+    /// Makes sure all written sectors have reached the storage.
+    fn flush(&self) -> Result<(), BlockError> {
+        Ok(())
+    }
+
+    fn read_sectors_async<'a>(&'a self, lba: u64, buf: &'a mut [u8]) -> BlockFuture<'a, ()> {
+        Box::pin(core::future::ready(self.read_sectors(lba, buf)))
+    }
+
+    fn write_sectors_async<'a>(&'a self, lba: u64, buf: &'a [u8]) -> BlockFuture<'a, ()> {
+        Box::pin(core::future::ready(self.write_sectors(lba, buf)))
+    }
+
+    fn flush_async(&self) -> BlockFuture<'_, ()> {
+        Box::pin(core::future::ready(self.flush()))
+    }
+}
+
+/// Checks that a request for `len` bytes at `lba` fits on `device` and
+/// returns the number of sectors it covers.
+pub fn check_request(device: &dyn BlockDevice, lba: u64, len: usize) -> Result<u64, BlockError> {
+    let sector_size = device.sector_size();
+    if !len.is_multiple_of(sector_size) {
+        return Err(BlockError::UnalignedBuffer);
+    }
+    let count = (len / sector_size) as u64;
+    match lba.checked_add(count) {
+        Some(end) if end <= device.sector_count() => Ok(count),
+        _ => Err(BlockError::OutOfRange),
+    }
+}
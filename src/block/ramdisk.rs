@@ -0,0 +1,72 @@
+use super::{check_request, BlockDevice, BlockError};
+use alloc::{boxed::Box, collections::BTreeMap};
+use spin::Mutex;
+
+/// A disk held in memory, e.g. an image embedded with `include_bytes!`.
+///
+/// Written sectors are kept in an overlay on the heap, so the image itself
+/// can stay in read-only memory and only changes cost heap space. Sectors
+/// past the end of the image read as zeroes.
+pub struct RamDisk {
+    image: &'static [u8],
+    sector_size: usize,
+    sector_count: u64,
+    written: Mutex<BTreeMap<u64, Box<[u8]>>>,
+}
+
+impl RamDisk {
+    pub fn new(image: &'static [u8], sector_size: usize) -> RamDisk {
+        RamDisk {
+            image,
+            sector_size,
+            sector_count: (image.len() / sector_size) as u64,
+            written: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// An empty disk of `sector_count` zeroed sectors.
+    pub fn blank(sector_count: u64, sector_size: usize) -> RamDisk {
+        RamDisk {
+            image: &[],
+            sector_size,
+            sector_count,
+            written: Mutex::new(BTreeMap::new()),
+        }
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn sector_size(&self) -> usize {
+        self.sector_size
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.sector_count
+    }
+
+    fn read_sectors(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        check_request(self, lba, buf.len())?;
+        let written = self.written.lock();
+        for (i, sector) in buf.chunks_exact_mut(self.sector_size).enumerate() {
+            let lba = lba + i as u64;
+            let start = lba as usize * self.sector_size;
+            match written.get(&lba) {
+                Some(data) => sector.copy_from_slice(data),
+                None => match self.image.get(start..start + self.sector_size) {
+                    Some(data) => sector.copy_from_slice(data),
+                    None => sector.fill(0),
+                },
+            }
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        check_request(self, lba, buf.len())?;
+        let mut written = self.written.lock();
+        for (i, sector) in buf.chunks_exact(self.sector_size).enumerate() {
+            written.insert(lba + i as u64, sector.into());
+        }
+        Ok(())
+    }
+}
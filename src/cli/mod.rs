@@ -1,19 +1,360 @@
-use crate::{print, task::keyboard::ScancodeStream};
+use crate::{
+    fs::vfs::{self, FileType},
+    networking::{
+        arp, capture, device, dhcp, dns,
+        icmp::{self, PingError},
+        icmpv6, ip,
+        ipv6::{self, AddressState, Ipv6Addr},
+        ndp, tcp,
+    },
+    print, println,
+    task::keyboard::ScancodeStream,
+    time,
+};
+use alloc::{
+    format,
+    string::{String, ToString},
+};
+use core::net::IpAddr;
 use futures_util::stream::StreamExt;
 use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
 
+const PROMPT: &str = "> ";
+
 pub async fn tty() {
     let mut scancord = ScancodeStream::new();
     let mut keyboard = Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore);
+    let mut line = String::new();
+
+    print!("{}", PROMPT);
 
     while let Some(scancode) = scancord.next().await {
         if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
             if let Some(key) = keyboard.process_keyevent(key_event) {
                 match key {
-                    DecodedKey::Unicode(character) => print!("{}", character),
-                    DecodedKey::RawKey(key) => print!("{:?}", key),
+                    DecodedKey::Unicode('\n') => {
+                        println!();
+                        run_command(&line).await;
+                        line.clear();
+                        print!("{}", PROMPT);
+                    }
+                    DecodedKey::Unicode('\u{8}') => {
+                        if line.pop().is_some() {
+                            print!("\u{8}");
+                        }
+                    }
+                    DecodedKey::Unicode(character) => {
+                        line.push(character);
+                        print!("{}", character);
+                    }
+                    DecodedKey::RawKey(_) => {}
+                }
+            }
+        }
+    }
+}
+
+async fn run_command(line: &str) {
+    let mut args = line.split_whitespace();
+
+    match args.next() {
+        None => {}
+        Some("help") => {
+            println!("help          show this message");
+            println!("ls [path]     list a directory");
+            println!("cat <path>    print a file");
+            println!("cd [path]     change the working directory");
+            println!("pwd           print the working directory");
+            println!("mkdir <path>  create a directory");
+            println!("rm <path>     remove a file or empty directory");
+            println!("mounts        list mounted filesystems");
+            println!("sync          write cached data to disk");
+            println!("ifconfig      list network interfaces");
+            println!("arp           show the neighbor cache");
+            println!("ndp           show the IPv6 neighbor cache");
+            println!("route         show the routing table");
+            println!("netstat       list TCP connections");
+            println!("ping <host> [count]  send echo requests");
+            println!("host <name>   look up a hostname");
+            println!("capture [on|off]  capture frames to COM2 as pcap");
+        }
+        Some("ls") => ls(args.next().unwrap_or(".")),
+        Some("cat") => match args.next() {
+            Some(path) => cat(path),
+            None => println!("cat: missing path"),
+        },
+        Some("cd") => {
+            let path = args.next().unwrap_or("/");
+            if let Err(err) = vfs::set_cwd(path) {
+                println!("cd: {}: {:?}", path, err);
+            }
+        }
+        Some("pwd") => match vfs::cwd() {
+            Ok(cwd) => println!("{}", cwd),
+            Err(err) => println!("pwd: {:?}", err),
+        },
+        Some("mkdir") => match args.next() {
+            Some(path) => {
+                if let Err(err) = vfs::create_dir(path) {
+                    println!("mkdir: {}: {:?}", path, err);
+                }
+            }
+            None => println!("mkdir: missing path"),
+        },
+        Some("rm") => match args.next() {
+            Some(path) => {
+                if let Err(err) = vfs::remove(path) {
+                    println!("rm: {}: {:?}", path, err);
+                }
+            }
+            None => println!("rm: missing path"),
+        },
+        Some("mounts") => match vfs::get() {
+            Ok(vfs) => {
+                vfs.for_each_mount(|mount| println!("{} on {}", mount.fs.name(), mount.path))
+            }
+            Err(err) => println!("mounts: {:?}", err),
+        },
+        Some("sync") => {
+            if let Err(err) = vfs::sync() {
+                println!("sync: {:?}", err);
+            }
+        }
+        Some("ifconfig") => ifconfig(),
+        Some("arp") => arp(),
+        Some("ndp") => ndp(),
+        Some("route") => route(),
+        Some("netstat") => netstat(),
+        Some("ping") => match args.next() {
+            Some(host) => match args.next().map(str::parse::<u16>).unwrap_or(Ok(4)) {
+                Ok(count) => match host.parse::<Ipv6Addr>() {
+                    Ok(addr) => ping(addr.into(), count).await,
+                    Err(_) => match dns::lookup_host(host).await {
+                        Ok(addr) => ping(addr.into(), count).await,
+                        Err(err) => println!("ping: {}: {:?}", host, err),
+                    },
+                },
+                Err(_) => println!("ping: invalid count"),
+            },
+            None => println!("ping: missing host"),
+        },
+        Some("host") => match args.next() {
+            Some(name) => host(name).await,
+            None => println!("host: missing name"),
+        },
+        Some("capture") => match args.next() {
+            Some("on") => capture::start(),
+            Some("off") => capture::stop(),
+            None => println!(
+                "capture {}",
+                if capture::is_enabled() { "on" } else { "off" }
+            ),
+            Some(_) => println!("capture: expected on or off"),
+        },
+        Some(command) => println!("{}: command not found", command),
+    }
+}
+
+fn ls(path: &str) {
+    match vfs::read_dir(path) {
+        Ok(entries) => {
+            for entry in entries {
+                match entry.file_type {
+                    FileType::Directory => println!("{}/", entry.name),
+                    _ => println!("{}", entry.name),
+                }
+            }
+        }
+        Err(err) => println!("ls: {}: {:?}", path, err),
+    }
+}
+
+fn cat(path: &str) {
+    match vfs::read(path) {
+        Ok(data) => match core::str::from_utf8(&data) {
+            Ok(text) => print!("{}", text),
+            Err(_) => println!("cat: {}: not a text file", path),
+        },
+        Err(err) => println!("cat: {}: {:?}", path, err),
+    }
+}
+
+fn ifconfig() {
+    for interface in device::interfaces() {
+        let nic = &interface.device;
+        let stats = nic.stats();
+        println!(
+            "{}: {} {:02x?} mtu {} link {}",
+            interface.name,
+            nic.driver(),
+            nic.mac(),
+            nic.mtu(),
+            if nic.link_up() { "up" } else { "down" }
+        );
+        if let Some(config) = interface.ipv4() {
+            print!("    inet {}/{}", config.address, config.prefix_len);
+            match config.gateway {
+                Some(gateway) => println!(" via {}", gateway),
+                None => println!(),
+            }
+        }
+        for address in interface.ipv6() {
+            print!("    inet6 {}/{}", address.address, address.prefix_len);
+            match address.state {
+                AddressState::Tentative => println!(" tentative"),
+                AddressState::Preferred => println!(),
+            }
+        }
+        for server in interface.dns_servers() {
+            println!("    dns {}", server);
+        }
+        if let Some(lease) = dhcp::lease(&interface.name) {
+            print!("    leased from {}", lease.server);
+            match lease.expires_ms {
+                Some(expires) => {
+                    println!(
+                        ", {}s left",
+                        expires.saturating_sub(time::uptime_ms()) / 1000
+                    )
                 }
+                None => println!(" for good"),
             }
         }
+        println!(
+            "    rx {} packets {} bytes {} errors {} dropped",
+            stats.rx_packets, stats.rx_bytes, stats.rx_errors, stats.rx_dropped
+        );
+        println!(
+            "    tx {} packets {} bytes {} errors",
+            stats.tx_packets, stats.tx_bytes, stats.tx_errors
+        );
+    }
+}
+
+fn arp() {
+    for neighbor in arp::neighbors() {
+        match neighbor.mac {
+            Some(mac) => print!("{:<15} {:02x?}", neighbor.address, mac),
+            None => print!("{:<15} (incomplete)", neighbor.address),
+        }
+        println!(" {} {}s", neighbor.interface, neighbor.age_ms / 1000);
+    }
+}
+
+fn ndp() {
+    for neighbor in ndp::neighbors() {
+        match neighbor.mac {
+            Some(mac) => print!("{:<25} {:02x?}", neighbor.address, mac),
+            None => print!("{:<25} (incomplete)", neighbor.address),
+        }
+        println!(" {} {}s", neighbor.interface, neighbor.age_ms / 1000);
+    }
+}
+
+fn route() {
+    for route in ip::routes() {
+        print!("{}/{}", route.destination, route.prefix_len);
+        if let Some(gateway) = route.gateway {
+            print!(" via {}", gateway);
+        }
+        println!(" dev {}", route.interface);
+    }
+    for route in ipv6::routes() {
+        print!("{}/{}", route.destination, route.prefix_len);
+        if let Some(gateway) = route.gateway {
+            print!(" via {}", gateway);
+        }
+        print!(" dev {}", route.interface);
+        match route.expires_ms {
+            Some(expires) => println!(
+                " {}s left",
+                expires.saturating_sub(time::uptime_ms()) / 1000
+            ),
+            None => println!(),
+        }
+    }
+}
+
+fn netstat() {
+    for connection in tcp::connections() {
+        println!(
+            "{:<21} {:<21} {:<12} send {} recv {}",
+            connection.local.to_string(),
+            connection.remote.to_string(),
+            format!("{:?}", connection.state),
+            connection.send_queue,
+            connection.recv_queue
+        );
+    }
+}
+
+async fn host(name: &str) {
+    match dns::lookup_ipv4(name).await {
+        Ok(addresses) => {
+            for address in addresses {
+                println!("{} has address {}", name, address);
+            }
+        }
+        Err(err) => println!("host: {}: {:?}", name, err),
+    }
+    if let Ok(addresses) = dns::lookup_ipv6(name).await {
+        for address in addresses {
+            println!("{} has IPv6 address {}", name, address);
+        }
+    }
+}
+
+async fn ping(addr: IpAddr, count: u16) {
+    let ident = icmp::new_ident();
+    let mut received = 0;
+    let mut total_rtt = 0;
+    println!("PING {}: {} data bytes", addr, icmp::PING_DATA_SIZE);
+    for seq in 0..count {
+        let start = time::uptime_ms();
+        let result = match addr {
+            IpAddr::V4(addr) => icmp::echo(addr, ident, seq, icmp::PING_TIMEOUT_MS).await,
+            IpAddr::V6(addr) => icmpv6::echo(addr, ident, seq, icmp::PING_TIMEOUT_MS).await,
+        };
+        match result {
+            Ok(reply) => {
+                received += 1;
+                total_rtt += reply.rtt_ms;
+                println!(
+                    "{} bytes from {}: icmp_seq={} ttl={} time={} ms",
+                    reply.len, reply.from, reply.seq, reply.ttl, reply.rtt_ms
+                );
+            }
+            Err(PingError::Timeout) => println!("request timeout for icmp_seq={}", seq),
+            Err(PingError::Unreachable(from, code)) => println!(
+                "from {}: destination unreachable (code {}) icmp_seq={}",
+                from, code, seq
+            ),
+            Err(PingError::TimeExceeded(from)) => {
+                println!("from {}: time to live exceeded icmp_seq={}", from, seq)
+            }
+            Err(PingError::Ip(err)) => {
+                println!("ping: {:?}", err);
+                return;
+            }
+        }
+        if seq + 1 < count {
+            time::sleep(1000u64.saturating_sub(time::uptime_ms() - start)).await;
+        }
+    }
+
+    print!(
+        "{} packets transmitted, {} received, {}% packet loss",
+        count,
+        received,
+        if count == 0 {
+            0
+        } else {
+            (count - received) as u32 * 100 / count as u32
+        }
+    );
+    if received > 0 {
+        println!(", average {} ms", total_rtt / received as u64);
+    } else {
+        println!();
     }
 }
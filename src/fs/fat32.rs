@@ -1,28 +1,1441 @@
+use super::vfs::{self, FileSystem, FileType, FsError, Inode, Metadata};
+use crate::{
+    block::BlockDevice,
+    rtc::{self, DateTime},
+};
+use alloc::{
+    collections::BTreeMap,
+    format,
+    string::String,
+    sync::{Arc, Weak},
+    vec,
+    vec::Vec,
+};
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+
+const DIR_ENTRY_SIZE: usize = 32;
+/// A directory may hold at most 65536 entries, i.e. 2 MiB.
+const MAX_DIR_ENTRIES: usize = 65536;
+const MAX_FILE_SIZE: u64 = u32::MAX as u64;
+const MAX_NAME_LEN: usize = 255;
+
+const ATTR_READ_ONLY: u8 = 0x01;
+const ATTR_HIDDEN: u8 = 0x02;
+const ATTR_SYSTEM: u8 = 0x04;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_LONG_NAME: u8 = ATTR_READ_ONLY | ATTR_HIDDEN | ATTR_SYSTEM | ATTR_VOLUME_ID;
+
+const LAST_LONG_ENTRY: u8 = 0x40;
+const DELETED_ENTRY: u8 = 0xE5;
+
+/// `DirectoryEntry::userattrib` flags set by Windows for all-lowercase names.
+const LOWERCASE_BASE: u8 = 0x08;
+const LOWERCASE_EXT: u8 = 0x10;
+
+/// Byte offsets of the 13 UTF-16 characters in a long file name entry.
+const LFN_CHAR_OFFSETS: [usize; 13] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+const LFN_CHARS_PER_ENTRY: usize = LFN_CHAR_OFFSETS.len();
+
+/// Characters allowed in short names besides upper case letters and digits.
+const SHORT_NAME_SPECIAL: &[u8] = b"$%'-_@~`!(){}^#&";
+
+const FAT_ENTRY_MASK: u32 = 0x0FFF_FFFF;
+const FREE_CLUSTER: u32 = 0;
+const END_OF_CHAIN: u32 = 0x0FFF_FFF8;
+const END_OF_CHAIN_MARK: u32 = 0x0FFF_FFFF;
+const BAD_CLUSTER: u32 = 0x0FFF_FFF7;
+
+const FSINFO_LEAD_SIGNATURE: u32 = 0x4161_5252;
+const FSINFO_STRUCT_SIGNATURE: u32 = 0x6141_7272;
+const FSINFO_TRAIL_SIGNATURE: u32 = 0xAA55_0000;
+
+#[derive(Debug, Clone, Copy)]
 #[repr(C, packed)]
-struct DirectoryEntry {
-    name: [u8; 8],
-    ext: [u8; 3],
-    attrib: u8,
-    userattrib: u8,
-    undelete: u8,
-    createtime: u16,
-    createdate: u16,
-    accessdate: u16,
-    clusterhigh: u16,
-    modifiedtime: u16,
-    modifieddate: u16,
-    clusterlow: u16,
-    filesize: u32,
+pub struct DirectoryEntry {
+    pub name: [u8; 8],
+    pub ext: [u8; 3],
+    pub attrib: u8,
+    pub userattrib: u8,
+    /// Tenths of a second added to `createtime`, which has 2s resolution.
+    pub undelete: u8,
+    pub createtime: u16,
+    pub createdate: u16,
+    pub accessdate: u16,
+    pub clusterhigh: u16,
+    pub modifiedtime: u16,
+    pub modifieddate: u16,
+    pub clusterlow: u16,
+    pub filesize: u32,
+}
+
+impl DirectoryEntry {
+    pub fn parse(bytes: &[u8]) -> DirectoryEntry {
+        let u16_at = |offset: usize| u16::from_le_bytes([bytes[offset], bytes[offset + 1]]);
+        let mut name = [0; 8];
+        let mut ext = [0; 3];
+        name.copy_from_slice(&bytes[0..8]);
+        ext.copy_from_slice(&bytes[8..11]);
+
+        DirectoryEntry {
+            name,
+            ext,
+            attrib: bytes[11],
+            userattrib: bytes[12],
+            undelete: bytes[13],
+            createtime: u16_at(14),
+            createdate: u16_at(16),
+            accessdate: u16_at(18),
+            clusterhigh: u16_at(20),
+            modifiedtime: u16_at(22),
+            modifieddate: u16_at(24),
+            clusterlow: u16_at(26),
+            filesize: u32::from_le_bytes([bytes[28], bytes[29], bytes[30], bytes[31]]),
+        }
+    }
+
+    /// A new entry with all timestamps set to `now`.
+    pub fn new(short_name: [u8; 11], attrib: u8, cluster: u32, now: &DateTime) -> DirectoryEntry {
+        let (date, time, tenths) = fat_timestamp(now);
+        let mut entry = DirectoryEntry::parse(&[0; DIR_ENTRY_SIZE]);
+        entry.set_short_name(short_name);
+        entry.attrib = attrib;
+        entry.undelete = tenths;
+        entry.createtime = time;
+        entry.createdate = date;
+        entry.accessdate = date;
+        entry.modifiedtime = time;
+        entry.modifieddate = date;
+        entry.set_cluster(cluster);
+        entry
+    }
+
+    pub fn to_bytes(&self) -> [u8; DIR_ENTRY_SIZE] {
+        let mut raw = [0; DIR_ENTRY_SIZE];
+        raw[0..11].copy_from_slice(&self.short_name());
+        raw[11] = self.attrib;
+        raw[12] = self.userattrib;
+        raw[13] = self.undelete;
+        raw[14..16].copy_from_slice(&{ self.createtime }.to_le_bytes());
+        raw[16..18].copy_from_slice(&{ self.createdate }.to_le_bytes());
+        raw[18..20].copy_from_slice(&{ self.accessdate }.to_le_bytes());
+        raw[20..22].copy_from_slice(&{ self.clusterhigh }.to_le_bytes());
+        raw[22..24].copy_from_slice(&{ self.modifiedtime }.to_le_bytes());
+        raw[24..26].copy_from_slice(&{ self.modifieddate }.to_le_bytes());
+        raw[26..28].copy_from_slice(&{ self.clusterlow }.to_le_bytes());
+        raw[28..32].copy_from_slice(&{ self.filesize }.to_le_bytes());
+        raw
+    }
+
+    pub fn cluster(&self) -> u32 {
+        (self.clusterhigh as u32) << 16 | self.clusterlow as u32
+    }
+
+    pub fn set_cluster(&mut self, cluster: u32) {
+        self.clusterhigh = (cluster >> 16) as u16;
+        self.clusterlow = cluster as u16;
+    }
+
+    /// Updates the modification and access dates to `now`.
+    pub fn touch(&mut self, now: &DateTime) {
+        let (date, time, _) = fat_timestamp(now);
+        self.modifiedtime = time;
+        self.modifieddate = date;
+        self.accessdate = date;
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.attrib & ATTR_DIRECTORY != 0
+    }
+
+    /// The 11 byte name the long file name checksum is calculated over.
+    pub fn short_name(&self) -> [u8; 11] {
+        let mut short_name = [0; 11];
+        short_name[..8].copy_from_slice(&self.name);
+        short_name[8..].copy_from_slice(&self.ext);
+        short_name
+    }
+
+    fn set_short_name(&mut self, short_name: [u8; 11]) {
+        self.name.copy_from_slice(&short_name[..8]);
+        self.ext.copy_from_slice(&short_name[8..]);
+    }
+
+    /// Formats the 8.3 name as `NAME.EXT`, honouring the lowercase flags.
+    pub fn display_name(&self) -> String {
+        let mut name = String::new();
+        let base = self.name;
+        let ext = self.ext;
+
+        for (i, &b) in base.iter().enumerate() {
+            // 0x05 stands in for a real 0xE5, which would mark the entry deleted
+            let b = if i == 0 && b == 0x05 {
+                DELETED_ENTRY
+            } else {
+                b
+            };
+            if b == b' ' {
+                break;
+            }
+            name.push(if self.userattrib & LOWERCASE_BASE != 0 {
+                b.to_ascii_lowercase() as char
+            } else {
+                b as char
+            });
+        }
+
+        if ext[0] != b' ' {
+            name.push('.');
+            for &b in ext.iter().take_while(|&&b| b != b' ') {
+                name.push(if self.userattrib & LOWERCASE_EXT != 0 {
+                    b.to_ascii_lowercase() as char
+                } else {
+                    b as char
+                });
+            }
+        }
+
+        name
+    }
+}
+
+/// Checksum of an 8.3 name stored in every long file name entry.
+pub fn lfn_checksum(short_name: &[u8; 11]) -> u8 {
+    short_name
+        .iter()
+        .fold(0u8, |sum, &b| sum.rotate_right(1).wrapping_add(b))
+}
+
+/// Builds the long file name entries for `name` in the order they are
+/// stored on disk, i.e. the last part of the name first.
+fn long_name_entries(name: &str, checksum: u8) -> Vec<u8> {
+    let mut units: Vec<u16> = name.encode_utf16().collect();
+    let count = units.len().div_ceil(LFN_CHARS_PER_ENTRY);
+    // The name is NUL terminated unless it fills the last entry exactly, and
+    // the rest is padded with 0xFFFF
+    if !units.len().is_multiple_of(LFN_CHARS_PER_ENTRY) {
+        units.push(0x0000);
+    }
+    units.resize(count * LFN_CHARS_PER_ENTRY, 0xFFFF);
+
+    let mut entries = Vec::with_capacity(count * DIR_ENTRY_SIZE);
+    for ord in (1..=count).rev() {
+        let mut raw = [0u8; DIR_ENTRY_SIZE];
+        raw[0] = ord as u8 | if ord == count { LAST_LONG_ENTRY } else { 0 };
+        raw[11] = ATTR_LONG_NAME;
+        raw[13] = checksum;
+        let part = &units[(ord - 1) * LFN_CHARS_PER_ENTRY..ord * LFN_CHARS_PER_ENTRY];
+        for (&offset, unit) in LFN_CHAR_OFFSETS.iter().zip(part) {
+            raw[offset..offset + 2].copy_from_slice(&unit.to_le_bytes());
+        }
+        entries.extend_from_slice(&raw);
+    }
+    entries
+}
+
+fn validate_name(name: &str) -> Result<(), FsError> {
+    let invalid = name.is_empty()
+        || name.encode_utf16().count() > MAX_NAME_LEN
+        || name.ends_with(['.', ' '])
+        || name.chars().any(|c| c < ' ' || "\"*/:<>?\\|".contains(c));
+    if invalid {
+        Err(FsError::InvalidInput)
+    } else {
+        Ok(())
+    }
+}
+
+fn is_short_name_char(b: u8) -> bool {
+    b.is_ascii_uppercase() || b.is_ascii_digit() || SHORT_NAME_SPECIAL.contains(&b)
+}
+
+/// Returns the 8.3 name and lowercase flags if `name` can be stored without
+/// a long file name.
+fn exact_short_name(name: &str) -> Option<([u8; 11], u8)> {
+    let (base, ext) = name.rsplit_once('.').unwrap_or((name, ""));
+    if base.is_empty() || base.len() > 8 || ext.len() > 3 {
+        return None;
+    }
+
+    let mut short_name = [b' '; 11];
+    let mut flags = 0;
+    let (base_field, ext_field) = short_name.split_at_mut(8);
+    for (part, field, lowercase_flag) in [
+        (base, base_field, LOWERCASE_BASE),
+        (ext, ext_field, LOWERCASE_EXT),
+    ] {
+        let lower = part.bytes().any(|b| b.is_ascii_lowercase());
+        let upper = part.bytes().any(|b| b.is_ascii_uppercase());
+        if lower && upper {
+            return None;
+        }
+        for (slot, b) in field.iter_mut().zip(part.bytes()) {
+            *slot = b.to_ascii_uppercase();
+            if !is_short_name_char(*slot) {
+                return None;
+            }
+        }
+        if lower {
+            flags |= lowercase_flag;
+        }
+    }
+
+    Some((short_name, flags))
+}
+
+/// The short name a long name is abbreviated to before a numeric tail is
+/// added, following the basis name algorithm from the FAT specification.
+fn basis_name(name: &str) -> [u8; 11] {
+    let name = name.trim_start_matches('.');
+    let (base, ext) = name.rsplit_once('.').unwrap_or((name, ""));
+
+    let mut short_name = [b' '; 11];
+    let (base_field, ext_field) = short_name.split_at_mut(8);
+    for (part, field) in [(base, base_field), (ext, ext_field)] {
+        let chars = part.chars().filter(|&c| c != ' ' && c != '.');
+        for (slot, c) in field.iter_mut().zip(chars) {
+            let b = if c.is_ascii() {
+                c.to_ascii_uppercase() as u8
+            } else {
+                b'_'
+            };
+            *slot = if is_short_name_char(b) { b } else { b'_' };
+        }
+    }
+    if short_name[0] == b' ' {
+        short_name[0] = b'_';
+    }
+    short_name
+}
+
+/// Replaces the end of the basis name with `~n`.
+fn numeric_tail(basis: &[u8; 11], n: u32) -> [u8; 11] {
+    let tail = format!("~{}", n);
+    let base_len = basis[..8].iter().take_while(|&&b| b != b' ').count();
+    let keep = base_len.min(8 - tail.len());
+
+    let mut short_name = [b' '; 11];
+    short_name[..keep].copy_from_slice(&basis[..keep]);
+    short_name[keep..keep + tail.len()].copy_from_slice(tail.as_bytes());
+    short_name[8..].copy_from_slice(&basis[8..]);
+    short_name
+}
+
+/// Packs `now` into a FAT date, a FAT time with 2s resolution and the
+/// tenths of a second byte that refines the creation time.
+fn fat_timestamp(now: &DateTime) -> (u16, u16, u8) {
+    let year = now.year.saturating_sub(1980).min(127);
+    let date = year << 9 | (now.month as u16) << 5 | now.day as u16;
+    let time = (now.hour as u16) << 11 | (now.minute as u16) << 5 | (now.second as u16 / 2);
+    (date, time, now.second % 2 * 100)
 }
 
 #[repr(C, packed)]
 struct ClusterToLBA {
-    FirstUsableCluster: u32,
-    SectorsPerCluster: u32,
+    first_usable_cluster: u32,
+    sectors_per_cluster: u32,
 }
 
 impl ClusterToLBA {
     fn cluster_to_lba(&self, cluster: u32) -> u32 {
-        self.FirstUsableCluster + cluster * self.SectorsPerCluster - (2 * self.SectorsPerCluster)
+        // Clusters are numbered from 2
+        self.first_usable_cluster + (cluster - 2) * self.sectors_per_cluster
+    }
+}
+
+/// The BIOS parameter block at the start of the volume.
+#[derive(Debug, Clone)]
+pub struct BiosParameterBlock {
+    pub bytes_per_sector: u16,
+    pub sectors_per_cluster: u8,
+    pub reserved_sectors: u16,
+    pub fat_count: u8,
+    pub total_sectors: u32,
+    pub sectors_per_fat: u32,
+    pub root_cluster: u32,
+    pub fs_info_sector: u16,
+    pub volume_id: u32,
+    pub volume_label: [u8; 11],
+}
+
+impl BiosParameterBlock {
+    pub fn parse(sector: &[u8]) -> Result<BiosParameterBlock, FsError> {
+        let u16_at = |offset: usize| u16::from_le_bytes([sector[offset], sector[offset + 1]]);
+        let u32_at = |offset: usize| {
+            u32::from_le_bytes([
+                sector[offset],
+                sector[offset + 1],
+                sector[offset + 2],
+                sector[offset + 3],
+            ])
+        };
+
+        if sector.len() < 512 || sector[510] != 0x55 || sector[511] != 0xAA {
+            return Err(FsError::Corrupted);
+        }
+
+        let bytes_per_sector = u16_at(11);
+        let sectors_per_cluster = sector[13];
+        let root_entry_count = u16_at(17);
+        let sectors_per_fat_16 = u16_at(22);
+
+        if !matches!(bytes_per_sector, 512 | 1024 | 2048 | 4096)
+            || !sectors_per_cluster.is_power_of_two()
+        {
+            return Err(FsError::Corrupted);
+        }
+        // FAT12/16 have a fixed size root directory and keep the FAT size in
+        // the 16-bit field
+        if root_entry_count != 0 || sectors_per_fat_16 != 0 {
+            return Err(FsError::Unsupported);
+        }
+
+        let total_sectors = match u16_at(19) {
+            0 => u32_at(32),
+            count => count as u32,
+        };
+
+        let mut volume_label = [0; 11];
+        volume_label.copy_from_slice(&sector[71..82]);
+
+        let bpb = BiosParameterBlock {
+            bytes_per_sector,
+            sectors_per_cluster,
+            reserved_sectors: u16_at(14),
+            fat_count: sector[16],
+            total_sectors,
+            sectors_per_fat: u32_at(36),
+            root_cluster: u32_at(44),
+            fs_info_sector: u16_at(48),
+            volume_id: u32_at(67),
+            volume_label,
+        };
+        // The boot sector and at least one FAT come before the data, which
+        // has to fit in the volume and hold the root directory
+        if bpb.reserved_sectors == 0 || bpb.fat_count == 0 || bpb.sectors_per_fat == 0 {
+            return Err(FsError::Corrupted);
+        }
+        let cluster_count = bpb.cluster_count().ok_or(FsError::Corrupted)?;
+        if bpb.root_cluster < 2 || bpb.root_cluster - 2 >= cluster_count {
+            return Err(FsError::Corrupted);
+        }
+        // Each FAT needs an entry for every cluster, plus the two reserved
+        // ones, or lookups run into the next FAT or the data
+        let fat_entries = bpb.sectors_per_fat as u64 * bytes_per_sector as u64 / 4;
+        if fat_entries < cluster_count as u64 + 2 {
+            return Err(FsError::Corrupted);
+        }
+        Ok(bpb)
+    }
+
+    /// `None` if the FATs end past what a `u32` can count.
+    fn first_data_sector(&self) -> Option<u32> {
+        (self.fat_count as u32)
+            .checked_mul(self.sectors_per_fat)?
+            .checked_add(self.reserved_sectors as u32)
+    }
+
+    /// `None` if the FATs take up more than the whole volume.
+    fn cluster_count(&self) -> Option<u32> {
+        let data_sectors = self.total_sectors.checked_sub(self.first_data_sector()?)?;
+        Some(data_sectors / self.sectors_per_cluster as u32)
+    }
+}
+
+/// The FSInfo sector, which caches allocation hints.
+#[derive(Debug, Clone, Copy)]
+pub struct FsInfo {
+    /// Last known number of free clusters, `0xFFFFFFFF` if unknown.
+    pub free_count: u32,
+    /// Cluster to start searching for free clusters at, `0xFFFFFFFF` if unknown.
+    pub next_free: u32,
+}
+
+impl FsInfo {
+    pub fn parse(sector: &[u8]) -> Result<FsInfo, FsError> {
+        let u32_at = |offset: usize| {
+            u32::from_le_bytes([
+                sector[offset],
+                sector[offset + 1],
+                sector[offset + 2],
+                sector[offset + 3],
+            ])
+        };
+
+        if u32_at(0) != FSINFO_LEAD_SIGNATURE
+            || u32_at(484) != FSINFO_STRUCT_SIGNATURE
+            || u32_at(508) != FSINFO_TRAIL_SIGNATURE
+        {
+            return Err(FsError::Corrupted);
+        }
+
+        Ok(FsInfo {
+            free_count: u32_at(488),
+            next_free: u32_at(492),
+        })
+    }
+
+    /// Stores the hints in an FSInfo sector read from disk.
+    pub fn write_to(&self, sector: &mut [u8]) {
+        sector[488..492].copy_from_slice(&self.free_count.to_le_bytes());
+        sector[492..496].copy_from_slice(&self.next_free.to_le_bytes());
+    }
+}
+
+/// A directory entry with its long file name resolved.
+#[derive(Debug, Clone)]
+pub struct FatDirEntry {
+    pub name: String,
+    pub entry: DirectoryEntry,
+    /// Index of the short entry within its directory.
+    pub index: usize,
+    /// Number of entries used, including the long file name entries.
+    pub slots: usize,
+}
+
+impl FatDirEntry {
+    /// Compares case-insensitively against both the long and the short name.
+    fn matches(&self, name: &str) -> bool {
+        self.name.eq_ignore_ascii_case(name) || self.entry.display_name().eq_ignore_ascii_case(name)
+    }
+}
+
+/// Collects the pieces of a long file name, which are stored in reverse
+/// order in front of the short entry they belong to.
+struct LongNameBuilder {
+    parts: Vec<[u16; 13]>,
+    checksum: u8,
+    next_ord: u8,
+}
+
+impl LongNameBuilder {
+    fn new() -> LongNameBuilder {
+        LongNameBuilder {
+            parts: Vec::new(),
+            checksum: 0,
+            next_ord: 0,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.parts.len()
+    }
+
+    fn reset(&mut self) {
+        self.parts.clear();
+        self.next_ord = 0;
+    }
+
+    fn push(&mut self, raw: &[u8]) {
+        let ord = raw[0] & !LAST_LONG_ENTRY;
+        if raw[0] & LAST_LONG_ENTRY != 0 {
+            self.reset();
+            self.checksum = raw[13];
+        } else if ord != self.next_ord || raw[13] != self.checksum {
+            // Out of sequence, so whatever we collected is orphaned
+            self.reset();
+            return;
+        }
+        if ord == 0 {
+            self.reset();
+            return;
+        }
+
+        let mut part = [0u16; 13];
+        for (i, &offset) in LFN_CHAR_OFFSETS.iter().enumerate() {
+            part[i] = u16::from_le_bytes([raw[offset], raw[offset + 1]]);
+        }
+        self.parts.push(part);
+        self.next_ord = ord - 1;
+    }
+
+    /// Returns the long name if a complete one matching `entry` was collected.
+    fn finish(&mut self, entry: &DirectoryEntry) -> Option<String> {
+        let complete = !self.parts.is_empty() && self.next_ord == 0;
+        let matches = self.checksum == lfn_checksum(&entry.short_name());
+        let name = if complete && matches {
+            let units = self
+                .parts
+                .iter()
+                .rev()
+                .flat_map(|part| part.iter().copied())
+                .take_while(|&unit| unit != 0x0000 && unit != 0xFFFF);
+            Some(
+                char::decode_utf16(units)
+                    .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+                    .collect(),
+            )
+        } else {
+            None
+        };
+        self.reset();
+        name
+    }
+}
+
+/// Where the short entry of a file or directory lives.
+#[derive(Debug, Clone, Copy)]
+struct EntryLocation {
+    /// First cluster of the directory holding the entry.
+    dir: u32,
+    /// Index of the short entry within that directory.
+    index: usize,
+}
+
+/// State shared by every inode referring to the same directory entry.
+struct Node {
+    /// `None` for the root directory, which has no entry of its own.
+    location: Option<EntryLocation>,
+    entry: DirectoryEntry,
+    removed: bool,
+}
+
+/// Shared node state keyed by the directory cluster and entry index.
+type NodeTable = BTreeMap<(u32, usize), Weak<Mutex<Node>>>;
+
+/// A mounted FAT32 volume.
+pub struct Volume {
+    disk: Arc<dyn BlockDevice>,
+    bpb: BiosParameterBlock,
+    fs_info: Mutex<Option<FsInfo>>,
+    fs_info_dirty: AtomicBool,
+    cluster_size: usize,
+    cluster_count: u32,
+    first_data_sector: u32,
+    /// Open files and directories by the location of their entry, so that
+    /// every lookup of an entry sees the same size and start cluster.
+    nodes: Mutex<NodeTable>,
+}
+
+impl Volume {
+    pub fn new(disk: Arc<dyn BlockDevice>) -> Result<Volume, FsError> {
+        let mut sector = vec![0u8; disk.sector_size()];
+        disk.read_sectors(0, &mut sector)?;
+        let bpb = BiosParameterBlock::parse(&sector)?;
+        if bpb.bytes_per_sector as usize != disk.sector_size() {
+            return Err(FsError::Unsupported);
+        }
+        if bpb.total_sectors as u64 > disk.sector_count() {
+            return Err(FsError::Corrupted);
+        }
+        // Both were checked by the parser
+        let cluster_count = bpb.cluster_count().ok_or(FsError::Corrupted)?;
+        let first_data_sector = bpb.first_data_sector().ok_or(FsError::Corrupted)?;
+
+        let fs_info = match bpb.fs_info_sector {
+            0 | 0xFFFF => None,
+            lba => {
+                disk.read_sectors(lba as u64, &mut sector)?;
+                FsInfo::parse(&sector).ok()
+            }
+        };
+
+        Ok(Volume {
+            cluster_size: bpb.bytes_per_sector as usize * bpb.sectors_per_cluster as usize,
+            cluster_count,
+            first_data_sector,
+            disk,
+            bpb,
+            fs_info: Mutex::new(fs_info),
+            fs_info_dirty: AtomicBool::new(false),
+            nodes: Mutex::new(BTreeMap::new()),
+        })
+    }
+
+    pub fn bpb(&self) -> &BiosParameterBlock {
+        &self.bpb
+    }
+
+    pub fn fs_info(&self) -> Option<FsInfo> {
+        *self.fs_info.lock()
+    }
+
+    pub fn cluster_size(&self) -> usize {
+        self.cluster_size
+    }
+
+    fn cluster_to_lba(&self, cluster: u32) -> Result<u64, FsError> {
+        if !self.is_valid_cluster(cluster) {
+            return Err(FsError::Corrupted);
+        }
+        let lba = ClusterToLBA {
+            first_usable_cluster: self.first_data_sector,
+            sectors_per_cluster: self.bpb.sectors_per_cluster as u32,
+        }
+        .cluster_to_lba(cluster);
+        Ok(lba as u64)
+    }
+
+    fn is_valid_cluster(&self, cluster: u32) -> bool {
+        cluster >= 2 && cluster < self.cluster_count + 2
+    }
+
+    /// Reads the FAT entry for `cluster` from the first FAT.
+    fn fat_entry(&self, cluster: u32) -> Result<u32, FsError> {
+        let sector_size = self.bpb.bytes_per_sector as usize;
+        let offset = cluster as usize * 4;
+        let lba = self.bpb.reserved_sectors as u64 + (offset / sector_size) as u64;
+
+        let mut sector = vec![0u8; sector_size];
+        self.disk.read_sectors(lba, &mut sector)?;
+        let offset = offset % sector_size;
+        let entry = u32::from_le_bytes([
+            sector[offset],
+            sector[offset + 1],
+            sector[offset + 2],
+            sector[offset + 3],
+        ]);
+        Ok(entry & FAT_ENTRY_MASK)
+    }
+
+    /// Sets the FAT entry for `cluster` in every copy of the FAT, keeping
+    /// the reserved top four bits.
+    fn set_fat_entry(&self, cluster: u32, value: u32) -> Result<(), FsError> {
+        let sector_size = self.bpb.bytes_per_sector as usize;
+        let offset = cluster as usize * 4;
+        let mut sector = vec![0u8; sector_size];
+
+        for fat in 0..self.bpb.fat_count as u64 {
+            let lba = self.bpb.reserved_sectors as u64
+                + fat * self.bpb.sectors_per_fat as u64
+                + (offset / sector_size) as u64;
+            self.disk.read_sectors(lba, &mut sector)?;
+
+            let entry = &mut sector[offset % sector_size..offset % sector_size + 4];
+            let old = u32::from_le_bytes([entry[0], entry[1], entry[2], entry[3]]);
+            let new = old & !FAT_ENTRY_MASK | value & FAT_ENTRY_MASK;
+            entry.copy_from_slice(&new.to_le_bytes());
+
+            self.disk.write_sectors(lba, &sector)?;
+        }
+        Ok(())
+    }
+
+    /// Allocates a zeroed cluster and links it after `prev`, if given. The
+    /// search starts at the next free hint from the FSInfo sector.
+    pub fn allocate_cluster(&self, prev: Option<u32>) -> Result<u32, FsError> {
+        let mut fs_info = self.fs_info.lock();
+        let hint = fs_info
+            .map(|info| info.next_free)
+            .filter(|&cluster| self.is_valid_cluster(cluster))
+            .unwrap_or(2);
+
+        let mut found = None;
+        for i in 0..self.cluster_count {
+            let cluster = 2 + (hint - 2 + i) % self.cluster_count;
+            if self.fat_entry(cluster)? == FREE_CLUSTER {
+                found = Some(cluster);
+                break;
+            }
+        }
+        let cluster = found.ok_or(FsError::NoSpace)?;
+
+        self.write_cluster(cluster, &vec![0; self.cluster_size])?;
+        self.set_fat_entry(cluster, END_OF_CHAIN_MARK)?;
+        if let Some(prev) = prev {
+            self.set_fat_entry(prev, cluster)?;
+        }
+
+        if let Some(info) = fs_info.as_mut() {
+            if info.free_count != u32::MAX {
+                info.free_count = info.free_count.saturating_sub(1);
+            }
+            info.next_free = cluster + 1;
+            self.fs_info_dirty.store(true, Ordering::Relaxed);
+        }
+        Ok(cluster)
+    }
+
+    /// Marks every cluster of the chain starting at `start` as free.
+    pub fn free_chain(&self, start: u32) -> Result<(), FsError> {
+        let chain = self.cluster_chain(start)?;
+        self.free_clusters(&chain)
+    }
+
+    fn free_clusters(&self, clusters: &[u32]) -> Result<(), FsError> {
+        if clusters.is_empty() {
+            return Ok(());
+        }
+
+        let mut fs_info = self.fs_info.lock();
+        for &cluster in clusters {
+            self.set_fat_entry(cluster, FREE_CLUSTER)?;
+        }
+
+        if let Some(info) = fs_info.as_mut() {
+            if info.free_count != u32::MAX {
+                info.free_count = (info.free_count + clusters.len() as u32).min(self.cluster_count);
+            }
+            self.fs_info_dirty.store(true, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    /// Grows or shrinks the chain starting at `start` to `count` clusters
+    /// and returns its new start, which is 0 once the chain is empty.
+    pub fn resize_chain(&self, start: u32, count: usize) -> Result<u32, FsError> {
+        let mut chain = self.cluster_chain(start)?;
+        let old_len = chain.len();
+
+        if count < old_len {
+            if count > 0 {
+                self.set_fat_entry(chain[count - 1], END_OF_CHAIN_MARK)?;
+            }
+            self.free_clusters(&chain[count..])?;
+            chain.truncate(count);
+        }
+
+        while chain.len() < count {
+            match self.allocate_cluster(chain.last().copied()) {
+                Ok(cluster) => chain.push(cluster),
+                Err(err) => {
+                    // Give back whatever was allocated before running out
+                    if old_len > 0 {
+                        self.set_fat_entry(chain[old_len - 1], END_OF_CHAIN_MARK)?;
+                    }
+                    self.free_clusters(&chain[old_len..])?;
+                    return Err(err);
+                }
+            }
+        }
+
+        Ok(chain.first().copied().unwrap_or(0))
+    }
+
+    /// Follows the cluster chain starting at `start`.
+    pub fn cluster_chain(&self, start: u32) -> Result<Vec<u32>, FsError> {
+        let mut chain = Vec::new();
+        let mut cluster = start;
+        if cluster == 0 {
+            return Ok(chain);
+        }
+
+        loop {
+            if !self.is_valid_cluster(cluster) || chain.len() > self.cluster_count as usize {
+                // Out of range or longer than the volume, so the FAT is broken
+                return Err(FsError::Corrupted);
+            }
+            chain.push(cluster);
+
+            match self.fat_entry(cluster)? {
+                next if next >= END_OF_CHAIN => return Ok(chain),
+                BAD_CLUSTER | FREE_CLUSTER => return Err(FsError::Corrupted),
+                next => cluster = next,
+            }
+        }
+    }
+
+    pub fn read_cluster(&self, cluster: u32, buf: &mut [u8]) -> Result<(), FsError> {
+        let lba = self.cluster_to_lba(cluster)?;
+        Ok(self.disk.read_sectors(lba, &mut buf[..self.cluster_size])?)
+    }
+
+    pub fn write_cluster(&self, cluster: u32, buf: &[u8]) -> Result<(), FsError> {
+        let lba = self.cluster_to_lba(cluster)?;
+        Ok(self.disk.write_sectors(lba, &buf[..self.cluster_size])?)
+    }
+
+    /// Reads `buf.len()` bytes starting `offset` bytes into `chain`.
+    fn read_chain(&self, chain: &[u32], offset: usize, buf: &mut [u8]) -> Result<(), FsError> {
+        let mut cluster_buf = vec![0u8; self.cluster_size];
+        let mut done = 0;
+        while done < buf.len() {
+            let position = offset + done;
+            let cluster = *chain
+                .get(position / self.cluster_size)
+                .ok_or(FsError::Corrupted)?;
+            self.read_cluster(cluster, &mut cluster_buf)?;
+
+            let start = position % self.cluster_size;
+            let count = (self.cluster_size - start).min(buf.len() - done);
+            buf[done..done + count].copy_from_slice(&cluster_buf[start..start + count]);
+            done += count;
+        }
+        Ok(())
+    }
+
+    /// Writes `data` starting `offset` bytes into `chain`.
+    fn write_chain(&self, chain: &[u32], offset: usize, data: &[u8]) -> Result<(), FsError> {
+        let mut cluster_buf = vec![0u8; self.cluster_size];
+        let mut done = 0;
+        while done < data.len() {
+            let position = offset + done;
+            let cluster = *chain
+                .get(position / self.cluster_size)
+                .ok_or(FsError::Corrupted)?;
+
+            let start = position % self.cluster_size;
+            let count = (self.cluster_size - start).min(data.len() - done);
+            if count < self.cluster_size {
+                self.read_cluster(cluster, &mut cluster_buf)?;
+            }
+            cluster_buf[start..start + count].copy_from_slice(&data[done..done + count]);
+            self.write_cluster(cluster, &cluster_buf)?;
+            done += count;
+        }
+        Ok(())
+    }
+
+    /// Reads up to `buf.len()` bytes at `offset` from the file starting at
+    /// `start`, which is `size` bytes long.
+    pub fn read_file(
+        &self,
+        start: u32,
+        size: u64,
+        offset: u64,
+        buf: &mut [u8],
+    ) -> Result<usize, FsError> {
+        if offset >= size {
+            return Ok(0);
+        }
+        let len = buf.len().min((size - offset) as usize);
+        let chain = self.cluster_chain(start)?;
+        self.read_chain(&chain, offset as usize, &mut buf[..len])?;
+        Ok(len)
+    }
+
+    /// Writes `data` at `offset` into the file starting at `start`, whose
+    /// chain has to be long enough already.
+    pub fn write_file(&self, start: u32, offset: u64, data: &[u8]) -> Result<(), FsError> {
+        let chain = self.cluster_chain(start)?;
+        self.write_chain(&chain, offset as usize, data)
+    }
+
+    /// Lists the directory starting at `cluster`, skipping `.`, `..`,
+    /// deleted entries and the volume label.
+    pub fn read_dir(&self, cluster: u32) -> Result<Vec<FatDirEntry>, FsError> {
+        let mut entries = Vec::new();
+        let mut long_name = LongNameBuilder::new();
+        let mut cluster_buf = vec![0u8; self.cluster_size];
+        let entries_per_cluster = self.cluster_size / DIR_ENTRY_SIZE;
+
+        for (i, cluster) in self.cluster_chain(cluster)?.into_iter().enumerate() {
+            self.read_cluster(cluster, &mut cluster_buf)?;
+
+            for (j, raw) in cluster_buf.chunks_exact(DIR_ENTRY_SIZE).enumerate() {
+                match raw[0] {
+                    0x00 => return Ok(entries),
+                    DELETED_ENTRY => {
+                        long_name.reset();
+                        continue;
+                    }
+                    _ => {}
+                }
+
+                if raw[11] & 0x3F == ATTR_LONG_NAME {
+                    long_name.push(raw);
+                    continue;
+                }
+
+                let entry = DirectoryEntry::parse(raw);
+                let long_name_entries = long_name.len();
+                let name = long_name.finish(&entry);
+                if entry.attrib & ATTR_VOLUME_ID != 0 || raw[0] == b'.' {
+                    continue;
+                }
+
+                entries.push(FatDirEntry {
+                    slots: if name.is_some() {
+                        long_name_entries + 1
+                    } else {
+                        1
+                    },
+                    name: name.unwrap_or_else(|| entry.display_name()),
+                    entry,
+                    index: i * entries_per_cluster + j,
+                });
+            }
+        }
+
+        Ok(entries)
+    }
+
+    pub fn find(&self, dir_cluster: u32, name: &str) -> Result<FatDirEntry, FsError> {
+        self.read_dir(dir_cluster)?
+            .into_iter()
+            .find(|entry| entry.matches(name))
+            .ok_or(FsError::NotFound)
+    }
+
+    /// Overwrites raw directory entries starting at entry `index` of the
+    /// directory starting at `dir`.
+    fn write_slots(&self, dir: u32, index: usize, slots: &[u8]) -> Result<(), FsError> {
+        let chain = self.cluster_chain(dir)?;
+        self.write_chain(&chain, index * DIR_ENTRY_SIZE, slots)
+    }
+
+    /// Stores raw directory entries in the first run of free entries long
+    /// enough for them, growing the directory if there is none. Returns the
+    /// index of the first entry.
+    fn insert_slots(&self, dir: u32, slots: &[u8]) -> Result<usize, FsError> {
+        let count = slots.len() / DIR_ENTRY_SIZE;
+        let mut chain = self.cluster_chain(dir)?;
+        let mut raw = vec![0u8; chain.len() * self.cluster_size];
+        self.read_chain(&chain, 0, &mut raw)?;
+        let total = raw.len() / DIR_ENTRY_SIZE;
+
+        // Everything after the first 0x00 entry counts as free
+        let mut end = false;
+        let mut run = 0;
+        let mut first = None;
+        for (index, entry) in raw.chunks_exact(DIR_ENTRY_SIZE).enumerate() {
+            end |= entry[0] == 0x00;
+            if end || entry[0] == DELETED_ENTRY {
+                run += 1;
+                if run == count {
+                    first = Some(index + 1 - count);
+                    break;
+                }
+            } else {
+                run = 0;
+            }
+        }
+
+        let first = match first {
+            Some(first) => first,
+            None => {
+                // Extend the run of free entries at the end of the directory
+                if total - run + count > MAX_DIR_ENTRIES {
+                    return Err(FsError::NoSpace);
+                }
+                let missing = (count - run) * DIR_ENTRY_SIZE;
+                for _ in 0..missing.div_ceil(self.cluster_size) {
+                    let cluster = self.allocate_cluster(chain.last().copied())?;
+                    chain.push(cluster);
+                }
+                total - run
+            }
+        };
+
+        self.write_chain(&chain, first * DIR_ENTRY_SIZE, slots)?;
+        Ok(first)
+    }
+
+    /// Marks `count` directory entries starting at `first` as deleted.
+    fn remove_slots(&self, dir: u32, first: usize, count: usize) -> Result<(), FsError> {
+        let chain = self.cluster_chain(dir)?;
+        for index in first..first + count {
+            self.write_chain(&chain, index * DIR_ENTRY_SIZE, &[DELETED_ENTRY])?;
+        }
+        Ok(())
+    }
+
+    /// Adds `entry` to the directory starting at `dir` as `name`, using long
+    /// file name entries if it doesn't fit in 8.3. `existing` is the current
+    /// content of the directory, which generated short names must not clash
+    /// with.
+    fn add_entry(
+        &self,
+        dir: u32,
+        name: &str,
+        entry: &mut DirectoryEntry,
+        existing: &[FatDirEntry],
+    ) -> Result<EntryLocation, FsError> {
+        entry.userattrib &= !(LOWERCASE_BASE | LOWERCASE_EXT);
+        let mut slots = Vec::new();
+
+        match exact_short_name(name) {
+            Some((short_name, flags)) => {
+                entry.set_short_name(short_name);
+                entry.userattrib |= flags;
+            }
+            None => {
+                let basis = basis_name(name);
+                let short_name = (1..1_000_000)
+                    .map(|n| numeric_tail(&basis, n))
+                    .find(|candidate| {
+                        existing
+                            .iter()
+                            .all(|other| other.entry.short_name() != *candidate)
+                    })
+                    .ok_or(FsError::NoSpace)?;
+                entry.set_short_name(short_name);
+                slots = long_name_entries(name, lfn_checksum(&short_name));
+            }
+        }
+        slots.extend_from_slice(&entry.to_bytes());
+
+        let first = self.insert_slots(dir, &slots)?;
+        Ok(EntryLocation {
+            dir,
+            index: first + slots.len() / DIR_ENTRY_SIZE - 1,
+        })
+    }
+
+    /// Writes the `.` and `..` entries a new directory starts with.
+    fn write_dot_entries(&self, cluster: u32, parent: u32, now: &DateTime) -> Result<(), FsError> {
+        // The root directory is referred to as cluster 0
+        let parent = if parent == self.bpb.root_cluster {
+            0
+        } else {
+            parent
+        };
+
+        let mut slots = DirectoryEntry::new(*b".          ", ATTR_DIRECTORY, cluster, now)
+            .to_bytes()
+            .to_vec();
+        slots.extend_from_slice(
+            &DirectoryEntry::new(*b"..         ", ATTR_DIRECTORY, parent, now).to_bytes(),
+        );
+        self.write_file(cluster, 0, &slots)
+    }
+
+    /// Returns the state shared by everyone using the entry at `location`.
+    fn node(&self, location: EntryLocation, entry: DirectoryEntry) -> Arc<Mutex<Node>> {
+        let mut nodes = self.nodes.lock();
+        let key = (location.dir, location.index);
+        if let Some(node) = nodes.get(&key).and_then(Weak::upgrade) {
+            return node;
+        }
+
+        nodes.retain(|_, node| node.strong_count() > 0);
+        let node = Arc::new(Mutex::new(Node {
+            location: Some(location),
+            entry,
+            removed: false,
+        }));
+        nodes.insert(key, Arc::downgrade(&node));
+        node
+    }
+
+    /// Points anyone still using the entry at `from` to its new place and
+    /// short name after a rename.
+    fn move_node(&self, from: EntryLocation, to: EntryLocation, entry: &DirectoryEntry) {
+        let mut nodes = self.nodes.lock();
+        if let Some(node) = nodes
+            .remove(&(from.dir, from.index))
+            .and_then(|node| node.upgrade())
+        {
+            let mut state = node.lock();
+            state.location = Some(to);
+            state.entry.set_short_name(entry.short_name());
+            state.entry.userattrib = entry.userattrib;
+            nodes.insert((to.dir, to.index), Arc::downgrade(&node));
+        }
+    }
+
+    /// Marks the entry at `location` as removed for anyone still using it.
+    fn forget_node(&self, location: EntryLocation) {
+        let node = self.nodes.lock().remove(&(location.dir, location.index));
+        if let Some(node) = node.and_then(|node| node.upgrade()) {
+            node.lock().removed = true;
+        }
+    }
+
+    /// Writes the FSInfo hints back if allocations changed them and
+    /// flushes the disk.
+    pub fn sync(&self) -> Result<(), FsError> {
+        let fs_info = self.fs_info.lock();
+        if let Some(info) = *fs_info {
+            if self.fs_info_dirty.load(Ordering::Relaxed) {
+                let lba = self.bpb.fs_info_sector as u64;
+                let mut sector = vec![0u8; self.bpb.bytes_per_sector as usize];
+                self.disk.read_sectors(lba, &mut sector)?;
+                info.write_to(&mut sector);
+                self.disk.write_sectors(lba, &sector)?;
+                self.fs_info_dirty.store(false, Ordering::Relaxed);
+            }
+        }
+        Ok(self.disk.flush()?)
+    }
+}
+
+/// A FAT32 volume that can be mounted into the VFS.
+pub struct Fat32 {
+    volume: Arc<Volume>,
+}
+
+impl Fat32 {
+    pub fn new(disk: Arc<dyn BlockDevice>) -> Result<Fat32, FsError> {
+        Ok(Fat32 {
+            volume: Arc::new(Volume::new(disk)?),
+        })
+    }
+
+    pub fn volume(&self) -> &Arc<Volume> {
+        &self.volume
+    }
+}
+
+impl FileSystem for Fat32 {
+    fn name(&self) -> &'static str {
+        "fat32"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        let mut entry = DirectoryEntry::parse(&[0; DIR_ENTRY_SIZE]);
+        entry.attrib = ATTR_DIRECTORY;
+        entry.set_cluster(self.volume.bpb.root_cluster);
+
+        FatInode::new(
+            &self.volume,
+            Arc::new(Mutex::new(Node {
+                location: None,
+                entry,
+                removed: false,
+            })),
+        )
+    }
+
+    fn sync(&self) -> Result<(), FsError> {
+        self.volume.sync()
+    }
+}
+
+struct FatInode {
+    volume: Arc<Volume>,
+    node: Arc<Mutex<Node>>,
+}
+
+impl FatInode {
+    fn new(volume: &Arc<Volume>, node: Arc<Mutex<Node>>) -> Arc<FatInode> {
+        Arc::new(FatInode {
+            volume: volume.clone(),
+            node,
+        })
+    }
+
+    /// Returns the first cluster of `node` if it is a directory that still
+    /// exists.
+    fn dir_cluster(node: &Node) -> Result<u32, FsError> {
+        if node.removed {
+            Err(FsError::NotFound)
+        } else if !node.entry.is_dir() {
+            Err(FsError::NotADirectory)
+        } else {
+            Ok(node.entry.cluster())
+        }
+    }
+
+    fn check_writable_file(node: &Node) -> Result<(), FsError> {
+        if node.removed {
+            Err(FsError::NotFound)
+        } else if node.entry.is_dir() {
+            Err(FsError::IsADirectory)
+        } else if node.entry.attrib & ATTR_READ_ONLY != 0 {
+            Err(FsError::ReadOnly)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Grows or shrinks the file to `size` bytes. Anything between the old
+    /// and the new end reads as zeroes.
+    fn resize(&self, node: &mut Node, size: u64) -> Result<(), FsError> {
+        if size > MAX_FILE_SIZE {
+            return Err(FsError::NoSpace);
+        }
+
+        let cluster_size = self.volume.cluster_size as u64;
+        let old_size = node.entry.filesize as u64;
+        let start = self
+            .volume
+            .resize_chain(node.entry.cluster(), size.div_ceil(cluster_size) as usize)?;
+        node.entry.set_cluster(start);
+
+        // New clusters are zeroed when they are allocated, but the old last
+        // cluster may still hold data from before an earlier truncate
+        let stale_end = size.min(old_size.next_multiple_of(cluster_size));
+        if stale_end > old_size {
+            self.volume
+                .write_file(start, old_size, &vec![0; (stale_end - old_size) as usize])?;
+        }
+
+        node.entry.filesize = size as u32;
+        Ok(())
+    }
+
+    /// Stamps the modification time and writes the entry back to disk.
+    fn write_back(&self, node: &mut Node) -> Result<(), FsError> {
+        node.entry.touch(&rtc::now());
+        match node.location {
+            Some(location) => {
+                self.volume
+                    .write_slots(location.dir, location.index, &node.entry.to_bytes())
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+impl Inode for FatInode {
+    fn metadata(&self) -> Metadata {
+        let node = self.node.lock();
+        Metadata {
+            inode: match node.location {
+                Some(location) => (location.dir as u64) << 32 | location.index as u64,
+                None => node.entry.cluster() as u64,
+            },
+            file_type: if node.entry.is_dir() {
+                FileType::Directory
+            } else {
+                FileType::File
+            },
+            size: node.entry.filesize as u64,
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        let dir = FatInode::dir_cluster(&self.node.lock())?;
+        let found = self.volume.find(dir, name)?;
+        let location = EntryLocation {
+            dir,
+            index: found.index,
+        };
+        Ok(FatInode::new(
+            &self.volume,
+            self.volume.node(location, found.entry),
+        ))
+    }
+
+    fn read_dir(&self) -> Result<Vec<vfs::DirEntry>, FsError> {
+        let dir = FatInode::dir_cluster(&self.node.lock())?;
+        Ok(self
+            .volume
+            .read_dir(dir)?
+            .into_iter()
+            .map(|entry| vfs::DirEntry {
+                file_type: if entry.entry.is_dir() {
+                    FileType::Directory
+                } else {
+                    FileType::File
+                },
+                name: entry.name,
+            })
+            .collect())
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let node = self.node.lock();
+        if node.removed {
+            return Err(FsError::NotFound);
+        }
+        if node.entry.is_dir() {
+            return Err(FsError::IsADirectory);
+        }
+        self.volume.read_file(
+            node.entry.cluster(),
+            node.entry.filesize as u64,
+            offset,
+            buf,
+        )
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+        let mut node = self.node.lock();
+        FatInode::check_writable_file(&node)?;
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let end = offset
+            .checked_add(buf.len() as u64)
+            .ok_or(FsError::InvalidInput)?;
+        if end > MAX_FILE_SIZE {
+            return Err(FsError::NoSpace);
+        }
+        if end > node.entry.filesize as u64 {
+            self.resize(&mut node, end)?;
+        }
+        self.volume.write_file(node.entry.cluster(), offset, buf)?;
+        self.write_back(&mut node)?;
+        Ok(buf.len())
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), FsError> {
+        let mut node = self.node.lock();
+        FatInode::check_writable_file(&node)?;
+        self.resize(&mut node, size)?;
+        self.write_back(&mut node)
+    }
+
+    fn create(&self, name: &str, file_type: FileType) -> Result<Arc<dyn Inode>, FsError> {
+        let node = self.node.lock();
+        let dir = FatInode::dir_cluster(&node)?;
+        validate_name(name)?;
+
+        let existing = self.volume.read_dir(dir)?;
+        if existing.iter().any(|entry| entry.matches(name)) {
+            return Err(FsError::AlreadyExists);
+        }
+
+        let now = rtc::now();
+        let (attrib, cluster) = match file_type {
+            FileType::File => (ATTR_ARCHIVE, 0),
+            FileType::Directory => (ATTR_DIRECTORY, self.volume.allocate_cluster(None)?),
+            FileType::Symlink => return Err(FsError::Unsupported),
+        };
+        let mut entry = DirectoryEntry::new([b' '; 11], attrib, cluster, &now);
+
+        let added = if file_type == FileType::Directory {
+            self.volume.write_dot_entries(cluster, dir, &now)
+        } else {
+            Ok(())
+        }
+        .and_then(|()| self.volume.add_entry(dir, name, &mut entry, &existing));
+
+        match added {
+            Ok(location) => Ok(FatInode::new(
+                &self.volume,
+                self.volume.node(location, entry),
+            )),
+            Err(err) => {
+                self.volume.free_chain(cluster)?;
+                Err(err)
+            }
+        }
+    }
+
+    fn unlink(&self, name: &str) -> Result<(), FsError> {
+        let node = self.node.lock();
+        let dir = FatInode::dir_cluster(&node)?;
+        let found = self.volume.find(dir, name)?;
+        if found.entry.is_dir() && !self.volume.read_dir(found.entry.cluster())?.is_empty() {
+            return Err(FsError::NotEmpty);
+        }
+
+        self.volume
+            .remove_slots(dir, found.index + 1 - found.slots, found.slots)?;
+        self.volume.free_chain(found.entry.cluster())?;
+        self.volume.forget_node(EntryLocation {
+            dir,
+            index: found.index,
+        });
+        Ok(())
+    }
+
+    fn rename(&self, old_name: &str, new_name: &str) -> Result<(), FsError> {
+        let node = self.node.lock();
+        let dir = FatInode::dir_cluster(&node)?;
+        validate_name(new_name)?;
+
+        let existing = self.volume.read_dir(dir)?;
+        let found = existing
+            .iter()
+            .find(|entry| entry.matches(old_name))
+            .ok_or(FsError::NotFound)?;
+        // Renaming to a different case of the same name is allowed
+        if existing
+            .iter()
+            .any(|entry| entry.index != found.index && entry.matches(new_name))
+        {
+            return Err(FsError::AlreadyExists);
+        }
+
+        let mut entry = found.entry;
+        let location = self
+            .volume
+            .add_entry(dir, new_name, &mut entry, &existing)?;
+        self.volume
+            .remove_slots(dir, found.index + 1 - found.slots, found.slots)?;
+
+        let old_location = EntryLocation {
+            dir,
+            index: found.index,
+        };
+        self.volume.move_node(old_location, location, &entry);
+        Ok(())
     }
 }
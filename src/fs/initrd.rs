@@ -0,0 +1,480 @@
+use super::vfs::{self, FileSystem, FileType, FsError, Inode, Metadata};
+use alloc::{
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+use conquer_once::spin::OnceCell;
+
+/// The archive packed from the `initrd/` directory by `build.rs`.
+pub static EMBEDDED: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/initrd.tar"));
+
+static INITRD: OnceCell<Initrd> = OnceCell::uninit();
+
+const TAR_BLOCK_SIZE: usize = 512;
+const CPIO_HEADER_SIZE: usize = 110;
+const CPIO_MAGIC: &[u8] = b"070701";
+const CPIO_TRAILER: &str = "TRAILER!!!";
+
+const MODE_TYPE_MASK: u32 = 0o170000;
+const MODE_DIRECTORY: u32 = 0o040000;
+const MODE_REGULAR: u32 = 0o100000;
+const MODE_SYMLINK: u32 = 0o120000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Ustar,
+    NewcCpio,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    Directory,
+    Symlink(&'static str),
+    Other,
+}
+
+#[derive(Debug)]
+pub struct Entry {
+    /// Path relative to the archive root, without leading `./` or `/`.
+    pub path: String,
+    pub kind: EntryKind,
+    pub mode: u32,
+    pub data: &'static [u8],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DirEntry<'a> {
+    pub name: &'a str,
+    pub kind: EntryKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InitrdError {
+    UnknownFormat,
+    Truncated,
+    BadChecksum,
+    InvalidHeader,
+    NotFound,
+    NotADirectory,
+    IsADirectory,
+    AlreadyInitialized,
+}
+
+/// A read-only view of a USTAR or newc cpio archive.
+///
+/// File contents are borrowed straight out of the archive, so it has to live
+/// for the rest of the kernel's lifetime.
+#[derive(Debug)]
+pub struct Initrd {
+    format: Format,
+    entries: Vec<Entry>,
+}
+
+impl Initrd {
+    pub fn parse(archive: &'static [u8]) -> Result<Initrd, InitrdError> {
+        if archive.starts_with(CPIO_MAGIC) {
+            Ok(Initrd {
+                format: Format::NewcCpio,
+                entries: parse_cpio(archive)?,
+            })
+        } else if archive.len() >= TAR_BLOCK_SIZE && &archive[257..262] == b"ustar" {
+            Ok(Initrd {
+                format: Format::Ustar,
+                entries: parse_ustar(archive)?,
+            })
+        } else if archive.len() >= TAR_BLOCK_SIZE
+            && archive[..TAR_BLOCK_SIZE].iter().all(|&b| b == 0)
+        {
+            // An empty tar archive only consists of the end-of-archive blocks
+            Ok(Initrd {
+                format: Format::Ustar,
+                entries: Vec::new(),
+            })
+        } else {
+            Err(InitrdError::UnknownFormat)
+        }
+    }
+
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    pub fn get(&self, path: &str) -> Option<&Entry> {
+        let path = normalize(path);
+        self.entries.iter().find(|entry| entry.path == path)
+    }
+
+    /// Returns the contents of the regular file at `path`.
+    pub fn read(&self, path: &str) -> Result<&'static [u8], InitrdError> {
+        match self.get(path) {
+            Some(entry) => match entry.kind {
+                EntryKind::Directory => Err(InitrdError::IsADirectory),
+                _ => Ok(entry.data),
+            },
+            None if self.is_dir(path) => Err(InitrdError::IsADirectory),
+            None => Err(InitrdError::NotFound),
+        }
+    }
+
+    /// Whether `path` is a directory, either explicitly or because other
+    /// entries live below it.
+    pub fn is_dir(&self, path: &str) -> bool {
+        let path = normalize(path);
+        if path.is_empty() {
+            return true;
+        }
+        self.entries.iter().any(|entry| {
+            (entry.path == path && entry.kind == EntryKind::Directory)
+                || (entry.path.starts_with(path)
+                    && entry.path.as_bytes().get(path.len()) == Some(&b'/'))
+        })
+    }
+
+    /// Lists the direct children of the directory at `path`.
+    ///
+    /// Archives don't have to contain entries for every directory, so
+    /// directories that only show up as path components are listed too.
+    pub fn read_dir(&self, path: &str) -> Result<Vec<DirEntry<'_>>, InitrdError> {
+        let path = normalize(path);
+        if !self.is_dir(path) {
+            return Err(match self.get(path) {
+                Some(_) => InitrdError::NotADirectory,
+                None => InitrdError::NotFound,
+            });
+        }
+
+        let mut children: Vec<DirEntry> = Vec::new();
+        for entry in &self.entries {
+            let rest = if path.is_empty() {
+                entry.path.as_str()
+            } else {
+                match entry.path.strip_prefix(path) {
+                    Some(rest) if rest.starts_with('/') => &rest[1..],
+                    _ => continue,
+                }
+            };
+            if rest.is_empty() {
+                continue;
+            }
+
+            let child = match rest.split_once('/') {
+                Some((name, _)) => DirEntry {
+                    name,
+                    kind: EntryKind::Directory,
+                },
+                None => DirEntry {
+                    name: rest,
+                    kind: entry.kind,
+                },
+            };
+
+            match children.iter_mut().find(|c| c.name == child.name) {
+                Some(existing) if child.kind != EntryKind::Directory => *existing = child,
+                Some(_) => {}
+                None => children.push(child),
+            }
+        }
+
+        Ok(children)
+    }
+}
+
+pub fn init(archive: &'static [u8]) -> Result<&'static Initrd, InitrdError> {
+    let initrd = Initrd::parse(archive)?;
+    INITRD
+        .try_init_once(|| initrd)
+        .map_err(|_| InitrdError::AlreadyInitialized)?;
+    Ok(INITRD.get().unwrap())
+}
+
+pub fn get() -> Option<&'static Initrd> {
+    INITRD.get()
+}
+
+impl From<InitrdError> for FsError {
+    fn from(err: InitrdError) -> FsError {
+        match err {
+            InitrdError::NotFound => FsError::NotFound,
+            InitrdError::NotADirectory => FsError::NotADirectory,
+            InitrdError::IsADirectory => FsError::IsADirectory,
+            InitrdError::AlreadyInitialized => FsError::Busy,
+            _ => FsError::Corrupted,
+        }
+    }
+}
+
+/// Exposes an [`Initrd`] read-only through the VFS.
+pub struct InitrdFs {
+    initrd: &'static Initrd,
+}
+
+impl InitrdFs {
+    pub fn new(initrd: &'static Initrd) -> InitrdFs {
+        InitrdFs { initrd }
+    }
+}
+
+impl FileSystem for InitrdFs {
+    fn name(&self) -> &'static str {
+        "initrd"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        Arc::new(InitrdInode {
+            initrd: self.initrd,
+            path: String::new(),
+        })
+    }
+}
+
+struct InitrdInode {
+    initrd: &'static Initrd,
+    path: String,
+}
+
+impl Inode for InitrdInode {
+    fn metadata(&self) -> Metadata {
+        // Archives have no inode numbers we could rely on, so derive a
+        // stable one from the path
+        let inode = self.path.bytes().fold(0xcbf29ce484222325u64, |hash, b| {
+            (hash ^ b as u64).wrapping_mul(0x100000001b3)
+        });
+        let (file_type, size) = match self.initrd.get(&self.path) {
+            Some(entry) => match entry.kind {
+                EntryKind::Directory => (FileType::Directory, 0),
+                EntryKind::Symlink(target) => (FileType::Symlink, target.len() as u64),
+                _ => (FileType::File, entry.data.len() as u64),
+            },
+            None => (FileType::Directory, 0),
+        };
+        Metadata {
+            inode,
+            file_type,
+            size,
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        if !self.initrd.is_dir(&self.path) {
+            return Err(FsError::NotADirectory);
+        }
+        let path = if self.path.is_empty() {
+            name.to_string()
+        } else {
+            alloc::format!("{}/{}", self.path, name)
+        };
+        if self.initrd.get(&path).is_none() && !self.initrd.is_dir(&path) {
+            return Err(FsError::NotFound);
+        }
+        Ok(Arc::new(InitrdInode {
+            initrd: self.initrd,
+            path,
+        }))
+    }
+
+    fn read_dir(&self) -> Result<Vec<vfs::DirEntry>, FsError> {
+        Ok(self
+            .initrd
+            .read_dir(&self.path)?
+            .into_iter()
+            .map(|entry| vfs::DirEntry {
+                name: entry.name.to_string(),
+                file_type: match entry.kind {
+                    EntryKind::Directory => FileType::Directory,
+                    EntryKind::Symlink(_) => FileType::Symlink,
+                    _ => FileType::File,
+                },
+            })
+            .collect())
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let data = self.initrd.read(&self.path)?;
+        let start = (offset as usize).min(data.len());
+        let len = buf.len().min(data.len() - start);
+        buf[..len].copy_from_slice(&data[start..start + len]);
+        Ok(len)
+    }
+
+    fn read_link(&self) -> Result<String, FsError> {
+        match self.initrd.get(&self.path).map(|entry| entry.kind) {
+            Some(EntryKind::Symlink(target)) => Ok(target.to_string()),
+            _ => Err(FsError::InvalidInput),
+        }
+    }
+}
+
+fn normalize(path: &str) -> &str {
+    let path = path.trim_end_matches('/');
+    let path = path.strip_prefix("./").unwrap_or(path);
+    let path = path.trim_start_matches('/');
+    if path == "." {
+        ""
+    } else {
+        path
+    }
+}
+
+fn kind_from_mode(mode: u32, data: &'static [u8]) -> EntryKind {
+    match mode & MODE_TYPE_MASK {
+        MODE_REGULAR => EntryKind::File,
+        MODE_DIRECTORY => EntryKind::Directory,
+        MODE_SYMLINK => EntryKind::Symlink(core::str::from_utf8(data).unwrap_or("")),
+        _ => EntryKind::Other,
+    }
+}
+
+fn parse_ustar(archive: &'static [u8]) -> Result<Vec<Entry>, InitrdError> {
+    let mut entries = Vec::new();
+    let mut offset = 0;
+
+    while offset + TAR_BLOCK_SIZE <= archive.len() {
+        let header = &archive[offset..offset + TAR_BLOCK_SIZE];
+        if header.iter().all(|&b| b == 0) {
+            break;
+        }
+
+        let expected = parse_octal(&header[148..156])?;
+        let checksum: u32 = header
+            .iter()
+            .enumerate()
+            .map(|(i, &b)| {
+                if (148..156).contains(&i) {
+                    b' ' as u32
+                } else {
+                    b as u32
+                }
+            })
+            .sum();
+        if checksum != expected {
+            return Err(InitrdError::BadChecksum);
+        }
+
+        let size = parse_octal(&header[124..136])? as usize;
+        let mode = parse_octal(&header[100..108])?;
+        let data_start = offset + TAR_BLOCK_SIZE;
+        let data = archive
+            .get(data_start..data_start + size)
+            .ok_or(InitrdError::Truncated)?;
+
+        let name = cstr(&header[0..100])?;
+        // GNU tar reuses the prefix field for other data and marks that with
+        // a different magic
+        let prefix = if &header[257..263] == b"ustar\0" {
+            cstr(&header[345..500])?
+        } else {
+            ""
+        };
+        let mut path = String::new();
+        if !prefix.is_empty() {
+            path.push_str(prefix);
+            path.push('/');
+        }
+        path.push_str(name);
+
+        let kind = match header[156] {
+            b'0' | b'\0' | b'7' => EntryKind::File,
+            b'5' => EntryKind::Directory,
+            b'2' => EntryKind::Symlink(cstr(&archive[offset + 157..offset + 257])?),
+            _ => EntryKind::Other,
+        };
+
+        entries.push(Entry {
+            path: String::from(normalize(&path)),
+            kind,
+            mode,
+            data,
+        });
+
+        offset = data_start + align_up(size, TAR_BLOCK_SIZE);
+    }
+
+    entries.retain(|entry| !entry.path.is_empty());
+    Ok(entries)
+}
+
+fn parse_cpio(archive: &'static [u8]) -> Result<Vec<Entry>, InitrdError> {
+    let mut entries = Vec::new();
+    let mut offset = 0;
+
+    loop {
+        let header = archive
+            .get(offset..offset + CPIO_HEADER_SIZE)
+            .ok_or(InitrdError::Truncated)?;
+        if !header.starts_with(CPIO_MAGIC) {
+            return Err(InitrdError::InvalidHeader);
+        }
+
+        // Fields after the magic are 8 hex digits each
+        let field = |index: usize| parse_hex(&header[6 + index * 8..6 + (index + 1) * 8]);
+        let mode = field(1)?;
+        let size = field(6)? as usize;
+        let name_size = field(11)? as usize;
+
+        let name_start = offset + CPIO_HEADER_SIZE;
+        let name = archive
+            .get(name_start..name_start + name_size)
+            .ok_or(InitrdError::Truncated)?;
+        let name = cstr(name)?;
+        if name == CPIO_TRAILER {
+            break;
+        }
+
+        let data_start = align_up(name_start + name_size, 4);
+        let data = archive
+            .get(data_start..data_start + size)
+            .ok_or(InitrdError::Truncated)?;
+
+        let path = normalize(name);
+        if !path.is_empty() {
+            entries.push(Entry {
+                path: String::from(path),
+                kind: kind_from_mode(mode, data),
+                mode: mode & !MODE_TYPE_MASK,
+                data,
+            });
+        }
+
+        offset = align_up(data_start + size, 4);
+    }
+
+    Ok(entries)
+}
+
+fn cstr(bytes: &[u8]) -> Result<&str, InitrdError> {
+    let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    core::str::from_utf8(&bytes[..len]).map_err(|_| InitrdError::InvalidHeader)
+}
+
+fn parse_octal(field: &[u8]) -> Result<u32, InitrdError> {
+    let mut value: u32 = 0;
+    for &b in field {
+        match b {
+            b'0'..=b'7' => {
+                value = value
+                    .checked_mul(8)
+                    .and_then(|value| value.checked_add((b - b'0') as u32))
+                    .ok_or(InitrdError::InvalidHeader)?;
+            }
+            b' ' | b'\0' if value == 0 => {}
+            b' ' | b'\0' => break,
+            _ => return Err(InitrdError::InvalidHeader),
+        }
+    }
+    Ok(value)
+}
+
+fn parse_hex(field: &[u8]) -> Result<u32, InitrdError> {
+    let text = core::str::from_utf8(field).map_err(|_| InitrdError::InvalidHeader)?;
+    u32::from_str_radix(text, 16).map_err(|_| InitrdError::InvalidHeader)
+}
+
+fn align_up(value: usize, align: usize) -> usize {
+    value.div_ceil(align) * align
+}
@@ -1 +1,4 @@
 pub mod fat32;
+pub mod initrd;
+pub mod tmpfs;
+pub mod vfs;
@@ -0,0 +1,205 @@
+use super::vfs::{DirEntry, FileSystem, FileType, FsError, Inode, Metadata};
+use alloc::{
+    collections::BTreeMap,
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+
+/// Files can't grow past this. Up to it, growing fails with
+/// [`FsError::NoSpace`] once the heap runs out.
+pub const MAX_FILE_SIZE: u64 = 1 << 32;
+
+static NEXT_INODE: AtomicU64 = AtomicU64::new(1);
+
+/// A filesystem that keeps everything on the heap.
+pub struct TmpFs {
+    root: Arc<TmpInode>,
+}
+
+impl TmpFs {
+    pub fn new() -> TmpFs {
+        TmpFs {
+            root: TmpInode::new(Content::Directory(BTreeMap::new())),
+        }
+    }
+}
+
+impl Default for TmpFs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FileSystem for TmpFs {
+    fn name(&self) -> &'static str {
+        "tmpfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
+
+enum Content {
+    File(Vec<u8>),
+    Directory(BTreeMap<String, Arc<TmpInode>>),
+}
+
+struct TmpInode {
+    inode: u64,
+    content: Mutex<Content>,
+}
+
+impl TmpInode {
+    fn new(content: Content) -> Arc<TmpInode> {
+        Arc::new(TmpInode {
+            inode: NEXT_INODE.fetch_add(1, Ordering::Relaxed),
+            content: Mutex::new(content),
+        })
+    }
+
+    fn file_type(content: &Content) -> FileType {
+        match content {
+            Content::File(_) => FileType::File,
+            Content::Directory(_) => FileType::Directory,
+        }
+    }
+}
+
+impl Inode for TmpInode {
+    fn metadata(&self) -> Metadata {
+        let content = self.content.lock();
+        let size = match &*content {
+            Content::File(data) => data.len() as u64,
+            Content::Directory(entries) => entries.len() as u64,
+        };
+        Metadata {
+            inode: self.inode,
+            file_type: TmpInode::file_type(&content),
+            size,
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        match &*self.content.lock() {
+            Content::Directory(entries) => match entries.get(name) {
+                Some(inode) => Ok(inode.clone()),
+                None => Err(FsError::NotFound),
+            },
+            _ => Err(FsError::NotADirectory),
+        }
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        match &*self.content.lock() {
+            Content::Directory(entries) => Ok(entries
+                .iter()
+                .map(|(name, inode)| DirEntry {
+                    name: name.clone(),
+                    file_type: TmpInode::file_type(&inode.content.lock()),
+                })
+                .collect()),
+            _ => Err(FsError::NotADirectory),
+        }
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        match &*self.content.lock() {
+            Content::File(data) => {
+                let start = (offset as usize).min(data.len());
+                let len = buf.len().min(data.len() - start);
+                buf[..len].copy_from_slice(&data[start..start + len]);
+                Ok(len)
+            }
+            _ => Err(FsError::IsADirectory),
+        }
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+        match &mut *self.content.lock() {
+            Content::File(data) => {
+                let end = offset
+                    .checked_add(buf.len() as u64)
+                    .ok_or(FsError::InvalidInput)?;
+                if (data.len() as u64) < end {
+                    resize(data, end)?;
+                }
+                data[offset as usize..end as usize].copy_from_slice(buf);
+                Ok(buf.len())
+            }
+            _ => Err(FsError::IsADirectory),
+        }
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), FsError> {
+        match &mut *self.content.lock() {
+            Content::File(data) => resize(data, size),
+            _ => Err(FsError::IsADirectory),
+        }
+    }
+
+    fn create(&self, name: &str, file_type: FileType) -> Result<Arc<dyn Inode>, FsError> {
+        match &mut *self.content.lock() {
+            Content::Directory(entries) => {
+                if entries.contains_key(name) {
+                    return Err(FsError::AlreadyExists);
+                }
+                let inode = match file_type {
+                    FileType::File => TmpInode::new(Content::File(Vec::new())),
+                    FileType::Directory => TmpInode::new(Content::Directory(BTreeMap::new())),
+                    FileType::Symlink => return Err(FsError::Unsupported),
+                };
+                entries.insert(name.to_string(), inode.clone());
+                Ok(inode)
+            }
+            _ => Err(FsError::NotADirectory),
+        }
+    }
+
+    fn unlink(&self, name: &str) -> Result<(), FsError> {
+        match &mut *self.content.lock() {
+            Content::Directory(entries) => {
+                let inode = entries.get(name).ok_or(FsError::NotFound)?;
+                if let Content::Directory(children) = &*inode.content.lock() {
+                    if !children.is_empty() {
+                        return Err(FsError::NotEmpty);
+                    }
+                }
+                entries.remove(name);
+                Ok(())
+            }
+            _ => Err(FsError::NotADirectory),
+        }
+    }
+
+    fn rename(&self, old_name: &str, new_name: &str) -> Result<(), FsError> {
+        match &mut *self.content.lock() {
+            Content::Directory(entries) => {
+                if entries.contains_key(new_name) {
+                    return Err(FsError::AlreadyExists);
+                }
+                let inode = entries.remove(old_name).ok_or(FsError::NotFound)?;
+                entries.insert(new_name.to_string(), inode);
+                Ok(())
+            }
+            _ => Err(FsError::NotADirectory),
+        }
+    }
+}
+
+/// Resizes a file's data without panicking when the heap can't hold it.
+fn resize(data: &mut Vec<u8>, size: u64) -> Result<(), FsError> {
+    if size > MAX_FILE_SIZE {
+        return Err(FsError::NoSpace);
+    }
+    let size = size as usize;
+    if size > data.len() {
+        data.try_reserve_exact(size - data.len())
+            .map_err(|_| FsError::NoSpace)?;
+    }
+    data.resize(size, 0);
+    Ok(())
+}
@@ -0,0 +1,696 @@
+use crate::{println, time};
+use alloc::{
+    collections::BTreeMap,
+    string::{String, ToString},
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use conquer_once::spin::OnceCell;
+use spin::Mutex;
+
+/// How many symlinks may be followed while resolving a single path.
+const MAX_SYMLINK_DEPTH: usize = 8;
+
+/// How often `run` writes cached filesystem data back to disk.
+pub const SYNC_INTERVAL_MS: u64 = 5000;
+
+static VFS: OnceCell<Vfs> = OnceCell::uninit();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsError {
+    NotFound,
+    NotADirectory,
+    IsADirectory,
+    AlreadyExists,
+    NotEmpty,
+    ReadOnly,
+    InvalidPath,
+    InvalidInput,
+    Unsupported,
+    Busy,
+    TooManyLinks,
+    NoSpace,
+    Corrupted,
+    Io,
+    NotInitialized,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    File,
+    Directory,
+    Symlink,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Metadata {
+    pub inode: u64,
+    pub file_type: FileType,
+    pub size: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    pub name: String,
+    pub file_type: FileType,
+}
+
+/// A mountable filesystem.
+pub trait FileSystem: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn root(&self) -> Arc<dyn Inode>;
+
+    /// Writes any cached state back to the underlying storage.
+    fn sync(&self) -> Result<(), FsError> {
+        Ok(())
+    }
+}
+
+/// A file, directory or symlink inside a [`FileSystem`].
+///
+/// Only `metadata` is required; everything else defaults to the error a
+/// read-only filesystem or the wrong file type would return.
+pub trait Inode: Send + Sync {
+    fn metadata(&self) -> Metadata;
+
+    fn lookup(&self, _name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        Err(FsError::NotADirectory)
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        Err(FsError::NotADirectory)
+    }
+
+    fn read_at(&self, _offset: u64, _buf: &mut [u8]) -> Result<usize, FsError> {
+        Err(FsError::IsADirectory)
+    }
+
+    fn write_at(&self, _offset: u64, _buf: &[u8]) -> Result<usize, FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn truncate(&self, _size: u64) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn create(&self, _name: &str, _file_type: FileType) -> Result<Arc<dyn Inode>, FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn unlink(&self, _name: &str) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    /// Renames an entry within this directory.
+    fn rename(&self, _old_name: &str, _new_name: &str) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn read_link(&self) -> Result<String, FsError> {
+        Err(FsError::InvalidInput)
+    }
+}
+
+/// A cached name to inode binding.
+///
+/// Dentries form the tree that paths are resolved against. A dentry that has
+/// a filesystem mounted on it redirects lookups to the root of that
+/// filesystem, whose parent is the mount point's parent so that `..` leaves
+/// the mount again.
+pub struct Dentry {
+    name: String,
+    inode: Arc<dyn Inode>,
+    parent: Option<Weak<Dentry>>,
+    children: Mutex<BTreeMap<String, Arc<Dentry>>>,
+    mounted: Mutex<Option<Arc<Dentry>>>,
+}
+
+impl Dentry {
+    fn new(name: &str, inode: Arc<dyn Inode>, parent: Option<&Arc<Dentry>>) -> Arc<Dentry> {
+        Arc::new(Dentry {
+            name: name.to_string(),
+            inode,
+            parent: parent.map(Arc::downgrade),
+            children: Mutex::new(BTreeMap::new()),
+            mounted: Mutex::new(None),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn inode(&self) -> &Arc<dyn Inode> {
+        &self.inode
+    }
+
+    pub fn parent(&self) -> Option<Arc<Dentry>> {
+        self.parent.as_ref().and_then(Weak::upgrade)
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.inode.metadata().file_type == FileType::Directory
+    }
+
+    /// The absolute path of this dentry.
+    pub fn path(&self) -> String {
+        let mut components = Vec::new();
+        let mut parent = self.parent();
+        if parent.is_some() {
+            components.push(self.name.clone());
+        }
+        while let Some(dentry) = parent {
+            if dentry.parent.is_some() {
+                components.push(dentry.name.clone());
+            }
+            parent = dentry.parent();
+        }
+
+        let mut path = String::new();
+        for component in components.iter().rev() {
+            path.push('/');
+            path.push_str(component);
+        }
+        if path.is_empty() {
+            path.push('/');
+        }
+        path
+    }
+
+    /// Looks up a direct child, consulting the cache first and following
+    /// mounts.
+    fn child(self: &Arc<Self>, name: &str) -> Result<Arc<Dentry>, FsError> {
+        if !self.is_dir() {
+            return Err(FsError::NotADirectory);
+        }
+
+        let child = {
+            let mut children = self.children.lock();
+            match children.get(name) {
+                Some(child) => child.clone(),
+                None => {
+                    let inode = self.inode.lookup(name)?;
+                    let child = Dentry::new(name, inode, Some(self));
+                    children.insert(name.to_string(), child.clone());
+                    child
+                }
+            }
+        };
+
+        Ok(child.follow_mounts())
+    }
+
+    fn follow_mounts(self: Arc<Self>) -> Arc<Dentry> {
+        let mut dentry = self;
+        loop {
+            let mounted = dentry.mounted.lock().clone();
+            match mounted {
+                Some(root) => dentry = root,
+                None => return dentry,
+            }
+        }
+    }
+
+    fn forget(&self, name: &str) {
+        self.children.lock().remove(name);
+    }
+}
+
+pub struct Mount {
+    pub path: String,
+    pub fs: Arc<dyn FileSystem>,
+    mountpoint: Option<Arc<Dentry>>,
+}
+
+pub struct Vfs {
+    root: Arc<Dentry>,
+    cwd: Mutex<Arc<Dentry>>,
+    mounts: Mutex<Vec<Mount>>,
+}
+
+impl Vfs {
+    pub fn new(root_fs: Arc<dyn FileSystem>) -> Vfs {
+        let root = Dentry::new("/", root_fs.root(), None);
+        Vfs {
+            cwd: Mutex::new(root.clone()),
+            mounts: Mutex::new(alloc::vec![Mount {
+                path: String::from("/"),
+                fs: root_fs,
+                mountpoint: None,
+            }]),
+            root,
+        }
+    }
+
+    pub fn root(&self) -> Arc<Dentry> {
+        self.root.clone()
+    }
+
+    pub fn cwd(&self) -> Arc<Dentry> {
+        self.cwd.lock().clone()
+    }
+
+    /// Resolves `path`, which is relative to the working directory unless
+    /// it starts with `/`.
+    pub fn resolve(&self, path: &str) -> Result<Arc<Dentry>, FsError> {
+        self.walk(self.start_for(path), path, true, 0)
+    }
+
+    /// Resolves everything but the last component of `path`, returning the
+    /// parent directory and the final name.
+    fn resolve_parent<'a>(&self, path: &'a str) -> Result<(Arc<Dentry>, &'a str), FsError> {
+        let trimmed = path.trim_end_matches('/');
+        let (dir, name) = match trimmed.rfind('/') {
+            Some(index) => (&trimmed[..index + 1], &trimmed[index + 1..]),
+            None => ("", trimmed),
+        };
+        if name.is_empty() || name == "." || name == ".." {
+            return Err(FsError::InvalidPath);
+        }
+
+        let parent = self.walk(self.start_for(path), dir, true, 0)?;
+        if !parent.is_dir() {
+            return Err(FsError::NotADirectory);
+        }
+        Ok((parent, name))
+    }
+
+    fn start_for(&self, path: &str) -> Arc<Dentry> {
+        if path.starts_with('/') {
+            self.root()
+        } else {
+            self.cwd()
+        }
+    }
+
+    fn walk(
+        &self,
+        start: Arc<Dentry>,
+        path: &str,
+        follow_last: bool,
+        depth: usize,
+    ) -> Result<Arc<Dentry>, FsError> {
+        let mut current = start;
+        let mut components = path
+            .split('/')
+            .filter(|c| !c.is_empty() && *c != ".")
+            .peekable();
+
+        while let Some(component) = components.next() {
+            if component == ".." {
+                if let Some(parent) = current.parent() {
+                    current = parent;
+                }
+                continue;
+            }
+
+            let child = current.child(component)?;
+            let is_last = components.peek().is_none();
+            if child.inode.metadata().file_type == FileType::Symlink && (!is_last || follow_last) {
+                if depth >= MAX_SYMLINK_DEPTH {
+                    return Err(FsError::TooManyLinks);
+                }
+                let target = child.inode.read_link()?;
+                let start = if target.starts_with('/') {
+                    self.root()
+                } else {
+                    current.clone()
+                };
+                current = self.walk(start, &target, true, depth + 1)?;
+            } else {
+                current = child;
+            }
+        }
+
+        Ok(current)
+    }
+
+    pub fn open(&self, path: &str, options: &OpenOptions) -> Result<File, FsError> {
+        let dentry = match self.resolve(path) {
+            Ok(dentry) => {
+                if options.create_new {
+                    return Err(FsError::AlreadyExists);
+                }
+                dentry
+            }
+            Err(FsError::NotFound) if options.create || options.create_new => {
+                let (parent, name) = self.resolve_parent(path)?;
+                parent.inode.create(name, FileType::File)?;
+                parent.child(name)?
+            }
+            Err(err) => return Err(err),
+        };
+
+        if dentry.is_dir() && (options.write || options.append) {
+            return Err(FsError::IsADirectory);
+        }
+        if options.truncate && (options.write || options.append) {
+            dentry.inode.truncate(0)?;
+        }
+
+        Ok(File {
+            dentry,
+            offset: 0,
+            options: options.clone(),
+        })
+    }
+
+    pub fn metadata(&self, path: &str) -> Result<Metadata, FsError> {
+        Ok(self.resolve(path)?.inode.metadata())
+    }
+
+    pub fn read_dir(&self, path: &str) -> Result<Vec<DirEntry>, FsError> {
+        self.resolve(path)?.inode.read_dir()
+    }
+
+    pub fn create_dir(&self, path: &str) -> Result<(), FsError> {
+        let (parent, name) = self.resolve_parent(path)?;
+        if parent.child(name).is_ok() {
+            return Err(FsError::AlreadyExists);
+        }
+        parent.inode.create(name, FileType::Directory)?;
+        Ok(())
+    }
+
+    /// Removes a file or an empty directory.
+    pub fn remove(&self, path: &str) -> Result<(), FsError> {
+        let (parent, name) = self.resolve_parent(path)?;
+        let dentry = parent.child(name)?;
+        if dentry.mounted.lock().is_some() || self.is_mount_root(&dentry) {
+            return Err(FsError::Busy);
+        }
+        parent.inode.unlink(name)?;
+        parent.forget(name);
+        Ok(())
+    }
+
+    /// Renames `from` to `to`. Both have to be in the same directory.
+    pub fn rename(&self, from: &str, to: &str) -> Result<(), FsError> {
+        let (from_parent, from_name) = self.resolve_parent(from)?;
+        let (to_parent, to_name) = self.resolve_parent(to)?;
+        if !Arc::ptr_eq(&from_parent, &to_parent) {
+            return Err(FsError::Unsupported);
+        }
+        from_parent.inode.rename(from_name, to_name)?;
+        from_parent.forget(from_name);
+        from_parent.forget(to_name);
+        Ok(())
+    }
+
+    pub fn set_cwd(&self, path: &str) -> Result<(), FsError> {
+        let dentry = self.resolve(path)?;
+        if !dentry.is_dir() {
+            return Err(FsError::NotADirectory);
+        }
+        *self.cwd.lock() = dentry;
+        Ok(())
+    }
+
+    /// Mounts `fs` on top of the directory at `path`.
+    pub fn mount(&self, path: &str, fs: Arc<dyn FileSystem>) -> Result<(), FsError> {
+        let mountpoint = self.resolve(path)?;
+        if mountpoint.parent.is_none() {
+            return Err(FsError::Busy);
+        }
+        if !mountpoint.is_dir() {
+            return Err(FsError::NotADirectory);
+        }
+
+        let mut mounted = mountpoint.mounted.lock();
+        if mounted.is_some() {
+            return Err(FsError::Busy);
+        }
+
+        let root = Arc::new(Dentry {
+            name: mountpoint.name.clone(),
+            inode: fs.root(),
+            parent: mountpoint.parent.clone(),
+            children: Mutex::new(BTreeMap::new()),
+            mounted: Mutex::new(None),
+        });
+        *mounted = Some(root);
+        drop(mounted);
+
+        self.mounts.lock().push(Mount {
+            path: mountpoint.path(),
+            fs,
+            mountpoint: Some(mountpoint),
+        });
+        Ok(())
+    }
+
+    pub fn unmount(&self, path: &str) -> Result<(), FsError> {
+        let root = self.resolve(path)?;
+        let mut mounts = self.mounts.lock();
+        let index = mounts
+            .iter()
+            .position(|mount| mount.path == root.path() && mount.mountpoint.is_some())
+            .ok_or(FsError::InvalidInput)?;
+
+        if Arc::ptr_eq(&root, &self.cwd()) {
+            return Err(FsError::Busy);
+        }
+
+        let mount = mounts.remove(index);
+        mount.fs.sync()?;
+        if let Some(mountpoint) = mount.mountpoint {
+            *mountpoint.mounted.lock() = None;
+        }
+        Ok(())
+    }
+
+    /// Calls `f` with every mount, in the order they were mounted.
+    pub fn for_each_mount(&self, mut f: impl FnMut(&Mount)) {
+        for mount in self.mounts.lock().iter() {
+            f(mount);
+        }
+    }
+
+    pub fn sync(&self) -> Result<(), FsError> {
+        for mount in self.mounts.lock().iter() {
+            mount.fs.sync()?;
+        }
+        Ok(())
+    }
+
+    fn is_mount_root(&self, dentry: &Arc<Dentry>) -> bool {
+        self.mounts
+            .lock()
+            .iter()
+            .any(|mount| match &mount.mountpoint {
+                Some(mountpoint) => mountpoint
+                    .mounted
+                    .lock()
+                    .as_ref()
+                    .is_some_and(|root| Arc::ptr_eq(root, dentry)),
+                None => false,
+            })
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct OpenOptions {
+    read: bool,
+    write: bool,
+    append: bool,
+    truncate: bool,
+    create: bool,
+    create_new: bool,
+}
+
+impl OpenOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn read(&mut self, read: bool) -> &mut Self {
+        self.read = read;
+        self
+    }
+
+    pub fn write(&mut self, write: bool) -> &mut Self {
+        self.write = write;
+        self
+    }
+
+    pub fn append(&mut self, append: bool) -> &mut Self {
+        self.append = append;
+        self
+    }
+
+    pub fn truncate(&mut self, truncate: bool) -> &mut Self {
+        self.truncate = truncate;
+        self
+    }
+
+    pub fn create(&mut self, create: bool) -> &mut Self {
+        self.create = create;
+        self
+    }
+
+    pub fn create_new(&mut self, create_new: bool) -> &mut Self {
+        self.create_new = create_new;
+        self
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeekFrom {
+    Start(u64),
+    End(i64),
+    Current(i64),
+}
+
+/// An open file with its own position.
+pub struct File {
+    dentry: Arc<Dentry>,
+    offset: u64,
+    options: OpenOptions,
+}
+
+impl File {
+    pub fn dentry(&self) -> &Arc<Dentry> {
+        &self.dentry
+    }
+
+    pub fn metadata(&self) -> Metadata {
+        self.dentry.inode.metadata()
+    }
+
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, FsError> {
+        if !self.options.read {
+            return Err(FsError::InvalidInput);
+        }
+        let read = self.dentry.inode.read_at(self.offset, buf)?;
+        self.offset += read as u64;
+        Ok(read)
+    }
+
+    pub fn read_to_end(&mut self) -> Result<Vec<u8>, FsError> {
+        let mut data = Vec::new();
+        let mut chunk = [0u8; 512];
+        loop {
+            match self.read(&mut chunk)? {
+                0 => return Ok(data),
+                read => data.extend_from_slice(&chunk[..read]),
+            }
+        }
+    }
+
+    pub fn write(&mut self, buf: &[u8]) -> Result<usize, FsError> {
+        if !self.options.write && !self.options.append {
+            return Err(FsError::InvalidInput);
+        }
+        if self.options.append {
+            self.offset = self.metadata().size;
+        }
+        let written = self.dentry.inode.write_at(self.offset, buf)?;
+        self.offset += written as u64;
+        Ok(written)
+    }
+
+    pub fn seek(&mut self, pos: SeekFrom) -> Result<u64, FsError> {
+        let offset = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(delta) => self.metadata().size.checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.offset.checked_add_signed(delta),
+        };
+        self.offset = offset.ok_or(FsError::InvalidInput)?;
+        Ok(self.offset)
+    }
+
+    pub fn set_len(&mut self, size: u64) -> Result<(), FsError> {
+        if !self.options.write && !self.options.append {
+            return Err(FsError::InvalidInput);
+        }
+        self.dentry.inode.truncate(size)
+    }
+}
+
+/// Sets up the global VFS with `root_fs` mounted at `/`.
+pub fn init(root_fs: Arc<dyn FileSystem>) -> Result<(), FsError> {
+    VFS.try_init_once(|| Vfs::new(root_fs))
+        .map_err(|_| FsError::Busy)
+}
+
+pub fn get() -> Result<&'static Vfs, FsError> {
+    VFS.get().ok_or(FsError::NotInitialized)
+}
+
+pub fn open(path: &str, options: &OpenOptions) -> Result<File, FsError> {
+    get()?.open(path, options)
+}
+
+/// Reads the whole file at `path`.
+pub fn read(path: &str) -> Result<Vec<u8>, FsError> {
+    open(path, OpenOptions::new().read(true))?.read_to_end()
+}
+
+/// Creates or replaces the file at `path` with `data`.
+pub fn write(path: &str, data: &[u8]) -> Result<(), FsError> {
+    let mut file = open(
+        path,
+        OpenOptions::new().write(true).create(true).truncate(true),
+    )?;
+    let mut written = 0;
+    while written < data.len() {
+        match file.write(&data[written..])? {
+            // The filesystem took nothing, trying again won't change that
+            0 => return Err(FsError::NoSpace),
+            len => written += len,
+        }
+    }
+    Ok(())
+}
+
+pub fn read_dir(path: &str) -> Result<Vec<DirEntry>, FsError> {
+    get()?.read_dir(path)
+}
+
+pub fn metadata(path: &str) -> Result<Metadata, FsError> {
+    get()?.metadata(path)
+}
+
+pub fn create_dir(path: &str) -> Result<(), FsError> {
+    get()?.create_dir(path)
+}
+
+pub fn remove(path: &str) -> Result<(), FsError> {
+    get()?.remove(path)
+}
+
+pub fn rename(from: &str, to: &str) -> Result<(), FsError> {
+    get()?.rename(from, to)
+}
+
+pub fn mount(path: &str, fs: Arc<dyn FileSystem>) -> Result<(), FsError> {
+    get()?.mount(path, fs)
+}
+
+pub fn unmount(path: &str) -> Result<(), FsError> {
+    get()?.unmount(path)
+}
+
+pub fn set_cwd(path: &str) -> Result<(), FsError> {
+    get()?.set_cwd(path)
+}
+
+pub fn cwd() -> Result<String, FsError> {
+    Ok(get()?.cwd().path())
+}
+
+pub fn sync() -> Result<(), FsError> {
+    get()?.sync()
+}
+
+/// Writes cached filesystem data back to disk every `SYNC_INTERVAL_MS`, so a
+/// reset loses at most that much. Runs as a task.
+pub async fn run() {
+    loop {
+        time::sleep(SYNC_INTERVAL_MS).await;
+        if let Err(err) = sync() {
+            println!("vfs: sync failed: {:?}", err);
+        }
+    }
+}
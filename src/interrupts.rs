@@ -1,8 +1,9 @@
 use crate::gdt;
 use crate::hlt_loop;
-use crate::pci::drivers::rtl8139::rtl8139_interrupt_handler;
+use crate::pci::drivers::ata::{primary_ata_interrupt_handler, secondary_ata_interrupt_handler};
 use crate::print;
 use crate::println;
+use alloc::{boxed::Box, vec::Vec};
 use lazy_static::lazy_static;
 use x86_64::structures::idt::PageFaultErrorCode;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
@@ -28,7 +29,13 @@ lazy_static! {
         idt.page_fault.set_handler_fn(page_fault_handler);
         idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
         idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
-        idt[InterruptIndex::RTL8139.as_usize()].set_handler_fn(rtl8139_interrupt_handler);
+        idt[InterruptIndex::PrimaryAta.as_usize()].set_handler_fn(primary_ata_interrupt_handler);
+        idt[InterruptIndex::SecondaryAta.as_usize()]
+            .set_handler_fn(secondary_ata_interrupt_handler);
+        idt[(PIC_1_OFFSET + 5) as usize].set_handler_fn(irq5_handler);
+        idt[(PIC_2_OFFSET + 1) as usize].set_handler_fn(irq9_handler);
+        idt[(PIC_2_OFFSET + 2) as usize].set_handler_fn(irq10_handler);
+        idt[(PIC_2_OFFSET + 3) as usize].set_handler_fn(irq11_handler);
         idt
     };
 }
@@ -59,7 +66,8 @@ fn test_breakpoint_exception() {
 pub enum InterruptIndex {
     Timer = PIC_1_OFFSET,
     Keyboard,
-    RTL8139,
+    PrimaryAta = PIC_2_OFFSET + 6,
+    SecondaryAta = PIC_2_OFFSET + 7,
 }
 
 impl InterruptIndex {
@@ -72,11 +80,81 @@ impl InterruptIndex {
     }
 }
 
-static TIMER_COUNT: spin::RwLock<u32> = spin::RwLock::new(0);
+/// Lets `irq` through the PICs, including the cascade line for IRQs handled
+/// by the secondary PIC.
+pub fn unmask_irq(irq: u8) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut pics = PICS.lock();
+        let [mut primary, mut secondary] = unsafe { pics.read_masks() };
+        if irq < 8 {
+            primary &= !(1 << irq);
+        } else {
+            primary &= !(1 << 2);
+            secondary &= !(1 << (irq - 8));
+        }
+        unsafe { pics.write_masks(primary, secondary) };
+    });
+}
+
+type IrqHandler = Box<dyn Fn() + Send + Sync>;
+
+/// Handlers for the IRQ lines PCI devices are routed to. The lines are
+/// level triggered and can be shared, so every handler on a line runs.
+static IRQ_HANDLERS: spin::Mutex<Vec<(u8, IrqHandler)>> = spin::Mutex::new(Vec::new());
+
+/// The IRQ lines that [`register_irq_handler`] can be used with, which are
+/// the ones the BIOS hands out to PCI devices.
+pub const PCI_IRQS: [u8; 4] = [5, 9, 10, 11];
+
+/// Runs `handler` whenever `irq` fires, and unmasks it. The handler runs
+/// in interrupt context, so it must not take locks that are held with
+/// interrupts enabled.
+pub fn register_irq_handler(irq: u8, handler: IrqHandler) -> Result<(), u8> {
+    if !PCI_IRQS.contains(&irq) {
+        return Err(irq);
+    }
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        IRQ_HANDLERS.lock().push((irq, handler));
+    });
+    unmask_irq(irq);
+    Ok(())
+}
+
+fn dispatch_irq(irq: u8) {
+    for (line, handler) in IRQ_HANDLERS.lock().iter() {
+        if *line == irq {
+            handler();
+        }
+    }
+
+    let vector = if irq < 8 {
+        PIC_1_OFFSET + irq
+    } else {
+        PIC_2_OFFSET + irq - 8
+    };
+    unsafe {
+        PICS.lock().notify_end_of_interrupt(vector);
+    }
+}
+
+extern "x86-interrupt" fn irq5_handler(_stack_frame: InterruptStackFrame) {
+    dispatch_irq(5);
+}
+
+extern "x86-interrupt" fn irq9_handler(_stack_frame: InterruptStackFrame) {
+    dispatch_irq(9);
+}
+
+extern "x86-interrupt" fn irq10_handler(_stack_frame: InterruptStackFrame) {
+    dispatch_irq(10);
+}
+
+extern "x86-interrupt" fn irq11_handler(_stack_frame: InterruptStackFrame) {
+    dispatch_irq(11);
+}
 
 extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    let mut count = TIMER_COUNT.write();
-    *count += 1;
+    crate::time::tick();
 
     unsafe {
         PICS.lock()
@@ -0,0 +1,166 @@
+//! Kernel state as JSON over HTTP, for looking at a running kernel from
+//! the host with e.g. `curl localhost:8080/status`.
+//!
+//! | Path      | Contents                                          |
+//! |-----------|---------------------------------------------------|
+//! | `/status` | uptime, wall clock time and the interfaces        |
+//! | `/heap`   | size and use of the kernel heap                   |
+//! | `/pci`    | the functions found on the PCI bus at boot        |
+//! | `/tasks`  | the tasks on the executor and how often they ran  |
+
+use crate::networking::{
+    device,
+    http::{self, Method, Request, Response},
+    tcp,
+};
+use crate::{allocator, pci, rtc, task, time};
+use alloc::{format, string::String, vec::Vec};
+use core::fmt::Write;
+
+/// The port the server listens on. `cargo run` forwards host port 8080
+/// to it.
+pub const PORT: u16 = 80;
+
+pub const ENDPOINTS: [&str; 4] = ["/status", "/heap", "/pci", "/tasks"];
+
+/// Serves the endpoints on [`PORT`].
+pub async fn run() {
+    http::serve(PORT, handle).await;
+}
+
+/// Answers `request` with the JSON for its path.
+pub fn handle(request: &Request) -> Response {
+    if !matches!(request.method, Method::Get | Method::Head) {
+        return Response::error(405);
+    }
+    let body = match request.path.as_str() {
+        "/" => array(ENDPOINTS.iter().map(|path| string(path))),
+        "/status" => status(),
+        "/heap" => heap(),
+        "/pci" => pci(),
+        "/tasks" => tasks(),
+        _ => return Response::error(404),
+    };
+    Response::json(200, body)
+}
+
+pub fn status() -> String {
+    let now = rtc::now();
+    let interfaces = device::interfaces().into_iter().map(|interface| {
+        let nic = &interface.device;
+        let stats = nic.stats();
+        let mac = nic.mac();
+        let ipv4 = match interface.ipv4() {
+            Some(config) => string(&format!("{}/{}", config.address, config.prefix_len)),
+            None => String::from("null"),
+        };
+        let ipv6 = array(
+            interface
+                .ipv6()
+                .into_iter()
+                .map(|address| string(&format!("{}/{}", address.address, address.prefix_len))),
+        );
+        format!(
+            "{{\"name\":{},\"driver\":{},\"mac\":\"{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}\",\
+             \"mtu\":{},\"link_up\":{},\"ipv4\":{},\"ipv6\":{},\
+             \"rx_packets\":{},\"rx_bytes\":{},\"tx_packets\":{},\"tx_bytes\":{}}}",
+            string(&interface.name),
+            string(nic.driver()),
+            mac[0],
+            mac[1],
+            mac[2],
+            mac[3],
+            mac[4],
+            mac[5],
+            nic.mtu(),
+            nic.link_up(),
+            ipv4,
+            ipv6,
+            stats.rx_packets,
+            stats.rx_bytes,
+            stats.tx_packets,
+            stats.tx_bytes
+        )
+    });
+    format!(
+        "{{\"uptime_ms\":{},\"time\":\"{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z\",\
+         \"tasks\":{},\"tcp_connections\":{},\"interfaces\":{}}}",
+        time::uptime_ms(),
+        now.year,
+        now.month,
+        now.day,
+        now.hour,
+        now.minute,
+        now.second,
+        task::tasks().len(),
+        tcp::connections().len(),
+        array(interfaces)
+    )
+}
+
+pub fn heap() -> String {
+    let stats = allocator::stats();
+    format!(
+        "{{\"start\":{},\"size\":{},\"used\":{},\"free\":{}}}",
+        allocator::HEAP_START,
+        stats.size,
+        stats.used,
+        stats.free
+    )
+}
+
+pub fn pci() -> String {
+    array(pci::functions().iter().map(|function| {
+        format!(
+            "{{\"bus\":{},\"device\":{},\"function\":{},\"vendor_id\":{},\"device_id\":{},\
+             \"class\":{},\"subclass\":{},\"prog_if\":{},\"revision_id\":{}}}",
+            function.bus,
+            function.device,
+            function.function,
+            function.vendor_id,
+            function.device_id,
+            function.class,
+            function.subclass,
+            function.prog_if,
+            function.revision_id
+        )
+    }))
+}
+
+pub fn tasks() -> String {
+    array(task::tasks().into_iter().map(|info| {
+        format!(
+            "{{\"id\":{},\"name\":{},\"polls\":{}}}",
+            info.id,
+            string(info.name),
+            info.polls
+        )
+    }))
+}
+
+/// `s` as a JSON string, quotes included.
+pub fn string(s: &str) -> String {
+    let mut json = String::with_capacity(s.len() + 2);
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(json, "\\u{:04x}", c as u32);
+            }
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+/// A JSON array of values that are JSON already.
+fn array(values: impl Iterator<Item = String>) -> String {
+    let values: Vec<String> = values.collect();
+    format!("[{}]", values.join(","))
+}
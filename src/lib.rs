@@ -15,10 +15,14 @@ pub mod gdt;
 pub mod memory;
 pub mod allocator;
 pub mod task;
+pub mod block;
 pub mod fs;
 pub mod pci;
 pub mod networking;
 pub mod cli;
+pub mod rtc;
+pub mod time;
+pub mod introspect;
 
 use core::panic::PanicInfo;
 
@@ -26,6 +30,7 @@ pub fn init() {
     gdt::init();
     interrupts::init_idt();
     unsafe { interrupts::PICS.lock().initialize() };
+    time::init();
     x86_64::instructions::interrupts::enable();
 }
 
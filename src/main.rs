@@ -6,12 +6,16 @@
 
 extern crate alloc;
 
-use blight_os::networking::ethernet;
-use blight_os::pci::drivers::rtl8139;
-use blight_os::pci::find_network_card;
-use blight_os::pci::get_network_card;
-use blight_os::println;
-use blight_os::task::keyboard;
+use alloc::{format, sync::Arc};
+use blight_os::block::{cache::BufferCache, BlockDevice};
+use blight_os::cli;
+use blight_os::fs::{fat32::Fat32, initrd, tmpfs::TmpFs, vfs};
+use blight_os::networking::{
+    self, arp, dhcp, ip, ndp,
+    tcp::{self, TcpListener},
+};
+use blight_os::pci::drivers::{ahci, ata, virtio};
+use blight_os::{introspect, println, time};
 use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
 
@@ -34,45 +38,59 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 
     // new
     allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
-
-    for bus in 0..=255 {
-        blight_os::pci::check_bus(bus);
+    memory::init_kernel_memory(phys_mem_offset, mapper, frame_allocator);
+
+    vfs::init(Arc::new(TmpFs::new())).expect("failed to mount root filesystem");
+
+    match initrd::init(initrd::EMBEDDED) {
+        Ok(initrd) => {
+            println!("initrd: {} entries", initrd.entries().len());
+            vfs::create_dir("/initrd").expect("failed to create /initrd");
+            vfs::mount("/initrd", Arc::new(initrd::InitrdFs::new(initrd)))
+                .expect("failed to mount initrd");
+            if let Ok(motd) = vfs::read("/initrd/etc/motd") {
+                println!("{}", core::str::from_utf8(&motd).unwrap_or(""));
+            }
+        }
+        Err(err) => println!("initrd: failed to load: {:?}", err),
     }
 
-    let network_card = get_network_card().unwrap();
-
-    println!("Netcard: {:?}", network_card);
-
-    println!("Vendor ID: {:X}", network_card.vendor_id);
-    println!("Device ID: {:X}", network_card.device_id);
-
-    assert_eq!(network_card.vendor_id, 0x10EC);
-    assert_eq!(network_card.device_id, 0x8139);
-
-    let mut rtl8139 = rtl8139::Rtl8139::new(phys_mem_offset).unwrap();
+    blight_os::pci::scan();
 
-    println!("Mac: {:?}", rtl8139.mac);
-
-    let mut packet = ethernet::Packet::new();
-
-    packet.dest = rtl8139.mac;
-    packet.src = rtl8139.mac;
-    for i in 0..6 {
-        packet.data[i] = i as u8;
+    vfs::create_dir("/mnt").expect("failed to create /mnt");
+    for (i, drive) in ata::probe().into_iter().enumerate() {
+        mount_fat32(drive, &format!("/mnt/ata{}", i));
+    }
+    for (i, drive) in ahci::probe().into_iter().enumerate() {
+        mount_fat32(drive, &format!("/mnt/sata{}", i));
+    }
+    for (i, drive) in virtio::blk::probe().into_iter().enumerate() {
+        mount_fat32(drive, &format!("/mnt/vd{}", i));
     }
 
-    packet.len = 6;
-
-    rtl8139.send_packet(&packet.to_slice());
-
-    println!("You can now use the network card");
+    networking::init();
+    networking::device::probe();
 
     #[cfg(test)]
     test_main();
 
     let mut executor = Executor::new();
     executor.spawn(Task::new(example_task()));
-    executor.spawn(Task::new(keyboard::print_keypresses())); // new
+    executor.spawn(Task::new(cli::tty()));
+    executor.spawn(Task::new(time::run()));
+    executor.spawn(Task::new(vfs::run()));
+    executor.spawn(Task::new(arp::run()));
+    executor.spawn(Task::new(ip::run()));
+    executor.spawn(Task::new(ndp::run()));
+    executor.spawn(Task::new(tcp::run()));
+    executor.spawn(Task::new(tcp_echo()));
+    executor.spawn(Task::new(introspect::run()));
+    for interface in networking::device::interfaces() {
+        executor.spawn(Task::new(networking::receive(interface)));
+    }
+    if let Some(eth0) = networking::device::interface("eth0") {
+        executor.spawn(Task::new(dhcp::run(eth0)));
+    }
     executor.run();
 }
 
@@ -90,6 +108,19 @@ fn panic(info: &PanicInfo) -> ! {
     blight_os::test_panic_handler(info)
 }
 
+/// Mounts the FAT32 volume on `drive` at `path`, if it has one.
+fn mount_fat32(drive: Arc<dyn BlockDevice>, path: &str) {
+    let cache = Arc::new(BufferCache::with_default_capacity(drive));
+    // The boot image is usually the first drive and isn't FAT32
+    let Ok(fs) = Fat32::new(cache) else {
+        return;
+    };
+    match vfs::create_dir(path).and_then(|_| vfs::mount(path, Arc::new(fs))) {
+        Ok(()) => println!("mounted FAT32 volume at {}", path),
+        Err(err) => println!("failed to mount {}: {:?}", path, err),
+    }
+}
+
 async fn async_number() -> u32 {
     42
 }
@@ -98,3 +129,27 @@ async fn example_task() {
     let number = async_number().await;
     println!("async number: {}", number);
 }
+
+/// Port of the echo service, which QEMU forwards host port 5555 to.
+const ECHO_PORT: u16 = 7;
+
+/// Sends back whatever a TCP peer sends, one connection at a time.
+async fn tcp_echo() {
+    let listener = match TcpListener::bind(ECHO_PORT) {
+        Ok(listener) => listener,
+        Err(err) => {
+            println!("tcp echo: {:?}", err);
+            return;
+        }
+    };
+    loop {
+        let (stream, peer) = listener.accept().await;
+        println!("tcp echo: connection from {}", peer);
+        let mut buffer = [0; 512];
+        while let Ok(len) = stream.read(&mut buffer).await {
+            if len == 0 || stream.write_all(&buffer[..len]).await.is_err() {
+                break;
+            }
+        }
+    }
+}
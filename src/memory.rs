@@ -1,6 +1,9 @@
+use conquer_once::spin::OnceCell;
+use spin::Mutex;
 use x86_64::{
     structures::paging::{
-        FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PhysFrame, Size4KiB,
+        mapper::MapToError, FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PhysFrame,
+        Size4KiB,
     },
     PhysAddr, VirtAddr,
 };
@@ -151,3 +154,211 @@ unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
         frame
     }
 }
+
+/// Start of the virtual range that device registers are mapped into.
+pub const MMIO_START: u64 = 0x_5555_5555_0000;
+
+/// Page table and frame allocator kept for drivers, which need physically
+/// contiguous memory for DMA and uncached mappings for their registers.
+struct KernelMemory {
+    mapper: OffsetPageTable<'static>,
+    frame_allocator: BootInfoFrameAllocator,
+    next_mmio: u64,
+}
+
+static PHYSICAL_MEMORY_OFFSET: OnceCell<VirtAddr> = OnceCell::uninit();
+static KERNEL_MEMORY: OnceCell<Mutex<KernelMemory>> = OnceCell::uninit();
+
+/// Hands the mapper and frame allocator over to the DMA and MMIO helpers.
+/// Must be called after the heap is set up, since both keep using them.
+pub fn init_kernel_memory(
+    physical_memory_offset: VirtAddr,
+    mapper: OffsetPageTable<'static>,
+    frame_allocator: BootInfoFrameAllocator,
+) {
+    PHYSICAL_MEMORY_OFFSET.init_once(|| physical_memory_offset);
+    KERNEL_MEMORY.init_once(|| {
+        Mutex::new(KernelMemory {
+            mapper,
+            frame_allocator,
+            next_mmio: MMIO_START,
+        })
+    });
+}
+
+/// Returns the address physical memory can be accessed at through the
+/// bootloader's mapping of all physical memory.
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    let offset = PHYSICAL_MEMORY_OFFSET
+        .get()
+        .expect("kernel memory not initialized");
+    *offset + addr.as_u64()
+}
+
+/// Physically contiguous, zeroed memory that devices can read and write.
+///
+/// The frames are never returned to the frame allocator, which can't free,
+/// so drivers should allocate their buffers once and reuse them.
+#[derive(Debug)]
+pub struct DmaBuffer {
+    phys: PhysAddr,
+    virt: VirtAddr,
+    size: usize,
+}
+
+impl DmaBuffer {
+    /// The address to give to the device.
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.phys
+    }
+
+    pub fn as_ptr<T>(&self) -> *mut T {
+        self.virt.as_mut_ptr()
+    }
+
+    pub fn len(&self) -> usize {
+        self.size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.virt.as_ptr(), self.size) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.virt.as_mut_ptr(), self.size) }
+    }
+}
+
+/// The end of the memory devices with 32-bit DMA addresses can reach.
+pub const DMA32_LIMIT: u64 = 1 << 32;
+
+/// Allocates `size` bytes of contiguous physical memory, page aligned.
+///
+/// Usable frames come out of the allocator in address order, so a run is
+/// only broken at the edge of a memory region, in which case the frames
+/// before the gap are skipped.
+pub fn alloc_dma(size: usize) -> Option<DmaBuffer> {
+    alloc_dma_below(size, u64::MAX)
+}
+
+/// Like [`alloc_dma`], for devices that only take 32-bit addresses.
+pub fn alloc_dma32(size: usize) -> Option<DmaBuffer> {
+    alloc_dma_below(size, DMA32_LIMIT)
+}
+
+/// Allocates a DMA buffer that ends at or below physical address `limit`,
+/// or fails once the frames left are all above it.
+fn alloc_dma_below(size: usize, limit: u64) -> Option<DmaBuffer> {
+    let pages = size.div_ceil(4096).max(1) as u64;
+    let mut memory = KERNEL_MEMORY.get()?.lock();
+
+    let first = memory.frame_allocator.allocate_frame()?;
+    let mut start = first.start_address();
+    let mut count = 1;
+    while count < pages {
+        let frame = memory.frame_allocator.allocate_frame()?;
+        if frame.start_address() == start + count * 4096 {
+            count += 1;
+        } else {
+            start = frame.start_address();
+            count = 1;
+        }
+    }
+    drop(memory);
+    // Frames only get higher from here, so there is no point in going on
+    if start.as_u64().checked_add(pages * 4096)? > limit {
+        return None;
+    }
+
+    let buffer = DmaBuffer {
+        phys: start,
+        virt: phys_to_virt(start),
+        size: (pages * 4096) as usize,
+    };
+    unsafe { core::ptr::write_bytes(buffer.as_ptr::<u8>(), 0, buffer.size) };
+    Some(buffer)
+}
+
+/// Maps `size` bytes of device registers at `phys` as uncached memory.
+pub fn map_mmio(phys: PhysAddr, size: usize) -> Result<MmioRegion, MapToError<Size4KiB>> {
+    use x86_64::structures::paging::PageTableFlags as Flags;
+
+    let mut memory = KERNEL_MEMORY
+        .get()
+        .ok_or(MapToError::FrameAllocationFailed)?
+        .lock();
+    let memory = &mut *memory;
+
+    let first_frame = PhysFrame::<Size4KiB>::containing_address(phys);
+    let last_frame = PhysFrame::containing_address(phys + (size.max(1) - 1) as u64);
+    let base = VirtAddr::new(memory.next_mmio);
+    let flags = Flags::PRESENT | Flags::WRITABLE | Flags::NO_CACHE | Flags::WRITE_THROUGH;
+
+    for (i, frame) in PhysFrame::range_inclusive(first_frame, last_frame).enumerate() {
+        let page = Page::containing_address(base + i as u64 * 4096);
+        unsafe {
+            memory
+                .mapper
+                .map_to(page, frame, flags, &mut memory.frame_allocator)?
+                .flush()
+        };
+        memory.next_mmio += 4096;
+    }
+
+    Ok(MmioRegion {
+        base: base + (phys.as_u64() - first_frame.start_address().as_u64()),
+        size,
+    })
+}
+
+/// Device registers mapped by [`map_mmio`], accessed with volatile reads
+/// and writes at byte offsets.
+#[derive(Debug, Clone, Copy)]
+pub struct MmioRegion {
+    base: VirtAddr,
+    size: usize,
+}
+
+impl MmioRegion {
+    fn ptr<T>(&self, offset: usize) -> *mut T {
+        assert!(offset + core::mem::size_of::<T>() <= self.size);
+        (self.base + offset as u64).as_mut_ptr()
+    }
+
+    pub fn read_u8(&self, offset: usize) -> u8 {
+        unsafe { self.ptr::<u8>(offset).read_volatile() }
+    }
+
+    pub fn read_u16(&self, offset: usize) -> u16 {
+        unsafe { self.ptr::<u16>(offset).read_volatile() }
+    }
+
+    pub fn read_u32(&self, offset: usize) -> u32 {
+        unsafe { self.ptr::<u32>(offset).read_volatile() }
+    }
+
+    pub fn write_u8(&self, offset: usize, value: u8) {
+        unsafe { self.ptr::<u8>(offset).write_volatile(value) }
+    }
+
+    pub fn write_u16(&self, offset: usize, value: u16) {
+        unsafe { self.ptr::<u16>(offset).write_volatile(value) }
+    }
+
+    pub fn write_u32(&self, offset: usize, value: u32) {
+        unsafe { self.ptr::<u32>(offset).write_volatile(value) }
+    }
+
+    /// Returns the part of the region starting at `offset`.
+    pub fn subregion(&self, offset: usize, size: usize) -> MmioRegion {
+        assert!(offset + size <= self.size);
+        MmioRegion {
+            base: self.base + offset as u64,
+            size,
+        }
+    }
+}
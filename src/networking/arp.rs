@@ -0,0 +1,230 @@
+//! Address resolution for IPv4 over Ethernet.
+//!
+//! Resolved neighbors are kept for [`REACHABLE_MS`]. Packets sent to a
+//! neighbor that isn't resolved yet wait in its entry until the reply
+//! arrives, or are dropped once [`MAX_RETRIES`] requests went unanswered.
+
+use super::{
+    device::{self, Interface, MacAddress, NetError},
+    ethernet::{EtherType, Header, BROADCAST},
+    ip::Ipv4Addr,
+    neighbor::{self, NeighborCache, Resolver},
+};
+use crate::{println, time};
+use alloc::{collections::BTreeMap, string::String, vec::Vec};
+
+const HTYPE_ETHERNET: u16 = 1;
+const PTYPE_IPV4: u16 = 0x0800;
+pub const PACKET_SIZE: usize = 28;
+
+/// How long a resolved neighbor is trusted without hearing from it.
+pub const REACHABLE_MS: u64 = 60_000;
+const RETRY_MS: u64 = 1000;
+pub const MAX_RETRIES: u32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    Request,
+    Reply,
+}
+
+/// An ARP packet for IPv4 over Ethernet, the only kind handled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ArpPacket {
+    pub operation: Operation,
+    pub sender_mac: MacAddress,
+    pub sender_ip: Ipv4Addr,
+    pub target_mac: MacAddress,
+    pub target_ip: Ipv4Addr,
+}
+
+impl ArpPacket {
+    pub fn parse(buffer: &[u8]) -> Option<ArpPacket> {
+        if buffer.len() < PACKET_SIZE
+            || u16::from_be_bytes([buffer[0], buffer[1]]) != HTYPE_ETHERNET
+            || u16::from_be_bytes([buffer[2], buffer[3]]) != PTYPE_IPV4
+            || buffer[4] != 6
+            || buffer[5] != 4
+        {
+            return None;
+        }
+        let operation = match u16::from_be_bytes([buffer[6], buffer[7]]) {
+            1 => Operation::Request,
+            2 => Operation::Reply,
+            _ => return None,
+        };
+        Some(ArpPacket {
+            operation,
+            sender_mac: buffer[8..14].try_into().unwrap(),
+            sender_ip: Ipv4Addr::new(buffer[14], buffer[15], buffer[16], buffer[17]),
+            target_mac: buffer[18..24].try_into().unwrap(),
+            target_ip: Ipv4Addr::new(buffer[24], buffer[25], buffer[26], buffer[27]),
+        })
+    }
+
+    pub fn to_bytes(&self) -> [u8; PACKET_SIZE] {
+        let mut buffer = [0; PACKET_SIZE];
+        buffer[0..2].copy_from_slice(&HTYPE_ETHERNET.to_be_bytes());
+        buffer[2..4].copy_from_slice(&PTYPE_IPV4.to_be_bytes());
+        buffer[4] = 6;
+        buffer[5] = 4;
+        let operation: u16 = match self.operation {
+            Operation::Request => 1,
+            Operation::Reply => 2,
+        };
+        buffer[6..8].copy_from_slice(&operation.to_be_bytes());
+        buffer[8..14].copy_from_slice(&self.sender_mac);
+        buffer[14..18].copy_from_slice(&self.sender_ip.octets());
+        buffer[18..24].copy_from_slice(&self.target_mac);
+        buffer[24..28].copy_from_slice(&self.target_ip.octets());
+        buffer
+    }
+}
+
+static NEIGHBORS: NeighborCache<Ipv4Addr> = NeighborCache::new(Resolver {
+    name: "arp",
+    reachable_ms: REACHABLE_MS,
+    retry_ms: RETRY_MS,
+    max_retries: MAX_RETRIES,
+    solicit: send_request,
+});
+
+/// A neighbor cache entry, as shown by the `arp` command.
+pub type NeighborInfo = neighbor::NeighborInfo<Ipv4Addr>;
+
+pub fn neighbors() -> Vec<NeighborInfo> {
+    NEIGHBORS.neighbors()
+}
+
+pub fn lookup(address: Ipv4Addr) -> Option<MacAddress> {
+    NEIGHBORS.lookup(address)
+}
+
+/// Forgets every neighbor, dropping the packets still waiting for one.
+pub fn flush() {
+    NEIGHBORS.flush();
+}
+
+fn send_packet(interface: &Interface, dest: MacAddress, packet: ArpPacket) {
+    let header = Header::new(dest, interface.device.mac(), EtherType::Arp);
+    if let Err(err) = interface.device.send(&header.build(&packet.to_bytes())) {
+        println!("arp: {}: failed to send: {:?}", interface.name, err);
+    }
+}
+
+fn send_request(interface: &Interface, address: Ipv4Addr) {
+    let Some(config) = interface.ipv4() else {
+        return;
+    };
+    let packet = ArpPacket {
+        operation: Operation::Request,
+        sender_mac: interface.device.mac(),
+        sender_ip: config.address,
+        target_mac: [0; 6],
+        target_ip: address,
+    };
+    send_packet(interface, BROADCAST, packet);
+}
+
+/// Broadcasts a gratuitous ARP request for the interface's own address,
+/// so neighbors update their caches and conflicts show up.
+pub fn announce(interface: &Interface) {
+    let Some(config) = interface.ipv4() else {
+        return;
+    };
+    if config.address.is_loopback() {
+        return;
+    }
+    let packet = ArpPacket {
+        operation: Operation::Request,
+        sender_mac: interface.device.mac(),
+        sender_ip: config.address,
+        target_mac: [0; 6],
+        target_ip: config.address,
+    };
+    send_packet(interface, BROADCAST, packet);
+}
+
+/// Starts resolving `address` unless it is resolved or being resolved.
+pub fn resolve(interface: &Interface, address: Ipv4Addr) {
+    NEIGHBORS.resolve(interface, address);
+}
+
+/// Sends `payload` to the neighbor `next_hop`, or queues it until its
+/// address is resolved. Loopback addresses need no resolving, since the
+/// interface they are on only ever talks to itself.
+pub fn send_to(
+    interface: &Interface,
+    next_hop: Ipv4Addr,
+    ether_type: EtherType,
+    payload: &[u8],
+) -> Result<(), NetError> {
+    let broadcast = next_hop == Ipv4Addr::BROADCAST
+        || interface
+            .ipv4()
+            .is_some_and(|config| next_hop == config.broadcast());
+    let dest = if broadcast {
+        BROADCAST
+    } else if next_hop.is_loopback() {
+        interface.device.mac()
+    } else {
+        return NEIGHBORS.send_to(interface, next_hop, ether_type, payload);
+    };
+
+    let header = Header::new(dest, interface.device.mac(), ether_type);
+    interface.device.send(&header.build(payload))
+}
+
+/// Handles an ARP packet `interface` received.
+pub fn handle(interface: &Interface, payload: &[u8]) {
+    let Some(packet) = ArpPacket::parse(payload) else {
+        return;
+    };
+    let Some(config) = interface.ipv4() else {
+        return;
+    };
+    if packet.sender_ip == config.address && packet.sender_mac != interface.device.mac() {
+        println!(
+            "arp: {}: {} is also used by {:02x?}",
+            interface.name, config.address, packet.sender_mac
+        );
+        return;
+    }
+
+    // As in RFC 826, refresh entries we already have, and learn the sender
+    // if the packet is meant for us
+    let known = NEIGHBORS.contains(packet.sender_ip);
+    let for_us = packet.target_ip == config.address;
+    if (known || for_us) && !packet.sender_ip.is_unspecified() {
+        NEIGHBORS.update(interface, packet.sender_ip, packet.sender_mac);
+    }
+
+    if for_us && packet.operation == Operation::Request {
+        let reply = ArpPacket {
+            operation: Operation::Reply,
+            sender_mac: interface.device.mac(),
+            sender_ip: config.address,
+            target_mac: packet.sender_mac,
+            target_ip: packet.sender_ip,
+        };
+        send_packet(interface, packet.sender_mac, reply);
+    }
+}
+
+/// Keeps the neighbor cache up to date and announces interfaces whose link
+/// comes back up. Runs as a task.
+pub async fn run() {
+    let mut link_up: BTreeMap<String, bool> = BTreeMap::new();
+    loop {
+        NEIGHBORS.expire();
+        for interface in device::interfaces() {
+            let up = interface.device.link_up();
+            // Interfaces are announced when they are configured, so only
+            // changes after that matter
+            if link_up.insert(interface.name.clone(), up) == Some(false) && up {
+                announce(&interface);
+            }
+        }
+        time::sleep(RETRY_MS).await;
+    }
+}
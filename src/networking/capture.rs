@@ -0,0 +1,160 @@
+//! Packet capture: every frame the interfaces send or receive, streamed to
+//! COM2 in the pcap format Wireshark opens.
+//!
+//! Capturing is off until [`start`] is called. The pcap file header goes
+//! out the first time, and each frame after that becomes one record,
+//! timestamped with the RTC time at the start plus the uptime since.
+
+use super::device::{
+    self, ChecksumRequest, FrameFuture, MacAddress, NetError, NetStats, NetworkDevice,
+};
+use crate::{rtc, serial, time};
+use alloc::{boxed::Box, sync::Arc};
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+
+/// Written in our byte order, so readers can tell which it is.
+const MAGIC: u32 = 0xA1B2_C3D4;
+const VERSION_MAJOR: u16 = 2;
+const VERSION_MINOR: u16 = 4;
+/// Frames are recorded whole, and none are longer than this.
+pub const SNAPLEN: u32 = 65535;
+const LINKTYPE_ETHERNET: u32 = 1;
+
+pub const FILE_HEADER_SIZE: usize = 24;
+/// Timestamp seconds and microseconds, captured length and original length.
+pub const RECORD_HEADER_SIZE: usize = 16;
+
+/// The header a pcap file of Ethernet frames starts with.
+pub fn file_header() -> [u8; FILE_HEADER_SIZE] {
+    let mut header = [0; FILE_HEADER_SIZE];
+    header[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+    header[4..6].copy_from_slice(&VERSION_MAJOR.to_le_bytes());
+    header[6..8].copy_from_slice(&VERSION_MINOR.to_le_bytes());
+    // The time zone offset and timestamp accuracy stay 0
+    header[16..20].copy_from_slice(&SNAPLEN.to_le_bytes());
+    header[20..24].copy_from_slice(&LINKTYPE_ETHERNET.to_le_bytes());
+    header
+}
+
+/// The header of the record for a `len` byte frame captured
+/// `timestamp_us` microseconds after the Unix epoch.
+pub fn record_header(timestamp_us: u64, len: usize) -> [u8; RECORD_HEADER_SIZE] {
+    let mut header = [0; RECORD_HEADER_SIZE];
+    let secs = (timestamp_us / 1_000_000) as u32;
+    let micros = (timestamp_us % 1_000_000) as u32;
+    header[0..4].copy_from_slice(&secs.to_le_bytes());
+    header[4..8].copy_from_slice(&micros.to_le_bytes());
+    header[8..12].copy_from_slice(&(len as u32).to_le_bytes());
+    header[12..16].copy_from_slice(&(len as u32).to_le_bytes());
+    header
+}
+
+/// The Unix time and uptime when capturing first started, to timestamp
+/// records with.
+#[derive(Clone, Copy)]
+struct Clock {
+    unix_secs: u64,
+    uptime_ms: u64,
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static CLOCK: Mutex<Option<Clock>> = Mutex::new(None);
+
+/// Starts capturing, writing the file header first if this is the first
+/// time.
+pub fn start() {
+    let mut clock = CLOCK.lock();
+    if clock.is_none() {
+        *clock = Some(Clock {
+            unix_secs: rtc::now().unix_timestamp(),
+            uptime_ms: time::uptime_ms(),
+        });
+        serial::write_com2(&file_header());
+    }
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Stops capturing. Starting again continues the same stream.
+pub fn stop() {
+    ENABLED.store(false, Ordering::Relaxed);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Writes `frame` to the capture if capturing is on.
+pub fn record(frame: &[u8]) {
+    if !is_enabled() {
+        return;
+    }
+    let Some(clock) = *CLOCK.lock() else {
+        return;
+    };
+    let elapsed_ms = time::uptime_ms() - clock.uptime_ms;
+    let timestamp_us = (clock.unix_secs * 1000 + elapsed_ms) * 1000;
+    let len = frame.len().min(SNAPLEN as usize);
+    // Written in one go, so records from different tasks don't interleave
+    let mut record = record_header(timestamp_us, len).to_vec();
+    record.extend_from_slice(&frame[..len]);
+    serial::write_com2(&record);
+}
+
+/// Wraps a network card so the frames it sends and receives are captured.
+/// [`device::register`] puts every card in one.
+pub struct Tap {
+    inner: Arc<dyn NetworkDevice>,
+}
+
+impl Tap {
+    pub fn new(inner: Arc<dyn NetworkDevice>) -> Tap {
+        Tap { inner }
+    }
+}
+
+impl NetworkDevice for Tap {
+    fn driver(&self) -> &'static str {
+        self.inner.driver()
+    }
+
+    fn mac(&self) -> MacAddress {
+        self.inner.mac()
+    }
+
+    fn mtu(&self) -> usize {
+        self.inner.mtu()
+    }
+
+    fn link_up(&self) -> bool {
+        self.inner.link_up()
+    }
+
+    fn stats(&self) -> NetStats {
+        self.inner.stats()
+    }
+
+    fn send(&self, frame: &[u8]) -> Result<(), NetError> {
+        record(frame);
+        self.inner.send(frame)
+    }
+
+    fn send_with_checksum(&self, frame: &[u8], checksum: ChecksumRequest) -> Result<(), NetError> {
+        if is_enabled() {
+            // Capture the frame as it goes on the wire, with the checksum
+            // the card fills in
+            let mut filled = frame.to_vec();
+            device::fill_checksum(&mut filled, checksum);
+            record(&filled);
+        }
+        self.inner.send_with_checksum(frame, checksum)
+    }
+
+    fn receive(&self) -> FrameFuture<'_> {
+        Box::pin(async move {
+            let frame = self.inner.receive().await;
+            record(&frame);
+            frame
+        })
+    }
+}
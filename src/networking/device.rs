@@ -0,0 +1,295 @@
+use super::{
+    capture::Tap,
+    ip::{Ipv4Addr, Ipv4Config},
+    ipv6::{Ipv6Addr, Ipv6Address},
+};
+use crate::{
+    pci::drivers::{
+        e1000,
+        rtl8139::{Rtl8139, Rtl8139Error},
+        virtio,
+    },
+    println,
+};
+use alloc::{boxed::Box, format, string::String, sync::Arc, vec::Vec};
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+};
+use spin::Mutex;
+
+pub type MacAddress = [u8; 6];
+
+/// Largest Ethernet frame without the FCS: the header and a 1500 byte
+/// payload.
+pub const MAX_FRAME_SIZE: usize = 1514;
+
+/// Payload size of a standard Ethernet frame.
+pub const DEFAULT_MTU: usize = 1500;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NetError {
+    FrameTooLarge,
+    /// Every transmit buffer is still owned by the device.
+    QueueFull,
+    Io,
+}
+
+pub type FrameFuture<'a> = Pin<Box<dyn Future<Output = Vec<u8>> + 'a>>;
+
+/// Where the device should store a checksum it calculates, as offsets into
+/// the frame. The checksum field itself must hold the pseudo header sum.
+#[derive(Debug, Clone, Copy)]
+pub struct ChecksumRequest {
+    pub start: usize,
+    pub offset: usize,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NetStats {
+    pub rx_packets: u64,
+    pub rx_bytes: u64,
+    pub rx_errors: u64,
+    /// Frames the card received but had nowhere to put.
+    pub rx_dropped: u64,
+    pub tx_packets: u64,
+    pub tx_bytes: u64,
+    pub tx_errors: u64,
+}
+
+/// Counters behind [`NetStats`] that drivers can update from their
+/// interrupt handlers.
+#[derive(Debug, Default)]
+pub struct NetCounters {
+    rx_packets: AtomicU64,
+    rx_bytes: AtomicU64,
+    rx_errors: AtomicU64,
+    rx_dropped: AtomicU64,
+    tx_packets: AtomicU64,
+    tx_bytes: AtomicU64,
+    tx_errors: AtomicU64,
+}
+
+impl NetCounters {
+    pub fn received(&self, len: usize) {
+        self.rx_packets.fetch_add(1, Ordering::Relaxed);
+        self.rx_bytes.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    pub fn sent(&self, len: usize) {
+        self.tx_packets.fetch_add(1, Ordering::Relaxed);
+        self.tx_bytes.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    pub fn rx_error(&self) {
+        self.rx_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn rx_dropped(&self) {
+        self.rx_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn tx_error(&self) {
+        self.tx_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> NetStats {
+        NetStats {
+            rx_packets: self.rx_packets.load(Ordering::Relaxed),
+            rx_bytes: self.rx_bytes.load(Ordering::Relaxed),
+            rx_errors: self.rx_errors.load(Ordering::Relaxed),
+            rx_dropped: self.rx_dropped.load(Ordering::Relaxed),
+            tx_packets: self.tx_packets.load(Ordering::Relaxed),
+            tx_bytes: self.tx_bytes.load(Ordering::Relaxed),
+            tx_errors: self.tx_errors.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// A network card that sends and receives Ethernet frames.
+pub trait NetworkDevice: Send + Sync {
+    /// Name of the driver, for log messages.
+    fn driver(&self) -> &'static str;
+
+    fn mac(&self) -> MacAddress;
+
+    /// Largest payload of a frame the card sends or receives.
+    fn mtu(&self) -> usize {
+        DEFAULT_MTU
+    }
+
+    fn link_up(&self) -> bool;
+
+    fn stats(&self) -> NetStats;
+
+    /// Queues `frame` for transmission. The frame doesn't include the FCS,
+    /// which the card appends.
+    fn send(&self, frame: &[u8]) -> Result<(), NetError>;
+
+    /// Sends `frame` with the ones' complement checksum described by
+    /// `checksum` still to be filled in. Cards that can't do that in
+    /// hardware have it calculated here.
+    fn send_with_checksum(&self, frame: &[u8], checksum: ChecksumRequest) -> Result<(), NetError> {
+        let mut frame = frame.to_vec();
+        fill_checksum(&mut frame, checksum);
+        self.send(&frame)
+    }
+
+    /// Waits for the next received frame.
+    fn receive(&self) -> FrameFuture<'_>;
+}
+
+/// Folds the checksum of `frame[start..]` into the field at `start +
+/// offset`, which holds the partial sum to start from.
+pub fn fill_checksum(frame: &mut [u8], checksum: ChecksumRequest) {
+    let field = checksum.start + checksum.offset;
+    if field + 2 > frame.len() {
+        return;
+    }
+    let mut sum = 0u32;
+    for chunk in frame[checksum.start..].chunks(2) {
+        let word = match chunk {
+            [high, low] => u16::from_be_bytes([*high, *low]),
+            [high] => u16::from_be_bytes([*high, 0]),
+            _ => unreachable!(),
+        };
+        sum += word as u32;
+    }
+    while sum > 0xFFFF {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    frame[field..field + 2].copy_from_slice(&(!(sum as u16)).to_be_bytes());
+}
+
+/// A registered network card, the name it goes by and its addresses.
+pub struct Interface {
+    pub name: String,
+    pub device: Arc<dyn NetworkDevice>,
+    ipv4: Mutex<Option<Ipv4Config>>,
+    ipv6: Mutex<Vec<Ipv6Address>>,
+    dns_servers: Mutex<Vec<Ipv4Addr>>,
+}
+
+impl Interface {
+    pub fn ipv4(&self) -> Option<Ipv4Config> {
+        *self.ipv4.lock()
+    }
+
+    /// Changes the address without telling the network. See
+    /// [`super::configure`] for that.
+    pub fn set_ipv4(&self, config: Option<Ipv4Config>) {
+        *self.ipv4.lock() = config;
+    }
+
+    pub fn ipv6(&self) -> Vec<Ipv6Address> {
+        self.ipv6.lock().clone()
+    }
+
+    /// Adds `address`, or updates it if the interface already has it.
+    pub fn add_ipv6(&self, address: Ipv6Address) {
+        let mut addresses = self.ipv6.lock();
+        match addresses
+            .iter_mut()
+            .find(|other| other.address == address.address)
+        {
+            Some(other) => *other = address,
+            None => addresses.push(address),
+        }
+    }
+
+    pub fn remove_ipv6(&self, address: Ipv6Addr) {
+        self.ipv6.lock().retain(|other| other.address != address);
+    }
+
+    /// The DNS servers the network told us about.
+    pub fn dns_servers(&self) -> Vec<Ipv4Addr> {
+        self.dns_servers.lock().clone()
+    }
+
+    pub fn set_dns_servers(&self, servers: Vec<Ipv4Addr>) {
+        *self.dns_servers.lock() = servers;
+    }
+}
+
+static INTERFACES: Mutex<Vec<Arc<Interface>>> = Mutex::new(Vec::new());
+
+/// Adds `device` to the registry as the next free `ethN`, behind a
+/// [`Tap`] so its frames can be captured.
+pub fn register(device: Arc<dyn NetworkDevice>) -> Arc<Interface> {
+    let mut interfaces = INTERFACES.lock();
+    let cards = interfaces
+        .iter()
+        .filter(|interface| interface.name.starts_with("eth"))
+        .count();
+    add(&mut interfaces, format!("eth{}", cards), device)
+}
+
+/// Adds `device` to the registry as `name`, for interfaces that aren't
+/// network cards.
+pub fn register_named(name: &str, device: Arc<dyn NetworkDevice>) -> Arc<Interface> {
+    add(&mut INTERFACES.lock(), String::from(name), device)
+}
+
+fn add(
+    interfaces: &mut Vec<Arc<Interface>>,
+    name: String,
+    device: Arc<dyn NetworkDevice>,
+) -> Arc<Interface> {
+    let interface = Arc::new(Interface {
+        name,
+        device: Arc::new(Tap::new(device)),
+        ipv4: Mutex::new(None),
+        ipv6: Mutex::new(Vec::new()),
+        dns_servers: Mutex::new(Vec::new()),
+    });
+    interfaces.push(interface.clone());
+    interface
+}
+
+/// Every registered interface, in the order they were registered.
+pub fn interfaces() -> Vec<Arc<Interface>> {
+    INTERFACES.lock().clone()
+}
+
+pub fn interface(name: &str) -> Option<Arc<Interface>> {
+    INTERFACES
+        .lock()
+        .iter()
+        .find(|interface| interface.name == name)
+        .cloned()
+}
+
+pub fn get(name: &str) -> Option<Arc<dyn NetworkDevice>> {
+    interface(name).map(|interface| interface.device.clone())
+}
+
+/// Sets up every supported network card and registers them, virtio-net
+/// cards first, then the e1000s and finally the RTL8139. Returns how many
+/// were found.
+pub fn probe() -> usize {
+    let mut devices: Vec<Arc<dyn NetworkDevice>> = Vec::new();
+    for device in virtio::net::probe() {
+        devices.push(device);
+    }
+    for device in e1000::probe() {
+        devices.push(device);
+    }
+    match Rtl8139::new() {
+        Ok(device) => devices.push(device),
+        Err(Rtl8139Error::InvalidHeader) => {}
+        Err(err) => println!("rtl8139: failed to set up device: {:?}", err),
+    }
+
+    let count = devices.len();
+    for device in devices {
+        let interface = register(device.clone());
+        println!(
+            "{}: {} {:02x?}",
+            interface.name,
+            device.driver(),
+            device.mac()
+        );
+    }
+    count
+}
@@ -0,0 +1,507 @@
+//! DHCPv4 client, as in RFC 2131.
+//!
+//! [`run`] leases an address for an interface, configures the interface
+//! with it and keeps renewing it. Without an answer it keeps asking, with
+//! the backoff the RFC suggests.
+
+use super::{
+    device::{Interface, MacAddress},
+    ip::{Ipv4Addr, Ipv4Config},
+    udp::{UdpError, UdpSocket},
+};
+use crate::{println, time};
+use alloc::{collections::BTreeMap, string::String, sync::Arc, vec::Vec};
+use core::net::SocketAddrV4;
+use spin::Mutex;
+
+pub const SERVER_PORT: u16 = 67;
+pub const CLIENT_PORT: u16 = 68;
+
+const OP_REQUEST: u8 = 1;
+const OP_REPLY: u8 = 2;
+const HTYPE_ETHERNET: u8 = 1;
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+/// Everything before the options, including the magic cookie.
+const FIXED_SIZE: usize = 240;
+/// Some old BOOTP relays drop anything shorter.
+const MIN_MESSAGE_SIZE: usize = 300;
+
+const OPTION_PAD: u8 = 0;
+const OPTION_SUBNET_MASK: u8 = 1;
+const OPTION_ROUTER: u8 = 3;
+const OPTION_DNS_SERVERS: u8 = 6;
+const OPTION_REQUESTED_IP: u8 = 50;
+const OPTION_LEASE_TIME: u8 = 51;
+const OPTION_MESSAGE_TYPE: u8 = 53;
+const OPTION_SERVER_ID: u8 = 54;
+const OPTION_PARAMETER_LIST: u8 = 55;
+const OPTION_RENEWAL_TIME: u8 = 58;
+const OPTION_REBINDING_TIME: u8 = 59;
+const OPTION_END: u8 = 255;
+
+/// The first retransmission timeout, doubled up to [`MAX_TIMEOUT_MS`].
+const INITIAL_TIMEOUT_MS: u64 = 4000;
+const MAX_TIMEOUT_MS: u64 = 64_000;
+/// Requests while renewing or rebinding aren't retried sooner than this.
+const MIN_RENEW_WAIT_MS: u64 = 60_000;
+/// Lease time that means forever.
+const INFINITE: u32 = u32::MAX;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageType {
+    Discover,
+    Offer,
+    Request,
+    Decline,
+    Ack,
+    Nak,
+    Release,
+    Inform,
+}
+
+impl MessageType {
+    fn from_u8(value: u8) -> Option<MessageType> {
+        Some(match value {
+            1 => MessageType::Discover,
+            2 => MessageType::Offer,
+            3 => MessageType::Request,
+            4 => MessageType::Decline,
+            5 => MessageType::Ack,
+            6 => MessageType::Nak,
+            7 => MessageType::Release,
+            8 => MessageType::Inform,
+            _ => return None,
+        })
+    }
+
+    fn to_u8(self) -> u8 {
+        match self {
+            MessageType::Discover => 1,
+            MessageType::Offer => 2,
+            MessageType::Request => 3,
+            MessageType::Decline => 4,
+            MessageType::Ack => 5,
+            MessageType::Nak => 6,
+            MessageType::Release => 7,
+            MessageType::Inform => 8,
+        }
+    }
+}
+
+/// A DHCP message with the options this client uses.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub op: u8,
+    pub xid: u32,
+    /// Seconds since the client started asking.
+    pub secs: u16,
+    /// Client address, while renewing.
+    pub ciaddr: Ipv4Addr,
+    /// The address the server offers.
+    pub yiaddr: Ipv4Addr,
+    pub chaddr: MacAddress,
+    pub message_type: MessageType,
+    pub requested_ip: Option<Ipv4Addr>,
+    pub server_id: Option<Ipv4Addr>,
+    pub subnet_mask: Option<Ipv4Addr>,
+    pub router: Option<Ipv4Addr>,
+    pub dns_servers: Vec<Ipv4Addr>,
+    pub lease_secs: Option<u32>,
+    pub renewal_secs: Option<u32>,
+    pub rebinding_secs: Option<u32>,
+}
+
+fn read_addr(bytes: &[u8]) -> Ipv4Addr {
+    Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3])
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes(bytes[..4].try_into().unwrap())
+}
+
+impl Message {
+    /// A message from the client with `mac`.
+    pub fn request(message_type: MessageType, xid: u32, mac: MacAddress) -> Message {
+        Message {
+            op: OP_REQUEST,
+            xid,
+            secs: 0,
+            ciaddr: Ipv4Addr::UNSPECIFIED,
+            yiaddr: Ipv4Addr::UNSPECIFIED,
+            chaddr: mac,
+            message_type,
+            requested_ip: None,
+            server_id: None,
+            subnet_mask: None,
+            router: None,
+            dns_servers: Vec::new(),
+            lease_secs: None,
+            renewal_secs: None,
+            rebinding_secs: None,
+        }
+    }
+
+    pub fn parse(buffer: &[u8]) -> Option<Message> {
+        if buffer.len() < FIXED_SIZE
+            || buffer[1] != HTYPE_ETHERNET
+            || buffer[2] != 6
+            || buffer[236..240] != MAGIC_COOKIE
+        {
+            return None;
+        }
+        let mut message_type = None;
+        let mut message = Message::request(MessageType::Discover, 0, [0; 6]);
+        message.op = buffer[0];
+        message.xid = read_u32(&buffer[4..8]);
+        message.secs = u16::from_be_bytes([buffer[8], buffer[9]]);
+        message.ciaddr = read_addr(&buffer[12..16]);
+        message.yiaddr = read_addr(&buffer[16..20]);
+        message.chaddr = buffer[28..34].try_into().unwrap();
+
+        let mut options = &buffer[FIXED_SIZE..];
+        while let Some(&code) = options.first() {
+            match code {
+                OPTION_PAD => {
+                    options = &options[1..];
+                    continue;
+                }
+                OPTION_END => break,
+                _ => {}
+            }
+            let len = *options.get(1)? as usize;
+            let data = options.get(2..2 + len)?;
+            match (code, len) {
+                (OPTION_MESSAGE_TYPE, 1) => message_type = MessageType::from_u8(data[0]),
+                (OPTION_SUBNET_MASK, 4) => message.subnet_mask = Some(read_addr(data)),
+                (OPTION_ROUTER, 4..) => message.router = Some(read_addr(data)),
+                (OPTION_DNS_SERVERS, _) => {
+                    message.dns_servers = data.chunks_exact(4).map(read_addr).collect()
+                }
+                (OPTION_REQUESTED_IP, 4) => message.requested_ip = Some(read_addr(data)),
+                (OPTION_SERVER_ID, 4) => message.server_id = Some(read_addr(data)),
+                (OPTION_LEASE_TIME, 4) => message.lease_secs = Some(read_u32(data)),
+                (OPTION_RENEWAL_TIME, 4) => message.renewal_secs = Some(read_u32(data)),
+                (OPTION_REBINDING_TIME, 4) => message.rebinding_secs = Some(read_u32(data)),
+                _ => {}
+            }
+            options = &options[2 + len..];
+        }
+
+        message.message_type = message_type?;
+        Some(message)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buffer = Vec::with_capacity(MIN_MESSAGE_SIZE);
+        buffer.extend_from_slice(&[self.op, HTYPE_ETHERNET, 6, 0]);
+        buffer.extend_from_slice(&self.xid.to_be_bytes());
+        buffer.extend_from_slice(&self.secs.to_be_bytes());
+        buffer.extend_from_slice(&[0, 0]);
+        buffer.extend_from_slice(&self.ciaddr.octets());
+        buffer.extend_from_slice(&self.yiaddr.octets());
+        // siaddr and giaddr are for servers and relays
+        buffer.extend_from_slice(&[0; 8]);
+        buffer.extend_from_slice(&self.chaddr);
+        // The rest of chaddr, sname and file
+        buffer.resize(236, 0);
+        buffer.extend_from_slice(&MAGIC_COOKIE);
+
+        buffer.extend_from_slice(&[OPTION_MESSAGE_TYPE, 1, self.message_type.to_u8()]);
+        let addresses = [
+            (OPTION_REQUESTED_IP, self.requested_ip),
+            (OPTION_SERVER_ID, self.server_id),
+            (OPTION_SUBNET_MASK, self.subnet_mask),
+            (OPTION_ROUTER, self.router),
+        ];
+        for (code, address) in addresses {
+            if let Some(address) = address {
+                buffer.extend_from_slice(&[code, 4]);
+                buffer.extend_from_slice(&address.octets());
+            }
+        }
+        if !self.dns_servers.is_empty() {
+            buffer.extend_from_slice(&[OPTION_DNS_SERVERS, 4 * self.dns_servers.len() as u8]);
+            for server in &self.dns_servers {
+                buffer.extend_from_slice(&server.octets());
+            }
+        }
+        let times = [
+            (OPTION_LEASE_TIME, self.lease_secs),
+            (OPTION_RENEWAL_TIME, self.renewal_secs),
+            (OPTION_REBINDING_TIME, self.rebinding_secs),
+        ];
+        for (code, time) in times {
+            if let Some(time) = time {
+                buffer.extend_from_slice(&[code, 4]);
+                buffer.extend_from_slice(&time.to_be_bytes());
+            }
+        }
+        if self.op == OP_REQUEST {
+            buffer.extend_from_slice(&[
+                OPTION_PARAMETER_LIST,
+                6,
+                OPTION_SUBNET_MASK,
+                OPTION_ROUTER,
+                OPTION_DNS_SERVERS,
+                OPTION_LEASE_TIME,
+                OPTION_RENEWAL_TIME,
+                OPTION_REBINDING_TIME,
+            ]);
+        }
+        buffer.push(OPTION_END);
+        if buffer.len() < MIN_MESSAGE_SIZE {
+            buffer.resize(MIN_MESSAGE_SIZE, 0);
+        }
+        buffer
+    }
+}
+
+/// An address leased from a server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lease {
+    pub config: Ipv4Config,
+    pub dns_servers: Vec<Ipv4Addr>,
+    pub server: Ipv4Addr,
+    /// Uptime when the lease was granted, and the times from there on when
+    /// to renew it (T1), rebind it (T2) and when it runs out. `None` for
+    /// leases that don't.
+    pub obtained_ms: u64,
+    pub renew_ms: Option<u64>,
+    pub rebind_ms: Option<u64>,
+    pub expires_ms: Option<u64>,
+}
+
+impl Lease {
+    /// The lease an ACK grants, if it has everything needed.
+    pub fn from_ack(ack: &Message, now: u64) -> Option<Lease> {
+        let server = ack.server_id?;
+        let lease_secs = ack.lease_secs?;
+        // Servers send the mask in practice, but assume a /24 without it
+        let prefix_len = ack
+            .subnet_mask
+            .map_or(24, |mask| u32::from(mask).leading_ones() as u8);
+        let at = |secs: u32| (lease_secs != INFINITE).then(|| now + secs as u64 * 1000);
+        Some(Lease {
+            config: Ipv4Config {
+                address: ack.yiaddr,
+                prefix_len,
+                gateway: ack.router,
+            },
+            dns_servers: ack.dns_servers.clone(),
+            server,
+            obtained_ms: now,
+            renew_ms: at(ack.renewal_secs.unwrap_or(lease_secs / 2)),
+            rebind_ms: at(ack.rebinding_secs.unwrap_or(lease_secs / 8 * 7)),
+            expires_ms: at(lease_secs),
+        })
+    }
+}
+
+static LEASES: Mutex<BTreeMap<String, Lease>> = Mutex::new(BTreeMap::new());
+
+/// The lease of the interface called `name`, if it has one.
+pub fn lease(name: &str) -> Option<Lease> {
+    LEASES.lock().get(name).cloned()
+}
+
+fn apply(interface: &Interface, lease: Option<&Lease>) {
+    match lease {
+        Some(lease) => {
+            if interface.ipv4() != Some(lease.config) {
+                super::configure(interface, Some(lease.config));
+            }
+            interface.set_dns_servers(lease.dns_servers.clone());
+            LEASES.lock().insert(interface.name.clone(), lease.clone());
+        }
+        None => {
+            super::configure(interface, None);
+            interface.set_dns_servers(Vec::new());
+            LEASES.lock().remove(&interface.name);
+        }
+    }
+}
+
+/// Sends `message` and waits until `deadline_ms` for a reply to it of one
+/// of `types`.
+async fn exchange(
+    socket: &UdpSocket,
+    interface: &Interface,
+    message: &Message,
+    dst: Ipv4Addr,
+    deadline_ms: u64,
+    types: &[MessageType],
+) -> Option<Message> {
+    let src = message.ciaddr;
+    let dst = SocketAddrV4::new(dst, SERVER_PORT);
+    let sent = if src.is_unspecified() {
+        socket
+            .send_via(interface, src, &message.to_bytes(), dst)
+            .await
+    } else {
+        socket.send_to(&message.to_bytes(), dst.into()).await
+    };
+    if let Err(err) = sent {
+        println!("dhcp: {}: failed to send: {:?}", interface.name, err);
+    }
+
+    loop {
+        let remaining = deadline_ms.saturating_sub(time::uptime_ms());
+        let (buffer, _) = time::timeout(remaining, socket.recv_from()).await.ok()?;
+        let Some(reply) = Message::parse(&buffer) else {
+            continue;
+        };
+        if reply.op == OP_REPLY
+            && reply.xid == message.xid
+            && reply.chaddr == message.chaddr
+            && types.contains(&reply.message_type)
+        {
+            return Some(reply);
+        }
+    }
+}
+
+/// A transaction ID. There is no randomness to draw from, so the time and
+/// MAC address keep clients from picking the same ones.
+fn new_xid(mac: MacAddress) -> u32 {
+    let mac = u32::from_be_bytes(mac[2..6].try_into().unwrap());
+    (time::ticks() as u32).wrapping_mul(0x9E37_79B9) ^ mac
+}
+
+/// Gets an offer and requests it, until a server acknowledges.
+async fn acquire(socket: &UdpSocket, interface: &Interface) -> Lease {
+    let mac = interface.device.mac();
+    let started = time::uptime_ms();
+    let mut timeout = INITIAL_TIMEOUT_MS;
+    loop {
+        let xid = new_xid(mac);
+        let mut discover = Message::request(MessageType::Discover, xid, mac);
+        discover.secs = ((time::uptime_ms() - started) / 1000) as u16;
+        let deadline = time::uptime_ms() + timeout;
+        timeout = (timeout * 2).min(MAX_TIMEOUT_MS);
+        let offer = exchange(
+            socket,
+            interface,
+            &discover,
+            Ipv4Addr::BROADCAST,
+            deadline,
+            &[MessageType::Offer],
+        )
+        .await;
+        let Some(offer) = offer else {
+            continue;
+        };
+
+        let mut request = Message::request(MessageType::Request, xid, mac);
+        request.secs = discover.secs;
+        request.requested_ip = Some(offer.yiaddr);
+        request.server_id = offer.server_id;
+        let deadline = time::uptime_ms() + INITIAL_TIMEOUT_MS;
+        let reply = exchange(
+            socket,
+            interface,
+            &request,
+            Ipv4Addr::BROADCAST,
+            deadline,
+            &[MessageType::Ack, MessageType::Nak],
+        )
+        .await;
+        let now = time::uptime_ms();
+        match reply.and_then(|reply| Lease::from_ack(&reply, now)) {
+            Some(lease) => return lease,
+            None => continue,
+        }
+    }
+}
+
+/// Asks for `lease` to be extended until `until_ms`: from its server while
+/// renewing, from any server while rebinding. Returns the reply, or `None`
+/// once the time is up.
+async fn extend(
+    socket: &UdpSocket,
+    interface: &Interface,
+    lease: &Lease,
+    rebinding: bool,
+    until_ms: u64,
+) -> Option<Message> {
+    let mac = interface.device.mac();
+    let dst = if rebinding {
+        Ipv4Addr::BROADCAST
+    } else {
+        lease.server
+    };
+    loop {
+        let now = time::uptime_ms();
+        if now >= until_ms {
+            return None;
+        }
+        // Half of what is left, as RFC 2131 suggests
+        let wait = ((until_ms - now) / 2).max(MIN_RENEW_WAIT_MS);
+        let mut request = Message::request(MessageType::Request, new_xid(mac), mac);
+        request.ciaddr = lease.config.address;
+        let types = [MessageType::Ack, MessageType::Nak];
+        let deadline = (now + wait).min(until_ms);
+        if let Some(reply) = exchange(socket, interface, &request, dst, deadline, &types).await {
+            return Some(reply);
+        }
+    }
+}
+
+/// Keeps `interface` configured with a leased address. Runs as a task.
+///
+/// Only one interface can run it, since UDP sockets aren't tied to an
+/// interface.
+pub async fn run(interface: Arc<Interface>) {
+    let socket = match UdpSocket::bind(CLIENT_PORT) {
+        Ok(socket) => socket,
+        Err(UdpError::AddrInUse) => {
+            println!("dhcp: {}: another interface runs DHCP", interface.name);
+            return;
+        }
+        Err(err) => {
+            println!("dhcp: {}: {:?}", interface.name, err);
+            return;
+        }
+    };
+
+    loop {
+        let mut lease = acquire(&socket, &interface).await;
+        println!(
+            "dhcp: {}: leased {}/{} from {}",
+            interface.name, lease.config.address, lease.config.prefix_len, lease.server
+        );
+        apply(&interface, Some(&lease));
+
+        // Bound, until the lease has to be renewed
+        loop {
+            let (Some(renew), Some(rebind), Some(expires)) =
+                (lease.renew_ms, lease.rebind_ms, lease.expires_ms)
+            else {
+                // An infinite lease
+                return;
+            };
+            time::sleep(renew.saturating_sub(time::uptime_ms())).await;
+
+            let mut reply = extend(&socket, &interface, &lease, false, rebind).await;
+            if reply.is_none() {
+                reply = extend(&socket, &interface, &lease, true, expires).await;
+            }
+            let now = time::uptime_ms();
+            match reply {
+                Some(ack) if ack.message_type == MessageType::Ack => {
+                    match Lease::from_ack(&ack, now) {
+                        Some(renewed) => {
+                            lease = renewed;
+                            apply(&interface, Some(&lease));
+                        }
+                        None => break,
+                    }
+                }
+                _ => {
+                    println!("dhcp: {}: lost {}", interface.name, lease.config.address);
+                    break;
+                }
+            }
+        }
+        apply(&interface, None);
+    }
+}
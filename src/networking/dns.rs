@@ -0,0 +1,470 @@
+//! DNS stub resolver.
+//!
+//! Queries go over UDP to the servers the interfaces learned over DHCP,
+//! each tried in turn. Answers are cached for as long as their TTL allows.
+
+use super::{
+    device,
+    ip::Ipv4Addr,
+    udp::{UdpError, UdpSocket},
+};
+use crate::time;
+use alloc::{
+    collections::BTreeMap,
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::{
+    net::{Ipv6Addr, SocketAddr},
+    sync::atomic::{AtomicU16, Ordering},
+};
+use spin::Mutex;
+
+pub const PORT: u16 = 53;
+
+const HEADER_SIZE: usize = 12;
+const CLASS_IN: u16 = 1;
+/// Recursion desired.
+const FLAG_RD: u16 = 1 << 8;
+const FLAG_QR: u16 = 1 << 15;
+const FLAG_TC: u16 = 1 << 9;
+const RCODE_NXDOMAIN: u8 = 3;
+
+const MAX_LABEL_LEN: usize = 63;
+const MAX_NAME_LEN: usize = 255;
+/// Compression pointers followed in one name before giving up on a loop.
+const MAX_POINTERS: usize = 16;
+/// CNAMEs followed before giving up on a loop.
+const MAX_CNAME_DEPTH: usize = 8;
+
+const TIMEOUT_MS: u64 = 2000;
+/// Attempts per server.
+const ATTEMPTS: usize = 2;
+const MAX_CACHED: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RecordType {
+    A,
+    Aaaa,
+    Cname,
+    Other(u16),
+}
+
+impl From<u16> for RecordType {
+    fn from(value: u16) -> RecordType {
+        match value {
+            1 => RecordType::A,
+            5 => RecordType::Cname,
+            28 => RecordType::Aaaa,
+            other => RecordType::Other(other),
+        }
+    }
+}
+
+impl From<RecordType> for u16 {
+    fn from(record_type: RecordType) -> u16 {
+        match record_type {
+            RecordType::A => 1,
+            RecordType::Cname => 5,
+            RecordType::Aaaa => 28,
+            RecordType::Other(other) => other,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RecordData {
+    A(Ipv4Addr),
+    Aaaa(Ipv6Addr),
+    Cname(String),
+    Other(u16),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub name: String,
+    pub ttl: u32,
+    pub data: RecordData,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DnsError {
+    InvalidName,
+    /// No interface knows a DNS server.
+    NoServers,
+    /// The name doesn't exist, or has no records of the type asked for.
+    NotFound,
+    /// A server answered with this error code.
+    ServerFailure(u8),
+    Malformed,
+    TimedOut,
+    Udp(UdpError),
+}
+
+impl From<UdpError> for DnsError {
+    fn from(err: UdpError) -> DnsError {
+        DnsError::Udp(err)
+    }
+}
+
+/// Builds a query for the `record_type` records of `name`.
+pub fn query(id: u16, name: &str, record_type: RecordType) -> Result<Vec<u8>, DnsError> {
+    let mut message = Vec::with_capacity(HEADER_SIZE + name.len() + 6);
+    message.extend_from_slice(&id.to_be_bytes());
+    message.extend_from_slice(&FLAG_RD.to_be_bytes());
+    // One question, no records
+    message.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+
+    let name = name.strip_suffix('.').unwrap_or(name);
+    if name.is_empty() || name.len() + 2 > MAX_NAME_LEN {
+        return Err(DnsError::InvalidName);
+    }
+    for label in name.split('.') {
+        if label.is_empty() || label.len() > MAX_LABEL_LEN {
+            return Err(DnsError::InvalidName);
+        }
+        message.push(label.len() as u8);
+        message.extend_from_slice(label.as_bytes());
+    }
+    message.push(0);
+    message.extend_from_slice(&u16::from(record_type).to_be_bytes());
+    message.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(message)
+}
+
+/// Reads the possibly compressed name at `offset`. Returns it with the
+/// offset after it.
+fn read_name(message: &[u8], offset: usize) -> Result<(String, usize), DnsError> {
+    let mut name = String::new();
+    let mut offset = offset;
+    // Where the name ends in the message, which is after the first pointer
+    let mut end = None;
+    let mut pointers = 0;
+    loop {
+        let len = *message.get(offset).ok_or(DnsError::Malformed)? as usize;
+        match len {
+            0 => break,
+            _ if len & 0xC0 == 0xC0 => {
+                let low = *message.get(offset + 1).ok_or(DnsError::Malformed)? as usize;
+                pointers += 1;
+                if pointers > MAX_POINTERS {
+                    return Err(DnsError::Malformed);
+                }
+                end.get_or_insert(offset + 2);
+                offset = (len & 0x3F) << 8 | low;
+            }
+            _ if len > MAX_LABEL_LEN => return Err(DnsError::Malformed),
+            _ => {
+                let label = message
+                    .get(offset + 1..offset + 1 + len)
+                    .ok_or(DnsError::Malformed)?;
+                if !name.is_empty() {
+                    name.push('.');
+                }
+                name.extend(label.iter().map(|byte| *byte as char));
+                if name.len() > MAX_NAME_LEN {
+                    return Err(DnsError::Malformed);
+                }
+                offset += 1 + len;
+            }
+        }
+    }
+    Ok((name, end.unwrap_or(offset + 1)))
+}
+
+fn read_u16(message: &[u8], offset: usize) -> Result<u16, DnsError> {
+    let bytes = message.get(offset..offset + 2).ok_or(DnsError::Malformed)?;
+    Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+/// A parsed response.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Response {
+    pub id: u16,
+    pub rcode: u8,
+    pub truncated: bool,
+    /// The name and type asked about.
+    pub question: Option<(String, RecordType)>,
+    pub answers: Vec<Record>,
+}
+
+pub fn parse_response(message: &[u8]) -> Result<Response, DnsError> {
+    if message.len() < HEADER_SIZE {
+        return Err(DnsError::Malformed);
+    }
+    let flags = read_u16(message, 2)?;
+    if flags & FLAG_QR == 0 {
+        return Err(DnsError::Malformed);
+    }
+    let questions = read_u16(message, 4)?;
+    let answers = read_u16(message, 6)?;
+
+    let mut offset = HEADER_SIZE;
+    let mut question = None;
+    for _ in 0..questions {
+        let (name, next) = read_name(message, offset)?;
+        question = Some((name, RecordType::from(read_u16(message, next)?)));
+        offset = next + 4;
+    }
+
+    let mut records = Vec::new();
+    for _ in 0..answers {
+        let (name, next) = read_name(message, offset)?;
+        let record_type = RecordType::from(read_u16(message, next)?);
+        let class = read_u16(message, next + 2)?;
+        let ttl_bytes = message.get(next + 4..next + 8).ok_or(DnsError::Malformed)?;
+        let ttl = u32::from_be_bytes(ttl_bytes.try_into().unwrap());
+        let len = read_u16(message, next + 8)? as usize;
+        let start = next + 10;
+        let data = message.get(start..start + len).ok_or(DnsError::Malformed)?;
+        offset = start + len;
+        if class != CLASS_IN {
+            continue;
+        }
+
+        let data = match record_type {
+            RecordType::A if len == 4 => {
+                RecordData::A(Ipv4Addr::new(data[0], data[1], data[2], data[3]))
+            }
+            RecordType::Aaaa if len == 16 => {
+                RecordData::Aaaa(Ipv6Addr::from(<[u8; 16]>::try_from(data).unwrap()))
+            }
+            // The target may point back into the rest of the message
+            RecordType::Cname => RecordData::Cname(read_name(message, start)?.0),
+            _ => RecordData::Other(record_type.into()),
+        };
+        records.push(Record { name, ttl, data });
+    }
+
+    Ok(Response {
+        id: read_u16(message, 0)?,
+        rcode: (flags & 0xF) as u8,
+        truncated: flags & FLAG_TC != 0,
+        question,
+        answers: records,
+    })
+}
+
+/// Follows the CNAMEs in `answers` from `name`. Returns the records of
+/// `record_type` at the end of the chain, and the name the chain ended
+/// at if it has none.
+fn follow<'a>(
+    answers: &'a [Record],
+    name: &str,
+    record_type: RecordType,
+) -> (Vec<&'a Record>, String) {
+    let mut name = name.to_string();
+    for _ in 0..MAX_CNAME_DEPTH {
+        let matching: Vec<&Record> = answers
+            .iter()
+            .filter(|record| record.name.eq_ignore_ascii_case(&name))
+            .collect();
+        let found: Vec<&Record> = matching
+            .iter()
+            .copied()
+            .filter(|record| record_type_of(&record.data) == record_type)
+            .collect();
+        if !found.is_empty() {
+            return (found, name);
+        }
+        match matching.iter().find_map(|record| match &record.data {
+            RecordData::Cname(target) => Some(target.clone()),
+            _ => None,
+        }) {
+            Some(target) => name = target,
+            None => break,
+        }
+    }
+    (Vec::new(), name)
+}
+
+fn record_type_of(data: &RecordData) -> RecordType {
+    match data {
+        RecordData::A(_) => RecordType::A,
+        RecordData::Aaaa(_) => RecordType::Aaaa,
+        RecordData::Cname(_) => RecordType::Cname,
+        RecordData::Other(value) => RecordType::from(*value),
+    }
+}
+
+struct CacheEntry {
+    records: Vec<Record>,
+    expires_ms: u64,
+}
+
+static CACHE: Mutex<BTreeMap<(String, RecordType), CacheEntry>> = Mutex::new(BTreeMap::new());
+static NEXT_ID: AtomicU16 = AtomicU16::new(1);
+
+fn cached(name: &str, record_type: RecordType) -> Option<Vec<Record>> {
+    let now = time::uptime_ms();
+    let mut cache = CACHE.lock();
+    let key = (name.to_ascii_lowercase(), record_type);
+    match cache.get(&key) {
+        Some(entry) if entry.expires_ms > now => Some(entry.records.clone()),
+        Some(_) => {
+            cache.remove(&key);
+            None
+        }
+        None => None,
+    }
+}
+
+fn cache(name: &str, record_type: RecordType, records: &[Record]) {
+    let Some(ttl) = records.iter().map(|record| record.ttl).min() else {
+        return;
+    };
+    let now = time::uptime_ms();
+    let mut cache = CACHE.lock();
+    cache.retain(|_, entry| entry.expires_ms > now);
+    if cache.len() >= MAX_CACHED {
+        let soonest = cache
+            .iter()
+            .min_by_key(|(_, entry)| entry.expires_ms)
+            .map(|(key, _)| key.clone());
+        if let Some(key) = soonest {
+            cache.remove(&key);
+        }
+    }
+    let entry = CacheEntry {
+        records: records.to_vec(),
+        expires_ms: now + ttl as u64 * 1000,
+    };
+    cache.insert((name.to_ascii_lowercase(), record_type), entry);
+}
+
+/// Forgets every cached answer.
+pub fn flush() {
+    CACHE.lock().clear();
+}
+
+/// The DNS servers of every interface.
+pub fn servers() -> Vec<Ipv4Addr> {
+    let mut servers = Vec::new();
+    for interface in device::interfaces() {
+        for server in interface.dns_servers() {
+            if !servers.contains(&server) {
+                servers.push(server);
+            }
+        }
+    }
+    servers
+}
+
+/// Asks `server` once and waits for its answer.
+async fn ask(
+    socket: &UdpSocket,
+    server: Ipv4Addr,
+    name: &str,
+    record_type: RecordType,
+) -> Result<Response, DnsError> {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let server = SocketAddr::new(server.into(), PORT);
+    socket
+        .send_to(&query(id, name, record_type)?, server)
+        .await?;
+    let deadline = time::uptime_ms() + TIMEOUT_MS;
+    loop {
+        let remaining = deadline.saturating_sub(time::uptime_ms());
+        let (message, from) = time::timeout(remaining, socket.recv_from())
+            .await
+            .map_err(|_| DnsError::TimedOut)?;
+        // Anything else is a late answer or a spoofing attempt
+        let Ok(response) = parse_response(&message) else {
+            continue;
+        };
+        let question_matches = response
+            .question
+            .as_ref()
+            .is_some_and(|(asked, asked_type)| {
+                asked.eq_ignore_ascii_case(name.strip_suffix('.').unwrap_or(name))
+                    && *asked_type == record_type
+            });
+        if from == server && response.id == id && question_matches {
+            return Ok(response);
+        }
+    }
+}
+
+/// Looks up the `record_type` records of `name`, following CNAMEs.
+pub async fn resolve(name: &str, record_type: RecordType) -> Result<Vec<Record>, DnsError> {
+    let mut name = name.strip_suffix('.').unwrap_or(name).to_string();
+    for _ in 0..MAX_CNAME_DEPTH {
+        let answers = match cached(&name, record_type) {
+            Some(answers) => answers,
+            None => {
+                let answers = query_servers(&name, record_type).await?;
+                cache(&name, record_type, &answers);
+                answers
+            }
+        };
+        let (found, end) = follow(&answers, &name, record_type);
+        if !found.is_empty() {
+            return Ok(found.into_iter().cloned().collect());
+        }
+        if end.eq_ignore_ascii_case(&name) {
+            return Err(DnsError::NotFound);
+        }
+        // The answer stopped at a CNAME, so ask about its target
+        name = end;
+    }
+    Err(DnsError::NotFound)
+}
+
+/// Asks each server in turn until one answers.
+async fn query_servers(name: &str, record_type: RecordType) -> Result<Vec<Record>, DnsError> {
+    let servers = servers();
+    if servers.is_empty() {
+        return Err(DnsError::NoServers);
+    }
+    let socket = UdpSocket::bind(0)?;
+    let mut error = DnsError::TimedOut;
+    for _ in 0..ATTEMPTS {
+        for server in &servers {
+            match ask(&socket, *server, name, record_type).await {
+                Ok(response) if response.rcode == RCODE_NXDOMAIN => return Err(DnsError::NotFound),
+                Ok(response) if response.rcode != 0 => {
+                    error = DnsError::ServerFailure(response.rcode)
+                }
+                // A truncated answer would need TCP, but what made it in
+                // is enough for addresses
+                Ok(response) => return Ok(response.answers),
+                Err(err) => error = err,
+            }
+        }
+    }
+    Err(error)
+}
+
+pub async fn lookup_ipv4(name: &str) -> Result<Vec<Ipv4Addr>, DnsError> {
+    let records = resolve(name, RecordType::A).await?;
+    Ok(records
+        .iter()
+        .filter_map(|record| match record.data {
+            RecordData::A(address) => Some(address),
+            _ => None,
+        })
+        .collect())
+}
+
+pub async fn lookup_ipv6(name: &str) -> Result<Vec<Ipv6Addr>, DnsError> {
+    let records = resolve(name, RecordType::Aaaa).await?;
+    Ok(records
+        .iter()
+        .filter_map(|record| match record.data {
+            RecordData::Aaaa(address) => Some(address),
+            _ => None,
+        })
+        .collect())
+}
+
+/// The address of `host`, which may also be an address already.
+pub async fn lookup_host(host: &str) -> Result<Ipv4Addr, DnsError> {
+    if let Ok(address) = host.parse() {
+        return Ok(address);
+    }
+    lookup_ipv4(host)
+        .await?
+        .first()
+        .copied()
+        .ok_or(DnsError::NotFound)
+}
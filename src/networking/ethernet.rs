@@ -1,93 +1,254 @@
-pub struct Packet {
-    pub sfd: u8,
-    pub dest: [u8; 6],
-    pub src: [u8; 6],
-    pub data: [u8; 1500 - 46],
-    pub len: usize,
+//! Ethernet II frames, as the network cards hand them over: without the
+//! preamble and start frame delimiter, which the cards add and strip
+//! themselves, and usually without the frame check sequence.
+
+use super::device::MacAddress;
+use alloc::vec::Vec;
+
+pub const BROADCAST: MacAddress = [0xFF; 6];
+
+/// Destination, source and EtherType.
+pub const HEADER_SIZE: usize = 14;
+/// An 802.1Q tag adds its TPID and TCI in front of the EtherType.
+pub const VLAN_TAG_SIZE: usize = 4;
+pub const FCS_SIZE: usize = 4;
+/// Frames shorter than this, without the FCS, are padded before sending.
+pub const MIN_FRAME_SIZE: usize = 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EtherType {
+    Ipv4,
+    Arp,
+    /// An 802.1Q tag follows instead of the payload.
+    Vlan,
+    Ipv6,
+    Other(u16),
 }
 
-impl Packet {
-    pub fn new() -> Packet {
-        Packet {
-            sfd: 0b10101011,
-            dest: [0; 6],
-            src: [0; 6],
-            data: [0; 1500 - 46],
-            len: 0,
+impl From<u16> for EtherType {
+    fn from(value: u16) -> EtherType {
+        match value {
+            0x0800 => EtherType::Ipv4,
+            0x0806 => EtherType::Arp,
+            0x8100 => EtherType::Vlan,
+            0x86DD => EtherType::Ipv6,
+            other => EtherType::Other(other),
         }
     }
+}
 
-    pub fn from_slice(slice: &[u8]) -> Result<Packet, PacketError> {
-        if slice.len() < 14 {
-            return Err(PacketError::InvalidPacket);
+impl From<EtherType> for u16 {
+    fn from(ether_type: EtherType) -> u16 {
+        match ether_type {
+            EtherType::Ipv4 => 0x0800,
+            EtherType::Arp => 0x0806,
+            EtherType::Vlan => 0x8100,
+            EtherType::Ipv6 => 0x86DD,
+            EtherType::Other(other) => other,
         }
+    }
+}
+
+/// The tag control information of an 802.1Q tag.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VlanTag {
+    /// Priority code point, 0 to 7.
+    pub priority: u8,
+    /// Drop eligible indicator.
+    pub drop_eligible: bool,
+    /// VLAN identifier, 12 bits.
+    pub id: u16,
+}
 
-        for i in 0..7 {
-            if slice[i] != 0b10101010 {
-                return Err(PacketError::NoStart);
-            }
+impl VlanTag {
+    pub fn from_tci(tci: u16) -> VlanTag {
+        VlanTag {
+            priority: (tci >> 13) as u8,
+            drop_eligible: tci & (1 << 12) != 0,
+            id: tci & 0x0FFF,
         }
+    }
 
-        let slice = &slice[8..];
+    pub fn tci(&self) -> u16 {
+        (self.priority as u16 & 0x7) << 13 | (self.drop_eligible as u16) << 12 | (self.id & 0x0FFF)
+    }
+}
 
-        let mut out = Packet::new();
-        out.sfd = 0b10101011;
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameError {
+    /// The buffer is shorter than the header.
+    Truncated,
+}
 
-        for i in 0..6 {
-            out.dest[i] = slice[i];
-        }
+/// A received frame, read in place.
+#[derive(Debug, Clone, Copy)]
+pub struct Frame<'a> {
+    buffer: &'a [u8],
+}
 
-        for i in 0..6 {
-            out.src[i] = slice[i + 6];
+impl<'a> Frame<'a> {
+    /// Checks that `buffer` holds a whole header. `buffer` must not include
+    /// the FCS, or it ends up in the payload.
+    pub fn parse(buffer: &'a [u8]) -> Result<Frame<'a>, FrameError> {
+        if buffer.len() < HEADER_SIZE {
+            return Err(FrameError::Truncated);
+        }
+        let frame = Frame { buffer };
+        if frame.raw_ether_type() == EtherType::Vlan && buffer.len() < HEADER_SIZE + VLAN_TAG_SIZE {
+            return Err(FrameError::Truncated);
         }
+        Ok(frame)
+    }
 
-        let len = slice[12] as usize * 256 + slice[13] as usize;
+    pub fn dest(&self) -> MacAddress {
+        self.buffer[0..6].try_into().unwrap()
+    }
 
-        let slice = &slice[14..];
+    pub fn src(&self) -> MacAddress {
+        self.buffer[6..12].try_into().unwrap()
+    }
+
+    fn raw_ether_type(&self) -> EtherType {
+        u16::from_be_bytes([self.buffer[12], self.buffer[13]]).into()
+    }
+
+    pub fn vlan(&self) -> Option<VlanTag> {
+        if self.raw_ether_type() != EtherType::Vlan {
+            return None;
+        }
+        Some(VlanTag::from_tci(u16::from_be_bytes([
+            self.buffer[14],
+            self.buffer[15],
+        ])))
+    }
 
-        for i in 0..len {
-            out.data[i] = slice[i];
+    /// The type of the payload, after the VLAN tag if there is one.
+    pub fn ether_type(&self) -> EtherType {
+        match self.vlan() {
+            Some(_) => u16::from_be_bytes([self.buffer[16], self.buffer[17]]).into(),
+            None => self.raw_ether_type(),
         }
+    }
 
-        Ok(out)
+    pub fn header_len(&self) -> usize {
+        match self.vlan() {
+            Some(_) => HEADER_SIZE + VLAN_TAG_SIZE,
+            None => HEADER_SIZE,
+        }
     }
 
-    pub fn to_slice(&self) -> [u8; 1504] {
-        let mut out = [0; 1504];
+    /// Everything after the header, including any padding the sender added.
+    pub fn payload(&self) -> &'a [u8] {
+        &self.buffer[self.header_len()..]
+    }
 
-        for i in 0..7 {
-            out[i] = 0b10101010;
+    pub fn header(&self) -> Header {
+        Header {
+            dest: self.dest(),
+            src: self.src(),
+            vlan: self.vlan(),
+            ether_type: self.ether_type(),
         }
+    }
+}
 
-        out[7] = self.sfd;
+/// The header of a frame to send.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub dest: MacAddress,
+    pub src: MacAddress,
+    pub vlan: Option<VlanTag>,
+    pub ether_type: EtherType,
+}
 
-        for i in 0..6 {
-            out[i + 8] = self.dest[i];
+impl Header {
+    pub fn new(dest: MacAddress, src: MacAddress, ether_type: EtherType) -> Header {
+        Header {
+            dest,
+            src,
+            vlan: None,
+            ether_type,
         }
+    }
 
-        for i in 0..6 {
-            out[i + 14] = self.src[i];
+    pub fn header_len(&self) -> usize {
+        match self.vlan {
+            Some(_) => HEADER_SIZE + VLAN_TAG_SIZE,
+            None => HEADER_SIZE,
         }
+    }
 
-        out[14] = (self.len / 256) as u8;
-        out[15] = (self.len % 256) as u8;
+    /// Writes the header to the start of `buffer`, which must be at least
+    /// [`Header::header_len`] bytes long.
+    pub fn write(&self, buffer: &mut [u8]) {
+        buffer[0..6].copy_from_slice(&self.dest);
+        buffer[6..12].copy_from_slice(&self.src);
+        let mut offset = 12;
+        if let Some(tag) = self.vlan {
+            buffer[12..14].copy_from_slice(&u16::from(EtherType::Vlan).to_be_bytes());
+            buffer[14..16].copy_from_slice(&tag.tci().to_be_bytes());
+            offset += VLAN_TAG_SIZE;
+        }
+        buffer[offset..offset + 2].copy_from_slice(&u16::from(self.ether_type).to_be_bytes());
+    }
 
-        for i in 0..self.len {
-            out[i + 16] = self.data[i];
+    /// Builds a frame carrying `payload`. The frame isn't padded and has no
+    /// FCS, since the cards add both.
+    pub fn build(&self, payload: &[u8]) -> Vec<u8> {
+        let mut frame = Vec::with_capacity(self.header_len() + payload.len());
+        frame.resize(self.header_len(), 0);
+        self.write(&mut frame);
+        frame.extend_from_slice(payload);
+        frame
+    }
+}
+
+const CRC32_TABLE: [u32; 256] = crc32_table();
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
         }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
 
-        // TODO: CRC
-        out[1500] = 0;
-        out[1501] = 0;
-        out[1502] = 0;
-        out[1503] = 0;
+/// The CRC-32 used for the Ethernet FCS.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFF;
+    for byte in data {
+        crc = CRC32_TABLE[((crc ^ *byte as u32) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
 
-        out
+/// Pads `frame` to the minimum length and appends its FCS, for cards that
+/// don't do that themselves.
+pub fn append_fcs(frame: &mut Vec<u8>) {
+    if frame.len() < MIN_FRAME_SIZE {
+        frame.resize(MIN_FRAME_SIZE, 0);
     }
+    let fcs = crc32(frame);
+    frame.extend_from_slice(&fcs.to_le_bytes());
 }
 
-#[derive(Debug)]
-pub enum PacketError {
-    NoStart,
-    InvalidPacket,
+/// Checks the FCS at the end of `frame`.
+pub fn check_fcs(frame: &[u8]) -> bool {
+    if frame.len() < FCS_SIZE {
+        return false;
+    }
+    let (data, fcs) = frame.split_at(frame.len() - FCS_SIZE);
+    crc32(data).to_le_bytes() == fcs
 }
@@ -0,0 +1,215 @@
+//! A small HTTP/1.1 server on top of [`TcpListener`].
+//!
+//! Each connection carries one request: the response says
+//! `Connection: close` and the server closes its side after sending it.
+//! Connections are served one after another, which is plenty for looking
+//! at the kernel with curl.
+
+use super::tcp::{TcpListener, TcpStream};
+use crate::{println, time};
+use alloc::{format, string::String, vec::Vec};
+
+/// Requests with a longer head are answered with 431.
+pub const MAX_HEAD_SIZE: usize = 4096;
+/// How long a client gets to send its request.
+pub const REQUEST_TIMEOUT_MS: u64 = 5000;
+/// How long a client gets to take the response.
+pub const RESPONSE_TIMEOUT_MS: u64 = 5000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HttpError {
+    /// The request line or a header line doesn't parse.
+    BadRequest,
+    /// The request line and headers don't fit in [`MAX_HEAD_SIZE`].
+    HeadTooLarge,
+    /// The client closed the connection before the request was complete.
+    Incomplete,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+    Other,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    pub method: Method,
+    /// Without the query string.
+    pub path: String,
+    pub query: Option<String>,
+    /// Names are lowercase.
+    pub headers: Vec<(String, String)>,
+}
+
+impl Request {
+    /// Parses the request line and headers at the start of `data`.
+    /// Returns `Ok(None)` if the blank line ending them hasn't arrived yet,
+    /// and otherwise the request with how many bytes its head took.
+    pub fn parse(data: &[u8]) -> Result<Option<(Request, usize)>, HttpError> {
+        let Some(end) = data.windows(4).position(|window| window == b"\r\n\r\n") else {
+            return if data.len() > MAX_HEAD_SIZE {
+                Err(HttpError::HeadTooLarge)
+            } else {
+                Ok(None)
+            };
+        };
+        if end + 4 > MAX_HEAD_SIZE {
+            return Err(HttpError::HeadTooLarge);
+        }
+        let head = core::str::from_utf8(&data[..end]).map_err(|_| HttpError::BadRequest)?;
+        let mut lines = head.split("\r\n");
+
+        let mut parts = lines.next().unwrap_or("").split(' ');
+        let (Some(method), Some(target), Some(version), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(HttpError::BadRequest);
+        };
+        if !version.starts_with("HTTP/1.") || !target.starts_with('/') {
+            return Err(HttpError::BadRequest);
+        }
+        let method = match method {
+            "GET" => Method::Get,
+            "HEAD" => Method::Head,
+            "POST" => Method::Post,
+            "PUT" => Method::Put,
+            "DELETE" => Method::Delete,
+            _ => Method::Other,
+        };
+        let (path, query) = match target.split_once('?') {
+            Some((path, query)) => (path, Some(String::from(query))),
+            None => (target, None),
+        };
+
+        let mut headers = Vec::new();
+        for line in lines {
+            let (name, value) = line.split_once(':').ok_or(HttpError::BadRequest)?;
+            headers.push((name.trim().to_ascii_lowercase(), String::from(value.trim())));
+        }
+        let request = Request {
+            method,
+            path: String::from(path),
+            query,
+            headers,
+        };
+        Ok(Some((request, end + 4)))
+    }
+
+    /// The value of header `name`, which must be lowercase.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(other, _)| other == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn json(status: u16, body: String) -> Response {
+        Response {
+            status,
+            content_type: "application/json",
+            body: body.into_bytes(),
+        }
+    }
+
+    /// A JSON body of the form `{"error":"..."}` with the reason phrase.
+    pub fn error(status: u16) -> Response {
+        Response::json(status, format!("{{\"error\":\"{}\"}}", reason(status)))
+    }
+
+    /// The status line, headers and, unless it answers a HEAD request, the
+    /// body.
+    pub fn to_bytes(&self, include_body: bool) -> Vec<u8> {
+        let mut bytes = format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            self.status,
+            reason(self.status),
+            self.content_type,
+            self.body.len()
+        )
+        .into_bytes();
+        if include_body {
+            bytes.extend_from_slice(&self.body);
+        }
+        bytes
+    }
+}
+
+/// The reason phrase for the status codes we send.
+pub fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        _ => "Unknown",
+    }
+}
+
+/// Accepts connections on `port` and answers each request with
+/// `handler`. Only returns if the port is taken.
+pub async fn serve(port: u16, handler: fn(&Request) -> Response) {
+    let listener = match TcpListener::bind(port) {
+        Ok(listener) => listener,
+        Err(err) => {
+            println!("http: port {}: {:?}", port, err);
+            return;
+        }
+    };
+    loop {
+        let (stream, _) = listener.accept().await;
+        let response = match time::timeout(REQUEST_TIMEOUT_MS, read_request(&stream)).await {
+            Ok(Ok(request)) => {
+                let response = handler(&request);
+                let include_body = request.method != Method::Head;
+                Some(response.to_bytes(include_body))
+            }
+            Ok(Err(HttpError::BadRequest)) => Some(Response::error(400).to_bytes(true)),
+            Ok(Err(HttpError::HeadTooLarge)) => Some(Response::error(431).to_bytes(true)),
+            Ok(Err(HttpError::Incomplete)) => None,
+            Err(time::TimedOut) => Some(Response::error(408).to_bytes(true)),
+        };
+        if let Some(response) = response {
+            // The client may have gone away already, nothing to do then.
+            // One that stopped reading would hold up everyone else
+            let written = time::timeout(RESPONSE_TIMEOUT_MS, stream.write_all(&response)).await;
+            if written.is_err() {
+                stream.abort();
+                continue;
+            }
+        }
+        stream.shutdown();
+    }
+}
+
+/// Reads from `stream` until a whole request head arrived. A body the
+/// client sends along is ignored.
+async fn read_request(stream: &TcpStream) -> Result<Request, HttpError> {
+    let mut data = Vec::new();
+    let mut buffer = [0; 512];
+    loop {
+        if let Some((request, _)) = Request::parse(&data)? {
+            return Ok(request);
+        }
+        match stream.read(&mut buffer).await {
+            Ok(len) if len > 0 => data.extend_from_slice(&buffer[..len]),
+            _ => return Err(HttpError::Incomplete),
+        }
+    }
+}
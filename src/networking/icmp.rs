@@ -0,0 +1,287 @@
+//! ICMPv4: answering and sending echo requests, and reporting packets that
+//! couldn't be delivered.
+
+use super::{
+    device::Interface,
+    ip::{self, IpError, Ipv4Addr, Ipv4Header, Protocol},
+};
+use crate::time;
+use alloc::{collections::BTreeMap, sync::Arc, vec, vec::Vec};
+use core::{
+    future::poll_fn,
+    net::IpAddr,
+    sync::atomic::{AtomicU16, Ordering},
+    task::{Poll, Waker},
+};
+use spin::Mutex;
+
+const TYPE_ECHO_REPLY: u8 = 0;
+const TYPE_DESTINATION_UNREACHABLE: u8 = 3;
+const TYPE_ECHO_REQUEST: u8 = 8;
+const TYPE_TIME_EXCEEDED: u8 = 11;
+
+pub const CODE_PROTOCOL_UNREACHABLE: u8 = 2;
+pub const CODE_PORT_UNREACHABLE: u8 = 3;
+pub const CODE_TTL_EXCEEDED: u8 = 0;
+pub const CODE_REASSEMBLY_TIME_EXCEEDED: u8 = 1;
+
+/// Type, code, checksum and four bytes that depend on the type.
+pub const HEADER_SIZE: usize = 8;
+/// Bytes of the offending packet's payload quoted in error messages.
+const QUOTED_PAYLOAD: usize = 8;
+
+/// Bytes of data in the echo requests [`ping`] sends.
+pub const PING_DATA_SIZE: usize = 56;
+pub const PING_TIMEOUT_MS: u64 = 1000;
+
+/// An error message to send about a packet we received.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorMessage {
+    DestinationUnreachable(u8),
+    TimeExceeded(u8),
+}
+
+/// Builds an ICMP message and fills in its checksum.
+fn build(kind: u8, code: u8, rest: [u8; 4], data: &[u8]) -> Vec<u8> {
+    let mut message = vec![kind, code, 0, 0];
+    message.extend_from_slice(&rest);
+    message.extend_from_slice(data);
+    let checksum = ip::checksum(&message);
+    message[2..4].copy_from_slice(&checksum.to_be_bytes());
+    message
+}
+
+/// Sends `error` to the sender of the packet with `header` and `payload`.
+///
+/// Like RFC 1122 asks, nothing is sent about ICMP errors, broadcasts,
+/// later fragments or packets without a proper source.
+pub fn send_error(error: ErrorMessage, header: &Ipv4Header, payload: &[u8]) {
+    if header.dst == Ipv4Addr::BROADCAST
+        || header.dst.is_multicast()
+        || header.src.is_unspecified()
+        || header.src == Ipv4Addr::BROADCAST
+        || header.src.is_multicast()
+        || header.fragment_offset != 0
+    {
+        return;
+    }
+    if header.protocol == Protocol::Icmp
+        && !matches!(payload.first(), Some(&TYPE_ECHO_REQUEST | &TYPE_ECHO_REPLY))
+    {
+        return;
+    }
+    if let Some((interface, _)) = ip::lookup_route(header.src) {
+        if interface
+            .ipv4()
+            .is_some_and(|config| header.dst == config.broadcast())
+        {
+            return;
+        }
+    }
+
+    let (kind, code) = match error {
+        ErrorMessage::DestinationUnreachable(code) => (TYPE_DESTINATION_UNREACHABLE, code),
+        ErrorMessage::TimeExceeded(code) => (TYPE_TIME_EXCEEDED, code),
+    };
+    let mut quote = vec![0; ip::HEADER_SIZE];
+    header.write(&mut quote);
+    quote.extend_from_slice(&payload[..payload.len().min(QUOTED_PAYLOAD)]);
+    // Best effort: there is nobody to tell if this fails
+    let _ = ip::send(
+        header.src,
+        Protocol::Icmp,
+        &build(kind, code, [0; 4], &quote),
+    );
+}
+
+/// What a ping is waiting for, by identifier and sequence number.
+#[derive(Default)]
+struct Waiting {
+    waker: Option<Waker>,
+    result: Option<Result<Echo, PingError>>,
+}
+
+/// A reply to an echo request, as the receive path records it. Shared
+/// with ICMPv6, whose pings wait in the same table.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Echo {
+    pub from: IpAddr,
+    /// TTL or hop limit.
+    pub ttl: u8,
+    pub len: usize,
+    pub received_ms: u64,
+}
+
+static WAITING: Mutex<BTreeMap<(u16, u16), Waiting>> = Mutex::new(BTreeMap::new());
+static NEXT_IDENT: AtomicU16 = AtomicU16::new(1);
+
+pub(super) fn complete(ident: u16, seq: u16, result: Result<Echo, PingError>) {
+    if let Some(waiting) = WAITING.lock().get_mut(&(ident, seq)) {
+        waiting.result = Some(result);
+        if let Some(waker) = waiting.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// Handles an ICMP message for us.
+pub fn handle(_interface: &Arc<Interface>, header: &Ipv4Header, message: &[u8]) {
+    if message.len() < HEADER_SIZE || ip::checksum(message) != 0 {
+        return;
+    }
+    let ident = u16::from_be_bytes([message[4], message[5]]);
+    let seq = u16::from_be_bytes([message[6], message[7]]);
+
+    match message[0] {
+        TYPE_ECHO_REQUEST => {
+            // Requests to broadcast addresses aren't answered
+            if header.dst == Ipv4Addr::BROADCAST || header.dst.is_multicast() {
+                return;
+            }
+            let reply = build(
+                TYPE_ECHO_REPLY,
+                0,
+                message[4..8].try_into().unwrap(),
+                &message[HEADER_SIZE..],
+            );
+            let _ = ip::send(header.src, Protocol::Icmp, &reply);
+        }
+        TYPE_ECHO_REPLY => {
+            let echo = Echo {
+                from: header.src.into(),
+                ttl: header.ttl,
+                len: message.len(),
+                received_ms: time::uptime_ms(),
+            };
+            complete(ident, seq, Ok(echo));
+        }
+        TYPE_DESTINATION_UNREACHABLE | TYPE_TIME_EXCEEDED => {
+            // Find the echo request this is about in the quoted packet
+            let quote = &message[HEADER_SIZE..];
+            let Ok(quoted) = Ipv4Header::parse_quoted(quote) else {
+                return;
+            };
+            let request = &quote[quoted.header_len..];
+            if quoted.protocol != Protocol::Icmp
+                || request.len() < HEADER_SIZE
+                || request[0] != TYPE_ECHO_REQUEST
+            {
+                return;
+            }
+            let ident = u16::from_be_bytes([request[4], request[5]]);
+            let seq = u16::from_be_bytes([request[6], request[7]]);
+            let error = if message[0] == TYPE_TIME_EXCEEDED {
+                PingError::TimeExceeded(header.src.into())
+            } else {
+                PingError::Unreachable(header.src.into(), message[1])
+            };
+            complete(ident, seq, Err(error));
+        }
+        _ => {}
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PingError {
+    /// The request couldn't be sent.
+    Ip(IpError),
+    Timeout,
+    /// A router or the host reported the destination unreachable, with the
+    /// code it gave.
+    Unreachable(IpAddr, u8),
+    /// The request's TTL ran out on the way.
+    TimeExceeded(IpAddr),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PingReply {
+    pub from: IpAddr,
+    pub seq: u16,
+    pub ttl: u8,
+    /// Length of the ICMP message.
+    pub len: usize,
+    pub rtt_ms: u64,
+}
+
+/// Sends one echo request to `dst` and waits up to `timeout_ms` for the
+/// reply.
+pub async fn echo(
+    dst: Ipv4Addr,
+    ident: u16,
+    seq: u16,
+    timeout_ms: u64,
+) -> Result<PingReply, PingError> {
+    expect_reply(ident, seq);
+    let data: Vec<u8> = (0..PING_DATA_SIZE).map(|i| i as u8).collect();
+    let [ident_high, ident_low] = ident.to_be_bytes();
+    let [seq_high, seq_low] = seq.to_be_bytes();
+    let fields = [ident_high, ident_low, seq_high, seq_low];
+    let request = build(TYPE_ECHO_REQUEST, 0, fields, &data);
+    let sent_ms = time::uptime_ms();
+    if let Err(err) = ip::send(dst, Protocol::Icmp, &request) {
+        WAITING.lock().remove(&(ident, seq));
+        return Err(PingError::Ip(err));
+    }
+    wait_for_reply(ident, seq, sent_ms, timeout_ms).await
+}
+
+/// Makes room for the reply to the echo request `ident` and `seq`, before
+/// it is sent.
+pub(super) fn expect_reply(ident: u16, seq: u16) {
+    WAITING.lock().insert((ident, seq), Waiting::default());
+}
+
+/// Waits up to `timeout_ms` for the reply [`expect_reply`] made room for.
+pub(super) async fn wait_for_reply(
+    ident: u16,
+    seq: u16,
+    sent_ms: u64,
+    timeout_ms: u64,
+) -> Result<PingReply, PingError> {
+    let key = (ident, seq);
+    let result = time::timeout(
+        timeout_ms,
+        poll_fn(|cx| {
+            let mut waiting = WAITING.lock();
+            let entry = waiting.entry(key).or_default();
+            match entry.result.take() {
+                Some(result) => Poll::Ready(result),
+                None => {
+                    entry.waker = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            }
+        }),
+    )
+    .await;
+    WAITING.lock().remove(&key);
+
+    let echo = result.map_err(|_| PingError::Timeout)??;
+    Ok(PingReply {
+        from: echo.from,
+        seq,
+        ttl: echo.ttl,
+        len: echo.len,
+        rtt_ms: echo.received_ms - sent_ms,
+    })
+}
+
+/// A new identifier for a series of echo requests.
+pub fn new_ident() -> u16 {
+    NEXT_IDENT.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Pings `dst` `count` times, a second apart, and returns the result of
+/// each request.
+pub async fn ping(dst: Ipv4Addr, count: u16) -> Vec<Result<PingReply, PingError>> {
+    let ident = new_ident();
+    let mut results = Vec::new();
+    for seq in 0..count {
+        let start = time::uptime_ms();
+        results.push(echo(dst, ident, seq, PING_TIMEOUT_MS).await);
+        if seq + 1 < count {
+            time::sleep(1000u64.saturating_sub(time::uptime_ms() - start)).await;
+        }
+    }
+    results
+}
@@ -0,0 +1,187 @@
+//! ICMPv6: echo requests and replies, and error messages. Neighbor
+//! discovery messages are handed to [`super::ndp`].
+
+use super::{
+    device::Interface,
+    icmp::{self, Echo, PingError, PingReply},
+    ip::Protocol,
+    ipv6::{self, Ipv6Addr, Ipv6Header, MIN_MTU},
+    ndp,
+};
+use crate::time;
+use alloc::{sync::Arc, vec, vec::Vec};
+
+const TYPE_DESTINATION_UNREACHABLE: u8 = 1;
+const TYPE_TIME_EXCEEDED: u8 = 3;
+const TYPE_PARAMETER_PROBLEM: u8 = 4;
+const TYPE_ECHO_REQUEST: u8 = 128;
+const TYPE_ECHO_REPLY: u8 = 129;
+pub const TYPE_ROUTER_SOLICITATION: u8 = 133;
+pub const TYPE_ROUTER_ADVERTISEMENT: u8 = 134;
+pub const TYPE_NEIGHBOR_SOLICITATION: u8 = 135;
+pub const TYPE_NEIGHBOR_ADVERTISEMENT: u8 = 136;
+
+pub const CODE_UNRECOGNIZED_NEXT_HEADER: u8 = 1;
+pub const CODE_PORT_UNREACHABLE: u8 = 4;
+
+/// Type, code, checksum and four bytes that depend on the type.
+pub const HEADER_SIZE: usize = 8;
+
+/// Builds an ICMPv6 message from `src` to `dst` and fills in its checksum.
+pub fn build(
+    src: Ipv6Addr,
+    dst: Ipv6Addr,
+    kind: u8,
+    code: u8,
+    rest: [u8; 4],
+    data: &[u8],
+) -> Vec<u8> {
+    let mut message = vec![kind, code, 0, 0];
+    message.extend_from_slice(&rest);
+    message.extend_from_slice(data);
+    let checksum = ipv6::pseudo_header(src, dst, Protocol::Icmpv6, message.len())
+        .add(&message)
+        .finish();
+    message[2..4].copy_from_slice(&checksum.to_be_bytes());
+    message
+}
+
+/// Sends a parameter problem error to the sender of the packet with
+/// `header` and `payload`, pointing at the byte `pointer` of the packet.
+pub fn send_parameter_problem(code: u8, pointer: u32, header: &Ipv6Header, payload: &[u8]) {
+    let rest = pointer.to_be_bytes();
+    send_error(TYPE_PARAMETER_PROBLEM, code, rest, header, payload);
+}
+
+/// Sends a destination unreachable error to the sender of the packet with
+/// `header` and `payload`.
+pub fn send_destination_unreachable(code: u8, header: &Ipv6Header, payload: &[u8]) {
+    send_error(TYPE_DESTINATION_UNREACHABLE, code, [0; 4], header, payload);
+}
+
+/// Sends an error about the packet with `header` and `payload` to its
+/// sender.
+///
+/// Like RFC 4443 asks, nothing is sent about ICMPv6 errors, multicast
+/// packets or packets without a proper source.
+fn send_error(kind: u8, code: u8, rest: [u8; 4], header: &Ipv6Header, payload: &[u8]) {
+    if header.dst.is_multicast() || header.src.is_unspecified() || header.src.is_multicast() {
+        return;
+    }
+    if header.next_header == Protocol::Icmpv6
+        && payload
+            .first()
+            .is_some_and(|kind| *kind < TYPE_ECHO_REQUEST)
+    {
+        return;
+    }
+    let Ok((interface, next_hop, src)) = ipv6::route(header.src) else {
+        return;
+    };
+
+    // As much of the packet as fits in the smallest MTU
+    let mut quote = vec![0; ipv6::HEADER_SIZE];
+    header.write(&mut quote);
+    let room = MIN_MTU - ipv6::HEADER_SIZE - HEADER_SIZE - quote.len();
+    quote.extend_from_slice(&payload[..payload.len().min(room)]);
+    let message = build(src, header.src, kind, code, rest, &quote);
+    // Best effort: there is nobody to tell if this fails
+    let _ = ipv6::send_via(
+        &interface,
+        next_hop,
+        Ipv6Header::new(src, header.src, Protocol::Icmpv6),
+        &message,
+    );
+}
+
+/// Handles an ICMPv6 message for us.
+pub fn handle(interface: &Arc<Interface>, header: &Ipv6Header, message: &[u8]) {
+    let checksum = ipv6::pseudo_header(header.src, header.dst, Protocol::Icmpv6, message.len())
+        .add(message)
+        .finish();
+    if message.len() < HEADER_SIZE || checksum != 0 {
+        return;
+    }
+    let ident = u16::from_be_bytes([message[4], message[5]]);
+    let seq = u16::from_be_bytes([message[6], message[7]]);
+
+    match message[0] {
+        TYPE_ECHO_REQUEST => {
+            // Requests to multicast groups aren't answered
+            if header.dst.is_multicast() {
+                return;
+            }
+            let reply = build(
+                header.dst,
+                header.src,
+                TYPE_ECHO_REPLY,
+                0,
+                message[4..8].try_into().unwrap(),
+                &message[HEADER_SIZE..],
+            );
+            let Some((interface, next_hop)) = ipv6::lookup_route(header.src) else {
+                return;
+            };
+            let reply_header = Ipv6Header::new(header.dst, header.src, Protocol::Icmpv6);
+            let _ = ipv6::send_via(&interface, next_hop, reply_header, &reply);
+        }
+        TYPE_ECHO_REPLY => {
+            let echo = Echo {
+                from: header.src.into(),
+                ttl: header.hop_limit,
+                len: message.len(),
+                received_ms: time::uptime_ms(),
+            };
+            icmp::complete(ident, seq, Ok(echo));
+        }
+        TYPE_DESTINATION_UNREACHABLE | TYPE_TIME_EXCEEDED => {
+            // Find the echo request this is about in the quoted packet
+            let quote = &message[HEADER_SIZE..];
+            let Ok(quoted) = Ipv6Header::parse_quoted(quote) else {
+                return;
+            };
+            let request = &quote[ipv6::HEADER_SIZE..];
+            if quoted.next_header != Protocol::Icmpv6
+                || request.len() < HEADER_SIZE
+                || request[0] != TYPE_ECHO_REQUEST
+            {
+                return;
+            }
+            let ident = u16::from_be_bytes([request[4], request[5]]);
+            let seq = u16::from_be_bytes([request[6], request[7]]);
+            let error = if message[0] == TYPE_TIME_EXCEEDED {
+                PingError::TimeExceeded(header.src.into())
+            } else {
+                PingError::Unreachable(header.src.into(), message[1])
+            };
+            icmp::complete(ident, seq, Err(error));
+        }
+        TYPE_ROUTER_SOLICITATION..=TYPE_NEIGHBOR_ADVERTISEMENT => {
+            ndp::handle(interface, header, message)
+        }
+        _ => {}
+    }
+}
+
+/// Sends one echo request to `dst` and waits up to `timeout_ms` for the
+/// reply.
+pub async fn echo(
+    dst: Ipv6Addr,
+    ident: u16,
+    seq: u16,
+    timeout_ms: u64,
+) -> Result<PingReply, PingError> {
+    let (interface, next_hop, src) = ipv6::route(dst).map_err(PingError::Ip)?;
+    icmp::expect_reply(ident, seq);
+    let data: Vec<u8> = (0..icmp::PING_DATA_SIZE).map(|i| i as u8).collect();
+    let [ident_high, ident_low] = ident.to_be_bytes();
+    let [seq_high, seq_low] = seq.to_be_bytes();
+    let fields = [ident_high, ident_low, seq_high, seq_low];
+    let request = build(src, dst, TYPE_ECHO_REQUEST, 0, fields, &data);
+    let sent_ms = time::uptime_ms();
+    let header = Ipv6Header::new(src, dst, Protocol::Icmpv6);
+    if let Err(err) = ipv6::send_via(&interface, next_hop, header, &request) {
+        icmp::complete(ident, seq, Err(PingError::Ip(err)));
+    }
+    icmp::wait_for_reply(ident, seq, sent_ms, timeout_ms).await
+}
@@ -0,0 +1,591 @@
+pub use core::net::{IpAddr, Ipv4Addr};
+
+use super::{
+    arp,
+    device::{self, Interface, NetError},
+    ethernet::EtherType,
+    icmp::{self, ErrorMessage},
+    ipv6,
+    route::{self, RouteTable},
+};
+use crate::time;
+use alloc::{collections::BTreeMap, sync::Arc, vec, vec::Vec};
+use core::sync::atomic::{AtomicU16, Ordering};
+use spin::Mutex;
+
+/// The IPv4 address of an interface and how to reach the rest of the
+/// network from it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ipv4Config {
+    pub address: Ipv4Addr,
+    pub prefix_len: u8,
+    pub gateway: Option<Ipv4Addr>,
+}
+
+impl Ipv4Config {
+    /// A `prefix_len` over 32 counts as 32.
+    pub fn netmask(&self) -> Ipv4Addr {
+        let bits = u32::MAX
+            .checked_shl(32u32.saturating_sub(self.prefix_len as u32))
+            .unwrap_or(0);
+        Ipv4Addr::from(bits)
+    }
+
+    /// Whether `address` is on the same subnet, so it can be reached
+    /// without the gateway.
+    pub fn is_local(&self, address: Ipv4Addr) -> bool {
+        let mask = u32::from(self.netmask());
+        u32::from(address) & mask == u32::from(self.address) & mask
+    }
+
+    pub fn broadcast(&self) -> Ipv4Addr {
+        Ipv4Addr::from(u32::from(self.address) | !u32::from(self.netmask()))
+    }
+}
+
+/// Header without options.
+pub const HEADER_SIZE: usize = 20;
+pub const DEFAULT_TTL: u8 = 64;
+/// Largest packet, header included.
+pub const MAX_PACKET_SIZE: usize = 65535;
+
+/// Unfinished reassemblies are dropped after this long.
+pub const REASSEMBLY_TIMEOUT_MS: u64 = 30_000;
+/// Packets that can be reassembled at once. The oldest go first.
+const MAX_REASSEMBLIES: usize = 16;
+
+const FLAG_DONT_FRAGMENT: u16 = 1 << 14;
+const FLAG_MORE_FRAGMENTS: u16 = 1 << 13;
+const FRAGMENT_OFFSET_MASK: u16 = 0x1FFF;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Protocol {
+    Icmp,
+    Tcp,
+    Udp,
+    Icmpv6,
+    Other(u8),
+}
+
+impl From<u8> for Protocol {
+    fn from(value: u8) -> Protocol {
+        match value {
+            1 => Protocol::Icmp,
+            6 => Protocol::Tcp,
+            17 => Protocol::Udp,
+            58 => Protocol::Icmpv6,
+            other => Protocol::Other(other),
+        }
+    }
+}
+
+impl From<Protocol> for u8 {
+    fn from(protocol: Protocol) -> u8 {
+        match protocol {
+            Protocol::Icmp => 1,
+            Protocol::Tcp => 6,
+            Protocol::Udp => 17,
+            Protocol::Icmpv6 => 58,
+            Protocol::Other(other) => other,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpError {
+    /// The packet is shorter than its header says.
+    Truncated,
+    /// Not an IP header, or one of the wrong version.
+    InvalidHeader,
+    BadChecksum,
+    /// No route, or no address on the interface the route goes through.
+    NoRoute,
+    /// Too large for one packet, or too large for the link and not allowed
+    /// to be fragmented.
+    TooLarge,
+    Device(NetError),
+}
+
+/// The ones' complement sum used by IPv4, ICMP, UDP and TCP.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Checksum {
+    sum: u64,
+}
+
+impl Checksum {
+    pub fn new() -> Checksum {
+        Checksum::default()
+    }
+
+    /// Adds `data` as big endian words. Only the last piece added may have
+    /// an odd length.
+    pub fn add(&mut self, data: &[u8]) -> &mut Checksum {
+        for chunk in data.chunks(2) {
+            let word = match chunk {
+                [high, low] => u16::from_be_bytes([*high, *low]),
+                [high] => u16::from_be_bytes([*high, 0]),
+                _ => unreachable!(),
+            };
+            self.sum += word as u64;
+        }
+        self
+    }
+
+    pub fn add_u16(&mut self, value: u16) -> &mut Checksum {
+        self.sum += value as u64;
+        self
+    }
+
+    /// The checksum to store, which is 0 when checking data that already
+    /// includes a correct one.
+    pub fn finish(&self) -> u16 {
+        let mut sum = self.sum;
+        while sum > 0xFFFF {
+            sum = (sum & 0xFFFF) + (sum >> 16);
+        }
+        !(sum as u16)
+    }
+}
+
+pub fn checksum(data: &[u8]) -> u16 {
+    Checksum::new().add(data).finish()
+}
+
+/// Starts the checksum of a UDP or TCP segment with the pseudo header.
+pub fn pseudo_header(src: Ipv4Addr, dst: Ipv4Addr, protocol: Protocol, len: usize) -> Checksum {
+    let mut checksum = Checksum::new();
+    checksum
+        .add(&src.octets())
+        .add(&dst.octets())
+        .add_u16(u8::from(protocol) as u16)
+        .add_u16(len as u16);
+    checksum
+}
+
+/// Like [`pseudo_header`], with the IPv6 pseudo header if either address is
+/// an IPv6 one. IPv4 addresses are mapped then, though sockets never mix
+/// the two.
+pub fn pseudo_header_any(src: IpAddr, dst: IpAddr, protocol: Protocol, len: usize) -> Checksum {
+    let mapped = |address: IpAddr| match address {
+        IpAddr::V4(address) => address.to_ipv6_mapped(),
+        IpAddr::V6(address) => address,
+    };
+    match (src, dst) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => pseudo_header(src, dst, protocol, len),
+        _ => ipv6::pseudo_header(mapped(src), mapped(dst), protocol, len),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ipv4Header {
+    /// DSCP and ECN.
+    pub tos: u8,
+    /// Length of the header, options included, and payload.
+    pub total_len: u16,
+    pub ident: u16,
+    pub dont_fragment: bool,
+    pub more_fragments: bool,
+    /// Offset of this fragment's payload in the original payload, in bytes.
+    pub fragment_offset: usize,
+    pub ttl: u8,
+    pub protocol: Protocol,
+    pub src: Ipv4Addr,
+    pub dst: Ipv4Addr,
+    /// Length of the header with options. Options are skipped on receive
+    /// and never sent.
+    pub header_len: usize,
+}
+
+impl Ipv4Header {
+    /// A header for an unfragmented packet, without its length yet.
+    pub fn new(src: Ipv4Addr, dst: Ipv4Addr, protocol: Protocol) -> Ipv4Header {
+        Ipv4Header {
+            tos: 0,
+            total_len: 0,
+            ident: 0,
+            dont_fragment: false,
+            more_fragments: false,
+            fragment_offset: 0,
+            ttl: DEFAULT_TTL,
+            protocol,
+            src,
+            dst,
+            header_len: HEADER_SIZE,
+        }
+    }
+
+    /// Parses and checks the header at the start of `packet`.
+    pub fn parse(packet: &[u8]) -> Result<Ipv4Header, IpError> {
+        let header = Ipv4Header::parse_quoted(packet)?;
+        if packet.len() < header.total_len as usize {
+            return Err(IpError::Truncated);
+        }
+        Ok(header)
+    }
+
+    /// Parses a header quoted in an ICMP error, which is followed by only
+    /// part of its payload.
+    pub fn parse_quoted(packet: &[u8]) -> Result<Ipv4Header, IpError> {
+        if packet.len() < HEADER_SIZE {
+            return Err(IpError::Truncated);
+        }
+        let header_len = (packet[0] & 0xF) as usize * 4;
+        if packet[0] >> 4 != 4 || header_len < HEADER_SIZE {
+            return Err(IpError::InvalidHeader);
+        }
+        let total_len = u16::from_be_bytes([packet[2], packet[3]]);
+        if packet.len() < header_len {
+            return Err(IpError::Truncated);
+        }
+        if (total_len as usize) < header_len {
+            return Err(IpError::InvalidHeader);
+        }
+        if checksum(&packet[..header_len]) != 0 {
+            return Err(IpError::BadChecksum);
+        }
+
+        let flags = u16::from_be_bytes([packet[6], packet[7]]);
+        Ok(Ipv4Header {
+            tos: packet[1],
+            total_len,
+            ident: u16::from_be_bytes([packet[4], packet[5]]),
+            dont_fragment: flags & FLAG_DONT_FRAGMENT != 0,
+            more_fragments: flags & FLAG_MORE_FRAGMENTS != 0,
+            fragment_offset: (flags & FRAGMENT_OFFSET_MASK) as usize * 8,
+            ttl: packet[8],
+            protocol: packet[9].into(),
+            src: Ipv4Addr::new(packet[12], packet[13], packet[14], packet[15]),
+            dst: Ipv4Addr::new(packet[16], packet[17], packet[18], packet[19]),
+            header_len,
+        })
+    }
+
+    pub fn is_fragment(&self) -> bool {
+        self.more_fragments || self.fragment_offset != 0
+    }
+
+    pub fn payload_len(&self) -> usize {
+        self.total_len as usize - self.header_len
+    }
+
+    /// Writes the header, without options, to the start of `buffer`.
+    pub fn write(&self, buffer: &mut [u8]) {
+        let mut flags = (self.fragment_offset / 8) as u16 & FRAGMENT_OFFSET_MASK;
+        if self.dont_fragment {
+            flags |= FLAG_DONT_FRAGMENT;
+        }
+        if self.more_fragments {
+            flags |= FLAG_MORE_FRAGMENTS;
+        }
+
+        let header = &mut buffer[..HEADER_SIZE];
+        header[0] = 0x45;
+        header[1] = self.tos;
+        header[2..4].copy_from_slice(&self.total_len.to_be_bytes());
+        header[4..6].copy_from_slice(&self.ident.to_be_bytes());
+        header[6..8].copy_from_slice(&flags.to_be_bytes());
+        header[8] = self.ttl;
+        header[9] = self.protocol.into();
+        header[10..12].fill(0);
+        header[12..16].copy_from_slice(&self.src.octets());
+        header[16..20].copy_from_slice(&self.dst.octets());
+        let checksum = checksum(header);
+        header[10..12].copy_from_slice(&checksum.to_be_bytes());
+    }
+
+    /// Builds a packet with this header and `payload`, setting the length.
+    pub fn build(&self, payload: &[u8]) -> Vec<u8> {
+        let mut header = *self;
+        header.header_len = HEADER_SIZE;
+        header.total_len = (HEADER_SIZE + payload.len()) as u16;
+        let mut packet = vec![0; HEADER_SIZE];
+        header.write(&mut packet);
+        packet.extend_from_slice(payload);
+        packet
+    }
+}
+
+pub type Route = route::Route<Ipv4Addr>;
+
+static ROUTES: RouteTable<Ipv4Addr> = RouteTable::new();
+
+/// Adds `route`, replacing the same route added before.
+pub fn add_route(route: Route) {
+    ROUTES.add(route);
+}
+
+/// Removes every route through `interface`.
+pub fn remove_routes(interface: &str) {
+    ROUTES.remove_interface(interface);
+}
+
+pub fn routes() -> Vec<Route> {
+    ROUTES.routes()
+}
+
+/// Finds the interface to send packets for `dst` through and the neighbor
+/// to hand them to, using the longest matching route.
+pub fn lookup_route(dst: Ipv4Addr) -> Option<(Arc<Interface>, Ipv4Addr)> {
+    let route = ROUTES.lookup(dst)?;
+    let next_hop = match route.gateway {
+        Some(gateway) if dst != Ipv4Addr::BROADCAST => gateway,
+        _ => dst,
+    };
+    let interface = device::interface(&route.interface)?;
+    Some((interface, next_hop))
+}
+
+/// The interface, next hop and source address for packets to `dst`.
+pub fn route(dst: Ipv4Addr) -> Result<(Arc<Interface>, Ipv4Addr, Ipv4Addr), IpError> {
+    let (interface, next_hop) = lookup_route(dst).ok_or(IpError::NoRoute)?;
+    let src = interface.ipv4().ok_or(IpError::NoRoute)?.address;
+    Ok((interface, next_hop, src))
+}
+
+/// Sends `payload` to `dst` with the default TTL.
+pub fn send(dst: Ipv4Addr, protocol: Protocol, payload: &[u8]) -> Result<(), IpError> {
+    let (interface, next_hop, src) = route(dst)?;
+    send_via(
+        &interface,
+        next_hop,
+        Ipv4Header::new(src, dst, protocol),
+        payload,
+    )
+}
+
+/// The address packets to `dst` are sent from, over IPv4 or IPv6.
+pub fn source_for(dst: IpAddr) -> Result<IpAddr, IpError> {
+    Ok(match dst {
+        IpAddr::V4(dst) => route(dst)?.2.into(),
+        IpAddr::V6(dst) => ipv6::route(dst)?.2.into(),
+    })
+}
+
+/// Like [`send`], over IPv6 if `dst` is an IPv6 address.
+pub fn send_any(dst: IpAddr, protocol: Protocol, payload: &[u8]) -> Result<(), IpError> {
+    match dst {
+        IpAddr::V4(dst) => send(dst, protocol, payload),
+        IpAddr::V6(dst) => ipv6::send(dst, protocol, payload),
+    }
+}
+
+/// The MTU of the interface packets to `dst` leave through, less the IP
+/// header.
+pub fn max_payload(dst: IpAddr) -> Option<usize> {
+    match dst {
+        IpAddr::V4(dst) => Some(lookup_route(dst)?.0.device.mtu() - HEADER_SIZE),
+        IpAddr::V6(dst) => Some(ipv6::lookup_route(dst)?.0.device.mtu() - ipv6::HEADER_SIZE),
+    }
+}
+
+static NEXT_IDENT: AtomicU16 = AtomicU16::new(1);
+
+/// Sends `payload` with `header` to the neighbor `next_hop`, splitting it
+/// into fragments if it doesn't fit the interface's MTU. The length,
+/// identification and fragment fields of `header` are filled in here.
+pub fn send_via(
+    interface: &Interface,
+    next_hop: Ipv4Addr,
+    header: Ipv4Header,
+    payload: &[u8],
+) -> Result<(), IpError> {
+    if HEADER_SIZE + payload.len() > MAX_PACKET_SIZE {
+        return Err(IpError::TooLarge);
+    }
+    let mut header = header;
+    header.ident = NEXT_IDENT.fetch_add(1, Ordering::Relaxed);
+
+    let mtu = interface.device.mtu();
+    if HEADER_SIZE + payload.len() <= mtu {
+        let packet = header.build(payload);
+        return arp::send_to(interface, next_hop, EtherType::Ipv4, &packet)
+            .map_err(IpError::Device);
+    }
+    if header.dont_fragment {
+        return Err(IpError::TooLarge);
+    }
+
+    // Every fragment but the last carries a multiple of 8 bytes
+    let fragment_size = (mtu - HEADER_SIZE) & !7;
+    for (i, chunk) in payload.chunks(fragment_size).enumerate() {
+        header.fragment_offset = i * fragment_size;
+        header.more_fragments = header.fragment_offset + chunk.len() < payload.len();
+        let packet = header.build(chunk);
+        arp::send_to(interface, next_hop, EtherType::Ipv4, &packet).map_err(IpError::Device)?;
+    }
+    Ok(())
+}
+
+/// Called with every packet addressed to us, after reassembly.
+pub type ProtocolHandler = fn(&Arc<Interface>, &Ipv4Header, &[u8]);
+
+static PROTOCOLS: Mutex<BTreeMap<Protocol, ProtocolHandler>> = Mutex::new(BTreeMap::new());
+
+/// Has packets for `protocol` passed to `handler`, replacing the handler
+/// registered before.
+pub fn register_protocol(protocol: Protocol, handler: ProtocolHandler) {
+    PROTOCOLS.lock().insert(protocol, handler);
+}
+
+/// The pieces of a fragmented packet received so far.
+struct Reassembly {
+    header: Option<Ipv4Header>,
+    payload: Vec<u8>,
+    /// Byte ranges of the payload received, sorted and merged.
+    received: Vec<(usize, usize)>,
+    /// Length of the payload, known once the last fragment arrived.
+    total_len: Option<usize>,
+    started_ms: u64,
+}
+
+impl Reassembly {
+    fn add(&mut self, header: &Ipv4Header, data: &[u8]) {
+        let start = header.fragment_offset;
+        let end = start + data.len();
+        if self.payload.len() < end {
+            self.payload.resize(end, 0);
+        }
+        self.payload[start..end].copy_from_slice(data);
+        if start == 0 {
+            self.header = Some(*header);
+        }
+        if !header.more_fragments {
+            self.total_len = Some(end);
+        }
+
+        self.received.push((start, end));
+        self.received.sort_unstable();
+        let mut merged: Vec<(usize, usize)> = Vec::new();
+        for &(start, end) in &self.received {
+            match merged.last_mut() {
+                Some(last) if start <= last.1 => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+        self.received = merged;
+    }
+
+    fn is_complete(&self) -> bool {
+        self.header.is_some()
+            && self
+                .total_len
+                .is_some_and(|len| self.received == [(0, len)])
+    }
+}
+
+/// Source, destination, protocol and identification.
+type ReassemblyKey = (Ipv4Addr, Ipv4Addr, Protocol, u16);
+
+static REASSEMBLIES: Mutex<BTreeMap<ReassemblyKey, Reassembly>> = Mutex::new(BTreeMap::new());
+
+/// Adds a fragment to its packet and returns the packet once every
+/// fragment is there.
+fn reassemble(header: &Ipv4Header, data: &[u8]) -> Option<(Ipv4Header, Vec<u8>)> {
+    if header.fragment_offset + data.len() > MAX_PACKET_SIZE - HEADER_SIZE {
+        return None;
+    }
+    let key = (header.src, header.dst, header.protocol, header.ident);
+    let mut reassemblies = REASSEMBLIES.lock();
+    if !reassemblies.contains_key(&key) && reassemblies.len() == MAX_REASSEMBLIES {
+        let oldest = reassemblies
+            .iter()
+            .min_by_key(|(_, reassembly)| reassembly.started_ms)
+            .map(|(key, _)| *key)?;
+        reassemblies.remove(&oldest);
+    }
+
+    let reassembly = reassemblies.entry(key).or_insert_with(|| Reassembly {
+        header: None,
+        payload: Vec::new(),
+        received: Vec::new(),
+        total_len: None,
+        started_ms: time::uptime_ms(),
+    });
+    reassembly.add(header, data);
+    if !reassembly.is_complete() {
+        return None;
+    }
+
+    let reassembly = reassemblies.remove(&key)?;
+    let mut header = reassembly.header?;
+    header.more_fragments = false;
+    header.fragment_offset = 0;
+    header.header_len = HEADER_SIZE;
+    header.total_len = (HEADER_SIZE + reassembly.payload.len()) as u16;
+    Some((header, reassembly.payload))
+}
+
+/// Drops reassemblies that have waited too long for their fragments, and
+/// tells the senders whose first fragment arrived.
+pub fn expire_reassemblies() {
+    let now = time::uptime_ms();
+    let mut expired = Vec::new();
+    REASSEMBLIES.lock().retain(|_, reassembly| {
+        if now - reassembly.started_ms < REASSEMBLY_TIMEOUT_MS {
+            return true;
+        }
+        if let Some(header) = reassembly.header {
+            expired.push((header, core::mem::take(&mut reassembly.payload)));
+        }
+        false
+    });
+
+    for (header, payload) in expired {
+        let error = ErrorMessage::TimeExceeded(icmp::CODE_REASSEMBLY_TIME_EXCEEDED);
+        icmp::send_error(error, &header, &payload);
+    }
+}
+
+/// Whether a packet for `dst` that arrived on `interface` is ours.
+fn is_for_us(interface: &Interface, dst: Ipv4Addr) -> bool {
+    match interface.ipv4() {
+        // All of 127.0.0.0/8 is ours on the loopback interface
+        Some(config) if config.address.is_loopback() => config.is_local(dst),
+        Some(config) => {
+            dst == config.address || dst == config.broadcast() || dst == Ipv4Addr::BROADCAST
+        }
+        // Without an address, take everything, since that is how DHCP
+        // offers arrive
+        None => true,
+    }
+}
+
+/// Handles an IPv4 packet `interface` received.
+pub fn handle(interface: &Arc<Interface>, packet: &[u8]) {
+    let Ok(header) = Ipv4Header::parse(packet) else {
+        return;
+    };
+    if !is_for_us(interface, header.dst) {
+        // Packets aren't forwarded
+        return;
+    }
+    // Anything past the total length is link layer padding
+    let payload = &packet[header.header_len..header.total_len as usize];
+
+    if header.is_fragment() {
+        if let Some((header, payload)) = reassemble(&header, payload) {
+            deliver(interface, &header, &payload);
+        }
+    } else {
+        deliver(interface, &header, payload);
+    }
+}
+
+fn deliver(interface: &Arc<Interface>, header: &Ipv4Header, payload: &[u8]) {
+    let handler = PROTOCOLS.lock().get(&header.protocol).copied();
+    match handler {
+        Some(handler) => handler(interface, header, payload),
+        None => {
+            let error = ErrorMessage::DestinationUnreachable(icmp::CODE_PROTOCOL_UNREACHABLE);
+            icmp::send_error(error, header, payload);
+        }
+    }
+}
+
+/// Drops stale reassemblies. Runs as a task.
+pub async fn run() {
+    loop {
+        expire_reassemblies();
+        time::sleep(1000).await;
+    }
+}
@@ -0,0 +1,342 @@
+//! IPv6 packets, addresses and routes.
+//!
+//! Every interface gets a link-local address made from its MAC, and global
+//! ones from the prefixes routers advertise, see [`super::ndp`]. Extension
+//! headers aren't supported, and packets too large for the link are refused
+//! instead of fragmented.
+
+pub use core::net::Ipv6Addr;
+
+use super::{
+    device::{self, Interface, MacAddress},
+    ethernet::EtherType,
+    icmpv6,
+    ip::{Checksum, IpError, Protocol},
+    ndp,
+    route::{self, RouteTable},
+};
+use crate::time;
+use alloc::{collections::BTreeMap, sync::Arc, vec, vec::Vec};
+use spin::Mutex;
+
+/// The fixed header. Extension headers would follow it.
+pub const HEADER_SIZE: usize = 40;
+pub const DEFAULT_HOP_LIMIT: u8 = 64;
+/// Smallest MTU a link carrying IPv6 may have.
+pub const MIN_MTU: usize = 1280;
+
+pub const ALL_NODES: Ipv6Addr = Ipv6Addr::new(0xFF02, 0, 0, 0, 0, 0, 0, 1);
+pub const ALL_ROUTERS: Ipv6Addr = Ipv6Addr::new(0xFF02, 0, 0, 0, 0, 0, 0, 2);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ipv6Header {
+    pub traffic_class: u8,
+    /// 20 bits.
+    pub flow_label: u32,
+    /// Length of what follows the fixed header.
+    pub payload_len: u16,
+    pub next_header: Protocol,
+    pub hop_limit: u8,
+    pub src: Ipv6Addr,
+    pub dst: Ipv6Addr,
+}
+
+impl Ipv6Header {
+    /// A header with the default hop limit, without its length yet.
+    pub fn new(src: Ipv6Addr, dst: Ipv6Addr, next_header: Protocol) -> Ipv6Header {
+        Ipv6Header {
+            traffic_class: 0,
+            flow_label: 0,
+            payload_len: 0,
+            next_header,
+            hop_limit: DEFAULT_HOP_LIMIT,
+            src,
+            dst,
+        }
+    }
+
+    /// Parses and checks the header at the start of `packet`.
+    pub fn parse(packet: &[u8]) -> Result<Ipv6Header, IpError> {
+        let header = Ipv6Header::parse_quoted(packet)?;
+        if packet.len() < HEADER_SIZE + header.payload_len as usize {
+            return Err(IpError::Truncated);
+        }
+        Ok(header)
+    }
+
+    /// Parses a header quoted in an ICMPv6 error, which is followed by only
+    /// part of its payload.
+    pub fn parse_quoted(packet: &[u8]) -> Result<Ipv6Header, IpError> {
+        if packet.len() < HEADER_SIZE {
+            return Err(IpError::Truncated);
+        }
+        if packet[0] >> 4 != 6 {
+            return Err(IpError::InvalidHeader);
+        }
+        let first = u32::from_be_bytes(packet[0..4].try_into().unwrap());
+        let src: [u8; 16] = packet[8..24].try_into().unwrap();
+        let dst: [u8; 16] = packet[24..40].try_into().unwrap();
+        Ok(Ipv6Header {
+            traffic_class: (first >> 20) as u8,
+            flow_label: first & 0xF_FFFF,
+            payload_len: u16::from_be_bytes([packet[4], packet[5]]),
+            next_header: packet[6].into(),
+            hop_limit: packet[7],
+            src: Ipv6Addr::from(src),
+            dst: Ipv6Addr::from(dst),
+        })
+    }
+
+    /// Writes the header to the start of `buffer`.
+    pub fn write(&self, buffer: &mut [u8]) {
+        let first = 6 << 28 | (self.traffic_class as u32) << 20 | (self.flow_label & 0xF_FFFF);
+        let header = &mut buffer[..HEADER_SIZE];
+        header[0..4].copy_from_slice(&first.to_be_bytes());
+        header[4..6].copy_from_slice(&self.payload_len.to_be_bytes());
+        header[6] = self.next_header.into();
+        header[7] = self.hop_limit;
+        header[8..24].copy_from_slice(&self.src.octets());
+        header[24..40].copy_from_slice(&self.dst.octets());
+    }
+
+    /// Builds a packet with this header and `payload`, setting the length.
+    pub fn build(&self, payload: &[u8]) -> Vec<u8> {
+        let mut header = *self;
+        header.payload_len = payload.len() as u16;
+        let mut packet = vec![0; HEADER_SIZE];
+        header.write(&mut packet);
+        packet.extend_from_slice(payload);
+        packet
+    }
+}
+
+/// Starts the checksum of an ICMPv6, UDP or TCP message with the IPv6
+/// pseudo header.
+pub fn pseudo_header(src: Ipv6Addr, dst: Ipv6Addr, next_header: Protocol, len: usize) -> Checksum {
+    let mut checksum = Checksum::new();
+    checksum
+        .add(&src.octets())
+        .add(&dst.octets())
+        .add(&(len as u32).to_be_bytes())
+        .add_u16(u8::from(next_header) as u16);
+    checksum
+}
+
+/// The modified EUI-64 interface identifier of RFC 4291 for `mac`: the MAC
+/// split by `ff:fe`, with the universal/local bit flipped.
+pub fn interface_id(mac: MacAddress) -> [u8; 8] {
+    [
+        mac[0] ^ 0x02,
+        mac[1],
+        mac[2],
+        0xFF,
+        0xFE,
+        mac[3],
+        mac[4],
+        mac[5],
+    ]
+}
+
+/// The address made of the first 64 bits of `prefix` and the interface
+/// identifier for `mac`.
+pub fn with_interface_id(prefix: Ipv6Addr, mac: MacAddress) -> Ipv6Addr {
+    let mut octets = prefix.octets();
+    octets[8..].copy_from_slice(&interface_id(mac));
+    Ipv6Addr::from(octets)
+}
+
+/// The `fe80::/64` address of the interface with `mac`.
+pub fn link_local(mac: MacAddress) -> Ipv6Addr {
+    with_interface_id(Ipv6Addr::new(0xFE80, 0, 0, 0, 0, 0, 0, 0), mac)
+}
+
+pub fn is_link_local(address: Ipv6Addr) -> bool {
+    address.segments()[0] & 0xFFC0 == 0xFE80
+}
+
+/// The multicast group that neighbor solicitations for `address` go to.
+pub fn solicited_node(address: Ipv6Addr) -> Ipv6Addr {
+    let octets = address.octets();
+    Ipv6Addr::from([
+        0xFF, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x01, 0xFF, octets[13], octets[14], octets[15],
+    ])
+}
+
+/// The Ethernet address multicast packets for `address` are sent to, as
+/// RFC 2464 maps them.
+pub fn multicast_mac(address: Ipv6Addr) -> MacAddress {
+    let octets = address.octets();
+    [0x33, 0x33, octets[12], octets[13], octets[14], octets[15]]
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressState {
+    /// Duplicate address detection hasn't finished, so the address can't be
+    /// used yet.
+    Tentative,
+    Preferred,
+}
+
+/// An IPv6 address of an interface.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ipv6Address {
+    pub address: Ipv6Addr,
+    pub prefix_len: u8,
+    pub state: AddressState,
+    /// When a router's advertisement stops covering the address, or `None`
+    /// if it doesn't expire.
+    pub valid_until_ms: Option<u64>,
+}
+
+pub type Route = route::Route<Ipv6Addr>;
+
+static ROUTES: RouteTable<Ipv6Addr> = RouteTable::new();
+
+/// Adds `route`, replacing the same route added before, since routers
+/// advertise theirs again and again.
+pub fn add_route(route: Route) {
+    ROUTES.add(route);
+}
+
+pub fn remove_route(route: &Route) {
+    ROUTES.remove(route);
+}
+
+/// Removes every route through `interface`.
+pub fn remove_routes(interface: &str) {
+    ROUTES.remove_interface(interface);
+}
+
+pub fn routes() -> Vec<Route> {
+    ROUTES.routes()
+}
+
+/// Finds the interface to send packets for `dst` through and the neighbor
+/// to hand them to, using the longest matching route.
+///
+/// Link-local and multicast destinations go through the first interface,
+/// since addresses don't say which link they mean.
+pub fn lookup_route(dst: Ipv6Addr) -> Option<(Arc<Interface>, Ipv6Addr)> {
+    let route = ROUTES.lookup(dst)?;
+    let next_hop = match route.gateway {
+        Some(gateway) if !dst.is_multicast() => gateway,
+        _ => dst,
+    };
+    let interface = device::interface(&route.interface)?;
+    Some((interface, next_hop))
+}
+
+/// The address of `interface` to send packets for `dst` from: a link-local
+/// one for destinations on the link, a global one otherwise, if there is
+/// one of the right kind.
+pub fn source_address(interface: &Interface, dst: Ipv6Addr) -> Option<Ipv6Addr> {
+    let usable: Vec<Ipv6Addr> = interface
+        .ipv6()
+        .iter()
+        .filter(|address| address.state == AddressState::Preferred)
+        .map(|address| address.address)
+        .collect();
+    // Link-local multicast groups have scope 2
+    let link_scope = is_link_local(dst) || (dst.is_multicast() && dst.segments()[0] & 0xF == 2);
+    usable
+        .iter()
+        .find(|address| is_link_local(**address) == link_scope)
+        .or(usable.first())
+        .copied()
+}
+
+/// The interface, next hop and source address for packets to `dst`.
+pub fn route(dst: Ipv6Addr) -> Result<(Arc<Interface>, Ipv6Addr, Ipv6Addr), IpError> {
+    let (interface, next_hop) = lookup_route(dst).ok_or(IpError::NoRoute)?;
+    let src = source_address(&interface, dst).ok_or(IpError::NoRoute)?;
+    Ok((interface, next_hop, src))
+}
+
+/// Sends `payload` to `dst` with the default hop limit.
+pub fn send(dst: Ipv6Addr, next_header: Protocol, payload: &[u8]) -> Result<(), IpError> {
+    let (interface, next_hop, src) = route(dst)?;
+    send_via(
+        &interface,
+        next_hop,
+        Ipv6Header::new(src, dst, next_header),
+        payload,
+    )
+}
+
+/// Sends `payload` with `header` to the neighbor `next_hop`. The length of
+/// `header` is filled in here.
+pub fn send_via(
+    interface: &Interface,
+    next_hop: Ipv6Addr,
+    header: Ipv6Header,
+    payload: &[u8],
+) -> Result<(), IpError> {
+    if HEADER_SIZE + payload.len() > interface.device.mtu() {
+        return Err(IpError::TooLarge);
+    }
+    let packet = header.build(payload);
+    ndp::send_to(interface, next_hop, EtherType::Ipv6, &packet).map_err(IpError::Device)
+}
+
+/// Called with every packet addressed to us.
+pub type ProtocolHandler = fn(&Arc<Interface>, &Ipv6Header, &[u8]);
+
+static PROTOCOLS: Mutex<BTreeMap<Protocol, ProtocolHandler>> = Mutex::new(BTreeMap::new());
+
+/// Has packets for `next_header` passed to `handler`, replacing the
+/// handler registered before.
+pub fn register_protocol(next_header: Protocol, handler: ProtocolHandler) {
+    PROTOCOLS.lock().insert(next_header, handler);
+}
+
+/// Whether a packet for `dst` that arrived on `interface` is ours. Packets
+/// for tentative addresses are, since their solicited-node group is how
+/// duplicates show up.
+fn is_for_us(interface: &Interface, dst: Ipv6Addr) -> bool {
+    dst == ALL_NODES
+        || interface
+            .ipv6()
+            .iter()
+            .any(|address| dst == address.address || dst == solicited_node(address.address))
+}
+
+/// Handles an IPv6 packet `interface` received.
+pub fn handle(interface: &Arc<Interface>, packet: &[u8]) {
+    let Ok(header) = Ipv6Header::parse(packet) else {
+        return;
+    };
+    if !is_for_us(interface, header.dst) {
+        // Packets aren't forwarded
+        return;
+    }
+    // Anything past the payload length is link layer padding
+    let payload = &packet[HEADER_SIZE..HEADER_SIZE + header.payload_len as usize];
+
+    let handler = PROTOCOLS.lock().get(&header.next_header).copied();
+    match handler {
+        Some(handler) => handler(interface, &header, payload),
+        None => {
+            // The pointer is the offset of the next header field
+            icmpv6::send_parameter_problem(
+                icmpv6::CODE_UNRECOGNIZED_NEXT_HEADER,
+                6,
+                &header,
+                payload,
+            );
+        }
+    }
+}
+
+/// Drops the addresses and routes routers stopped advertising.
+pub fn expire() {
+    let now = time::uptime_ms();
+    let alive = |until: Option<u64>| until.is_none_or(|until| now < until);
+    ROUTES.expire(now);
+    for interface in device::interfaces() {
+        for address in interface.ipv6() {
+            if !alive(address.valid_until_ms) {
+                interface.remove_ipv6(address.address);
+            }
+        }
+    }
+}
@@ -1,2 +1,95 @@
-pub mod ip;
+pub mod arp;
+pub mod capture;
+pub mod device;
+pub mod dhcp;
+pub mod dns;
 pub mod ethernet;
+pub mod http;
+pub mod icmp;
+pub mod icmpv6;
+pub mod ip;
+pub mod ipv6;
+pub mod loopback;
+pub mod ndp;
+pub mod neighbor;
+pub mod route;
+pub mod tcp;
+pub mod udp;
+
+use alloc::sync::Arc;
+use device::Interface;
+use ethernet::{EtherType, Frame};
+use ip::{Ipv4Addr, Ipv4Config, Protocol, Route};
+
+/// Registers the protocols carried over IPv4 and IPv6, and the loopback
+/// interface.
+pub fn init() {
+    ip::register_protocol(Protocol::Icmp, icmp::handle);
+    ip::register_protocol(Protocol::Tcp, tcp::handle);
+    ip::register_protocol(Protocol::Udp, udp::handle);
+    ipv6::register_protocol(Protocol::Icmpv6, icmpv6::handle);
+    ipv6::register_protocol(Protocol::Tcp, tcp::handle_ipv6);
+    ipv6::register_protocol(Protocol::Udp, udp::handle_ipv6);
+    loopback::init();
+}
+
+/// Gives `interface` an IPv4 address, or takes it away, sets up its routes
+/// and tells the neighbors about it.
+pub fn configure(interface: &Interface, config: Option<Ipv4Config>) {
+    interface.set_ipv4(config);
+    ip::remove_routes(&interface.name);
+    if let Some(config) = config {
+        let netmask = u32::from(config.netmask());
+        ip::add_route(Route {
+            destination: Ipv4Addr::from(u32::from(config.address) & netmask),
+            prefix_len: config.prefix_len,
+            gateway: None,
+            interface: interface.name.clone(),
+            expires_ms: None,
+        });
+        if let Some(gateway) = config.gateway {
+            ip::add_route(Route {
+                destination: Ipv4Addr::UNSPECIFIED,
+                prefix_len: 0,
+                gateway: Some(gateway),
+                interface: interface.name.clone(),
+                expires_ms: None,
+            });
+        }
+
+        if interface.device.link_up() {
+            arp::announce(interface);
+        }
+        if let Some(gateway) = config.gateway {
+            arp::resolve(interface, gateway);
+        }
+    }
+}
+
+/// Handles the frames `interface` receives. Runs as a task for each
+/// interface.
+pub async fn receive(interface: Arc<Interface>) {
+    loop {
+        let buffer = interface.device.receive().await;
+        handle_frame(&interface, &buffer);
+    }
+}
+
+/// Handles a frame `interface` received.
+pub fn handle_frame(interface: &Arc<Interface>, buffer: &[u8]) {
+    let Ok(frame) = Frame::parse(buffer) else {
+        return;
+    };
+    // Some cards also pass on frames for other addresses
+    let dest = frame.dest();
+    if dest != interface.device.mac() && dest[0] & 1 == 0 {
+        return;
+    }
+
+    match frame.ether_type() {
+        EtherType::Arp => arp::handle(interface, frame.payload()),
+        EtherType::Ipv4 => ip::handle(interface, frame.payload()),
+        EtherType::Ipv6 => ipv6::handle(interface, frame.payload()),
+        _ => {}
+    }
+}
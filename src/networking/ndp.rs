@@ -0,0 +1,638 @@
+//! Neighbor discovery for IPv6 over Ethernet, as in RFC 4861, and
+//! stateless address autoconfiguration, as in RFC 4862.
+//!
+//! Neighbors are resolved like [`super::arp`] resolves IPv4 ones, with
+//! solicitations sent to the target's solicited-node group instead of
+//! broadcast. Interfaces get their link-local address when they are first
+//! seen, then ask for routers, and add an address for every prefix a router
+//! lets them configure. Every address goes through duplicate address
+//! detection first.
+
+use super::{
+    device::{self, Interface, MacAddress, NetError},
+    ethernet::{EtherType, Header},
+    icmpv6::{
+        self, TYPE_NEIGHBOR_ADVERTISEMENT, TYPE_NEIGHBOR_SOLICITATION, TYPE_ROUTER_ADVERTISEMENT,
+        TYPE_ROUTER_SOLICITATION,
+    },
+    ip::Protocol,
+    ipv6::{self, AddressState, Ipv6Addr, Ipv6Address, Ipv6Header, Route, ALL_NODES, ALL_ROUTERS},
+    loopback,
+    neighbor::{self, NeighborCache, Resolver},
+};
+use crate::{println, time};
+use alloc::{collections::BTreeMap, string::String, vec::Vec};
+use spin::Mutex;
+
+/// Hop limit neighbor discovery messages are sent with. Messages that
+/// arrive with a lower one came from off the link and are ignored.
+const HOP_LIMIT: u8 = 255;
+
+/// How long a resolved neighbor is trusted without hearing from it.
+pub const REACHABLE_MS: u64 = 30_000;
+/// Time between solicitations, and how long duplicate address detection
+/// waits for an answer.
+pub const RETRANS_MS: u64 = 1000;
+pub const MAX_RETRIES: u32 = 3;
+const MAX_ROUTER_SOLICITATIONS: u32 = 3;
+const ROUTER_SOLICITATION_INTERVAL_MS: u64 = 4000;
+
+const OPTION_SOURCE_LINK_ADDRESS: u8 = 1;
+const OPTION_TARGET_LINK_ADDRESS: u8 = 2;
+const OPTION_PREFIX_INFO: u8 = 3;
+
+const FLAG_ROUTER: u8 = 0x80;
+const FLAG_SOLICITED: u8 = 0x40;
+const FLAG_OVERRIDE: u8 = 0x20;
+const PREFIX_ON_LINK: u8 = 0x80;
+const PREFIX_AUTONOMOUS: u8 = 0x40;
+/// A lifetime that never runs out.
+const INFINITE_LIFETIME: u32 = u32::MAX;
+/// Advertisements can't shorten an address's valid lifetime below this,
+/// so a forged one can't take the address away (RFC 4862 section 5.5.3).
+const MIN_VALID_LIFETIME_MS: u64 = 2 * 60 * 60 * 1000;
+
+/// A prefix a router advertises.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PrefixInfo {
+    pub prefix: Ipv6Addr,
+    pub prefix_len: u8,
+    /// Addresses with the prefix can be reached without the router.
+    pub on_link: bool,
+    /// Hosts may make themselves an address with the prefix.
+    pub autonomous: bool,
+    pub valid_secs: u32,
+    pub preferred_secs: u32,
+}
+
+/// A neighbor discovery message, with the options that are understood.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    RouterSolicitation {
+        source_mac: Option<MacAddress>,
+    },
+    RouterAdvertisement {
+        hop_limit: u8,
+        /// How long the router may be used as the default one, 0 if it
+        /// shouldn't be.
+        router_lifetime_secs: u16,
+        source_mac: Option<MacAddress>,
+        prefixes: Vec<PrefixInfo>,
+    },
+    NeighborSolicitation {
+        target: Ipv6Addr,
+        source_mac: Option<MacAddress>,
+    },
+    NeighborAdvertisement {
+        router: bool,
+        solicited: bool,
+        /// The MAC replaces the one the receiver has for the target.
+        overrides: bool,
+        target: Ipv6Addr,
+        target_mac: Option<MacAddress>,
+    },
+}
+
+/// Splits `data` into its options' types and contents, or returns `None`
+/// if one is malformed.
+fn parse_options(mut data: &[u8]) -> Option<Vec<(u8, &[u8])>> {
+    let mut options = Vec::new();
+    while data.len() >= 2 {
+        // In units of 8 bytes, type and length included
+        let len = data[1] as usize * 8;
+        if len == 0 || len > data.len() {
+            return None;
+        }
+        options.push((data[0], &data[2..len]));
+        data = &data[len..];
+    }
+    Some(options)
+}
+
+fn link_address(options: &[(u8, &[u8])], kind: u8) -> Option<MacAddress> {
+    options
+        .iter()
+        .find(|(other, data)| *other == kind && data.len() >= 6)
+        .map(|(_, data)| data[..6].try_into().unwrap())
+}
+
+fn push_link_address(data: &mut Vec<u8>, kind: u8, mac: Option<MacAddress>) {
+    if let Some(mac) = mac {
+        data.extend_from_slice(&[kind, 1]);
+        data.extend_from_slice(&mac);
+    }
+}
+
+impl Message {
+    /// Parses an ICMPv6 message, without checking its checksum.
+    pub fn parse(message: &[u8]) -> Option<Message> {
+        if message.len() < icmpv6::HEADER_SIZE || message[1] != 0 {
+            return None;
+        }
+        let address = |offset: usize| -> Option<Ipv6Addr> {
+            let octets: [u8; 16] = message.get(offset..offset + 16)?.try_into().unwrap();
+            Some(Ipv6Addr::from(octets))
+        };
+        match message[0] {
+            TYPE_ROUTER_SOLICITATION => {
+                let options = parse_options(&message[8..])?;
+                Some(Message::RouterSolicitation {
+                    source_mac: link_address(&options, OPTION_SOURCE_LINK_ADDRESS),
+                })
+            }
+            TYPE_ROUTER_ADVERTISEMENT => {
+                // Reachable time and retransmission timer come before the
+                // options. Prefixes longer than an address are nonsense and
+                // dropped.
+                let options = parse_options(message.get(16..)?)?;
+                let prefixes = options
+                    .iter()
+                    .filter(|(kind, data)| *kind == OPTION_PREFIX_INFO && data.len() >= 30)
+                    .filter(|(_, data)| data[0] <= 128)
+                    .map(|(_, data)| PrefixInfo {
+                        prefix: Ipv6Addr::from(<[u8; 16]>::try_from(&data[14..30]).unwrap()),
+                        prefix_len: data[0],
+                        on_link: data[1] & PREFIX_ON_LINK != 0,
+                        autonomous: data[1] & PREFIX_AUTONOMOUS != 0,
+                        valid_secs: u32::from_be_bytes(data[2..6].try_into().unwrap()),
+                        preferred_secs: u32::from_be_bytes(data[6..10].try_into().unwrap()),
+                    })
+                    .collect();
+                Some(Message::RouterAdvertisement {
+                    hop_limit: message[4],
+                    router_lifetime_secs: u16::from_be_bytes([message[6], message[7]]),
+                    source_mac: link_address(&options, OPTION_SOURCE_LINK_ADDRESS),
+                    prefixes,
+                })
+            }
+            TYPE_NEIGHBOR_SOLICITATION => {
+                let target = address(8)?;
+                let options = parse_options(&message[24..])?;
+                Some(Message::NeighborSolicitation {
+                    target,
+                    source_mac: link_address(&options, OPTION_SOURCE_LINK_ADDRESS),
+                })
+            }
+            TYPE_NEIGHBOR_ADVERTISEMENT => {
+                let target = address(8)?;
+                let options = parse_options(&message[24..])?;
+                Some(Message::NeighborAdvertisement {
+                    router: message[4] & FLAG_ROUTER != 0,
+                    solicited: message[4] & FLAG_SOLICITED != 0,
+                    overrides: message[4] & FLAG_OVERRIDE != 0,
+                    target,
+                    target_mac: link_address(&options, OPTION_TARGET_LINK_ADDRESS),
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// Builds the ICMPv6 message, with its checksum for `src` and `dst`.
+    pub fn to_bytes(&self, src: Ipv6Addr, dst: Ipv6Addr) -> Vec<u8> {
+        let mut data = Vec::new();
+        let (kind, rest) = match self {
+            Message::RouterSolicitation { source_mac } => {
+                push_link_address(&mut data, OPTION_SOURCE_LINK_ADDRESS, *source_mac);
+                (TYPE_ROUTER_SOLICITATION, [0; 4])
+            }
+            Message::RouterAdvertisement {
+                hop_limit,
+                router_lifetime_secs,
+                source_mac,
+                prefixes,
+            } => {
+                data.extend_from_slice(&[0; 8]);
+                push_link_address(&mut data, OPTION_SOURCE_LINK_ADDRESS, *source_mac);
+                for prefix in prefixes {
+                    let mut flags = 0;
+                    if prefix.on_link {
+                        flags |= PREFIX_ON_LINK;
+                    }
+                    if prefix.autonomous {
+                        flags |= PREFIX_AUTONOMOUS;
+                    }
+                    data.extend_from_slice(&[OPTION_PREFIX_INFO, 4, prefix.prefix_len, flags]);
+                    data.extend_from_slice(&prefix.valid_secs.to_be_bytes());
+                    data.extend_from_slice(&prefix.preferred_secs.to_be_bytes());
+                    data.extend_from_slice(&[0; 4]);
+                    data.extend_from_slice(&prefix.prefix.octets());
+                }
+                let [lifetime_high, lifetime_low] = router_lifetime_secs.to_be_bytes();
+                (
+                    TYPE_ROUTER_ADVERTISEMENT,
+                    [*hop_limit, 0, lifetime_high, lifetime_low],
+                )
+            }
+            Message::NeighborSolicitation { target, source_mac } => {
+                data.extend_from_slice(&target.octets());
+                push_link_address(&mut data, OPTION_SOURCE_LINK_ADDRESS, *source_mac);
+                (TYPE_NEIGHBOR_SOLICITATION, [0; 4])
+            }
+            Message::NeighborAdvertisement {
+                router,
+                solicited,
+                overrides,
+                target,
+                target_mac,
+            } => {
+                let mut flags = 0;
+                if *router {
+                    flags |= FLAG_ROUTER;
+                }
+                if *solicited {
+                    flags |= FLAG_SOLICITED;
+                }
+                if *overrides {
+                    flags |= FLAG_OVERRIDE;
+                }
+                data.extend_from_slice(&target.octets());
+                push_link_address(&mut data, OPTION_TARGET_LINK_ADDRESS, *target_mac);
+                (TYPE_NEIGHBOR_ADVERTISEMENT, [flags, 0, 0, 0])
+            }
+        };
+        icmpv6::build(src, dst, kind, 0, rest, &data)
+    }
+}
+
+static NEIGHBORS: NeighborCache<Ipv6Addr> = NeighborCache::new(Resolver {
+    name: "ndp",
+    reachable_ms: REACHABLE_MS,
+    retry_ms: RETRANS_MS,
+    max_retries: MAX_RETRIES,
+    solicit: send_solicitation,
+});
+
+/// Addresses going through duplicate address detection, with their
+/// interface and when the probe was sent.
+static TENTATIVE: Mutex<BTreeMap<Ipv6Addr, (String, u64)>> = Mutex::new(BTreeMap::new());
+
+/// A neighbor cache entry, as shown by the `ndp` command.
+pub type NeighborInfo = neighbor::NeighborInfo<Ipv6Addr>;
+
+pub fn neighbors() -> Vec<NeighborInfo> {
+    NEIGHBORS.neighbors()
+}
+
+pub fn lookup(address: Ipv6Addr) -> Option<MacAddress> {
+    NEIGHBORS.lookup(address)
+}
+
+/// Forgets every neighbor, dropping the packets still waiting for one.
+pub fn flush() {
+    NEIGHBORS.flush();
+}
+
+fn send_message(interface: &Interface, src: Ipv6Addr, dst: Ipv6Addr, message: &Message) {
+    let mut header = Ipv6Header::new(src, dst, Protocol::Icmpv6);
+    header.hop_limit = HOP_LIMIT;
+    if let Err(err) = ipv6::send_via(interface, dst, header, &message.to_bytes(src, dst)) {
+        println!("ndp: {}: failed to send: {:?}", interface.name, err);
+    }
+}
+
+fn send_solicitation(interface: &Interface, target: Ipv6Addr) {
+    let Some(src) = ipv6::source_address(interface, target) else {
+        return;
+    };
+    let message = Message::NeighborSolicitation {
+        target,
+        source_mac: Some(interface.device.mac()),
+    };
+    send_message(interface, src, ipv6::solicited_node(target), &message);
+}
+
+/// Sends `payload` to the neighbor `next_hop`, or queues it until its
+/// address is resolved. Multicast and loopback packets go straight out.
+pub fn send_to(
+    interface: &Interface,
+    next_hop: Ipv6Addr,
+    ether_type: EtherType,
+    payload: &[u8],
+) -> Result<(), NetError> {
+    let dest = if next_hop.is_multicast() {
+        ipv6::multicast_mac(next_hop)
+    } else if next_hop.is_loopback() {
+        interface.device.mac()
+    } else {
+        return NEIGHBORS.send_to(interface, next_hop, ether_type, payload);
+    };
+
+    let header = Header::new(dest, interface.device.mac(), ether_type);
+    interface.device.send(&header.build(payload))
+}
+
+/// Gives `interface` the tentative `address` and starts duplicate address
+/// detection for it. The address can be used once [`RETRANS_MS`] passed
+/// without anybody else claiming it.
+pub fn add_address(
+    interface: &Interface,
+    address: Ipv6Addr,
+    prefix_len: u8,
+    valid_until_ms: Option<u64>,
+) {
+    interface.add_ipv6(Ipv6Address {
+        address,
+        prefix_len,
+        state: AddressState::Tentative,
+        valid_until_ms,
+    });
+    TENTATIVE
+        .lock()
+        .insert(address, (interface.name.clone(), time::uptime_ms()));
+    // Probes come from the unspecified address and don't say who sent them
+    let message = Message::NeighborSolicitation {
+        target: address,
+        source_mac: None,
+    };
+    send_message(
+        interface,
+        Ipv6Addr::UNSPECIFIED,
+        ipv6::solicited_node(address),
+        &message,
+    );
+}
+
+/// Gives up `address`, which another node on the link already uses.
+fn duplicate(interface: &Interface, address: Ipv6Addr) {
+    interface.remove_ipv6(address);
+    TENTATIVE.lock().remove(&address);
+    println!(
+        "ndp: {}: {} is already in use, not using it",
+        interface.name, address
+    );
+}
+
+/// Makes the addresses nobody else claimed usable.
+fn finish_detection() {
+    let now = time::uptime_ms();
+    let mut done = Vec::new();
+    TENTATIVE.lock().retain(|address, (name, started_ms)| {
+        if now - *started_ms < RETRANS_MS {
+            return true;
+        }
+        done.push((name.clone(), *address));
+        false
+    });
+
+    for (name, address) in done {
+        let Some(interface) = device::interface(&name) else {
+            continue;
+        };
+        let found = interface
+            .ipv6()
+            .into_iter()
+            .find(|other| other.address == address);
+        if let Some(mut found) = found {
+            found.state = AddressState::Preferred;
+            interface.add_ipv6(found);
+        }
+    }
+}
+
+/// Gives `interface` its link-local address and the routes to the link.
+pub fn start(interface: &Interface) {
+    for destination in [
+        Ipv6Addr::new(0xFE80, 0, 0, 0, 0, 0, 0, 0),
+        Ipv6Addr::new(0xFF00, 0, 0, 0, 0, 0, 0, 0),
+    ] {
+        ipv6::add_route(Route {
+            destination,
+            prefix_len: if destination.is_multicast() { 8 } else { 64 },
+            gateway: None,
+            interface: interface.name.clone(),
+            expires_ms: None,
+        });
+    }
+    add_address(
+        interface,
+        ipv6::link_local(interface.device.mac()),
+        64,
+        None,
+    );
+}
+
+/// When a lifetime of `secs` seconds from `now` runs out.
+fn lifetime_end(now: u64, secs: u32) -> Option<u64> {
+    (secs != INFINITE_LIFETIME).then_some(now + secs as u64 * 1000)
+}
+
+/// The new end of the valid lifetime of an address that ends at `current`,
+/// after an advertisement said it should end at `advertised`.
+fn updated_valid_until(now: u64, current: Option<u64>, advertised: Option<u64>) -> Option<u64> {
+    let advertised_end = advertised?;
+    let remaining = current.map_or(u64::MAX, |end| end.saturating_sub(now));
+    let advertised_len = advertised_end - now;
+    if advertised_len > MIN_VALID_LIFETIME_MS || advertised_len > remaining {
+        advertised
+    } else if remaining <= MIN_VALID_LIFETIME_MS {
+        current
+    } else {
+        Some(now + MIN_VALID_LIFETIME_MS)
+    }
+}
+
+fn handle_router_advertisement(
+    interface: &Interface,
+    router: Ipv6Addr,
+    router_lifetime_secs: u16,
+    prefixes: &[PrefixInfo],
+) {
+    let now = time::uptime_ms();
+    let default_route = Route {
+        destination: Ipv6Addr::UNSPECIFIED,
+        prefix_len: 0,
+        gateway: Some(router),
+        interface: interface.name.clone(),
+        expires_ms: Some(now + router_lifetime_secs as u64 * 1000),
+    };
+    if router_lifetime_secs == 0 {
+        ipv6::remove_route(&default_route);
+    } else {
+        ipv6::add_route(default_route);
+    }
+
+    for prefix in prefixes {
+        if ipv6::is_link_local(prefix.prefix) || prefix.preferred_secs > prefix.valid_secs {
+            continue;
+        }
+        let valid_until_ms = lifetime_end(now, prefix.valid_secs);
+        if prefix.on_link {
+            ipv6::add_route(Route {
+                destination: prefix.prefix,
+                prefix_len: prefix.prefix_len,
+                gateway: None,
+                interface: interface.name.clone(),
+                expires_ms: valid_until_ms,
+            });
+        }
+
+        // Interface identifiers are 64 bits, so only /64 prefixes leave
+        // room for one
+        if !prefix.autonomous || prefix.prefix_len != 64 {
+            continue;
+        }
+        let address = ipv6::with_interface_id(prefix.prefix, interface.device.mac());
+        let existing = interface
+            .ipv6()
+            .into_iter()
+            .find(|other| other.address == address);
+        match existing {
+            Some(mut existing) => {
+                existing.valid_until_ms =
+                    updated_valid_until(now, existing.valid_until_ms, valid_until_ms);
+                interface.add_ipv6(existing);
+            }
+            None if prefix.valid_secs != 0 => {
+                add_address(interface, address, prefix.prefix_len, valid_until_ms)
+            }
+            None => {}
+        }
+    }
+}
+
+/// Handles a neighbor discovery message `interface` received.
+pub fn handle(interface: &Interface, header: &Ipv6Header, message: &[u8]) {
+    if header.hop_limit != HOP_LIMIT {
+        return;
+    }
+    let Some(message) = Message::parse(message) else {
+        return;
+    };
+    let ours = |target: Ipv6Addr| {
+        interface
+            .ipv6()
+            .into_iter()
+            .find(|address| address.address == target)
+    };
+
+    match message {
+        Message::NeighborSolicitation { target, source_mac } => {
+            let Some(address) = ours(target) else {
+                return;
+            };
+            if header.src.is_unspecified() {
+                // Somebody else's duplicate address detection
+                if address.state == AddressState::Tentative {
+                    duplicate(interface, target);
+                } else {
+                    advertise(interface, target, ALL_NODES, false);
+                }
+                return;
+            }
+            if address.state == AddressState::Tentative {
+                return;
+            }
+            if let Some(mac) = source_mac {
+                NEIGHBORS.update(interface, header.src, mac);
+            }
+            advertise(interface, target, header.src, true);
+        }
+        Message::NeighborAdvertisement {
+            target, target_mac, ..
+        } => {
+            if let Some(address) = ours(target) {
+                if address.state == AddressState::Tentative {
+                    duplicate(interface, target);
+                } else {
+                    println!(
+                        "ndp: {}: {} is also used by {:02x?}",
+                        interface.name, target, target_mac
+                    );
+                }
+                return;
+            }
+            // Only neighbors being resolved or already known are updated
+            let known = NEIGHBORS.contains(target);
+            if let (true, Some(mac)) = (known, target_mac) {
+                NEIGHBORS.update(interface, target, mac);
+            }
+        }
+        Message::RouterAdvertisement {
+            router_lifetime_secs,
+            source_mac,
+            prefixes,
+            ..
+        } => {
+            // Routers advertise from their link-local address
+            if !ipv6::is_link_local(header.src) {
+                return;
+            }
+            if let Some(mac) = source_mac {
+                NEIGHBORS.update(interface, header.src, mac);
+            }
+            handle_router_advertisement(interface, header.src, router_lifetime_secs, &prefixes);
+        }
+        // Only routers answer these
+        Message::RouterSolicitation { .. } => {}
+    }
+}
+
+/// Tells `dst` that `target` is ours.
+fn advertise(interface: &Interface, target: Ipv6Addr, dst: Ipv6Addr, solicited: bool) {
+    let message = Message::NeighborAdvertisement {
+        router: false,
+        solicited,
+        overrides: true,
+        target,
+        target_mac: Some(interface.device.mac()),
+    };
+    send_message(interface, target, dst, &message);
+}
+
+/// Router solicitations sent on an interface, and when the last one was.
+#[derive(Default)]
+struct Solicitations {
+    sent: u32,
+    last_ms: u64,
+}
+
+/// Asks for routers on `interface` until one answers or
+/// [`MAX_ROUTER_SOLICITATIONS`] went unanswered.
+fn solicit_router(interface: &Interface, solicitations: &mut Solicitations) {
+    let now = time::uptime_ms();
+    let has_router = ipv6::routes()
+        .iter()
+        .any(|route| route.interface == interface.name && route.gateway.is_some());
+    if has_router
+        || solicitations.sent == MAX_ROUTER_SOLICITATIONS
+        || (solicitations.sent > 0 && now - solicitations.last_ms < ROUTER_SOLICITATION_INTERVAL_MS)
+    {
+        return;
+    }
+    // Wait for the link-local address to be usable
+    let Some(src) = ipv6::source_address(interface, ALL_ROUTERS) else {
+        return;
+    };
+    let message = Message::RouterSolicitation {
+        source_mac: Some(interface.device.mac()),
+    };
+    send_message(interface, src, ALL_ROUTERS, &message);
+    solicitations.sent += 1;
+    solicitations.last_ms = now;
+}
+
+/// Sets up new interfaces, looks for routers and keeps the neighbor cache,
+/// addresses and routes up to date. Runs as a task.
+pub async fn run() {
+    let mut solicitations: BTreeMap<String, Solicitations> = BTreeMap::new();
+    loop {
+        NEIGHBORS.expire();
+        finish_detection();
+        ipv6::expire();
+        for interface in device::interfaces() {
+            // The loopback interface has ::1 and nobody to discover
+            if interface.name == loopback::NAME {
+                continue;
+            }
+            match solicitations.get_mut(&interface.name) {
+                Some(solicitations) => solicit_router(&interface, solicitations),
+                None => {
+                    start(&interface);
+                    solicitations.insert(interface.name.clone(), Solicitations::default());
+                }
+            }
+        }
+        time::sleep(RETRANS_MS).await;
+    }
+}
@@ -0,0 +1,228 @@
+//! Neighbor caches, shared by ARP and neighbor discovery.
+//!
+//! A cache maps the addresses of neighbors on a link to their MAC. Packets
+//! sent to a neighbor that isn't resolved yet wait in its entry until it
+//! is, or are dropped once the protocol gave up asking. How to ask is up to
+//! [`super::arp`] and [`super::ndp`].
+
+use super::{
+    device::{self, Interface, MacAddress, NetError},
+    ethernet::{EtherType, Header},
+};
+use crate::{println, time};
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    string::String,
+    vec::Vec,
+};
+use spin::Mutex;
+
+/// Packets held per unresolved neighbor. The oldest go first.
+const MAX_PENDING: usize = 16;
+
+enum State {
+    /// Waiting for an answer, with the packets to send once it arrives.
+    Incomplete {
+        retries: u32,
+        pending: VecDeque<(EtherType, Vec<u8>)>,
+    },
+    Reachable(MacAddress),
+}
+
+struct Neighbor {
+    interface: String,
+    state: State,
+    /// When the entry was created, resolved or last retried.
+    updated_ms: u64,
+}
+
+impl Neighbor {
+    fn incomplete(interface: &Interface, pending: VecDeque<(EtherType, Vec<u8>)>) -> Neighbor {
+        Neighbor {
+            interface: interface.name.clone(),
+            state: State::Incomplete {
+                retries: 0,
+                pending,
+            },
+            updated_ms: time::uptime_ms(),
+        }
+    }
+}
+
+/// A neighbor cache entry, as shown by the `arp` and `ndp` commands.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NeighborInfo<A> {
+    pub address: A,
+    /// `None` while the address is being resolved.
+    pub mac: Option<MacAddress>,
+    pub interface: String,
+    pub age_ms: u64,
+}
+
+/// How a protocol resolves addresses, and how long it trusts the answers.
+pub struct Resolver<A> {
+    /// Prefixes messages about the cache.
+    pub name: &'static str,
+    /// How long a resolved neighbor is trusted without hearing from it.
+    pub reachable_ms: u64,
+    /// Time between requests for a neighbor that doesn't answer.
+    pub retry_ms: u64,
+    pub max_retries: u32,
+    /// Asks for the MAC of the address on the interface.
+    pub solicit: fn(&Interface, A),
+}
+
+pub struct NeighborCache<A> {
+    resolver: Resolver<A>,
+    neighbors: Mutex<BTreeMap<A, Neighbor>>,
+}
+
+impl<A> NeighborCache<A> {
+    pub const fn new(resolver: Resolver<A>) -> NeighborCache<A> {
+        NeighborCache {
+            resolver,
+            neighbors: Mutex::new(BTreeMap::new()),
+        }
+    }
+}
+
+impl<A: Ord + Copy> NeighborCache<A> {
+    pub fn neighbors(&self) -> Vec<NeighborInfo<A>> {
+        let now = time::uptime_ms();
+        self.neighbors
+            .lock()
+            .iter()
+            .map(|(address, neighbor)| NeighborInfo {
+                address: *address,
+                mac: match neighbor.state {
+                    State::Reachable(mac) => Some(mac),
+                    State::Incomplete { .. } => None,
+                },
+                interface: neighbor.interface.clone(),
+                age_ms: now - neighbor.updated_ms,
+            })
+            .collect()
+    }
+
+    pub fn lookup(&self, address: A) -> Option<MacAddress> {
+        match self.neighbors.lock().get(&address)?.state {
+            State::Reachable(mac) => Some(mac),
+            State::Incomplete { .. } => None,
+        }
+    }
+
+    /// Whether `address` is resolved or being resolved.
+    pub fn contains(&self, address: A) -> bool {
+        self.neighbors.lock().contains_key(&address)
+    }
+
+    /// Forgets every neighbor, dropping the packets still waiting for one.
+    pub fn flush(&self) {
+        self.neighbors.lock().clear();
+    }
+
+    /// Starts resolving `address` unless it is resolved or being resolved.
+    pub fn resolve(&self, interface: &Interface, address: A) {
+        let mut neighbors = self.neighbors.lock();
+        if neighbors.contains_key(&address) {
+            return;
+        }
+        neighbors.insert(address, Neighbor::incomplete(interface, VecDeque::new()));
+        drop(neighbors);
+        (self.resolver.solicit)(interface, address);
+    }
+
+    /// Sends `payload` to the neighbor `next_hop`, or queues it until its
+    /// address is resolved.
+    pub fn send_to(
+        &self,
+        interface: &Interface,
+        next_hop: A,
+        ether_type: EtherType,
+        payload: &[u8],
+    ) -> Result<(), NetError> {
+        let mut neighbors = self.neighbors.lock();
+        let dest = match neighbors
+            .get_mut(&next_hop)
+            .map(|neighbor| &mut neighbor.state)
+        {
+            Some(State::Reachable(mac)) => *mac,
+            Some(State::Incomplete { pending, .. }) => {
+                if pending.len() == MAX_PENDING {
+                    pending.pop_front();
+                }
+                pending.push_back((ether_type, payload.to_vec()));
+                return Ok(());
+            }
+            None => {
+                let mut pending = VecDeque::new();
+                pending.push_back((ether_type, payload.to_vec()));
+                neighbors.insert(next_hop, Neighbor::incomplete(interface, pending));
+                drop(neighbors);
+                (self.resolver.solicit)(interface, next_hop);
+                return Ok(());
+            }
+        };
+        drop(neighbors);
+
+        let header = Header::new(dest, interface.device.mac(), ether_type);
+        interface.device.send(&header.build(payload))
+    }
+
+    /// Records `mac` for `address` and sends whatever waited for it.
+    pub fn update(&self, interface: &Interface, address: A, mac: MacAddress) {
+        let neighbor = Neighbor {
+            interface: interface.name.clone(),
+            state: State::Reachable(mac),
+            updated_ms: time::uptime_ms(),
+        };
+        let previous = self.neighbors.lock().insert(address, neighbor);
+
+        if let Some(Neighbor {
+            state: State::Incomplete { pending, .. },
+            ..
+        }) = previous
+        {
+            for (ether_type, payload) in pending {
+                let header = Header::new(mac, interface.device.mac(), ether_type);
+                if let Err(err) = interface.device.send(&header.build(&payload)) {
+                    println!(
+                        "{}: {}: failed to send queued packet: {:?}",
+                        self.resolver.name, interface.name, err
+                    );
+                }
+            }
+        }
+    }
+
+    /// Expires stale entries and retries unanswered requests.
+    pub fn expire(&self) {
+        let resolver = &self.resolver;
+        let now = time::uptime_ms();
+        let mut retry = Vec::new();
+        self.neighbors.lock().retain(|address, neighbor| {
+            let age = now - neighbor.updated_ms;
+            match &mut neighbor.state {
+                State::Reachable(_) => age < resolver.reachable_ms,
+                State::Incomplete { retries, .. } => {
+                    if age < resolver.retry_ms {
+                        true
+                    } else if *retries + 1 < resolver.max_retries {
+                        *retries += 1;
+                        neighbor.updated_ms = now;
+                        retry.push((neighbor.interface.clone(), *address));
+                        true
+                    } else {
+                        false
+                    }
+                }
+            }
+        });
+
+        for (name, address) in retry {
+            if let Some(interface) = device::interface(&name) {
+                (resolver.solicit)(&interface, address);
+            }
+        }
+    }
+}
@@ -0,0 +1,128 @@
+//! Routing tables, shared by IPv4 and IPv6.
+//!
+//! A table holds the routes for one address family and finds the longest
+//! one matching a destination. What the next hop is, and which source
+//! address to use, is up to [`super::ip`] and [`super::ipv6`].
+
+use super::{ip::Ipv4Addr, ipv6::Ipv6Addr};
+use alloc::{string::String, vec::Vec};
+use spin::Mutex;
+
+/// An address routes can be looked up for.
+pub trait Address: Copy + Eq {
+    /// Length of the address in bits, and the longest prefix.
+    const BITS: u32;
+
+    /// The address as a number, in the low bits if it is shorter.
+    fn to_bits(self) -> u128;
+}
+
+impl Address for Ipv4Addr {
+    const BITS: u32 = 32;
+
+    fn to_bits(self) -> u128 {
+        u32::from(self) as u128
+    }
+}
+
+impl Address for Ipv6Addr {
+    const BITS: u32 = 128;
+
+    fn to_bits(self) -> u128 {
+        u128::from(self)
+    }
+}
+
+/// Where packets for a range of addresses are sent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Route<A> {
+    pub destination: A,
+    pub prefix_len: u8,
+    /// The router to send through, or `None` if the destination is on the
+    /// interface's link.
+    pub gateway: Option<A>,
+    pub interface: String,
+    /// When the router that advertised the route stops vouching for it, or
+    /// `None` if it doesn't expire.
+    pub expires_ms: Option<u64>,
+}
+
+impl<A: Address> Route<A> {
+    /// A `prefix_len` over the address length counts as the address
+    /// length, matching `destination` only.
+    pub fn matches(&self, address: A) -> bool {
+        let mask = u128::MAX
+            .checked_shl(A::BITS.saturating_sub(self.prefix_len as u32))
+            .unwrap_or(0);
+        address.to_bits() & mask == self.destination.to_bits() & mask
+    }
+
+    /// Whether both go to the same place the same way, whatever their
+    /// lifetimes.
+    fn same_as(&self, other: &Route<A>) -> bool {
+        self.destination == other.destination
+            && self.prefix_len == other.prefix_len
+            && self.gateway == other.gateway
+            && self.interface == other.interface
+    }
+}
+
+pub struct RouteTable<A> {
+    routes: Mutex<Vec<Route<A>>>,
+}
+
+impl<A> RouteTable<A> {
+    pub const fn new() -> RouteTable<A> {
+        RouteTable {
+            routes: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+impl<A> Default for RouteTable<A> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<A: Address> RouteTable<A> {
+    /// Adds `route`, replacing the same route added before, since routers
+    /// advertise theirs again and again.
+    pub fn add(&self, route: Route<A>) {
+        let mut routes = self.routes.lock();
+        routes.retain(|other| !other.same_as(&route));
+        routes.push(route);
+    }
+
+    pub fn remove(&self, route: &Route<A>) {
+        self.routes.lock().retain(|other| !other.same_as(route));
+    }
+
+    /// Removes every route through `interface`.
+    pub fn remove_interface(&self, interface: &str) {
+        self.routes
+            .lock()
+            .retain(|route| route.interface != interface);
+    }
+
+    pub fn routes(&self) -> Vec<Route<A>> {
+        self.routes.lock().clone()
+    }
+
+    /// The longest route matching `dst`.
+    pub fn lookup(&self, dst: A) -> Option<Route<A>> {
+        self.routes
+            .lock()
+            .iter()
+            .filter(|route| route.matches(dst))
+            .max_by_key(|route| route.prefix_len)
+            .cloned()
+    }
+
+    /// Drops the routes that ran out at `now`.
+    pub fn expire(&self, now: u64) {
+        self.routes
+            .lock()
+            .retain(|route| route.expires_ms.is_none_or(|until| now < until));
+    }
+}
@@ -0,0 +1,1167 @@
+//! TCP, following RFC 9293 with RFC 6298 retransmission timers and RFC
+//! 1122 delayed acknowledgements.
+//!
+//! Connections live in a table keyed by their local and remote addresses,
+//! IPv4 or IPv6.
+//! The code handling a segment only queues the segments to send back, which
+//! go out once the table is unlocked. Segments that arrive out of order are
+//! dropped and the peer retransmits them. There is no congestion control:
+//! as much is sent as the peer's window allows.
+
+use super::{
+    device::Interface,
+    ip::{self, IpAddr, IpError, Ipv4Addr, Ipv4Header, Protocol},
+    ipv6::Ipv6Header,
+    udp::EPHEMERAL_PORTS,
+};
+use crate::time;
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    sync::Arc,
+    vec::Vec,
+};
+use core::{
+    future::poll_fn,
+    net::SocketAddr,
+    sync::atomic::{AtomicU32, Ordering},
+    task::{Poll, Waker},
+};
+use spin::Mutex;
+
+pub const FIN: u8 = 0x01;
+pub const SYN: u8 = 0x02;
+pub const RST: u8 = 0x04;
+pub const PSH: u8 = 0x08;
+pub const ACK: u8 = 0x10;
+
+/// Without options.
+pub const HEADER_SIZE: usize = 20;
+const OPTION_END: u8 = 0;
+const OPTION_NOP: u8 = 1;
+const OPTION_MSS: u8 = 2;
+/// What a peer that doesn't say otherwise accepts, from RFC 9293.
+pub const DEFAULT_MSS: usize = 536;
+
+const SEND_BUFFER_SIZE: usize = 16384;
+const RECV_BUFFER_SIZE: usize = 16384;
+/// Connections waiting to be accepted, per listener.
+const MAX_BACKLOG: usize = 16;
+
+/// RFC 6298 says a second, but that is far too slow on a LAN.
+const MIN_RTO_MS: u64 = 200;
+const MAX_RTO_MS: u64 = 60_000;
+const INITIAL_RTO_MS: u64 = 1000;
+/// Retransmissions before the connection is given up.
+const MAX_RETRIES: u32 = 8;
+const DELAYED_ACK_MS: u64 = 200;
+/// Twice the maximum segment lifetime.
+const TIME_WAIT_MS: u64 = 60_000;
+/// How often [`run`] checks the timers.
+const TIMER_MS: u64 = 50;
+
+/// `a` comes before `b` in sequence space.
+fn seq_lt(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+fn seq_le(a: u32, b: u32) -> bool {
+    !seq_lt(b, a)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TcpError {
+    /// Another listener is bound to the port.
+    AddrInUse,
+    NoPortsLeft,
+    /// The peer answered the SYN with a reset.
+    Refused,
+    /// The peer reset the connection.
+    Reset,
+    /// The peer stopped acknowledging what was sent.
+    TimedOut,
+    /// Writing after [`TcpStream::shutdown`] or once the connection closed.
+    Closed,
+    Ip(IpError),
+}
+
+impl From<IpError> for TcpError {
+    fn from(err: IpError) -> TcpError {
+        TcpError::Ip(err)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Closed,
+    Listen,
+    SynSent,
+    SynReceived,
+    Established,
+    FinWait1,
+    FinWait2,
+    CloseWait,
+    Closing,
+    LastAck,
+    TimeWait,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TcpHeader {
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub seq: u32,
+    pub ack: u32,
+    pub flags: u8,
+    pub window: u16,
+    /// The maximum segment size option, only sent with SYNs.
+    pub mss: Option<u16>,
+    /// Length of the header with its options.
+    pub header_len: usize,
+}
+
+impl TcpHeader {
+    pub fn new(src_port: u16, dst_port: u16, seq: u32, ack: u32, flags: u8) -> TcpHeader {
+        TcpHeader {
+            src_port,
+            dst_port,
+            seq,
+            ack,
+            flags,
+            window: 0,
+            mss: None,
+            header_len: HEADER_SIZE,
+        }
+    }
+
+    /// Parses the header at the start of `segment`. The checksum is
+    /// checked by [`handle`], which knows the addresses.
+    pub fn parse(segment: &[u8]) -> Option<TcpHeader> {
+        if segment.len() < HEADER_SIZE {
+            return None;
+        }
+        let header_len = (segment[12] >> 4) as usize * 4;
+        if header_len < HEADER_SIZE || segment.len() < header_len {
+            return None;
+        }
+
+        let mut mss = None;
+        let mut options = &segment[HEADER_SIZE..header_len];
+        while let Some(&kind) = options.first() {
+            match kind {
+                OPTION_END => break,
+                OPTION_NOP => options = &options[1..],
+                _ => {
+                    let len = *options.get(1)? as usize;
+                    if len < 2 || options.len() < len {
+                        return None;
+                    }
+                    if kind == OPTION_MSS && len == 4 {
+                        mss = Some(u16::from_be_bytes([options[2], options[3]]));
+                    }
+                    options = &options[len..];
+                }
+            }
+        }
+
+        Some(TcpHeader {
+            src_port: u16::from_be_bytes([segment[0], segment[1]]),
+            dst_port: u16::from_be_bytes([segment[2], segment[3]]),
+            seq: u32::from_be_bytes(segment[4..8].try_into().unwrap()),
+            ack: u32::from_be_bytes(segment[8..12].try_into().unwrap()),
+            flags: segment[13],
+            window: u16::from_be_bytes([segment[14], segment[15]]),
+            mss,
+            header_len,
+        })
+    }
+
+    /// Builds a segment from `src` to `dst` carrying `data`, with the
+    /// checksum filled in.
+    pub fn build(&self, src: IpAddr, dst: IpAddr, data: &[u8]) -> Vec<u8> {
+        let header_len = HEADER_SIZE + if self.mss.is_some() { 4 } else { 0 };
+        let mut segment = Vec::with_capacity(header_len + data.len());
+        segment.extend_from_slice(&self.src_port.to_be_bytes());
+        segment.extend_from_slice(&self.dst_port.to_be_bytes());
+        segment.extend_from_slice(&self.seq.to_be_bytes());
+        segment.extend_from_slice(&self.ack.to_be_bytes());
+        segment.push(((header_len / 4) as u8) << 4);
+        segment.push(self.flags);
+        segment.extend_from_slice(&self.window.to_be_bytes());
+        segment.extend_from_slice(&[0; 4]);
+        if let Some(mss) = self.mss {
+            segment.extend_from_slice(&[OPTION_MSS, 4]);
+            segment.extend_from_slice(&mss.to_be_bytes());
+        }
+        segment.extend_from_slice(data);
+
+        let checksum = ip::pseudo_header_any(src, dst, Protocol::Tcp, segment.len())
+            .add(&segment)
+            .finish();
+        segment[16..18].copy_from_slice(&checksum.to_be_bytes());
+        segment
+    }
+}
+
+/// Segments to send once the connection table is unlocked, with the
+/// address each goes to.
+type Outgoing = Vec<(IpAddr, Vec<u8>)>;
+
+/// Local and remote address.
+type Key = (SocketAddr, SocketAddr);
+
+struct Connection {
+    state: State,
+    local: SocketAddr,
+    remote: SocketAddr,
+    /// The port of the listener that created it, until it is accepted.
+    listener: Option<u16>,
+    /// Whether a [`TcpStream`] refers to it. Closed connections are
+    /// dropped once nothing does.
+    attached: bool,
+    error: Option<TcpError>,
+
+    /// Initial send sequence number, taken by our SYN.
+    iss: u32,
+    /// Oldest unacknowledged sequence number.
+    snd_una: u32,
+    /// Next sequence number to send.
+    snd_nxt: u32,
+    /// The window the peer last advertised.
+    snd_wnd: u32,
+    /// Largest segment the peer accepts, and we send.
+    mss: usize,
+    /// What the MTU of our interface allows the peer to send.
+    our_mss: usize,
+    /// Data written but not acknowledged yet, starting at [`Self::send_base`].
+    send_buffer: VecDeque<u8>,
+    /// The write side was shut down, so a FIN follows the data.
+    fin_queued: bool,
+    /// Sequence number of our FIN, once sent.
+    fin_seq: Option<u32>,
+
+    /// Next sequence number expected.
+    rcv_nxt: u32,
+    recv_buffer: VecDeque<u8>,
+    fin_received: bool,
+    /// The window in the last segment sent.
+    advertised_window: u16,
+
+    /// Retransmission timeout and the RFC 6298 estimates it comes from.
+    rto_ms: u64,
+    srtt_ms: Option<u64>,
+    rttvar_ms: u64,
+    /// The segment being timed: its sequence number and when it was sent.
+    timing: Option<(u32, u64)>,
+    retransmit_at: Option<u64>,
+    retries: u32,
+    /// When to send an acknowledgement if no data takes it along first.
+    ack_at: Option<u64>,
+    time_wait_until: Option<u64>,
+
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
+}
+
+static NEXT_ISS: AtomicU32 = AtomicU32::new(0);
+
+/// A new initial sequence number. RFC 9293 asks for a clock that ticks
+/// every 4 microseconds, which is what this adds up to, plus a counter so
+/// connections opened in the same millisecond differ.
+fn initial_sequence() -> u32 {
+    (time::uptime_ms() as u32)
+        .wrapping_mul(250)
+        .wrapping_add(NEXT_ISS.fetch_add(64_000, Ordering::Relaxed))
+}
+
+/// The MSS to advertise for packets from `remote`.
+fn local_mss(remote: IpAddr) -> usize {
+    ip::max_payload(remote).map_or(DEFAULT_MSS, |max| max - HEADER_SIZE)
+}
+
+impl Connection {
+    fn new(local: SocketAddr, remote: SocketAddr, state: State) -> Connection {
+        let iss = initial_sequence();
+        Connection {
+            state,
+            local,
+            remote,
+            listener: None,
+            attached: false,
+            error: None,
+            iss,
+            snd_una: iss,
+            snd_nxt: iss.wrapping_add(1),
+            snd_wnd: 0,
+            mss: DEFAULT_MSS,
+            our_mss: local_mss(remote.ip()),
+            send_buffer: VecDeque::new(),
+            fin_queued: false,
+            fin_seq: None,
+            rcv_nxt: 0,
+            recv_buffer: VecDeque::new(),
+            fin_received: false,
+            advertised_window: 0,
+            rto_ms: INITIAL_RTO_MS,
+            srtt_ms: None,
+            rttvar_ms: 0,
+            timing: None,
+            retransmit_at: None,
+            retries: 0,
+            ack_at: None,
+            time_wait_until: None,
+            read_waker: None,
+            write_waker: None,
+        }
+    }
+
+    /// Sequence number of the first byte in the send buffer. Our SYN comes
+    /// before it.
+    fn send_base(&self) -> u32 {
+        if self.snd_una == self.iss {
+            self.iss.wrapping_add(1)
+        } else {
+            self.snd_una
+        }
+    }
+
+    fn window(&self) -> u16 {
+        (RECV_BUFFER_SIZE - self.recv_buffer.len()).min(u16::MAX as usize) as u16
+    }
+
+    fn wake(&mut self) {
+        if let Some(waker) = self.read_waker.take() {
+            waker.wake();
+        }
+        if let Some(waker) = self.write_waker.take() {
+            waker.wake();
+        }
+    }
+
+    /// Queues a segment with our current acknowledgement and window.
+    fn send(&mut self, out: &mut Outgoing, flags: u8, seq: u32, data: &[u8]) {
+        let mut header = TcpHeader::new(self.local.port(), self.remote.port(), seq, 0, flags);
+        if flags & ACK != 0 {
+            header.ack = self.rcv_nxt;
+            self.ack_at = None;
+        }
+        header.window = self.window();
+        if flags & SYN != 0 {
+            header.mss = Some(self.our_mss.min(u16::MAX as usize) as u16);
+        }
+        self.advertised_window = header.window;
+        out.push((
+            self.remote.ip(),
+            header.build(self.local.ip(), self.remote.ip(), data),
+        ));
+    }
+
+    fn send_syn(&mut self, out: &mut Outgoing) {
+        let flags = if self.state == State::SynReceived {
+            SYN | ACK
+        } else {
+            SYN
+        };
+        self.send(out, flags, self.iss, &[]);
+    }
+
+    /// Gives up on the connection, telling the peer unless `error` came
+    /// from it.
+    fn abort(&mut self, out: &mut Outgoing, error: Option<TcpError>) {
+        if !matches!(self.state, State::SynSent | State::Closed | State::TimeWait) {
+            self.send(out, RST, self.snd_nxt, &[]);
+        }
+        self.close(error);
+    }
+
+    fn close(&mut self, error: Option<TcpError>) {
+        self.state = State::Closed;
+        self.error = self.error.or(error);
+        self.retransmit_at = None;
+        self.ack_at = None;
+        self.wake();
+    }
+
+    /// Sends as much of the send buffer as the peer's window allows, and
+    /// the FIN after it.
+    fn output(&mut self, out: &mut Outgoing, now: u64) {
+        if !matches!(self.state, State::Established | State::CloseWait) {
+            return;
+        }
+        while self.fin_seq.is_none() {
+            let sent = self.snd_nxt.wrapping_sub(self.send_base()) as usize;
+            let unsent = self.send_buffer.len() - sent;
+            let in_flight = self.snd_nxt.wrapping_sub(self.snd_una);
+            let window = self.snd_wnd.saturating_sub(in_flight) as usize;
+            let len = unsent.min(window).min(self.mss);
+            let fin = self.fin_queued && len == unsent;
+            if len == 0 && !fin {
+                // Probe a closed window once the timer runs out
+                if unsent > 0 && in_flight == 0 && self.retransmit_at.is_none() {
+                    self.retransmit_at = Some(now + self.rto_ms);
+                }
+                break;
+            }
+
+            let data: Vec<u8> = self.send_buffer.range(sent..sent + len).copied().collect();
+            let mut flags = ACK;
+            if len > 0 && len == unsent {
+                flags |= PSH;
+            }
+            if fin {
+                flags |= FIN;
+            }
+            let seq = self.snd_nxt;
+            self.send(out, flags, seq, &data);
+            if len > 0 && self.timing.is_none() {
+                self.timing = Some((seq, now));
+            }
+            self.snd_nxt = seq.wrapping_add(len as u32 + fin as u32);
+            if fin {
+                self.fin_seq = Some(self.snd_nxt.wrapping_sub(1));
+                self.state = match self.state {
+                    State::CloseWait => State::LastAck,
+                    _ => State::FinWait1,
+                };
+            }
+            if self.retransmit_at.is_none() {
+                self.retransmit_at = Some(now + self.rto_ms);
+            }
+        }
+    }
+
+    /// Resends the oldest unacknowledged segment, or probes a closed
+    /// window, after the retransmission timer ran out.
+    fn retransmit(&mut self, out: &mut Outgoing, now: u64) {
+        self.retries += 1;
+        if self.retries > MAX_RETRIES {
+            self.abort(out, Some(TcpError::TimedOut));
+            return;
+        }
+        // Back off, and don't time segments that were sent twice (Karn)
+        self.rto_ms = (self.rto_ms * 2).min(MAX_RTO_MS);
+        self.timing = None;
+
+        match self.state {
+            State::SynSent | State::SynReceived => self.send_syn(out),
+            _ => {
+                let base = self.send_base();
+                let fin_sent = self.fin_seq.is_some() as usize;
+                let in_flight = self.snd_nxt.wrapping_sub(base) as usize - fin_sent;
+                if in_flight > 0 || fin_sent == 1 {
+                    let len = in_flight.min(self.mss);
+                    let fin = fin_sent == 1 && len == in_flight;
+                    let data: Vec<u8> = self.send_buffer.range(..len).copied().collect();
+                    let flags = if fin { ACK | FIN } else { ACK };
+                    self.send(out, flags, base, &data);
+                } else if let Some(&byte) = self.send_buffer.front() {
+                    // One byte past the window, to learn when it opens
+                    let seq = self.snd_nxt;
+                    self.send(out, ACK, seq, &[byte]);
+                    self.snd_nxt = seq.wrapping_add(1);
+                } else {
+                    self.retransmit_at = None;
+                    return;
+                }
+            }
+        }
+        self.retransmit_at = Some(now + self.rto_ms);
+    }
+
+    /// Updates the RTO with a round trip time measurement, as in RFC 6298.
+    fn sample_rtt(&mut self, rtt: u64) {
+        match self.srtt_ms {
+            None => {
+                self.srtt_ms = Some(rtt);
+                self.rttvar_ms = rtt / 2;
+            }
+            Some(srtt) => {
+                self.rttvar_ms = (3 * self.rttvar_ms + srtt.abs_diff(rtt)) / 4;
+                self.srtt_ms = Some((7 * srtt + rtt) / 8);
+            }
+        }
+        let srtt = self.srtt_ms.unwrap_or(rtt);
+        self.rto_ms = (srtt + (4 * self.rttvar_ms).max(1)).clamp(MIN_RTO_MS, MAX_RTO_MS);
+    }
+
+    /// Handles a segment for a connection in SYN-SENT.
+    fn handle_syn_sent(&mut self, out: &mut Outgoing, header: &TcpHeader, now: u64) {
+        let ack_ok = header.flags & ACK != 0 && header.ack == self.iss.wrapping_add(1);
+        if header.flags & ACK != 0 && !ack_ok {
+            if header.flags & RST == 0 {
+                let reset =
+                    TcpHeader::new(self.local.port(), self.remote.port(), header.ack, 0, RST);
+                out.push((
+                    self.remote.ip(),
+                    reset.build(self.local.ip(), self.remote.ip(), &[]),
+                ));
+            }
+            return;
+        }
+        if header.flags & RST != 0 {
+            if ack_ok {
+                self.close(Some(TcpError::Refused));
+            }
+            return;
+        }
+        if header.flags & SYN == 0 {
+            return;
+        }
+
+        self.rcv_nxt = header.seq.wrapping_add(1);
+        self.snd_wnd = header.window as u32;
+        self.mss = (header.mss.map_or(DEFAULT_MSS, usize::from)).min(self.our_mss);
+        if ack_ok {
+            self.snd_una = header.ack;
+            if let Some((_, sent)) = self.timing.take() {
+                self.sample_rtt(now - sent);
+            }
+            self.retries = 0;
+            self.retransmit_at = None;
+            self.state = State::Established;
+            self.send(out, ACK, self.snd_nxt, &[]);
+            self.wake();
+        } else {
+            // Both sides opened at once
+            self.state = State::SynReceived;
+            self.send_syn(out);
+        }
+    }
+
+    /// Handles a segment for a synchronized connection. Returns whether a
+    /// listener has a new connection to accept.
+    fn handle(&mut self, out: &mut Outgoing, header: &TcpHeader, data: &[u8], now: u64) -> bool {
+        if self.state == State::SynSent {
+            self.handle_syn_sent(out, header, now);
+            return false;
+        }
+        let rst = header.flags & RST != 0;
+
+        // A SYN here is either a retransmission or an attack, and an ACK
+        // sorts out both (RFC 5961)
+        if header.flags & SYN != 0 {
+            if self.state == State::SynReceived {
+                self.send_syn(out);
+            } else if !rst {
+                self.send(out, ACK, self.snd_nxt, &[]);
+            }
+            return false;
+        }
+
+        // Only take the segment that comes next, trimming what we already
+        // have from its start
+        let mut data = data;
+        let fin = header.flags & FIN != 0;
+        if seq_lt(header.seq, self.rcv_nxt) {
+            let skip = self.rcv_nxt.wrapping_sub(header.seq) as usize;
+            if skip >= data.len() + fin as usize {
+                if !rst {
+                    self.send(out, ACK, self.snd_nxt, &[]);
+                }
+                return false;
+            }
+            data = &data[skip..];
+        } else if header.seq != self.rcv_nxt {
+            if !rst {
+                self.send(out, ACK, self.snd_nxt, &[]);
+            }
+            return false;
+        }
+
+        if rst {
+            if self.state == State::SynReceived && self.listener.is_some() {
+                self.close(None);
+            } else {
+                self.close(Some(TcpError::Reset));
+            }
+            return false;
+        }
+        if header.flags & ACK == 0 {
+            return false;
+        }
+
+        let mut accepted = false;
+        if self.state == State::SynReceived {
+            if header.ack != self.iss.wrapping_add(1) {
+                let reset =
+                    TcpHeader::new(self.local.port(), self.remote.port(), header.ack, 0, RST);
+                out.push((
+                    self.remote.ip(),
+                    reset.build(self.local.ip(), self.remote.ip(), &[]),
+                ));
+                return false;
+            }
+            self.state = State::Established;
+            accepted = self.listener.is_some();
+        }
+
+        // Acknowledgement
+        if seq_lt(self.snd_una, header.ack) && seq_le(header.ack, self.snd_nxt) {
+            let mut acked = header.ack.wrapping_sub(self.snd_una) as usize;
+            if self.snd_una == self.iss {
+                acked -= 1;
+            }
+            if self.fin_seq.is_some_and(|fin| seq_lt(fin, header.ack)) {
+                acked -= 1;
+            }
+            self.send_buffer.drain(..acked.min(self.send_buffer.len()));
+            self.snd_una = header.ack;
+            if let Some((seq, sent)) = self.timing {
+                if seq_lt(seq, header.ack) {
+                    self.timing = None;
+                    self.sample_rtt(now - sent);
+                }
+            }
+            self.retries = 0;
+            self.retransmit_at = if self.snd_una == self.snd_nxt {
+                None
+            } else {
+                Some(now + self.rto_ms)
+            };
+            if let Some(waker) = self.write_waker.take() {
+                waker.wake();
+            }
+        } else if seq_lt(self.snd_nxt, header.ack) {
+            self.send(out, ACK, self.snd_nxt, &[]);
+            return accepted;
+        }
+        if seq_le(self.snd_una, header.ack) {
+            // A peer that answers window probes is still there, however
+            // long its window stays closed (RFC 9293 section 3.8.6.1)
+            if self.snd_wnd == 0 {
+                self.retries = 0;
+            }
+            self.snd_wnd = header.window as u32;
+        }
+
+        let fin_acked = self.fin_seq.is_some_and(|fin| seq_lt(fin, self.snd_una));
+        if fin_acked {
+            match self.state {
+                State::FinWait1 => self.state = State::FinWait2,
+                State::Closing => self.enter_time_wait(now),
+                State::LastAck => {
+                    self.close(None);
+                    return accepted;
+                }
+                _ => {}
+            }
+        }
+
+        // Data
+        let mut fin = fin;
+        if !data.is_empty()
+            && matches!(
+                self.state,
+                State::Established | State::FinWait1 | State::FinWait2
+            )
+        {
+            let len = data.len().min(RECV_BUFFER_SIZE - self.recv_buffer.len());
+            self.recv_buffer.extend(&data[..len]);
+            self.rcv_nxt = self.rcv_nxt.wrapping_add(len as u32);
+            // The FIN only counts if all the data before it fit
+            fin &= len == data.len();
+            // Acknowledge every second segment right away, as RFC 1122 asks
+            self.ack_at = Some(match self.ack_at {
+                Some(_) => now,
+                None => now + DELAYED_ACK_MS,
+            });
+            if let Some(waker) = self.read_waker.take() {
+                waker.wake();
+            }
+        }
+
+        if fin && !self.fin_received {
+            self.rcv_nxt = self.rcv_nxt.wrapping_add(1);
+            self.fin_received = true;
+            self.ack_at = Some(now);
+            match self.state {
+                State::Established => self.state = State::CloseWait,
+                State::FinWait1 => self.state = State::Closing,
+                State::FinWait2 => self.enter_time_wait(now),
+                _ => {}
+            }
+            if let Some(waker) = self.read_waker.take() {
+                waker.wake();
+            }
+        }
+
+        self.output(out, now);
+        if self.ack_at.is_some_and(|at| at <= now) {
+            self.send(out, ACK, self.snd_nxt, &[]);
+        }
+        accepted
+    }
+
+    fn enter_time_wait(&mut self, now: u64) {
+        self.state = State::TimeWait;
+        self.retransmit_at = None;
+        self.time_wait_until = Some(now + TIME_WAIT_MS);
+        self.wake();
+    }
+
+    fn timers(&mut self, out: &mut Outgoing, now: u64) {
+        if self.retransmit_at.is_some_and(|at| at <= now) {
+            self.retransmit(out, now);
+        }
+        if self.ack_at.is_some_and(|at| at <= now) {
+            self.send(out, ACK, self.snd_nxt, &[]);
+        }
+        if self.time_wait_until.is_some_and(|at| at <= now) {
+            self.time_wait_until = None;
+            self.close(None);
+        }
+    }
+}
+
+struct Listener {
+    /// Established connections waiting for [`TcpListener::accept`].
+    ready: VecDeque<Key>,
+    waker: Option<Waker>,
+}
+
+static CONNECTIONS: Mutex<BTreeMap<Key, Connection>> = Mutex::new(BTreeMap::new());
+static LISTENERS: Mutex<BTreeMap<u16, Listener>> = Mutex::new(BTreeMap::new());
+
+/// Sends the segments queued while the tables were locked. Lost segments
+/// are retransmitted, so errors are only worth ignoring here.
+fn transmit(out: Outgoing) {
+    for (dst, segment) in out {
+        let _ = ip::send_any(dst, Protocol::Tcp, &segment);
+    }
+}
+
+/// Drops connections that are closed and no longer used.
+fn remove_closed(connections: &mut BTreeMap<Key, Connection>) {
+    connections.retain(|_, connection| connection.state != State::Closed || connection.attached);
+}
+
+/// A reset for a segment that no connection wants.
+fn reset_for(src: IpAddr, dst: IpAddr, tcp: &TcpHeader, data_len: usize) -> Option<Vec<u8>> {
+    if tcp.flags & RST != 0 {
+        return None;
+    }
+    let reset = if tcp.flags & ACK != 0 {
+        TcpHeader::new(tcp.dst_port, tcp.src_port, tcp.ack, 0, RST)
+    } else {
+        let len = data_len as u32 + (tcp.flags & SYN != 0) as u32 + (tcp.flags & FIN != 0) as u32;
+        TcpHeader::new(
+            tcp.dst_port,
+            tcp.src_port,
+            0,
+            tcp.seq.wrapping_add(len),
+            RST | ACK,
+        )
+    };
+    Some(reset.build(dst, src, &[]))
+}
+
+/// Handles a TCP segment for us that came over IPv4.
+pub fn handle(_interface: &Arc<Interface>, header: &Ipv4Header, segment: &[u8]) {
+    if header.dst == Ipv4Addr::BROADCAST || header.dst.is_multicast() {
+        return;
+    }
+    receive(header.src.into(), header.dst.into(), segment);
+}
+
+/// Handles a TCP segment for us that came over IPv6.
+pub fn handle_ipv6(_interface: &Arc<Interface>, header: &Ipv6Header, segment: &[u8]) {
+    if header.dst.is_multicast() {
+        return;
+    }
+    receive(header.src.into(), header.dst.into(), segment);
+}
+
+/// Handles a segment from `src` to `dst`.
+fn receive(src: IpAddr, dst: IpAddr, segment: &[u8]) {
+    let checksum = ip::pseudo_header_any(src, dst, Protocol::Tcp, segment.len())
+        .add(segment)
+        .finish();
+    if checksum != 0 {
+        return;
+    }
+    let Some(tcp) = TcpHeader::parse(segment) else {
+        return;
+    };
+    let data = &segment[tcp.header_len..];
+    let key = (
+        SocketAddr::new(dst, tcp.dst_port),
+        SocketAddr::new(src, tcp.src_port),
+    );
+    let now = time::uptime_ms();
+    let mut out = Vec::new();
+
+    let mut connections = CONNECTIONS.lock();
+    if let Some(connection) = connections.get_mut(&key) {
+        if connection.state != State::Closed {
+            if connection.handle(&mut out, &tcp, data, now) {
+                let port = connection.listener.unwrap_or(0);
+                let mut listeners = LISTENERS.lock();
+                if let Some(listener) = listeners.get_mut(&port) {
+                    listener.ready.push_back(key);
+                    if let Some(waker) = listener.waker.take() {
+                        waker.wake();
+                    }
+                }
+            }
+            remove_closed(&mut connections);
+            drop(connections);
+            transmit(out);
+            return;
+        }
+    }
+
+    // A closed connection still in the table belongs to a stream that
+    // hasn't been dropped, so it can't be replaced yet
+    let listening = LISTENERS.lock().contains_key(&tcp.dst_port);
+    if listening && !connections.contains_key(&key) && tcp.flags & (SYN | ACK | RST) == SYN {
+        let backlog = connections
+            .values()
+            .filter(|connection| connection.listener == Some(tcp.dst_port))
+            .count();
+        if backlog < MAX_BACKLOG {
+            let mut connection = Connection::new(key.0, key.1, State::SynReceived);
+            connection.listener = Some(tcp.dst_port);
+            connection.rcv_nxt = tcp.seq.wrapping_add(1);
+            connection.snd_wnd = tcp.window as u32;
+            connection.mss = tcp
+                .mss
+                .map_or(DEFAULT_MSS, usize::from)
+                .min(connection.our_mss);
+            connection.send_syn(&mut out);
+            connection.retransmit_at = Some(now + connection.rto_ms);
+            connections.insert(key, connection);
+        }
+    } else if let Some(reset) = reset_for(src, dst, &tcp, data.len()) {
+        out.push((src, reset));
+    }
+    drop(connections);
+    transmit(out);
+}
+
+/// Runs the retransmission, delayed acknowledgement and TIME-WAIT timers.
+/// Runs as a task.
+pub async fn run() {
+    loop {
+        let now = time::uptime_ms();
+        let mut out = Vec::new();
+        let mut connections = CONNECTIONS.lock();
+        for connection in connections.values_mut() {
+            connection.timers(&mut out, now);
+        }
+        remove_closed(&mut connections);
+        drop(connections);
+        transmit(out);
+        time::sleep(TIMER_MS).await;
+    }
+}
+
+/// A connection or listener, as shown by the `netstat` command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionInfo {
+    pub local: SocketAddr,
+    /// Unspecified for listeners.
+    pub remote: SocketAddr,
+    pub state: State,
+    /// Bytes written but not acknowledged yet.
+    pub send_queue: usize,
+    /// Bytes received but not read yet.
+    pub recv_queue: usize,
+}
+
+pub fn connections() -> Vec<ConnectionInfo> {
+    let unspecified = SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0);
+    let mut list: Vec<ConnectionInfo> = LISTENERS
+        .lock()
+        .keys()
+        .map(|port| ConnectionInfo {
+            local: SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), *port),
+            remote: unspecified,
+            state: State::Listen,
+            send_queue: 0,
+            recv_queue: 0,
+        })
+        .collect();
+    list.extend(
+        CONNECTIONS
+            .lock()
+            .values()
+            .map(|connection| ConnectionInfo {
+                local: connection.local,
+                remote: connection.remote,
+                state: connection.state,
+                send_queue: connection.send_buffer.len(),
+                recv_queue: connection.recv_buffer.len(),
+            }),
+    );
+    list
+}
+
+/// Picks a local port for a new connection to `remote`.
+fn ephemeral_port(
+    connections: &BTreeMap<Key, Connection>,
+    local: IpAddr,
+    remote: SocketAddr,
+) -> Option<u16> {
+    static NEXT: Mutex<u16> = Mutex::new(*EPHEMERAL_PORTS.start());
+    let listeners = LISTENERS.lock();
+    let mut next = NEXT.lock();
+    for _ in EPHEMERAL_PORTS {
+        let port = *next;
+        *next = if port == *EPHEMERAL_PORTS.end() {
+            *EPHEMERAL_PORTS.start()
+        } else {
+            port + 1
+        };
+        let key = (SocketAddr::new(local, port), remote);
+        if !listeners.contains_key(&port) && !connections.contains_key(&key) {
+            return Some(port);
+        }
+    }
+    None
+}
+
+/// Accepts connections on a port. Connections not accepted yet are reset
+/// when it is dropped.
+#[derive(Debug)]
+pub struct TcpListener {
+    port: u16,
+}
+
+impl TcpListener {
+    pub fn bind(port: u16) -> Result<TcpListener, TcpError> {
+        let mut listeners = LISTENERS.lock();
+        if listeners.contains_key(&port) {
+            return Err(TcpError::AddrInUse);
+        }
+        listeners.insert(
+            port,
+            Listener {
+                ready: VecDeque::new(),
+                waker: None,
+            },
+        );
+        Ok(TcpListener { port })
+    }
+
+    pub fn local_port(&self) -> u16 {
+        self.port
+    }
+
+    /// Waits for a connection and returns it with the peer's address.
+    pub async fn accept(&self) -> (TcpStream, SocketAddr) {
+        poll_fn(|cx| {
+            let mut connections = CONNECTIONS.lock();
+            let mut listeners = LISTENERS.lock();
+            let listener = listeners.get_mut(&self.port).expect("listener not bound");
+            while let Some(key) = listener.ready.pop_front() {
+                // It might have been reset while it waited
+                if let Some(connection) = connections.get_mut(&key) {
+                    connection.listener = None;
+                    connection.attached = true;
+                    return Poll::Ready((TcpStream { key }, key.1));
+                }
+            }
+            listener.waker = Some(cx.waker().clone());
+            Poll::Pending
+        })
+        .await
+    }
+}
+
+impl Drop for TcpListener {
+    fn drop(&mut self) {
+        let mut out = Vec::new();
+        let mut connections = CONNECTIONS.lock();
+        LISTENERS.lock().remove(&self.port);
+        for connection in connections.values_mut() {
+            if connection.listener == Some(self.port) {
+                connection.abort(&mut out, None);
+            }
+        }
+        remove_closed(&mut connections);
+        drop(connections);
+        transmit(out);
+    }
+}
+
+/// One end of a TCP connection. Dropping it closes the connection like
+/// [`TcpStream::shutdown`], or resets it if data was left unread.
+#[derive(Debug)]
+pub struct TcpStream {
+    key: Key,
+}
+
+impl TcpStream {
+    /// Opens a connection to `addr`.
+    pub async fn connect(addr: SocketAddr) -> Result<TcpStream, TcpError> {
+        let src = ip::source_for(addr.ip())?;
+        let mut out = Vec::new();
+        let mut connections = CONNECTIONS.lock();
+        let port = ephemeral_port(&connections, src, addr).ok_or(TcpError::NoPortsLeft)?;
+        let local = SocketAddr::new(src, port);
+        let mut connection = Connection::new(local, addr, State::SynSent);
+        connection.attached = true;
+        connection.send_syn(&mut out);
+        let now = time::uptime_ms();
+        connection.timing = Some((connection.iss, now));
+        connection.retransmit_at = Some(now + connection.rto_ms);
+        connections.insert((local, addr), connection);
+        drop(connections);
+        transmit(out);
+
+        // Dropping the stream cleans up if this fails or is cancelled
+        let stream = TcpStream { key: (local, addr) };
+        poll_fn(|cx| {
+            let mut connections = CONNECTIONS.lock();
+            let connection = connections.get_mut(&stream.key).unwrap();
+            match connection.state {
+                State::SynSent | State::SynReceived => {
+                    connection.write_waker = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+                State::Closed => Poll::Ready(Err(connection.error.unwrap_or(TcpError::Closed))),
+                _ => Poll::Ready(Ok(())),
+            }
+        })
+        .await?;
+        Ok(stream)
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.key.0
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.key.1
+    }
+
+    pub fn state(&self) -> State {
+        CONNECTIONS
+            .lock()
+            .get(&self.key)
+            .map_or(State::Closed, |connection| connection.state)
+    }
+
+    /// Reads what was received into `buffer`, waiting until there is
+    /// something. Returns 0 once the peer closed its side.
+    pub async fn read(&self, buffer: &mut [u8]) -> Result<usize, TcpError> {
+        poll_fn(|cx| {
+            let mut out = Vec::new();
+            let mut connections = CONNECTIONS.lock();
+            let connection = connections.get_mut(&self.key).unwrap();
+            let result = if !connection.recv_buffer.is_empty() {
+                let len = buffer.len().min(connection.recv_buffer.len());
+                for (byte, received) in buffer.iter_mut().zip(connection.recv_buffer.drain(..len)) {
+                    *byte = received;
+                }
+                // Tell the peer once the window opened noticeably, not
+                // byte by byte
+                let threshold = (RECV_BUFFER_SIZE / 2).min(connection.our_mss);
+                if connection.window() as usize >= connection.advertised_window as usize + threshold
+                    && !connection.fin_received
+                    && connection.state != State::Closed
+                {
+                    let seq = connection.snd_nxt;
+                    connection.send(&mut out, ACK, seq, &[]);
+                }
+                Poll::Ready(Ok(len))
+            } else if connection.fin_received {
+                Poll::Ready(Ok(0))
+            } else if connection.state == State::Closed {
+                Poll::Ready(connection.error.map_or(Ok(0), Err))
+            } else {
+                connection.read_waker = Some(cx.waker().clone());
+                Poll::Pending
+            };
+            drop(connections);
+            transmit(out);
+            result
+        })
+        .await
+    }
+
+    /// Queues as much of `data` as fits in the send buffer, waiting until
+    /// something does, and returns how much that was.
+    pub async fn write(&self, data: &[u8]) -> Result<usize, TcpError> {
+        poll_fn(|cx| {
+            let mut out = Vec::new();
+            let mut connections = CONNECTIONS.lock();
+            let connection = connections.get_mut(&self.key).unwrap();
+            let result = if let Some(err) = connection.error {
+                Poll::Ready(Err(err))
+            } else if connection.fin_queued
+                || !matches!(connection.state, State::Established | State::CloseWait)
+            {
+                Poll::Ready(Err(TcpError::Closed))
+            } else if connection.send_buffer.len() == SEND_BUFFER_SIZE {
+                connection.write_waker = Some(cx.waker().clone());
+                Poll::Pending
+            } else {
+                let len = data
+                    .len()
+                    .min(SEND_BUFFER_SIZE - connection.send_buffer.len());
+                connection.send_buffer.extend(&data[..len]);
+                connection.output(&mut out, time::uptime_ms());
+                Poll::Ready(Ok(len))
+            };
+            drop(connections);
+            transmit(out);
+            result
+        })
+        .await
+    }
+
+    pub async fn write_all(&self, mut data: &[u8]) -> Result<(), TcpError> {
+        while !data.is_empty() {
+            let len = self.write(data).await?;
+            data = &data[len..];
+        }
+        Ok(())
+    }
+
+    /// Closes our side of the connection: the peer gets a FIN once it has
+    /// everything written before. Reading still works.
+    pub fn shutdown(&self) {
+        let mut out = Vec::new();
+        let mut connections = CONNECTIONS.lock();
+        if let Some(connection) = connections.get_mut(&self.key) {
+            connection.fin_queued = true;
+            connection.output(&mut out, time::uptime_ms());
+        }
+        drop(connections);
+        transmit(out);
+    }
+
+    /// Resets the connection, throwing away whatever wasn't sent yet.
+    pub fn abort(&self) {
+        let mut out = Vec::new();
+        let mut connections = CONNECTIONS.lock();
+        if let Some(connection) = connections.get_mut(&self.key) {
+            connection.abort(&mut out, None);
+        }
+        drop(connections);
+        transmit(out);
+    }
+}
+
+impl Drop for TcpStream {
+    fn drop(&mut self) {
+        let mut out = Vec::new();
+        let mut connections = CONNECTIONS.lock();
+        if let Some(connection) = connections.get_mut(&self.key) {
+            connection.attached = false;
+            match connection.state {
+                // Data nobody will read means the application gave up, so
+                // the peer should know (RFC 1122 4.2.2.13)
+                _ if !connection.recv_buffer.is_empty() => connection.abort(&mut out, None),
+                State::SynSent | State::SynReceived => connection.abort(&mut out, None),
+                _ => {
+                    connection.fin_queued = true;
+                    connection.output(&mut out, time::uptime_ms());
+                }
+            }
+        }
+        remove_closed(&mut connections);
+        drop(connections);
+        transmit(out);
+    }
+}
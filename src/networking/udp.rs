@@ -0,0 +1,264 @@
+//! UDP sockets.
+//!
+//! Datagrams for a bound port wait in its socket until a task receives
+//! them, whether they came over IPv4 or IPv6. Datagrams for ports nobody
+//! bound are answered with an ICMP or ICMPv6 port unreachable error.
+
+use super::{
+    device::Interface,
+    icmp::{self, ErrorMessage},
+    icmpv6,
+    ip::{self, IpAddr, IpError, Ipv4Addr, Ipv4Header, Protocol},
+    ipv6::Ipv6Header,
+};
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    sync::Arc,
+    vec::Vec,
+};
+use core::{
+    future::poll_fn,
+    net::{SocketAddr, SocketAddrV4},
+    ops::RangeInclusive,
+    task::{Poll, Waker},
+};
+use spin::Mutex;
+
+/// Source port, destination port, length and checksum.
+pub const HEADER_SIZE: usize = 8;
+/// Ports handed out to sockets bound to port 0, as RFC 6335 suggests.
+pub const EPHEMERAL_PORTS: RangeInclusive<u16> = 49152..=65535;
+/// Datagrams kept per socket. Newer ones are dropped while it is full.
+const MAX_QUEUED: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UdpError {
+    /// Another socket is bound to the port.
+    AddrInUse,
+    /// Every ephemeral port is taken.
+    NoPortsLeft,
+    /// The payload doesn't fit in a datagram.
+    TooLarge,
+    Ip(IpError),
+}
+
+impl From<IpError> for UdpError {
+    fn from(err: IpError) -> UdpError {
+        UdpError::Ip(err)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UdpHeader {
+    pub src_port: u16,
+    pub dst_port: u16,
+    /// Length of the header and payload.
+    pub len: u16,
+    pub checksum: u16,
+}
+
+impl UdpHeader {
+    pub fn parse(datagram: &[u8]) -> Option<UdpHeader> {
+        if datagram.len() < HEADER_SIZE {
+            return None;
+        }
+        let header = UdpHeader {
+            src_port: u16::from_be_bytes([datagram[0], datagram[1]]),
+            dst_port: u16::from_be_bytes([datagram[2], datagram[3]]),
+            len: u16::from_be_bytes([datagram[4], datagram[5]]),
+            checksum: u16::from_be_bytes([datagram[6], datagram[7]]),
+        };
+        if (header.len as usize) < HEADER_SIZE || header.len as usize > datagram.len() {
+            return None;
+        }
+        Some(header)
+    }
+}
+
+/// Builds a datagram from `src` to `dst` with its checksum filled in.
+pub fn build(src: SocketAddr, dst: SocketAddr, payload: &[u8]) -> Result<Vec<u8>, UdpError> {
+    let len = HEADER_SIZE + payload.len();
+    if len > u16::MAX as usize {
+        return Err(UdpError::TooLarge);
+    }
+    let mut datagram = Vec::with_capacity(len);
+    datagram.extend_from_slice(&src.port().to_be_bytes());
+    datagram.extend_from_slice(&dst.port().to_be_bytes());
+    datagram.extend_from_slice(&(len as u16).to_be_bytes());
+    datagram.extend_from_slice(&[0, 0]);
+    datagram.extend_from_slice(payload);
+
+    let checksum = ip::pseudo_header_any(src.ip(), dst.ip(), Protocol::Udp, len)
+        .add(&datagram)
+        .finish();
+    // A zero checksum means there is none, and all ones is the same number
+    let checksum = if checksum == 0 { 0xFFFF } else { checksum };
+    datagram[6..8].copy_from_slice(&checksum.to_be_bytes());
+    Ok(datagram)
+}
+
+#[derive(Default)]
+struct Socket {
+    queue: VecDeque<(SocketAddr, Vec<u8>)>,
+    waker: Option<Waker>,
+}
+
+static SOCKETS: Mutex<BTreeMap<u16, Socket>> = Mutex::new(BTreeMap::new());
+static NEXT_EPHEMERAL: Mutex<u16> = Mutex::new(*EPHEMERAL_PORTS.start());
+
+/// Picks the next free ephemeral port, going round the range.
+fn ephemeral_port(sockets: &BTreeMap<u16, Socket>) -> Option<u16> {
+    let mut next = NEXT_EPHEMERAL.lock();
+    for _ in EPHEMERAL_PORTS {
+        let port = *next;
+        *next = if port == *EPHEMERAL_PORTS.end() {
+            *EPHEMERAL_PORTS.start()
+        } else {
+            port + 1
+        };
+        if !sockets.contains_key(&port) {
+            return Some(port);
+        }
+    }
+    None
+}
+
+/// A bound UDP port. The port is released when the socket is dropped.
+#[derive(Debug)]
+pub struct UdpSocket {
+    port: u16,
+}
+
+impl UdpSocket {
+    /// Binds `port` on every interface, or a free ephemeral port if `port`
+    /// is 0.
+    pub fn bind(port: u16) -> Result<UdpSocket, UdpError> {
+        let mut sockets = SOCKETS.lock();
+        let port = if port == 0 {
+            ephemeral_port(&sockets).ok_or(UdpError::NoPortsLeft)?
+        } else if sockets.contains_key(&port) {
+            return Err(UdpError::AddrInUse);
+        } else {
+            port
+        };
+        sockets.insert(port, Socket::default());
+        Ok(UdpSocket { port })
+    }
+
+    pub fn local_port(&self) -> u16 {
+        self.port
+    }
+
+    /// Sends `payload` to `dst`, from the address of the interface the route
+    /// to it goes through.
+    pub async fn send_to(&self, payload: &[u8], dst: SocketAddr) -> Result<(), UdpError> {
+        let src = ip::source_for(dst.ip())?;
+        let datagram = build(SocketAddr::new(src, self.port), dst, payload)?;
+        ip::send_any(dst.ip(), Protocol::Udp, &datagram)?;
+        Ok(())
+    }
+
+    /// Sends `payload` to `dst` through `interface`, which doesn't need an
+    /// address yet. For protocols like DHCP that run before there are any
+    /// routes.
+    pub async fn send_via(
+        &self,
+        interface: &Interface,
+        src: Ipv4Addr,
+        payload: &[u8],
+        dst: SocketAddrV4,
+    ) -> Result<(), UdpError> {
+        let datagram = build(
+            SocketAddrV4::new(src, self.port).into(),
+            dst.into(),
+            payload,
+        )?;
+        let header = Ipv4Header::new(src, *dst.ip(), Protocol::Udp);
+        ip::send_via(interface, *dst.ip(), header, &datagram)?;
+        Ok(())
+    }
+
+    /// Takes the oldest datagram waiting, with its sender, if there is one.
+    pub fn try_recv_from(&self) -> Option<(Vec<u8>, SocketAddr)> {
+        let mut sockets = SOCKETS.lock();
+        let (from, payload) = sockets.get_mut(&self.port)?.queue.pop_front()?;
+        Some((payload, from))
+    }
+
+    /// Waits for a datagram and returns it with its sender.
+    pub async fn recv_from(&self) -> (Vec<u8>, SocketAddr) {
+        poll_fn(|cx| {
+            let mut sockets = SOCKETS.lock();
+            let socket = sockets.get_mut(&self.port).expect("socket not bound");
+            match socket.queue.pop_front() {
+                Some((from, payload)) => Poll::Ready((payload, from)),
+                None => {
+                    socket.waker = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            }
+        })
+        .await
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        SOCKETS.lock().remove(&self.port);
+    }
+}
+
+/// Checks the datagram from `src` to `dst` and returns its header and the
+/// datagram without any padding. IPv6 requires a checksum, IPv4 doesn't.
+fn parse(src: IpAddr, dst: IpAddr, datagram: &[u8]) -> Option<(UdpHeader, &[u8])> {
+    let udp = UdpHeader::parse(datagram)?;
+    let datagram = &datagram[..udp.len as usize];
+    if udp.checksum != 0 || dst.is_ipv6() {
+        let checksum = ip::pseudo_header_any(src, dst, Protocol::Udp, datagram.len())
+            .add(datagram)
+            .finish();
+        if checksum != 0 {
+            return None;
+        }
+    }
+    Some((udp, datagram))
+}
+
+/// Queues `payload` for the socket bound to `port`. Returns whether there
+/// is one.
+fn deliver(from: SocketAddr, port: u16, payload: &[u8]) -> bool {
+    let mut sockets = SOCKETS.lock();
+    let Some(socket) = sockets.get_mut(&port) else {
+        return false;
+    };
+    if socket.queue.len() < MAX_QUEUED {
+        socket.queue.push_back((from, payload.to_vec()));
+    }
+    if let Some(waker) = socket.waker.take() {
+        waker.wake();
+    }
+    true
+}
+
+/// Handles a UDP datagram for us that came over IPv4.
+pub fn handle(_interface: &Arc<Interface>, header: &Ipv4Header, datagram: &[u8]) {
+    let Some((udp, datagram)) = parse(header.src.into(), header.dst.into(), datagram) else {
+        return;
+    };
+    let from = SocketAddr::new(header.src.into(), udp.src_port);
+    if !deliver(from, udp.dst_port, &datagram[HEADER_SIZE..]) {
+        let error = ErrorMessage::DestinationUnreachable(icmp::CODE_PORT_UNREACHABLE);
+        icmp::send_error(error, header, datagram);
+    }
+}
+
+/// Handles a UDP datagram for us that came over IPv6.
+pub fn handle_ipv6(_interface: &Arc<Interface>, header: &Ipv6Header, datagram: &[u8]) {
+    let Some((udp, datagram)) = parse(header.src.into(), header.dst.into(), datagram) else {
+        return;
+    };
+    let from = SocketAddr::new(header.src.into(), udp.src_port);
+    if !deliver(from, udp.dst_port, &datagram[HEADER_SIZE..]) {
+        icmpv6::send_destination_unreachable(icmpv6::CODE_PORT_UNREACHABLE, header, datagram);
+    }
+}
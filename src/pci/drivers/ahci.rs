@@ -0,0 +1,454 @@
+use crate::{
+    block::{check_request, BlockDevice, BlockError},
+    memory::{self, DmaBuffer, MmioRegion},
+    pci::{
+        self,
+        drivers::ata::Identity,
+        headers::{Header, BAR},
+        pci_config_write_word,
+    },
+    println,
+};
+use alloc::{string::String, sync::Arc, vec::Vec};
+use core::sync::atomic::{fence, Ordering};
+use spin::Mutex;
+use x86_64::PhysAddr;
+
+const MASS_STORAGE_CLASS: u8 = 0x01;
+const SATA_SUBCLASS: u8 = 0x06;
+const AHCI_PROG_IF: u8 = 0x01;
+
+/// The ABAR is always BAR5.
+const ABAR_INDEX: usize = 5;
+/// Generic host control registers followed by 32 port register sets.
+const ABAR_SIZE: usize = 0x100 + 32 * PORT_REGS_SIZE;
+
+pub const SECTOR_SIZE: usize = 512;
+/// Sectors moved by one command, limited by the size of the bounce buffer.
+const MAX_SECTORS_PER_COMMAND: usize = 128;
+const BOUNCE_SIZE: usize = MAX_SECTORS_PER_COMMAND * SECTOR_SIZE;
+
+/// How often a register is polled before giving up.
+const TIMEOUT_SPINS: u32 = 1_000_000;
+
+// Generic host control registers
+const HBA_CAP: usize = 0x00;
+const HBA_GHC: usize = 0x04;
+const HBA_PI: usize = 0x0C;
+const HBA_VS: usize = 0x10;
+
+/// The controller can address memory above 4 GiB.
+const CAP_S64A: u32 = 1 << 31;
+const GHC_AHCI_ENABLE: u32 = 1 << 31;
+
+// Port registers, relative to the port's register set
+const PORT_REGS_START: usize = 0x100;
+const PORT_REGS_SIZE: usize = 0x80;
+const PORT_CLB: usize = 0x00;
+const PORT_CLBU: usize = 0x04;
+const PORT_FB: usize = 0x08;
+const PORT_FBU: usize = 0x0C;
+const PORT_IS: usize = 0x10;
+const PORT_IE: usize = 0x14;
+const PORT_CMD: usize = 0x18;
+const PORT_TFD: usize = 0x20;
+const PORT_SIG: usize = 0x24;
+const PORT_SSTS: usize = 0x28;
+const PORT_SERR: usize = 0x30;
+const PORT_CI: usize = 0x38;
+
+const CMD_ST: u32 = 1 << 0;
+const CMD_SUD: u32 = 1 << 1;
+const CMD_POD: u32 = 1 << 2;
+const CMD_FRE: u32 = 1 << 4;
+const CMD_FR: u32 = 1 << 14;
+const CMD_CR: u32 = 1 << 15;
+
+/// Task file error status in the port's interrupt status.
+const IS_TFES: u32 = 1 << 30;
+
+const TFD_ERR: u32 = 0x01;
+const TFD_DRQ: u32 = 0x08;
+const TFD_BSY: u32 = 0x80;
+
+/// SStatus device detection: device present and link established.
+const SSTS_DET_PRESENT: u32 = 3;
+const SIG_ATA: u32 = 0x0000_0101;
+
+// Layout of the per-port DMA page
+const COMMAND_LIST_OFFSET: usize = 0;
+const RECEIVED_FIS_OFFSET: usize = 0x400;
+const COMMAND_TABLE_OFFSET: usize = 0x800;
+const PRDT_OFFSET: usize = 0x80;
+
+const FIS_TYPE_REG_H2D: u8 = 0x27;
+/// Length of a host to device register FIS in dwords.
+const FIS_REG_H2D_DWORDS: u32 = 5;
+
+const ATA_CMD_READ_DMA: u8 = 0xC8;
+const ATA_CMD_READ_DMA_EXT: u8 = 0x25;
+const ATA_CMD_WRITE_DMA: u8 = 0xCA;
+const ATA_CMD_WRITE_DMA_EXT: u8 = 0x35;
+const ATA_CMD_CACHE_FLUSH: u8 = 0xE7;
+const ATA_CMD_CACHE_FLUSH_EXT: u8 = 0xEA;
+const ATA_CMD_IDENTIFY: u8 = 0xEC;
+
+/// DMA memory owned by a port. Only command slot 0 is used, so requests on
+/// a port run one at a time.
+struct PortMemory {
+    /// Command list, received FIS area and the command table of slot 0.
+    tables: DmaBuffer,
+    /// Data is copied through here, since heap buffers aren't physically
+    /// contiguous.
+    bounce: DmaBuffer,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    None,
+    Read,
+    Write,
+}
+
+/// A SATA disk attached to an AHCI controller, moving data with DMA.
+pub struct AhciDrive {
+    port: usize,
+    regs: MmioRegion,
+    memory: Mutex<PortMemory>,
+    lba48: bool,
+    sector_count: u64,
+    pub model: String,
+    pub serial: String,
+}
+
+impl AhciDrive {
+    fn new(port: usize, regs: MmioRegion, dma64: bool) -> Result<AhciDrive, BlockError> {
+        // Without S64A the upper address registers are ignored, so the
+        // tables and data have to sit below 4 GiB
+        let alloc = if dma64 {
+            memory::alloc_dma
+        } else {
+            memory::alloc_dma32
+        };
+        let tables = alloc(4096).ok_or(BlockError::Io)?;
+        let bounce = alloc(BOUNCE_SIZE).ok_or(BlockError::Io)?;
+
+        let mut drive = AhciDrive {
+            port,
+            regs,
+            memory: Mutex::new(PortMemory { tables, bounce }),
+            lba48: false,
+            sector_count: 0,
+            model: String::new(),
+            serial: String::new(),
+        };
+        drive.start()?;
+
+        let identity = {
+            let mut memory = drive.memory.lock();
+            drive.execute(
+                &mut memory,
+                ATA_CMD_IDENTIFY,
+                0,
+                0,
+                Direction::Read,
+                SECTOR_SIZE,
+            )?;
+            Identity::parse(&memory.bounce.as_slice()[..SECTOR_SIZE])
+        };
+        drive.lba48 = identity.lba48;
+        drive.sector_count = identity.sector_count;
+        drive.model = identity.model;
+        drive.serial = identity.serial;
+        Ok(drive)
+    }
+
+    pub fn port(&self) -> usize {
+        self.port
+    }
+
+    /// Waits until the bits in `mask` of register `offset` are all clear.
+    fn wait_clear(&self, offset: usize, mask: u32) -> Result<(), BlockError> {
+        for _ in 0..TIMEOUT_SPINS {
+            if self.regs.read_u32(offset) & mask == 0 {
+                return Ok(());
+            }
+            core::hint::spin_loop();
+        }
+        Err(BlockError::Timeout)
+    }
+
+    /// Points the port at its command list and received FIS area and starts
+    /// the command engine.
+    fn start(&self) -> Result<(), BlockError> {
+        let regs = &self.regs;
+
+        // The engine has to be idle while the addresses change
+        regs.write_u32(PORT_CMD, regs.read_u32(PORT_CMD) & !CMD_ST);
+        self.wait_clear(PORT_CMD, CMD_CR)?;
+        regs.write_u32(PORT_CMD, regs.read_u32(PORT_CMD) & !CMD_FRE);
+        self.wait_clear(PORT_CMD, CMD_FR)?;
+
+        let memory = self.memory.lock();
+        let base = memory.tables.phys_addr().as_u64();
+        let command_list = base + COMMAND_LIST_OFFSET as u64;
+        let received_fis = base + RECEIVED_FIS_OFFSET as u64;
+        regs.write_u32(PORT_CLB, command_list as u32);
+        regs.write_u32(PORT_CLBU, (command_list >> 32) as u32);
+        regs.write_u32(PORT_FB, received_fis as u32);
+        regs.write_u32(PORT_FBU, (received_fis >> 32) as u32);
+        drop(memory);
+
+        // Completion is polled, so leave the port's interrupts off
+        regs.write_u32(PORT_IE, 0);
+        regs.write_u32(PORT_SERR, u32::MAX);
+        regs.write_u32(PORT_IS, u32::MAX);
+
+        let cmd = regs.read_u32(PORT_CMD) | CMD_SUD | CMD_POD | CMD_FRE;
+        regs.write_u32(PORT_CMD, cmd);
+        self.wait_clear(PORT_CMD, CMD_CR)?;
+        regs.write_u32(PORT_CMD, cmd | CMD_ST);
+        Ok(())
+    }
+
+    /// Builds a command in slot 0 and waits for it to complete. Data moves
+    /// through the start of the bounce buffer.
+    fn execute(
+        &self,
+        memory: &mut PortMemory,
+        command: u8,
+        lba: u64,
+        count: usize,
+        direction: Direction,
+        bytes: usize,
+    ) -> Result<(), BlockError> {
+        let regs = &self.regs;
+        self.wait_clear(PORT_TFD, TFD_BSY | TFD_DRQ)?;
+
+        let table_addr = memory.tables.phys_addr() + COMMAND_TABLE_OFFSET as u64;
+        let bounce_addr = memory.bounce.phys_addr().as_u64();
+        let tables = memory.tables.as_mut_slice();
+
+        let mut flags = FIS_REG_H2D_DWORDS;
+        if direction == Direction::Write {
+            flags |= 1 << 6;
+        }
+        let prdt_length = if direction == Direction::None { 0 } else { 1 };
+        let header = &mut tables[COMMAND_LIST_OFFSET..COMMAND_LIST_OFFSET + 32];
+        header.fill(0);
+        header[0..4].copy_from_slice(&(flags | prdt_length << 16).to_le_bytes());
+        header[8..16].copy_from_slice(&table_addr.as_u64().to_le_bytes());
+
+        let table = &mut tables[COMMAND_TABLE_OFFSET..COMMAND_TABLE_OFFSET + PRDT_OFFSET + 16];
+        table.fill(0);
+        let fis = &mut table[..20];
+        fis[0] = FIS_TYPE_REG_H2D;
+        // Marks the FIS as a command rather than a device control update
+        fis[1] = 0x80;
+        fis[2] = command;
+        fis[4] = lba as u8;
+        fis[5] = (lba >> 8) as u8;
+        fis[6] = (lba >> 16) as u8;
+        // LBA mode. 28-bit commands keep the top four address bits here
+        fis[7] = if self.lba48 {
+            0x40
+        } else {
+            0x40 | ((lba >> 24) & 0xF) as u8
+        };
+        fis[8] = (lba >> 24) as u8;
+        fis[9] = (lba >> 32) as u8;
+        fis[10] = (lba >> 40) as u8;
+        fis[12] = count as u8;
+        fis[13] = (count >> 8) as u8;
+
+        if direction != Direction::None {
+            let prd = &mut table[PRDT_OFFSET..PRDT_OFFSET + 16];
+            prd[0..8].copy_from_slice(&bounce_addr.to_le_bytes());
+            prd[12..16].copy_from_slice(&(bytes as u32 - 1).to_le_bytes());
+        }
+
+        regs.write_u32(PORT_IS, u32::MAX);
+        // The tables have to be in memory before the device is told to look
+        fence(Ordering::SeqCst);
+        regs.write_u32(PORT_CI, 1);
+
+        let mut done = false;
+        for _ in 0..TIMEOUT_SPINS {
+            if regs.read_u32(PORT_IS) & IS_TFES != 0 {
+                break;
+            }
+            if regs.read_u32(PORT_CI) & 1 == 0 {
+                done = true;
+                break;
+            }
+            core::hint::spin_loop();
+        }
+        fence(Ordering::SeqCst);
+
+        let tfd = regs.read_u32(PORT_TFD);
+        if regs.read_u32(PORT_IS) & IS_TFES != 0 || tfd & TFD_ERR != 0 {
+            println!(
+                "ahci: port {} command {:#04x} failed, tfd {:#06x}",
+                self.port, command, tfd
+            );
+            return Err(BlockError::Io);
+        }
+        if !done {
+            return Err(BlockError::Timeout);
+        }
+        Ok(())
+    }
+
+    fn transfer_command(&self, direction: Direction) -> u8 {
+        match (direction, self.lba48) {
+            (Direction::Write, true) => ATA_CMD_WRITE_DMA_EXT,
+            (Direction::Write, false) => ATA_CMD_WRITE_DMA,
+            (_, true) => ATA_CMD_READ_DMA_EXT,
+            (_, false) => ATA_CMD_READ_DMA,
+        }
+    }
+}
+
+impl BlockDevice for AhciDrive {
+    fn sector_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.sector_count
+    }
+
+    fn read_sectors(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        check_request(self, lba, buf.len())?;
+        let command = self.transfer_command(Direction::Read);
+        let mut memory = self.memory.lock();
+
+        for (i, chunk) in buf.chunks_mut(BOUNCE_SIZE).enumerate() {
+            let lba = lba + (i * MAX_SECTORS_PER_COMMAND) as u64;
+            let count = chunk.len() / SECTOR_SIZE;
+            self.execute(
+                &mut memory,
+                command,
+                lba,
+                count,
+                Direction::Read,
+                chunk.len(),
+            )?;
+            chunk.copy_from_slice(&memory.bounce.as_slice()[..chunk.len()]);
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        check_request(self, lba, buf.len())?;
+        let command = self.transfer_command(Direction::Write);
+        let mut memory = self.memory.lock();
+
+        for (i, chunk) in buf.chunks(BOUNCE_SIZE).enumerate() {
+            let lba = lba + (i * MAX_SECTORS_PER_COMMAND) as u64;
+            let count = chunk.len() / SECTOR_SIZE;
+            memory.bounce.as_mut_slice()[..chunk.len()].copy_from_slice(chunk);
+            self.execute(
+                &mut memory,
+                command,
+                lba,
+                count,
+                Direction::Write,
+                chunk.len(),
+            )?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        let command = if self.lba48 {
+            ATA_CMD_CACHE_FLUSH_EXT
+        } else {
+            ATA_CMD_CACHE_FLUSH
+        };
+        let mut memory = self.memory.lock();
+        self.execute(&mut memory, command, 0, 0, Direction::None, 0)
+    }
+}
+
+/// Returns the physical address of the controller's registers.
+fn abar_address(header: &Header) -> Option<PhysAddr> {
+    let standard = header.rest_of_header.to_standard().ok()?;
+    match &standard.base_address_registers[ABAR_INDEX] {
+        BAR::Memory(bar) => Some(PhysAddr::new(bar.address as u64)),
+        BAR::IO(_) => None,
+    }
+}
+
+/// Finds AHCI controllers on the PCI bus and brings up the SATA disks
+/// attached to their ports. ATAPI and port multiplier ports are skipped.
+pub fn probe() -> Vec<Arc<AhciDrive>> {
+    let mut drives = Vec::new();
+
+    for header in pci::get_devices_by_class(MASS_STORAGE_CLASS, SATA_SUBCLASS) {
+        if header.prog_if != AHCI_PROG_IF {
+            continue;
+        }
+        let Some(abar) = abar_address(&header) else {
+            println!("ahci: controller has no memory BAR5");
+            continue;
+        };
+        let hba = match memory::map_mmio(abar, ABAR_SIZE) {
+            Ok(hba) => hba,
+            Err(err) => {
+                println!("ahci: failed to map ABAR: {:?}", err);
+                continue;
+            }
+        };
+
+        // Enable memory space decoding and bus mastering, and mask the
+        // legacy interrupt since completion is polled
+        pci_config_write_word(
+            header.bus,
+            header.device,
+            header.function,
+            0x4,
+            header.command | 0x6 | 1 << 10,
+        );
+        hba.write_u32(HBA_GHC, hba.read_u32(HBA_GHC) | GHC_AHCI_ENABLE);
+
+        let version = hba.read_u32(HBA_VS);
+        println!(
+            "ahci: controller {:04x}:{:04x} at {}:{}.{}, AHCI {}.{}",
+            header.vendor_id,
+            header.device_id,
+            header.bus,
+            header.device,
+            header.function,
+            version >> 16,
+            (version >> 8) & 0xFF
+        );
+
+        let dma64 = hba.read_u32(HBA_CAP) & CAP_S64A != 0;
+        let implemented = hba.read_u32(HBA_PI);
+        for port in (0..32).filter(|port| implemented & (1 << port) != 0) {
+            let regs = hba.subregion(PORT_REGS_START + port * PORT_REGS_SIZE, PORT_REGS_SIZE);
+            if regs.read_u32(PORT_SSTS) & 0xF != SSTS_DET_PRESENT {
+                continue;
+            }
+            if regs.read_u32(PORT_SIG) != SIG_ATA {
+                continue;
+            }
+
+            match AhciDrive::new(port, regs, dma64) {
+                Ok(drive) => {
+                    println!(
+                        "ahci: port {}: {} ({} MiB)",
+                        port,
+                        drive.model,
+                        drive.sector_count * SECTOR_SIZE as u64 / (1024 * 1024)
+                    );
+                    drives.push(Arc::new(drive));
+                }
+                Err(err) => println!("ahci: port {} failed to start: {:?}", port, err),
+            }
+        }
+    }
+
+    drives
+}
@@ -0,0 +1,689 @@
+use crate::{
+    block::{check_request, BlockDevice, BlockError, BlockFuture},
+    interrupts::{self, InterruptIndex, PICS},
+    pci::{
+        self,
+        headers::{Header, BAR},
+        pci_config_write_word,
+    },
+    println,
+};
+use alloc::{boxed::Box, string::String, sync::Arc, vec::Vec};
+use core::{
+    future::poll_fn,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Poll, Waker},
+};
+use futures_util::task::AtomicWaker;
+use spin::{Mutex, MutexGuard};
+use x86_64::{instructions::port::Port, structures::idt::InterruptStackFrame};
+
+const MASS_STORAGE_CLASS: u8 = 0x01;
+const IDE_SUBCLASS: u8 = 0x01;
+
+/// Programming interface bits saying a channel uses its PCI BARs instead of
+/// the legacy ports.
+const PRIMARY_NATIVE: u8 = 0x01;
+const SECONDARY_NATIVE: u8 = 0x04;
+
+const PRIMARY_IO: u16 = 0x1F0;
+const PRIMARY_CONTROL: u16 = 0x3F6;
+const SECONDARY_IO: u16 = 0x170;
+const SECONDARY_CONTROL: u16 = 0x376;
+
+pub const SECTOR_SIZE: usize = 512;
+/// Sectors moved by a single command; LBA28 can't do more than this.
+const MAX_SECTORS_PER_COMMAND: usize = 256;
+const LBA28_LIMIT: u64 = 1 << 28;
+
+/// How often a status register is polled before giving up.
+const TIMEOUT_SPINS: u32 = 1_000_000;
+
+// Register offsets from the I/O base
+const REG_DATA: u16 = 0;
+const REG_ERROR: u16 = 1;
+const REG_SECTOR_COUNT: u16 = 2;
+const REG_LBA_LOW: u16 = 3;
+const REG_LBA_MID: u16 = 4;
+const REG_LBA_HIGH: u16 = 5;
+const REG_DRIVE: u16 = 6;
+const REG_STATUS: u16 = 7;
+const REG_COMMAND: u16 = 7;
+
+const STATUS_ERR: u8 = 0x01;
+const STATUS_DRQ: u8 = 0x08;
+const STATUS_DF: u8 = 0x20;
+const STATUS_BSY: u8 = 0x80;
+
+const CMD_READ_SECTORS: u8 = 0x20;
+const CMD_READ_SECTORS_EXT: u8 = 0x24;
+const CMD_WRITE_SECTORS: u8 = 0x30;
+const CMD_WRITE_SECTORS_EXT: u8 = 0x34;
+const CMD_CACHE_FLUSH: u8 = 0xE7;
+const CMD_CACHE_FLUSH_EXT: u8 = 0xEA;
+const CMD_IDENTIFY: u8 = 0xEC;
+
+const PRIMARY_IRQ: u8 = 14;
+const SECONDARY_IRQ: u8 = 15;
+
+/// Set by the interrupt handlers, one per channel.
+static IRQ_PENDING: [AtomicBool; 2] = [AtomicBool::new(false), AtomicBool::new(false)];
+static IRQ_WAKERS: [AtomicWaker; 2] = [AtomicWaker::new(), AtomicWaker::new()];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelKind {
+    Primary,
+    Secondary,
+}
+
+impl ChannelKind {
+    fn index(self) -> usize {
+        match self {
+            ChannelKind::Primary => 0,
+            ChannelKind::Secondary => 1,
+        }
+    }
+}
+
+/// One of the two IDE channels, each of which has a master and a slave
+/// drive that share its registers.
+///
+/// Requests on a channel run one at a time. Async requests wait for the
+/// channel without spinning, so the task holding it keeps running. Blocking
+/// requests can't wait for a task to finish its request, and fail with
+/// [`BlockError::Timeout`] if the channel stays taken.
+pub struct Channel {
+    kind: ChannelKind,
+    io_base: u16,
+    control_base: u16,
+    /// Native mode channels interrupt on the controller's PCI IRQ line,
+    /// which we don't listen on, so async requests poll their status.
+    native: bool,
+    lock: Mutex<()>,
+    /// Async requests waiting for the channel.
+    waiters: Mutex<Vec<Waker>>,
+}
+
+/// Holds a channel for one request, and lets the requests waiting for it
+/// try again when dropped.
+struct ChannelGuard<'a> {
+    channel: &'a Channel,
+    guard: Option<MutexGuard<'a, ()>>,
+}
+
+impl Drop for ChannelGuard<'_> {
+    fn drop(&mut self) {
+        self.guard.take();
+        for waker in self.channel.waiters.lock().drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+impl Channel {
+    fn new(kind: ChannelKind, io_base: u16, control_base: u16, native: bool) -> Channel {
+        Channel {
+            kind,
+            io_base,
+            control_base,
+            native,
+            lock: Mutex::new(()),
+            waiters: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Takes the channel for a blocking request. A task holding it can't
+    /// run while we spin, so this gives up after a while.
+    fn lock(&self) -> Result<ChannelGuard<'_>, BlockError> {
+        for _ in 0..TIMEOUT_SPINS {
+            if let Some(guard) = self.lock.try_lock() {
+                return Ok(ChannelGuard {
+                    channel: self,
+                    guard: Some(guard),
+                });
+            }
+            core::hint::spin_loop();
+        }
+        Err(BlockError::Timeout)
+    }
+
+    /// Takes the channel for an async request, waiting for the request
+    /// holding it to finish.
+    async fn lock_async(&self) -> ChannelGuard<'_> {
+        let guard = poll_fn(|cx| {
+            if let Some(guard) = self.lock.try_lock() {
+                return Poll::Ready(guard);
+            }
+            self.waiters.lock().push(cx.waker().clone());
+            // It may have been released before the waker was registered
+            match self.lock.try_lock() {
+                Some(guard) => Poll::Ready(guard),
+                None => Poll::Pending,
+            }
+        })
+        .await;
+        ChannelGuard {
+            channel: self,
+            guard: Some(guard),
+        }
+    }
+
+    fn read(&self, register: u16) -> u8 {
+        unsafe { Port::<u8>::new(self.io_base + register).read() }
+    }
+
+    fn write(&self, register: u16, value: u8) {
+        unsafe { Port::<u8>::new(self.io_base + register).write(value) }
+    }
+
+    /// Reads the status without acknowledging a pending interrupt.
+    fn alt_status(&self) -> u8 {
+        unsafe { Port::<u8>::new(self.control_base).read() }
+    }
+
+    fn set_control(&self, value: u8) {
+        unsafe { Port::<u8>::new(self.control_base).write(value) }
+    }
+
+    /// Waits the 400ns a drive needs to put its status on the bus.
+    fn delay(&self) {
+        for _ in 0..4 {
+            self.alt_status();
+        }
+    }
+
+    fn select(&self, slave: bool, bits: u8) {
+        self.write(REG_DRIVE, 0xA0 | bits | (slave as u8) << 4);
+        self.delay();
+    }
+
+    fn wait_not_busy(&self) -> Result<u8, BlockError> {
+        for _ in 0..TIMEOUT_SPINS {
+            let status = self.alt_status();
+            if status & STATUS_BSY == 0 {
+                return Ok(status);
+            }
+            core::hint::spin_loop();
+        }
+        Err(BlockError::Timeout)
+    }
+
+    /// Waits until the drive is ready to move a sector of data.
+    fn wait_drq(&self) -> Result<(), BlockError> {
+        for _ in 0..TIMEOUT_SPINS {
+            let status = self.wait_not_busy()?;
+            if status & (STATUS_ERR | STATUS_DF) != 0 {
+                return Err(self.error());
+            }
+            if status & STATUS_DRQ != 0 {
+                return Ok(());
+            }
+            core::hint::spin_loop();
+        }
+        Err(BlockError::Timeout)
+    }
+
+    fn error(&self) -> BlockError {
+        println!(
+            "ata: {:?} channel error {:#04x}",
+            self.kind,
+            self.read(REG_ERROR)
+        );
+        BlockError::Io
+    }
+
+    fn clear_irq(&self) {
+        IRQ_PENDING[self.kind.index()].store(false, Ordering::SeqCst);
+    }
+
+    /// Waits for the drive to raise its interrupt. If it never arrives, for
+    /// example because interrupts are disabled, this falls back to polling
+    /// the status register.
+    fn wait_irq(&self) -> Result<u8, BlockError> {
+        if self.native {
+            // No interrupt will come, give the drive time to go busy
+            self.delay();
+            return self.check_status();
+        }
+        for _ in 0..TIMEOUT_SPINS {
+            if IRQ_PENDING[self.kind.index()].swap(false, Ordering::SeqCst) {
+                break;
+            }
+            core::hint::spin_loop();
+        }
+        self.check_status()
+    }
+
+    /// Waits for the drive to raise its interrupt without blocking the
+    /// executor, or on native mode channels, for it to stop being busy.
+    async fn wait_async(&self) -> Result<u8, BlockError> {
+        let index = self.kind.index();
+        let mut polls = 0;
+        poll_fn(|cx| {
+            if self.native {
+                self.delay();
+                if self.alt_status() & STATUS_BSY == 0 {
+                    return Poll::Ready(Ok(()));
+                }
+                polls += 1;
+                if polls == TIMEOUT_SPINS {
+                    return Poll::Ready(Err(BlockError::Timeout));
+                }
+                // Nothing will tell us, so look again on the next round
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            if IRQ_PENDING[index].swap(false, Ordering::SeqCst) {
+                return Poll::Ready(Ok(()));
+            }
+            IRQ_WAKERS[index].register(cx.waker());
+            if IRQ_PENDING[index].swap(false, Ordering::SeqCst) {
+                IRQ_WAKERS[index].take();
+                Poll::Ready(Ok(()))
+            } else {
+                Poll::Pending
+            }
+        })
+        .await?;
+        self.check_status()
+    }
+
+    fn check_status(&self) -> Result<u8, BlockError> {
+        let status = self.wait_not_busy()?;
+        if status & (STATUS_ERR | STATUS_DF) != 0 {
+            return Err(self.error());
+        }
+        Ok(status)
+    }
+
+    fn read_data(&self, sector: &mut [u8]) {
+        let mut port = Port::<u16>::new(self.io_base + REG_DATA);
+        for word in sector.chunks_exact_mut(2) {
+            word.copy_from_slice(&unsafe { port.read() }.to_le_bytes());
+        }
+    }
+
+    fn write_data(&self, sector: &[u8]) {
+        let mut port = Port::<u16>::new(self.io_base + REG_DATA);
+        for word in sector.chunks_exact(2) {
+            unsafe { port.write(u16::from_le_bytes([word[0], word[1]])) };
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    Read,
+    Write,
+}
+
+/// An ATA hard disk driven with PIO transfers.
+pub struct AtaDrive {
+    channel: Arc<Channel>,
+    slave: bool,
+    lba48: bool,
+    sector_count: u64,
+    pub model: String,
+    pub serial: String,
+}
+
+impl AtaDrive {
+    /// Sends IDENTIFY to a drive, returning `None` if there is no ATA disk
+    /// (nothing attached, or an ATAPI or SATA device).
+    fn identify(channel: &Arc<Channel>, slave: bool) -> Option<AtaDrive> {
+        let _guard = channel.lock().ok()?;
+        if channel.alt_status() == 0xFF {
+            // Floating bus, nothing attached to this channel
+            return None;
+        }
+
+        channel.select(slave, 0);
+        for register in [REG_SECTOR_COUNT, REG_LBA_LOW, REG_LBA_MID, REG_LBA_HIGH] {
+            channel.write(register, 0);
+        }
+        channel.clear_irq();
+        channel.write(REG_COMMAND, CMD_IDENTIFY);
+        if channel.read(REG_STATUS) == 0 {
+            return None;
+        }
+
+        channel.wait_not_busy().ok()?;
+        if channel.read(REG_LBA_MID) != 0 || channel.read(REG_LBA_HIGH) != 0 {
+            return None;
+        }
+        channel.wait_drq().ok()?;
+
+        let mut data = [0u8; SECTOR_SIZE];
+        channel.read_data(&mut data);
+        let identity = Identity::parse(&data);
+
+        Some(AtaDrive {
+            channel: channel.clone(),
+            slave,
+            lba48: identity.lba48,
+            sector_count: identity.sector_count,
+            model: identity.model,
+            serial: identity.serial,
+        })
+    }
+
+    pub fn channel(&self) -> ChannelKind {
+        self.channel.kind
+    }
+
+    pub fn is_slave(&self) -> bool {
+        self.slave
+    }
+
+    /// Loads the task file and sends `command` for `count` sectors at `lba`.
+    fn issue(&self, lba: u64, count: usize, direction: Direction) {
+        let channel = &self.channel;
+        channel.clear_irq();
+
+        if self.lba48 && (lba + count as u64 > LBA28_LIMIT) {
+            channel.select(self.slave, 0x40);
+            // The high bytes go first, each register holds two bytes
+            channel.write(REG_SECTOR_COUNT, (count >> 8) as u8);
+            channel.write(REG_LBA_LOW, (lba >> 24) as u8);
+            channel.write(REG_LBA_MID, (lba >> 32) as u8);
+            channel.write(REG_LBA_HIGH, (lba >> 40) as u8);
+            channel.write(REG_SECTOR_COUNT, count as u8);
+            channel.write(REG_LBA_LOW, lba as u8);
+            channel.write(REG_LBA_MID, (lba >> 8) as u8);
+            channel.write(REG_LBA_HIGH, (lba >> 16) as u8);
+            channel.write(
+                REG_COMMAND,
+                match direction {
+                    Direction::Read => CMD_READ_SECTORS_EXT,
+                    Direction::Write => CMD_WRITE_SECTORS_EXT,
+                },
+            );
+        } else {
+            channel.select(self.slave, 0x40 | (lba >> 24) as u8 & 0x0F);
+            // A count of 0 means 256 sectors
+            channel.write(REG_SECTOR_COUNT, count as u8);
+            channel.write(REG_LBA_LOW, lba as u8);
+            channel.write(REG_LBA_MID, (lba >> 8) as u8);
+            channel.write(REG_LBA_HIGH, (lba >> 16) as u8);
+            channel.write(
+                REG_COMMAND,
+                match direction {
+                    Direction::Read => CMD_READ_SECTORS,
+                    Direction::Write => CMD_WRITE_SECTORS,
+                },
+            );
+        }
+    }
+
+    fn read_chunk(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        let _guard = self.channel.lock()?;
+        self.issue(lba, buf.len() / SECTOR_SIZE, Direction::Read);
+        for sector in buf.chunks_exact_mut(SECTOR_SIZE) {
+            self.channel.wait_irq()?;
+            self.channel.wait_drq()?;
+            self.channel.read_data(sector);
+        }
+        Ok(())
+    }
+
+    fn write_chunk(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        let _guard = self.channel.lock()?;
+        self.issue(lba, buf.len() / SECTOR_SIZE, Direction::Write);
+        for sector in buf.chunks_exact(SECTOR_SIZE) {
+            // The drive interrupts once it has taken each sector
+            self.channel.wait_drq()?;
+            self.channel.write_data(sector);
+            self.channel.wait_irq()?;
+        }
+        Ok(())
+    }
+
+    fn issue_flush(&self) {
+        self.channel.clear_irq();
+        self.channel.select(self.slave, 0);
+        self.channel.write(
+            REG_COMMAND,
+            if self.lba48 {
+                CMD_CACHE_FLUSH_EXT
+            } else {
+                CMD_CACHE_FLUSH
+            },
+        );
+    }
+}
+
+/// The parts of an IDENTIFY DEVICE response the drivers use. AHCI drives
+/// answer with the same 512 byte block.
+pub(crate) struct Identity {
+    pub lba48: bool,
+    pub sector_count: u64,
+    pub model: String,
+    pub serial: String,
+}
+
+impl Identity {
+    pub fn parse(data: &[u8]) -> Identity {
+        let word = |i: usize| u16::from_le_bytes([data[i * 2], data[i * 2 + 1]]);
+        let lba48 = word(83) & (1 << 10) != 0;
+        let sector_count = if lba48 {
+            (0..4).fold(0u64, |count, i| count | (word(100 + i) as u64) << (16 * i))
+        } else {
+            word(60) as u64 | (word(61) as u64) << 16
+        };
+
+        Identity {
+            lba48,
+            sector_count,
+            model: identify_string(&data[54..94]),
+            serial: identify_string(&data[20..40]),
+        }
+    }
+}
+
+/// Decodes an IDENTIFY string, which stores two characters per word with
+/// the first in the high byte.
+fn identify_string(raw: &[u8]) -> String {
+    let mut text = String::new();
+    for pair in raw.chunks_exact(2) {
+        text.push(pair[1] as char);
+        text.push(pair[0] as char);
+    }
+    String::from(text.trim())
+}
+
+impl BlockDevice for AtaDrive {
+    fn sector_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.sector_count
+    }
+
+    fn read_sectors(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        check_request(self, lba, buf.len())?;
+        let chunk_size = MAX_SECTORS_PER_COMMAND * SECTOR_SIZE;
+        for (i, chunk) in buf.chunks_mut(chunk_size).enumerate() {
+            self.read_chunk(lba + (i * MAX_SECTORS_PER_COMMAND) as u64, chunk)?;
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        check_request(self, lba, buf.len())?;
+        let chunk_size = MAX_SECTORS_PER_COMMAND * SECTOR_SIZE;
+        for (i, chunk) in buf.chunks(chunk_size).enumerate() {
+            self.write_chunk(lba + (i * MAX_SECTORS_PER_COMMAND) as u64, chunk)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        let _guard = self.channel.lock()?;
+        self.issue_flush();
+        self.channel.wait_irq().map(|_| ())
+    }
+
+    fn read_sectors_async<'a>(&'a self, lba: u64, buf: &'a mut [u8]) -> BlockFuture<'a, ()> {
+        Box::pin(async move {
+            check_request(self, lba, buf.len())?;
+            let chunk_size = MAX_SECTORS_PER_COMMAND * SECTOR_SIZE;
+
+            for (i, chunk) in buf.chunks_mut(chunk_size).enumerate() {
+                let _guard = self.channel.lock_async().await;
+                self.issue(
+                    lba + (i * MAX_SECTORS_PER_COMMAND) as u64,
+                    chunk.len() / SECTOR_SIZE,
+                    Direction::Read,
+                );
+                for sector in chunk.chunks_exact_mut(SECTOR_SIZE) {
+                    self.channel.wait_async().await?;
+                    self.channel.wait_drq()?;
+                    self.channel.read_data(sector);
+                }
+            }
+            Ok(())
+        })
+    }
+
+    fn write_sectors_async<'a>(&'a self, lba: u64, buf: &'a [u8]) -> BlockFuture<'a, ()> {
+        Box::pin(async move {
+            check_request(self, lba, buf.len())?;
+            let chunk_size = MAX_SECTORS_PER_COMMAND * SECTOR_SIZE;
+
+            for (i, chunk) in buf.chunks(chunk_size).enumerate() {
+                let _guard = self.channel.lock_async().await;
+                self.issue(
+                    lba + (i * MAX_SECTORS_PER_COMMAND) as u64,
+                    chunk.len() / SECTOR_SIZE,
+                    Direction::Write,
+                );
+                for sector in chunk.chunks_exact(SECTOR_SIZE) {
+                    self.channel.wait_drq()?;
+                    self.channel.write_data(sector);
+                    self.channel.wait_async().await?;
+                }
+            }
+            Ok(())
+        })
+    }
+
+    fn flush_async(&self) -> BlockFuture<'_, ()> {
+        Box::pin(async move {
+            let _guard = self.channel.lock_async().await;
+            self.issue_flush();
+            self.channel.wait_async().await.map(|_| ())
+        })
+    }
+}
+
+/// Whether a channel uses its PCI BARs and IRQ line instead of the legacy
+/// ports and IRQs.
+fn is_native(header: &Header, kind: ChannelKind) -> bool {
+    let bit = match kind {
+        ChannelKind::Primary => PRIMARY_NATIVE,
+        ChannelKind::Secondary => SECONDARY_NATIVE,
+    };
+    header.prog_if & bit != 0
+}
+
+/// Returns the I/O and control ports of a channel, which are either the
+/// legacy ISA ports or, in native mode, taken from the controller's BARs.
+fn channel_ports(header: &Header, kind: ChannelKind) -> Option<(u16, u16)> {
+    let (bar_index, legacy) = match kind {
+        ChannelKind::Primary => (0, (PRIMARY_IO, PRIMARY_CONTROL)),
+        ChannelKind::Secondary => (2, (SECONDARY_IO, SECONDARY_CONTROL)),
+    };
+    if !is_native(header, kind) {
+        return Some(legacy);
+    }
+
+    let bars = &header
+        .rest_of_header
+        .to_standard()
+        .ok()?
+        .base_address_registers;
+    match (&bars[bar_index], &bars[bar_index + 1]) {
+        // The control block register sits at offset 2 of its 4 byte BAR
+        (BAR::IO(io), BAR::IO(control)) => Some((io.address as u16, control.address as u16 + 2)),
+        _ => None,
+    }
+}
+
+/// Finds IDE controllers on the PCI bus and identifies the drives on both
+/// of their channels.
+pub fn probe() -> Vec<Arc<AtaDrive>> {
+    let mut drives = Vec::new();
+
+    for header in pci::get_devices_by_class(MASS_STORAGE_CLASS, IDE_SUBCLASS) {
+        println!(
+            "ata: IDE controller {:04x}:{:04x} at {}:{}.{}",
+            header.vendor_id, header.device_id, header.bus, header.device, header.function
+        );
+        // Enable I/O space decoding
+        pci_config_write_word(
+            header.bus,
+            header.device,
+            header.function,
+            0x4,
+            header.command | 0x1,
+        );
+
+        for kind in [ChannelKind::Primary, ChannelKind::Secondary] {
+            let Some((io_base, control_base)) = channel_ports(&header, kind) else {
+                continue;
+            };
+            let native = is_native(&header, kind);
+            let channel = Arc::new(Channel::new(kind, io_base, control_base, native));
+            channel.set_control(0);
+            if native {
+                println!("ata: {:?} channel is in native mode, polling only", kind);
+            } else {
+                // The legacy IRQs are only ever raised by legacy channels
+                interrupts::unmask_irq(match kind {
+                    ChannelKind::Primary => PRIMARY_IRQ,
+                    ChannelKind::Secondary => SECONDARY_IRQ,
+                });
+            }
+
+            for slave in [false, true] {
+                if let Some(drive) = AtaDrive::identify(&channel, slave) {
+                    println!(
+                        "ata: {:?} {}: {} ({} MiB{})",
+                        kind,
+                        if slave { "slave" } else { "master" },
+                        drive.model,
+                        drive.sector_count * SECTOR_SIZE as u64 / (1024 * 1024),
+                        if drive.lba48 { ", LBA48" } else { "" }
+                    );
+                    drives.push(Arc::new(drive));
+                }
+            }
+        }
+    }
+
+    drives
+}
+
+fn handle_interrupt(kind: ChannelKind, io_base: u16, index: InterruptIndex) {
+    // Reading the status register acknowledges the interrupt on the drive
+    unsafe { Port::<u8>::new(io_base + REG_STATUS).read() };
+    IRQ_PENDING[kind.index()].store(true, Ordering::SeqCst);
+    IRQ_WAKERS[kind.index()].wake();
+
+    unsafe {
+        PICS.lock().notify_end_of_interrupt(index.as_u8());
+    }
+}
+
+pub extern "x86-interrupt" fn primary_ata_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    handle_interrupt(ChannelKind::Primary, PRIMARY_IO, InterruptIndex::PrimaryAta);
+}
+
+pub extern "x86-interrupt" fn secondary_ata_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    handle_interrupt(
+        ChannelKind::Secondary,
+        SECONDARY_IO,
+        InterruptIndex::SecondaryAta,
+    );
+}
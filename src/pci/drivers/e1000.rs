@@ -0,0 +1,436 @@
+use crate::{
+    interrupts,
+    memory::{self, DmaBuffer, MmioRegion},
+    networking::device::{
+        FrameFuture, MacAddress, NetCounters, NetError, NetStats, NetworkDevice, MAX_FRAME_SIZE,
+    },
+    pci::{self, headers::Header, pci_config_write_word},
+    println,
+};
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
+use core::{
+    future::poll_fn,
+    sync::atomic::{fence, AtomicBool, Ordering},
+    task::Poll,
+};
+use futures_util::task::AtomicWaker;
+use spin::Mutex;
+use x86_64::PhysAddr;
+
+const INTEL_VENDOR_ID: u16 = 0x8086;
+
+/// 82540EM (QEMU's `e1000`), 82545EM, 82543GC and 82574L (QEMU's `e1000e`).
+const DEVICE_IDS: [u16; 4] = [0x100E, 0x100F, 0x1004, 0x10D3];
+const E1000E_DEVICE_ID: u16 = 0x10D3;
+
+const REGISTERS_SIZE: usize = 0x20000;
+
+// Registers
+const REG_CTRL: usize = 0x0000;
+const REG_STATUS: usize = 0x0008;
+const REG_EERD: usize = 0x0014;
+const REG_ICR: usize = 0x00C0;
+const REG_IMS: usize = 0x00D0;
+const REG_IMC: usize = 0x00D8;
+const REG_RCTL: usize = 0x0100;
+const REG_TCTL: usize = 0x0400;
+const REG_TIPG: usize = 0x0410;
+const REG_RDBAL: usize = 0x2800;
+const REG_RDBAH: usize = 0x2804;
+const REG_RDLEN: usize = 0x2808;
+const REG_RDH: usize = 0x2810;
+const REG_RDT: usize = 0x2818;
+const REG_TDBAL: usize = 0x3800;
+const REG_TDBAH: usize = 0x3804;
+const REG_TDLEN: usize = 0x3808;
+const REG_TDH: usize = 0x3810;
+const REG_TDT: usize = 0x3818;
+const REG_MTA: usize = 0x5200;
+const REG_RAL: usize = 0x5400;
+const REG_RAH: usize = 0x5404;
+
+const CTRL_ASDE: u32 = 1 << 5;
+const CTRL_SLU: u32 = 1 << 6;
+const CTRL_RST: u32 = 1 << 26;
+
+const STATUS_LU: u32 = 1 << 1;
+
+/// The EEPROM read register differs between the older parts and 82574.
+const EERD_START: u32 = 1;
+const EERD_DONE: u32 = 1 << 4;
+const EERD_ADDR_SHIFT: u32 = 8;
+const E1000E_EERD_DONE: u32 = 1 << 1;
+const E1000E_EERD_ADDR_SHIFT: u32 = 2;
+
+/// Receive address valid bit in RAH.
+const RAH_AV: u32 = 1 << 31;
+
+const INT_TXDW: u32 = 1 << 0;
+const INT_LSC: u32 = 1 << 2;
+const INT_RXDMT0: u32 = 1 << 4;
+const INT_RXO: u32 = 1 << 6;
+const INT_RXT0: u32 = 1 << 7;
+
+const RCTL_EN: u32 = 1 << 1;
+/// Accept every multicast frame, which IPv6 neighbor discovery needs.
+const RCTL_MPE: u32 = 1 << 4;
+const RCTL_BAM: u32 = 1 << 15;
+/// Strip the Ethernet CRC from received frames.
+const RCTL_SECRC: u32 = 1 << 26;
+
+const TCTL_EN: u32 = 1 << 1;
+/// Pad short packets to the minimum frame size.
+const TCTL_PSP: u32 = 1 << 3;
+const TCTL_CT: u32 = 0x10 << 4;
+const TCTL_COLD: u32 = 0x40 << 12;
+/// Inter packet gap values the manual recommends for copper.
+const TIPG_DEFAULT: u32 = 10 | 8 << 10 | 6 << 20;
+
+const RX_DESC_COUNT: usize = 32;
+const TX_DESC_COUNT: usize = 32;
+const DESC_SIZE: usize = 16;
+/// Matches the 2048 byte receive buffer size RCTL selects by default.
+const BUFFER_SIZE: usize = 2048;
+
+const DESC_STATUS_DD: u8 = 1 << 0;
+const DESC_STATUS_EOP: u8 = 1 << 1;
+
+const TX_CMD_EOP: u8 = 1 << 0;
+const TX_CMD_IFCS: u8 = 1 << 1;
+const TX_CMD_RS: u8 = 1 << 3;
+
+/// A descriptor ring and the buffers its descriptors point at.
+struct Ring {
+    descriptors: DmaBuffer,
+    buffers: DmaBuffer,
+    /// Next descriptor to look at.
+    next: usize,
+}
+
+impl Ring {
+    fn new(count: usize) -> Option<Ring> {
+        Some(Ring {
+            descriptors: memory::alloc_dma(count * DESC_SIZE)?,
+            buffers: memory::alloc_dma(count * BUFFER_SIZE)?,
+            next: 0,
+        })
+    }
+
+    fn descriptor(&mut self, index: usize) -> &mut [u8] {
+        &mut self.descriptors.as_mut_slice()[index * DESC_SIZE..][..DESC_SIZE]
+    }
+
+    fn buffer_addr(&self, index: usize) -> PhysAddr {
+        self.buffers.phys_addr() + (index * BUFFER_SIZE) as u64
+    }
+
+    fn buffer(&mut self, index: usize) -> &mut [u8] {
+        &mut self.buffers.as_mut_slice()[index * BUFFER_SIZE..][..BUFFER_SIZE]
+    }
+}
+
+/// An Intel 8254x or 82574 network card.
+pub struct E1000 {
+    regs: MmioRegion,
+    mac: MacAddress,
+    rx: Mutex<Ring>,
+    tx: Mutex<Ring>,
+    rx_waker: AtomicWaker,
+    link_up: AtomicBool,
+    stats: NetCounters,
+}
+
+impl E1000 {
+    fn new(header: &Header) -> Result<E1000, &'static str> {
+        let bar = header.memory_bar_address(0).ok_or("no memory BAR0")?;
+        let regs = memory::map_mmio(PhysAddr::new(bar), REGISTERS_SIZE)
+            .map_err(|_| "failed to map registers")?;
+
+        // Enable memory decoding and bus mastering
+        pci_config_write_word(
+            header.bus,
+            header.device,
+            header.function,
+            0x4,
+            header.command | 0x6,
+        );
+
+        reset(&regs);
+
+        regs.write_u32(REG_CTRL, regs.read_u32(REG_CTRL) | CTRL_SLU | CTRL_ASDE);
+        for i in 0..128 {
+            regs.write_u32(REG_MTA + i * 4, 0);
+        }
+
+        let mac = read_mac(&regs, header.device_id == E1000E_DEVICE_ID);
+        // Make sure the card accepts frames for the address we report
+        regs.write_u32(
+            REG_RAL,
+            u32::from_le_bytes([mac[0], mac[1], mac[2], mac[3]]),
+        );
+        regs.write_u32(
+            REG_RAH,
+            u16::from_le_bytes([mac[4], mac[5]]) as u32 | RAH_AV,
+        );
+
+        let mut rx = Ring::new(RX_DESC_COUNT).ok_or("out of DMA memory")?;
+        for i in 0..RX_DESC_COUNT {
+            let addr = rx.buffer_addr(i);
+            let descriptor = rx.descriptor(i);
+            descriptor.fill(0);
+            descriptor[0..8].copy_from_slice(&addr.as_u64().to_le_bytes());
+        }
+        let rx_base = rx.descriptors.phys_addr().as_u64();
+        regs.write_u32(REG_RDBAL, rx_base as u32);
+        regs.write_u32(REG_RDBAH, (rx_base >> 32) as u32);
+        regs.write_u32(REG_RDLEN, (RX_DESC_COUNT * DESC_SIZE) as u32);
+        regs.write_u32(REG_RDH, 0);
+        // Everything but the descriptor at the tail belongs to the card
+        regs.write_u32(REG_RDT, (RX_DESC_COUNT - 1) as u32);
+        regs.write_u32(REG_RCTL, RCTL_EN | RCTL_MPE | RCTL_BAM | RCTL_SECRC);
+
+        let mut tx = Ring::new(TX_DESC_COUNT).ok_or("out of DMA memory")?;
+        for i in 0..TX_DESC_COUNT {
+            let addr = tx.buffer_addr(i);
+            let descriptor = tx.descriptor(i);
+            descriptor.fill(0);
+            descriptor[0..8].copy_from_slice(&addr.as_u64().to_le_bytes());
+            // Unused descriptors count as done so they can be taken
+            descriptor[12] = DESC_STATUS_DD;
+        }
+        let tx_base = tx.descriptors.phys_addr().as_u64();
+        regs.write_u32(REG_TDBAL, tx_base as u32);
+        regs.write_u32(REG_TDBAH, (tx_base >> 32) as u32);
+        regs.write_u32(REG_TDLEN, (TX_DESC_COUNT * DESC_SIZE) as u32);
+        regs.write_u32(REG_TDH, 0);
+        regs.write_u32(REG_TDT, 0);
+        regs.write_u32(REG_TIPG, TIPG_DEFAULT);
+        regs.write_u32(REG_TCTL, TCTL_EN | TCTL_PSP | TCTL_CT | TCTL_COLD);
+
+        Ok(E1000 {
+            link_up: AtomicBool::new(regs.read_u32(REG_STATUS) & STATUS_LU != 0),
+            regs,
+            mac,
+            rx: Mutex::new(rx),
+            tx: Mutex::new(tx),
+            rx_waker: AtomicWaker::new(),
+            stats: NetCounters::default(),
+        })
+    }
+
+    /// Stops the card, so it no longer touches the rings.
+    fn reset(&self) {
+        reset(&self.regs);
+    }
+
+    fn enable_interrupts(&self) {
+        self.regs.write_u32(
+            REG_IMS,
+            INT_TXDW | INT_LSC | INT_RXDMT0 | INT_RXO | INT_RXT0,
+        );
+    }
+
+    /// Reading ICR acknowledges every pending cause.
+    fn handle_interrupt(&self) {
+        let cause = self.regs.read_u32(REG_ICR);
+        if cause & INT_LSC != 0 {
+            let up = self.regs.read_u32(REG_STATUS) & STATUS_LU != 0;
+            self.link_up.store(up, Ordering::SeqCst);
+        }
+        if cause & INT_RXO != 0 {
+            self.stats.rx_dropped();
+        }
+        if cause & (INT_RXT0 | INT_RXDMT0 | INT_RXO) != 0 {
+            self.rx_waker.wake();
+        }
+    }
+
+    /// Copies out the next received frame and gives its descriptor back.
+    fn poll_receive(&self) -> Option<Vec<u8>> {
+        let mut rx = self.rx.lock();
+        loop {
+            let index = rx.next;
+            let descriptor = rx.descriptor(index);
+            let status = descriptor[12];
+            if status & DESC_STATUS_DD == 0 {
+                return None;
+            }
+            fence(Ordering::SeqCst);
+            let len = u16::from_le_bytes([descriptor[8], descriptor[9]]) as usize;
+            let errors = descriptor[13];
+            descriptor[12] = 0;
+
+            // Frames never span buffers, since they are all large enough
+            let frame = if status & DESC_STATUS_EOP != 0 && errors == 0 {
+                Some(rx.buffer(index)[..len.min(BUFFER_SIZE)].to_vec())
+            } else {
+                None
+            };
+
+            rx.next = (index + 1) % RX_DESC_COUNT;
+            self.regs.write_u32(REG_RDT, index as u32);
+            match frame {
+                Some(frame) => {
+                    self.stats.received(frame.len());
+                    return Some(frame);
+                }
+                None => self.stats.rx_error(),
+            }
+        }
+    }
+}
+
+/// Reads the MAC address the firmware left in the receive address
+/// registers, or from the EEPROM if it didn't.
+fn read_mac(regs: &MmioRegion, e1000e: bool) -> MacAddress {
+    let high = regs.read_u32(REG_RAH);
+    if high & RAH_AV != 0 {
+        let low = regs.read_u32(REG_RAL).to_le_bytes();
+        let high = high.to_le_bytes();
+        return [low[0], low[1], low[2], low[3], high[0], high[1]];
+    }
+
+    let mut mac = [0; 6];
+    for word in 0..3 {
+        let data = read_eeprom(regs, word as u32, e1000e).to_le_bytes();
+        mac[word * 2] = data[0];
+        mac[word * 2 + 1] = data[1];
+    }
+    mac
+}
+
+fn read_eeprom(regs: &MmioRegion, address: u32, e1000e: bool) -> u16 {
+    let (done, shift) = if e1000e {
+        (E1000E_EERD_DONE, E1000E_EERD_ADDR_SHIFT)
+    } else {
+        (EERD_DONE, EERD_ADDR_SHIFT)
+    };
+    regs.write_u32(REG_EERD, EERD_START | address << shift);
+    for _ in 0..100_000 {
+        let value = regs.read_u32(REG_EERD);
+        if value & done != 0 {
+            return (value >> 16) as u16;
+        }
+        core::hint::spin_loop();
+    }
+    0
+}
+
+impl NetworkDevice for E1000 {
+    fn driver(&self) -> &'static str {
+        "e1000"
+    }
+
+    fn mac(&self) -> MacAddress {
+        self.mac
+    }
+
+    fn link_up(&self) -> bool {
+        self.link_up.load(Ordering::SeqCst)
+    }
+
+    fn stats(&self) -> NetStats {
+        self.stats.snapshot()
+    }
+
+    fn send(&self, frame: &[u8]) -> Result<(), NetError> {
+        if frame.len() > MAX_FRAME_SIZE {
+            return Err(NetError::FrameTooLarge);
+        }
+
+        let mut tx = self.tx.lock();
+        let index = tx.next;
+        if tx.descriptor(index)[12] & DESC_STATUS_DD == 0 {
+            return Err(NetError::QueueFull);
+        }
+        tx.buffer(index)[..frame.len()].copy_from_slice(frame);
+
+        let descriptor = tx.descriptor(index);
+        descriptor[8..10].copy_from_slice(&(frame.len() as u16).to_le_bytes());
+        descriptor[10] = 0;
+        descriptor[11] = TX_CMD_EOP | TX_CMD_IFCS | TX_CMD_RS;
+        descriptor[12] = 0;
+        fence(Ordering::SeqCst);
+
+        tx.next = (index + 1) % TX_DESC_COUNT;
+        self.regs.write_u32(REG_TDT, tx.next as u32);
+        self.stats.sent(frame.len());
+        Ok(())
+    }
+
+    fn receive(&self) -> FrameFuture<'_> {
+        Box::pin(poll_fn(|cx| {
+            if let Some(frame) = self.poll_receive() {
+                return Poll::Ready(frame);
+            }
+            self.rx_waker.register(cx.waker());
+            match self.poll_receive() {
+                Some(frame) => Poll::Ready(frame),
+                None => Poll::Pending,
+            }
+        }))
+    }
+}
+
+/// Resets the card and leaves its interrupts masked.
+fn reset(regs: &MmioRegion) {
+    regs.write_u32(REG_IMC, u32::MAX);
+    regs.write_u32(REG_CTRL, regs.read_u32(REG_CTRL) | CTRL_RST);
+    // The reset bit clears itself once the reset is done
+    for _ in 0..1_000_000 {
+        if regs.read_u32(REG_CTRL) & CTRL_RST == 0 {
+            break;
+        }
+        core::hint::spin_loop();
+    }
+    regs.write_u32(REG_IMC, u32::MAX);
+    regs.read_u32(REG_ICR);
+}
+
+/// Sets up every supported Intel card on the PCI bus.
+pub fn probe() -> Vec<Arc<E1000>> {
+    let mut cards = Vec::new();
+
+    let functions = pci::functions().iter().filter(|function| {
+        function.vendor_id == INTEL_VENDOR_ID && DEVICE_IDS.contains(&function.device_id)
+    });
+    for function in functions {
+        let device_id = function.device_id;
+        let Ok(header) = Header::new(function.bus, function.device, function.function) else {
+            continue;
+        };
+        let irq = match header.rest_of_header.to_standard() {
+            Ok(standard) => standard.interrupt_line,
+            Err(_) => continue,
+        };
+
+        let card = match E1000::new(&header) {
+            Ok(card) => Arc::new(card),
+            Err(err) => {
+                println!("e1000: {:04x}: {}", device_id, err);
+                continue;
+            }
+        };
+        let handler = card.clone();
+        if interrupts::register_irq_handler(irq, Box::new(move || handler.handle_interrupt()))
+            .is_err()
+        {
+            println!("e1000: can't take interrupts on IRQ {}", irq);
+            // The rings are freed with the card, so it has to stop using them
+            card.reset();
+            continue;
+        }
+        card.enable_interrupts();
+
+        println!(
+            "e1000: {:04x} {:02x?} on IRQ {}, link {}",
+            device_id,
+            card.mac,
+            irq,
+            if card.link_up() { "up" } else { "down" }
+        );
+        cards.push(card);
+    }
+
+    cards
+}
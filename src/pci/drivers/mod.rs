@@ -1 +1,5 @@
+pub mod ahci;
+pub mod ata;
+pub mod e1000;
 pub mod rtl8139;
+pub mod virtio;
@@ -1,55 +1,149 @@
 use crate::{
-    interrupts::{self, IDT},
-    memory,
+    interrupts,
+    memory::{self, DmaBuffer},
+    networking::device::{
+        FrameFuture, MacAddress, NetCounters, NetError, NetStats, NetworkDevice, MAX_FRAME_SIZE,
+    },
     pci::{
         self,
-        headers::{Header, HeaderType, IOBar, BAR},
+        headers::{IOBar, BAR},
         pci_config_write_word,
     },
-    print, println,
+    println,
 };
 
-const BUFFER_SIZE: usize = 8192 + 16;
-
-static mut BUFFER: [u8; BUFFER_SIZE] = [0; BUFFER_SIZE];
-
-use x86_64::{
-    instructions::port::{Port, PortWriteOnly},
-    structures::idt::InterruptStackFrame,
-    VirtAddr,
+/// Size of the receive ring selected in RCR.
+const RX_RING_SIZE: usize = 8192;
+/// With WRAP set the card writes a frame past the end of the ring instead
+/// of wrapping it, so there has to be room for one more frame plus the
+/// 16 bytes the card reserves.
+const RX_BUFFER_SIZE: usize = RX_RING_SIZE + 16 + 1536;
+
+/// Received frames held for consumers before new ones get dropped.
+const RX_QUEUE_LEN: usize = 32;
+
+/// The card has four transmit descriptors, used in turn.
+const TX_SLOTS: usize = 4;
+/// Each transmit buffer holds one frame.
+const TX_BUFFER_SIZE: usize = 2048;
+/// Shorter frames are padded, since the card doesn't do it itself.
+const MIN_TX_FRAME_SIZE: usize = 60;
+
+// Registers
+const REG_TSD: u16 = 0x10;
+const REG_TSAD: u16 = 0x20;
+const REG_CR: u16 = 0x37;
+const REG_CAPR: u16 = 0x38;
+const REG_IMR: u16 = 0x3C;
+const REG_ISR: u16 = 0x3E;
+const REG_RCR: u16 = 0x44;
+const REG_MSR: u16 = 0x58;
+
+const CR_BUFE: u8 = 1 << 0;
+const CR_TE: u8 = 1 << 2;
+const CR_RE: u8 = 1 << 3;
+
+/// Set while the link is down.
+const MSR_LINKB: u8 = 1 << 2;
+
+const INT_ROK: u16 = 1 << 0;
+const INT_RER: u16 = 1 << 1;
+const INT_TOK: u16 = 1 << 2;
+const INT_TER: u16 = 1 << 3;
+const INT_RXOVW: u16 = 1 << 4;
+const INT_FOVW: u16 = 1 << 6;
+
+const TSD_OWN: u32 = 1 << 13;
+const TSD_TUN: u32 = 1 << 14;
+const TSD_TOK: u32 = 1 << 15;
+const TSD_TABT: u32 = 1 << 30;
+
+/// Accept broadcast, multicast, our address and all physical addresses,
+/// with WRAP set.
+const RCR_CONFIG: u32 = 0xF | 1 << 7;
+
+/// Receive status bit in the header in front of every frame.
+const RX_STATUS_ROK: u16 = 1 << 0;
+/// Length of the header the card puts in front of each frame.
+const RX_HEADER_SIZE: usize = 4;
+const CRC_SIZE: usize = 4;
+/// Smallest frame the card passes on, including the CRC.
+const MIN_FRAME_SIZE: usize = 64;
+
+/// A received frame, in a buffer that is reused once it is consumed so the
+/// interrupt handler doesn't have to allocate.
+type FrameBuffer = Box<[u8; MAX_FRAME_SIZE]>;
+
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
+use core::{
+    future::poll_fn,
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    task::{Context, Poll},
+};
+use crossbeam_queue::ArrayQueue;
+use futures_util::{
+    stream::{Stream, StreamExt},
+    task::AtomicWaker,
 };
+use spin::Mutex;
+use x86_64::instructions::port::Port;
 
 #[derive(Debug)]
 pub enum Rtl8139Error {
     InvalidHeader,
+    OutOfMemory,
+    NoInterrupt,
+    /// The card never finished its software reset.
+    ResetTimeout,
 }
 
 #[derive(Debug)]
 pub struct Rtl8139 {
     bar: IOBar,
-    eeprom_exists: bool,
     pub mac: [u8; 6],
-    tx_cur: u32,
+    tx: Mutex<TxRing>,
+    tx_slots: [TxSlot; TX_SLOTS],
+    rx_buffer: DmaBuffer,
+    /// Offset of the next frame in the receive ring. Only the interrupt
+    /// handler touches it.
+    rx_offset: AtomicUsize,
+    /// Frames waiting to be received, and empty buffers for new ones.
+    rx_frames: ArrayQueue<(FrameBuffer, usize)>,
+    rx_free: ArrayQueue<FrameBuffer>,
+    rx_waker: AtomicWaker,
+    stats: NetCounters,
+}
+
+/// The transmit buffers and the descriptor to use next.
+#[derive(Debug)]
+struct TxRing {
+    memory: DmaBuffer,
+    next: usize,
+}
+
+/// State of one transmit descriptor. A descriptor is busy from the moment
+/// its frame is handed to the card until the card reports it sent or
+/// aborted.
+#[derive(Debug, Default)]
+struct TxSlot {
+    busy: AtomicBool,
+    failed: AtomicBool,
+    /// Length of the frame being sent, counted once the card reports it.
+    len: AtomicUsize,
+    waker: AtomicWaker,
 }
 
 const RTL8139_VENDOR_ID: u16 = 0x10EC;
 const RTL8139_DEVICE_ID: u16 = 0x8139;
 
 impl Rtl8139 {
-    pub fn new(phys_mem_offset: VirtAddr) -> Result<Rtl8139, Rtl8139Error> {
+    /// Sets up the card and starts taking its interrupts. The card is
+    /// shared with the interrupt handler, hence the `Arc`.
+    pub fn new() -> Result<Arc<Rtl8139>, Rtl8139Error> {
         let header = pci::get_device(RTL8139_VENDOR_ID, RTL8139_DEVICE_ID)
             .ok_or(Rtl8139Error::InvalidHeader)?;
 
-        if header.vendor_id != 0x10EC {
-            println!("Vendor ID: {}", header.vendor_id);
-            return Err(Rtl8139Error::InvalidHeader);
-        }
-
-        if header.device_id != 0x8139 {
-            println!("Device ID: {}", header.device_id);
-            return Err(Rtl8139Error::InvalidHeader);
-        }
-
         let io_base = if let BAR::IO(base) = &header
             .rest_of_header
             .to_standard()
@@ -58,7 +152,6 @@ impl Rtl8139 {
         {
             base
         } else {
-            println!("Invalid BAR");
             return Err(Rtl8139Error::InvalidHeader);
         };
 
@@ -97,68 +190,275 @@ impl Rtl8139 {
 
         let mut outport = Port::<u8>::new(io_base as u16 + 0x37);
 
-        println!("Resetting...");
-
+        // The reset bit clears itself once the reset is done
         unsafe {
             outport.write(0x10);
-            let mut out = outport.read();
-            while (out & 0x10) != 0 {
-                if out != 0xFF {
-                    println!("Error: {:#X}", out);
-                }
-                out = outport.read();
-            }
+        }
+        let reset = (0..1_000_000).any(|_| unsafe { outport.read() } & 0x10 == 0);
+        if !reset {
+            return Err(Rtl8139Error::ResetTimeout);
         }
 
-        println!("Reset complete");
-
+        let rx_buffer = memory::alloc_dma32(RX_BUFFER_SIZE).ok_or(Rtl8139Error::OutOfMemory)?;
         let mut outport = Port::<u32>::new(io_base as u16 + 0x30);
 
         unsafe {
-            outport.write(
-                memory::translate_addr(VirtAddr::new(BUFFER.as_ptr() as u64), phys_mem_offset)
-                    .ok_or(Rtl8139Error::InvalidHeader)?
-                    .as_u64() as u32,
-            );
+            outport.write(rx_buffer.phys_addr().as_u64() as u32);
         }
 
-        // Set TOK and ROK
-        let mut outport = Port::<u8>::new(io_base as u16 + 0x3C);
+        let tx_buffer =
+            memory::alloc_dma32(TX_SLOTS * TX_BUFFER_SIZE).ok_or(Rtl8139Error::OutOfMemory)?;
+        for slot in 0..TX_SLOTS {
+            let mut outport = Port::<u32>::new(io_base + REG_TSAD + 4 * slot as u16);
+            let address = tx_buffer.phys_addr() + (slot * TX_BUFFER_SIZE) as u64;
 
-        unsafe {
-            outport.write(0x0005);
+            unsafe {
+                outport.write(address.as_u64() as u32);
+            }
         }
 
-        let mut outport = Port::<u32>::new(io_base as u16 + 0x44);
+        let mut outport = Port::<u16>::new(io_base + REG_IMR);
 
         unsafe {
-            outport.write(0xf | (1 << 7));
+            outport.write(INT_ROK | INT_RER | INT_TOK | INT_TER | INT_RXOVW | INT_FOVW);
         }
 
-        let mut outport = Port::<u8>::new(io_base as u16 + 0x37);
+        let mut outport = Port::<u32>::new(io_base + REG_RCR);
 
         unsafe {
-            outport.write(0x0C);
+            outport.write(RCR_CONFIG);
         }
 
-        let mut outport = Port::<u16>::new(io_base as u16 + 0x3E);
+        let mut outport = Port::<u8>::new(io_base + REG_CR);
 
         unsafe {
-            outport.write(interrupts::InterruptIndex::RTL8139.as_usize() as u16);
+            outport.write(CR_RE | CR_TE);
+        }
+
+        let irq = header
+            .rest_of_header
+            .to_standard()
+            .map_err(|_| Rtl8139Error::InvalidHeader)?
+            .interrupt_line;
+
+        let rx_free = ArrayQueue::new(RX_QUEUE_LEN);
+        for _ in 0..RX_QUEUE_LEN {
+            let _ = rx_free.push(Box::new([0; MAX_FRAME_SIZE]));
         }
 
         let mut out = Rtl8139 {
             bar: bar.clone(),
-            eeprom_exists: false,
             mac: [0; 6],
-            tx_cur: 0,
+            tx: Mutex::new(TxRing {
+                memory: tx_buffer,
+                next: 0,
+            }),
+            tx_slots: Default::default(),
+            rx_buffer,
+            rx_offset: AtomicUsize::new(0),
+            rx_frames: ArrayQueue::new(RX_QUEUE_LEN),
+            rx_free,
+            rx_waker: AtomicWaker::new(),
+            stats: NetCounters::default(),
         };
 
         out.set_mac_address();
 
+        let out = Arc::new(out);
+        let handler = out.clone();
+        interrupts::register_irq_handler(irq, Box::new(move || handler.handle_interrupt()))
+            .map_err(|_| Rtl8139Error::NoInterrupt)?;
+        println!("RTL8139 on IRQ {}", irq);
+
         Ok(out)
     }
 
+    fn read_u8(&self, register: u16) -> u8 {
+        unsafe { Port::<u8>::new(self.bar.address as u16 + register).read() }
+    }
+
+    fn read_u16(&self, register: u16) -> u16 {
+        unsafe { Port::<u16>::new(self.bar.address as u16 + register).read() }
+    }
+
+    fn read_u32(&self, register: u16) -> u32 {
+        unsafe { Port::<u32>::new(self.bar.address as u16 + register).read() }
+    }
+
+    fn write_u8(&self, register: u16, value: u8) {
+        unsafe { Port::<u8>::new(self.bar.address as u16 + register).write(value) }
+    }
+
+    fn write_u16(&self, register: u16, value: u16) {
+        unsafe { Port::<u16>::new(self.bar.address as u16 + register).write(value) }
+    }
+
+    fn write_u32(&self, register: u16, value: u32) {
+        unsafe { Port::<u32>::new(self.bar.address as u16 + register).write(value) }
+    }
+
+    /// Called from the interrupt handler, so it must not block or allocate.
+    fn handle_interrupt(&self) {
+        let status = self.read_u16(REG_ISR);
+        // Writing the bits back acknowledges them
+        self.write_u16(REG_ISR, status);
+
+        if status & (INT_RXOVW | INT_FOVW) != 0 {
+            self.stats.rx_error();
+        }
+        if status & (INT_ROK | INT_RER | INT_RXOVW | INT_FOVW) != 0 {
+            self.receive_frames();
+        }
+        if status & (INT_TOK | INT_TER) != 0 {
+            self.collect_transmits();
+        }
+    }
+
+    /// Frees the transmit descriptors the card is done with and wakes
+    /// whoever waits for them. Safe to call from the interrupt handler and
+    /// the send path at once, as only one of them can take a slot from busy
+    /// to free.
+    fn collect_transmits(&self) {
+        for (index, slot) in self.tx_slots.iter().enumerate() {
+            if !slot.busy.load(Ordering::SeqCst) {
+                continue;
+            }
+            let status = self.read_u32(REG_TSD + 4 * index as u16);
+            if status & (TSD_TOK | TSD_TABT) == 0 {
+                continue;
+            }
+            // An underrun means the frame went out damaged
+            let failed = status & (TSD_TABT | TSD_TUN) != 0;
+            slot.failed.store(failed, Ordering::SeqCst);
+            if slot.busy.swap(false, Ordering::SeqCst) {
+                if failed {
+                    self.stats.tx_error();
+                } else {
+                    self.stats.sent(slot.len.load(Ordering::SeqCst));
+                }
+                slot.waker.wake();
+            }
+        }
+    }
+
+    /// Hands `frame` to the next transmit descriptor, padded to the minimum
+    /// frame size, and returns the descriptor's index.
+    fn start_transmit(&self, frame: &[u8]) -> Result<usize, NetError> {
+        if frame.len() > MAX_FRAME_SIZE {
+            return Err(NetError::FrameTooLarge);
+        }
+
+        let mut tx = self.tx.lock();
+        let index = tx.next;
+        let slot = &self.tx_slots[index];
+        if slot.busy.load(Ordering::SeqCst) {
+            self.collect_transmits();
+            if slot.busy.load(Ordering::SeqCst) {
+                return Err(NetError::QueueFull);
+            }
+        }
+
+        let buffer = &mut tx.memory.as_mut_slice()[index * TX_BUFFER_SIZE..][..TX_BUFFER_SIZE];
+        buffer[..frame.len()].copy_from_slice(frame);
+        let len = frame.len().max(MIN_TX_FRAME_SIZE);
+        buffer[frame.len()..len].fill(0);
+
+        slot.failed.store(false, Ordering::SeqCst);
+        slot.len.store(frame.len(), Ordering::SeqCst);
+        slot.busy.store(true, Ordering::SeqCst);
+        // Writing the size clears OWN, which starts the transmission
+        self.write_u32(REG_TSD + 4 * index as u16, len as u32 & !TSD_OWN);
+        tx.next = (index + 1) % TX_SLOTS;
+        Ok(index)
+    }
+
+    /// Sends `frame` and waits until the card has transmitted it.
+    pub async fn send_and_wait(&self, frame: &[u8]) -> Result<(), NetError> {
+        let slot = &self.tx_slots[self.start_transmit(frame)?];
+        poll_fn(|cx| {
+            if !slot.busy.load(Ordering::SeqCst) {
+                return Poll::Ready(());
+            }
+            slot.waker.register(cx.waker());
+            self.collect_transmits();
+            if slot.busy.load(Ordering::SeqCst) {
+                Poll::Pending
+            } else {
+                Poll::Ready(())
+            }
+        })
+        .await;
+
+        if slot.failed.load(Ordering::SeqCst) {
+            Err(NetError::Io)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Moves every frame in the receive ring to the frame queue.
+    fn receive_frames(&self) {
+        let ring = self.rx_buffer.as_slice();
+        let mut offset = self.rx_offset.load(Ordering::Relaxed);
+
+        while self.read_u8(REG_CR) & CR_BUFE == 0 {
+            let status = u16::from_le_bytes([ring[offset], ring[offset + 1]]);
+            let length = u16::from_le_bytes([ring[offset + 2], ring[offset + 3]]) as usize;
+
+            if status & RX_STATUS_ROK == 0
+                || !(MIN_FRAME_SIZE..=MAX_FRAME_SIZE + CRC_SIZE).contains(&length)
+            {
+                // The ring can't be walked past a bad header
+                self.stats.rx_error();
+                self.reset_receiver();
+                return;
+            }
+
+            let frame = &ring[offset + RX_HEADER_SIZE..][..length - CRC_SIZE];
+            match self.rx_free.pop() {
+                Ok(mut buffer) => {
+                    buffer[..frame.len()].copy_from_slice(frame);
+                    let _ = self.rx_frames.push((buffer, frame.len()));
+                    self.stats.received(frame.len());
+                    self.rx_waker.wake();
+                }
+                Err(_) => {
+                    self.stats.rx_dropped();
+                }
+            }
+
+            // Frames start on dword boundaries, and the WRAP bit means a
+            // frame can end past the ring, so wrap the offset here
+            offset = (offset + RX_HEADER_SIZE + length + 3) & !3;
+            offset %= RX_RING_SIZE;
+            self.rx_offset.store(offset, Ordering::Relaxed);
+            // CAPR lags 16 bytes behind the real read pointer
+            self.write_u16(REG_CAPR, (offset as u16).wrapping_sub(16));
+        }
+    }
+
+    /// Restarts reception from the start of the ring after a bad frame.
+    fn reset_receiver(&self) {
+        self.write_u8(REG_CR, CR_TE);
+        self.rx_offset.store(0, Ordering::Relaxed);
+        self.write_u32(0x30, self.rx_buffer.phys_addr().as_u64() as u32);
+        self.write_u8(REG_CR, CR_RE | CR_TE);
+        self.write_u32(REG_RCR, RCR_CONFIG);
+    }
+
+    /// Takes the next frame the interrupt handler received.
+    fn pop_frame(&self) -> Option<Vec<u8>> {
+        let (buffer, len) = self.rx_frames.pop().ok()?;
+        let frame = buffer[..len].to_vec();
+        let _ = self.rx_free.push(buffer);
+        Some(frame)
+    }
+
+    /// Returns a stream of received frames.
+    pub fn frames(&self) -> FrameStream<'_> {
+        FrameStream { card: self }
+    }
+
     pub fn set_mac_address(&mut self) {
         self.mac = self.get_mac_address();
     }
@@ -181,37 +481,62 @@ impl Rtl8139 {
         mac
     }
 
-    pub fn send_packet(&mut self, packet: &[u8]) {
-        let mut outport = Port::<u32>::new(self.bar.address as u16 + 0x20);
+    /// Queues `packet` for transmission without waiting for it to go out.
+    /// Fails if all four descriptors are still in use.
+    pub fn send_packet(&self, packet: &[u8]) -> Result<(), NetError> {
+        self.start_transmit(packet).map(|_| ())
+    }
+}
 
-        unsafe {
-            outport.write(self.tx_cur);
-        }
+impl NetworkDevice for Rtl8139 {
+    fn driver(&self) -> &'static str {
+        "rtl8139"
+    }
 
-        let mut tx_cur = self.tx_cur as usize;
+    fn mac(&self) -> MacAddress {
+        self.mac
+    }
 
-        for byte in packet {
-            unsafe {
-                BUFFER[tx_cur] = *byte;
-            }
-            tx_cur += 1;
-        }
+    fn link_up(&self) -> bool {
+        self.read_u8(REG_MSR) & MSR_LINKB == 0
+    }
 
-        self.tx_cur = (tx_cur as u32) + 4;
+    fn stats(&self) -> NetStats {
+        self.stats.snapshot()
+    }
 
-        let mut outport = Port::<u32>::new(self.bar.address as u16 + 0x10);
+    fn send(&self, frame: &[u8]) -> Result<(), NetError> {
+        self.send_packet(frame)
+    }
 
-        unsafe {
-            outport.write(0x1);
-        }
+    fn receive(&self) -> FrameFuture<'_> {
+        Box::pin(async move {
+            // The stream never ends
+            self.frames().next().await.unwrap()
+        })
     }
 }
 
-pub extern "x86-interrupt" fn rtl8139_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    let mut outport = Port::<u32>::new(0x20);
-    println!("RTL8139 interrupt");
+/// Frames received by an [`Rtl8139`], in the order they arrived.
+pub struct FrameStream<'a> {
+    card: &'a Rtl8139,
+}
+
+impl Stream for FrameStream<'_> {
+    type Item = Vec<u8>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Vec<u8>>> {
+        if let Some(frame) = self.card.pop_frame() {
+            return Poll::Ready(Some(frame));
+        }
 
-    unsafe {
-        outport.write(0x20);
+        self.card.rx_waker.register(cx.waker());
+        match self.card.pop_frame() {
+            Some(frame) => {
+                self.card.rx_waker.take();
+                Poll::Ready(Some(frame))
+            }
+            None => Poll::Pending,
+        }
     }
 }
@@ -0,0 +1,334 @@
+use super::{
+    find_devices,
+    queue::{Buffer, Virtqueue},
+    VirtioError, VirtioPci, DEVICE_TYPE_BLOCK, ISR_QUEUE,
+};
+use crate::{
+    block::{check_request, BlockDevice, BlockError, BlockFuture},
+    interrupts,
+    memory::{self, DmaBuffer},
+    println,
+    task::simple_executor::block_on,
+};
+use alloc::{boxed::Box, collections::BTreeMap, sync::Arc, vec::Vec};
+use core::{
+    future::poll_fn,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Poll, Waker},
+};
+use futures_util::task::AtomicWaker;
+use spin::{Mutex, MutexGuard};
+
+pub const SECTOR_SIZE: usize = 512;
+
+const F_RO: u64 = 1 << 5;
+const F_FLUSH: u64 = 1 << 9;
+
+const CONFIG_CAPACITY: usize = 0;
+
+const T_IN: u32 = 0;
+const T_OUT: u32 = 1;
+const T_FLUSH: u32 = 4;
+
+const S_OK: u8 = 0;
+const S_UNSUPP: u8 = 2;
+
+const REQUEST_QUEUE: u16 = 0;
+const MAX_QUEUE_SIZE: u16 = 128;
+
+/// Requests that can be in flight at once. Each one takes three
+/// descriptors: the header, the data and the status byte.
+const MAX_SLOTS: usize = 8;
+/// Bytes moved by one request, the rest of a slot's DMA buffer.
+const SLOT_DATA_SIZE: usize = 32 * 1024;
+const SLOT_DATA_OFFSET: usize = 4096;
+const SLOT_STATUS_OFFSET: usize = 16;
+
+/// DMA memory for one request: its header, status byte and data. A slot is
+/// free while its lock isn't held.
+struct Slot {
+    memory: Mutex<DmaBuffer>,
+    done: AtomicBool,
+    waker: AtomicWaker,
+}
+
+struct Queue {
+    queue: Virtqueue,
+    /// Slots waiting for the device, by the head descriptor of their chain.
+    in_flight: BTreeMap<u16, usize>,
+}
+
+/// A virtio block device. Requests complete through the device's
+/// interrupt, so several can be in flight from different tasks.
+///
+/// The blocking calls spin until their request completes. They must not
+/// be used from a task while other tasks hold every request slot, since
+/// those tasks can't run to release them.
+pub struct VirtioBlk {
+    device: VirtioPci,
+    queue: Mutex<Queue>,
+    slots: Vec<Slot>,
+    /// Tasks waiting for a free slot.
+    slot_waiters: Mutex<Vec<Waker>>,
+    read_only: bool,
+    can_flush: bool,
+    sector_count: u64,
+}
+
+impl VirtioBlk {
+    pub fn new(mut device: VirtioPci) -> Result<VirtioBlk, VirtioError> {
+        let features = device.begin_init(F_RO | F_FLUSH)?;
+        let queue = device.setup_queue(REQUEST_QUEUE, MAX_QUEUE_SIZE)?;
+
+        let slot_count = MAX_SLOTS.min(queue.size() as usize / 3).max(1);
+        let mut slots = Vec::new();
+        for _ in 0..slot_count {
+            let memory = memory::alloc_dma(SLOT_DATA_OFFSET + SLOT_DATA_SIZE)
+                .ok_or(VirtioError::OutOfMemory)?;
+            slots.push(Slot {
+                memory: Mutex::new(memory),
+                done: AtomicBool::new(false),
+                waker: AtomicWaker::new(),
+            });
+        }
+
+        device.finish_init();
+        Ok(VirtioBlk {
+            sector_count: device.config_u64(CONFIG_CAPACITY),
+            device,
+            queue: Mutex::new(Queue {
+                queue,
+                in_flight: BTreeMap::new(),
+            }),
+            slots,
+            slot_waiters: Mutex::new(Vec::new()),
+            read_only: features & F_RO != 0,
+            can_flush: features & F_FLUSH != 0,
+        })
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Acknowledges the interrupt and wakes every waiting request, which
+    /// collect their own completions. The queue lock isn't taken, since
+    /// it is held with interrupts enabled.
+    fn handle_interrupt(&self) {
+        if self.device.read_isr() & ISR_QUEUE != 0 {
+            for slot in &self.slots {
+                slot.waker.wake();
+            }
+        }
+    }
+
+    /// Moves finished requests from the used ring to their slots.
+    fn collect_completions(&self) {
+        let mut queue = self.queue.lock();
+        while let Some((head, _)) = queue.queue.pop_used() {
+            if let Some(index) = queue.in_flight.remove(&head) {
+                self.slots[index].done.store(true, Ordering::SeqCst);
+                self.slots[index].waker.wake();
+            }
+        }
+    }
+
+    async fn acquire_slot(&self) -> (usize, MutexGuard<'_, DmaBuffer>) {
+        poll_fn(|cx| {
+            for (index, slot) in self.slots.iter().enumerate() {
+                if let Some(memory) = slot.memory.try_lock() {
+                    return Poll::Ready((index, memory));
+                }
+            }
+            self.slot_waiters.lock().push(cx.waker().clone());
+            // A slot may have been freed before the waker was registered
+            for (index, slot) in self.slots.iter().enumerate() {
+                if let Some(memory) = slot.memory.try_lock() {
+                    return Poll::Ready((index, memory));
+                }
+            }
+            Poll::Pending
+        })
+        .await
+    }
+
+    fn release_slot(&self, memory: MutexGuard<'_, DmaBuffer>) {
+        drop(memory);
+        for waker in self.slot_waiters.lock().drain(..) {
+            waker.wake();
+        }
+    }
+
+    /// Sends one request and waits for it. Written data is read from, and
+    /// read data left in, the slot's data area.
+    async fn request(
+        &self,
+        index: usize,
+        memory: &mut DmaBuffer,
+        kind: u32,
+        sector: u64,
+        len: usize,
+    ) -> Result<(), BlockError> {
+        let header = memory.as_mut_slice();
+        header[0..4].copy_from_slice(&kind.to_le_bytes());
+        header[4..8].fill(0);
+        header[8..16].copy_from_slice(&sector.to_le_bytes());
+        header[SLOT_STATUS_OFFSET] = 0xFF;
+
+        let base = memory.phys_addr();
+        let header = Buffer::readable(base, 16);
+        let status = Buffer::writable(base + SLOT_STATUS_OFFSET as u64, 1);
+        let data = base + SLOT_DATA_OFFSET as u64;
+        let chain = match kind {
+            T_IN => [header, Buffer::writable(data, len), status].to_vec(),
+            T_OUT => [header, Buffer::readable(data, len), status].to_vec(),
+            _ => [header, status].to_vec(),
+        };
+
+        let slot = &self.slots[index];
+        slot.done.store(false, Ordering::SeqCst);
+        {
+            let mut queue = self.queue.lock();
+            // Every slot fits in the queue at once, so this can't fail
+            let head = queue.queue.add(&chain).ok_or(BlockError::Io)?;
+            queue.in_flight.insert(head, index);
+        }
+        self.device.notify(REQUEST_QUEUE);
+
+        poll_fn(|cx| {
+            self.collect_completions();
+            if slot.done.load(Ordering::SeqCst) {
+                return Poll::Ready(());
+            }
+            slot.waker.register(cx.waker());
+            self.collect_completions();
+            if slot.done.load(Ordering::SeqCst) {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        })
+        .await;
+
+        match memory.as_slice()[SLOT_STATUS_OFFSET] {
+            S_OK => Ok(()),
+            S_UNSUPP if kind == T_FLUSH => Ok(()),
+            status => {
+                println!("virtio-blk: request {} failed with status {}", kind, status);
+                Err(BlockError::Io)
+            }
+        }
+    }
+
+    async fn read(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        check_request(self, lba, buf.len())?;
+        for (i, chunk) in buf.chunks_mut(SLOT_DATA_SIZE).enumerate() {
+            let sector = lba + (i * SLOT_DATA_SIZE / SECTOR_SIZE) as u64;
+            let (index, mut memory) = self.acquire_slot().await;
+            let result = self
+                .request(index, &mut memory, T_IN, sector, chunk.len())
+                .await;
+            if result.is_ok() {
+                let data = &memory.as_slice()[SLOT_DATA_OFFSET..];
+                chunk.copy_from_slice(&data[..chunk.len()]);
+            }
+            self.release_slot(memory);
+            result?;
+        }
+        Ok(())
+    }
+
+    async fn write(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        check_request(self, lba, buf.len())?;
+        if self.read_only {
+            return Err(BlockError::ReadOnly);
+        }
+        for (i, chunk) in buf.chunks(SLOT_DATA_SIZE).enumerate() {
+            let sector = lba + (i * SLOT_DATA_SIZE / SECTOR_SIZE) as u64;
+            let (index, mut memory) = self.acquire_slot().await;
+            memory.as_mut_slice()[SLOT_DATA_OFFSET..][..chunk.len()].copy_from_slice(chunk);
+            let result = self
+                .request(index, &mut memory, T_OUT, sector, chunk.len())
+                .await;
+            self.release_slot(memory);
+            result?;
+        }
+        Ok(())
+    }
+
+    async fn flush_cache(&self) -> Result<(), BlockError> {
+        if !self.can_flush {
+            return Ok(());
+        }
+        let (index, mut memory) = self.acquire_slot().await;
+        let result = self.request(index, &mut memory, T_FLUSH, 0, 0).await;
+        self.release_slot(memory);
+        result
+    }
+}
+
+impl BlockDevice for VirtioBlk {
+    fn sector_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.sector_count
+    }
+
+    fn read_sectors(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        block_on(self.read(lba, buf))
+    }
+
+    fn write_sectors(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        block_on(self.write(lba, buf))
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        block_on(self.flush_cache())
+    }
+
+    fn read_sectors_async<'a>(&'a self, lba: u64, buf: &'a mut [u8]) -> BlockFuture<'a, ()> {
+        Box::pin(self.read(lba, buf))
+    }
+
+    fn write_sectors_async<'a>(&'a self, lba: u64, buf: &'a [u8]) -> BlockFuture<'a, ()> {
+        Box::pin(self.write(lba, buf))
+    }
+
+    fn flush_async(&self) -> BlockFuture<'_, ()> {
+        Box::pin(self.flush_cache())
+    }
+}
+
+/// Sets up every virtio block device on the PCI bus.
+pub fn probe() -> Vec<Arc<VirtioBlk>> {
+    let mut drives = Vec::new();
+
+    for header in find_devices(DEVICE_TYPE_BLOCK) {
+        let result = VirtioPci::new(header).and_then(|device| {
+            let irq = device.interrupt_line().ok_or(VirtioError::NoInterrupt)?;
+            let modern = device.is_modern();
+            let drive = Arc::new(VirtioBlk::new(device)?);
+
+            let handler = drive.clone();
+            interrupts::register_irq_handler(irq, Box::new(move || handler.handle_interrupt()))
+                .map_err(|_| VirtioError::NoInterrupt)?;
+            println!(
+                "virtio-blk: {} MiB on IRQ {}, {} transport{}",
+                drive.sector_count * SECTOR_SIZE as u64 / (1024 * 1024),
+                irq,
+                if modern { "modern" } else { "legacy" },
+                if drive.read_only { ", read only" } else { "" }
+            );
+            Ok(drive)
+        });
+
+        match result {
+            Ok(drive) => drives.push(drive),
+            Err(err) => println!("virtio-blk: failed to set up device: {:?}", err),
+        }
+    }
+
+    drives
+}
@@ -0,0 +1,466 @@
+//! The virtio PCI transport, shared by the virtio device drivers.
+//!
+//! Both transports are supported: the legacy one, which has every register
+//! in I/O space behind BAR0, and the modern one, which describes where its
+//! register blocks live with vendor specific PCI capabilities.
+
+pub mod blk;
+pub mod net;
+pub mod queue;
+
+use crate::{
+    memory::{self, MmioRegion},
+    pci::{
+        self,
+        headers::{Header, BAR},
+        pci_config_read_word, pci_config_write_word,
+    },
+    println,
+};
+use alloc::vec::Vec;
+use x86_64::{instructions::port::Port, PhysAddr};
+
+use self::queue::Virtqueue;
+
+pub const VIRTIO_VENDOR_ID: u16 = 0x1AF4;
+
+/// Device types, as found in the subsystem ID of a transitional device or
+/// added to 0x1040 for the device ID of a modern one.
+pub const DEVICE_TYPE_NET: u16 = 1;
+pub const DEVICE_TYPE_BLOCK: u16 = 2;
+
+const LEGACY_DEVICE_IDS: core::ops::RangeInclusive<u16> = 0x1000..=0x103F;
+const MODERN_DEVICE_ID_BASE: u16 = 0x1040;
+
+// Device status bits
+pub const STATUS_ACKNOWLEDGE: u8 = 1;
+pub const STATUS_DRIVER: u8 = 2;
+pub const STATUS_DRIVER_OK: u8 = 4;
+pub const STATUS_FEATURES_OK: u8 = 8;
+pub const STATUS_FAILED: u8 = 0x80;
+
+/// Feature bit every modern device offers and modern drivers must accept.
+pub const F_VERSION_1: u64 = 1 << 32;
+
+/// ISR status bit saying a queue has new used buffers.
+pub const ISR_QUEUE: u8 = 1;
+
+/// How often the status is polled while waiting for a reset to finish.
+const RESET_SPINS: u32 = 1_000_000;
+
+const PCI_CAP_ID_VENDOR: u8 = 0x09;
+const CAP_COMMON_CFG: u8 = 1;
+const CAP_NOTIFY_CFG: u8 = 2;
+const CAP_ISR_CFG: u8 = 3;
+const CAP_DEVICE_CFG: u8 = 4;
+
+// Legacy registers, relative to BAR0
+const LEGACY_DEVICE_FEATURES: u16 = 0x00;
+const LEGACY_DRIVER_FEATURES: u16 = 0x04;
+const LEGACY_QUEUE_ADDRESS: u16 = 0x08;
+const LEGACY_QUEUE_SIZE: u16 = 0x0C;
+const LEGACY_QUEUE_SELECT: u16 = 0x0E;
+const LEGACY_QUEUE_NOTIFY: u16 = 0x10;
+const LEGACY_DEVICE_STATUS: u16 = 0x12;
+const LEGACY_ISR_STATUS: u16 = 0x13;
+/// Device configuration starts here while MSI-X is disabled.
+const LEGACY_DEVICE_CONFIG: u16 = 0x14;
+
+// Common configuration registers of the modern transport
+const COMMON_DEVICE_FEATURE_SELECT: usize = 0x00;
+const COMMON_DEVICE_FEATURE: usize = 0x04;
+const COMMON_DRIVER_FEATURE_SELECT: usize = 0x08;
+const COMMON_DRIVER_FEATURE: usize = 0x0C;
+const COMMON_DEVICE_STATUS: usize = 0x14;
+const COMMON_QUEUE_SELECT: usize = 0x16;
+const COMMON_QUEUE_SIZE: usize = 0x18;
+const COMMON_QUEUE_ENABLE: usize = 0x1C;
+const COMMON_QUEUE_NOTIFY_OFF: usize = 0x1E;
+const COMMON_QUEUE_DESC: usize = 0x20;
+const COMMON_QUEUE_DRIVER: usize = 0x28;
+const COMMON_QUEUE_DEVICE: usize = 0x30;
+
+#[derive(Debug)]
+pub enum VirtioError {
+    /// Neither a legacy I/O BAR nor the modern capabilities were found.
+    NoTransport,
+    FeaturesRejected,
+    QueueUnavailable,
+    OutOfMemory,
+    NoInterrupt,
+    /// The device never reported that it finished resetting.
+    ResetTimeout,
+}
+
+struct ModernTransport {
+    common: MmioRegion,
+    notify: MmioRegion,
+    notify_off_multiplier: u32,
+    isr: MmioRegion,
+    device: MmioRegion,
+}
+
+enum Transport {
+    Legacy { io_base: u16 },
+    Modern(ModernTransport),
+}
+
+/// A virtio device found on the PCI bus, independent of its type.
+pub struct VirtioPci {
+    pub header: Header,
+    transport: Transport,
+    /// Notify register offsets of the queues set up so far, by queue index.
+    notify_offsets: Vec<usize>,
+}
+
+impl VirtioPci {
+    /// Picks the modern transport if the device describes one, falling back
+    /// to the legacy registers of a transitional device.
+    pub fn new(header: Header) -> Result<VirtioPci, VirtioError> {
+        // Enable I/O and memory decoding and bus mastering
+        pci_config_write_word(
+            header.bus,
+            header.device,
+            header.function,
+            0x4,
+            header.command | 0x7,
+        );
+
+        let transport = match modern_transport(&header) {
+            Some(modern) => Transport::Modern(modern),
+            None => match &header.rest_of_header.to_standard() {
+                Ok(standard) => match &standard.base_address_registers[0] {
+                    BAR::IO(bar) => Transport::Legacy {
+                        io_base: bar.address as u16,
+                    },
+                    BAR::Memory(_) => return Err(VirtioError::NoTransport),
+                },
+                Err(_) => return Err(VirtioError::NoTransport),
+            },
+        };
+
+        Ok(VirtioPci {
+            header,
+            transport,
+            notify_offsets: Vec::new(),
+        })
+    }
+
+    pub fn is_modern(&self) -> bool {
+        matches!(self.transport, Transport::Modern(_))
+    }
+
+    /// The legacy IRQ line the device interrupts on.
+    pub fn interrupt_line(&self) -> Option<u8> {
+        let standard = self.header.rest_of_header.to_standard().ok()?;
+        match standard.interrupt_line {
+            0 | 0xFF => None,
+            line => Some(line),
+        }
+    }
+
+    pub fn status(&self) -> u8 {
+        match &self.transport {
+            Transport::Legacy { io_base } => legacy_read_u8(*io_base, LEGACY_DEVICE_STATUS),
+            Transport::Modern(modern) => modern.common.read_u8(COMMON_DEVICE_STATUS),
+        }
+    }
+
+    pub fn set_status(&self, status: u8) {
+        match &self.transport {
+            Transport::Legacy { io_base } => {
+                legacy_write_u8(*io_base, LEGACY_DEVICE_STATUS, status)
+            }
+            Transport::Modern(modern) => modern.common.write_u8(COMMON_DEVICE_STATUS, status),
+        }
+    }
+
+    pub fn add_status(&self, status: u8) {
+        self.set_status(self.status() | status);
+    }
+
+    fn reset(&self) -> Result<(), VirtioError> {
+        self.set_status(0);
+        if !self.is_modern() {
+            return Ok(());
+        }
+        // A modern device finishes resetting when the status reads back 0
+        for _ in 0..RESET_SPINS {
+            if self.status() == 0 {
+                return Ok(());
+            }
+            core::hint::spin_loop();
+        }
+        Err(VirtioError::ResetTimeout)
+    }
+
+    fn device_features(&self) -> u64 {
+        match &self.transport {
+            Transport::Legacy { io_base } => {
+                legacy_read_u32(*io_base, LEGACY_DEVICE_FEATURES) as u64
+            }
+            Transport::Modern(modern) => {
+                let common = &modern.common;
+                common.write_u32(COMMON_DEVICE_FEATURE_SELECT, 0);
+                let low = common.read_u32(COMMON_DEVICE_FEATURE);
+                common.write_u32(COMMON_DEVICE_FEATURE_SELECT, 1);
+                let high = common.read_u32(COMMON_DEVICE_FEATURE);
+                (high as u64) << 32 | low as u64
+            }
+        }
+    }
+
+    fn set_driver_features(&self, features: u64) {
+        match &self.transport {
+            Transport::Legacy { io_base } => {
+                legacy_write_u32(*io_base, LEGACY_DRIVER_FEATURES, features as u32)
+            }
+            Transport::Modern(modern) => {
+                let common = &modern.common;
+                common.write_u32(COMMON_DRIVER_FEATURE_SELECT, 0);
+                common.write_u32(COMMON_DRIVER_FEATURE, features as u32);
+                common.write_u32(COMMON_DRIVER_FEATURE_SELECT, 1);
+                common.write_u32(COMMON_DRIVER_FEATURE, (features >> 32) as u32);
+            }
+        }
+    }
+
+    /// Resets the device and negotiates features, returning the ones both
+    /// sides support out of `wanted`. Queues are set up after this, and
+    /// [`VirtioPci::finish_init`] is called once they are.
+    pub fn begin_init(&mut self, wanted: u64) -> Result<u64, VirtioError> {
+        self.reset()?;
+        self.add_status(STATUS_ACKNOWLEDGE);
+        self.add_status(STATUS_DRIVER);
+
+        let wanted = if self.is_modern() {
+            wanted | F_VERSION_1
+        } else {
+            // The legacy transport only has 32 feature bits
+            wanted & 0xFFFF_FFFF
+        };
+        let features = self.device_features() & wanted;
+        self.set_driver_features(features);
+
+        if self.is_modern() {
+            self.add_status(STATUS_FEATURES_OK);
+            if self.status() & STATUS_FEATURES_OK == 0 || features & F_VERSION_1 == 0 {
+                self.set_status(STATUS_FAILED);
+                return Err(VirtioError::FeaturesRejected);
+            }
+        }
+        Ok(features)
+    }
+
+    pub fn finish_init(&self) {
+        self.add_status(STATUS_DRIVER_OK);
+    }
+
+    /// Sets up queue `index` with at most `max_size` entries.
+    pub fn setup_queue(&mut self, index: u16, max_size: u16) -> Result<Virtqueue, VirtioError> {
+        let notify_offset;
+        let queue = match &self.transport {
+            Transport::Legacy { io_base } => {
+                let io_base = *io_base;
+                legacy_write_u16(io_base, LEGACY_QUEUE_SELECT, index);
+                // Legacy devices don't let the driver pick the size
+                let size = legacy_read_u16(io_base, LEGACY_QUEUE_SIZE);
+                if size == 0 {
+                    return Err(VirtioError::QueueUnavailable);
+                }
+                let queue = Virtqueue::new(index, size).ok_or(VirtioError::OutOfMemory)?;
+                let pfn = queue.desc_addr().as_u64() / queue::LEGACY_ALIGN as u64;
+                legacy_write_u32(io_base, LEGACY_QUEUE_ADDRESS, pfn as u32);
+                notify_offset = LEGACY_QUEUE_NOTIFY as usize;
+                queue
+            }
+            Transport::Modern(modern) => {
+                let common = &modern.common;
+                common.write_u16(COMMON_QUEUE_SELECT, index);
+                let size = common.read_u16(COMMON_QUEUE_SIZE);
+                if size == 0 {
+                    return Err(VirtioError::QueueUnavailable);
+                }
+                // Sizes are powers of two, so this stays one
+                let size = size.min(max_size.next_power_of_two());
+                common.write_u16(COMMON_QUEUE_SIZE, size);
+                let queue = Virtqueue::new(index, size).ok_or(VirtioError::OutOfMemory)?;
+                write_u64(common, COMMON_QUEUE_DESC, queue.desc_addr());
+                write_u64(common, COMMON_QUEUE_DRIVER, queue.avail_addr());
+                write_u64(common, COMMON_QUEUE_DEVICE, queue.used_addr());
+                common.write_u16(COMMON_QUEUE_ENABLE, 1);
+                notify_offset = common.read_u16(COMMON_QUEUE_NOTIFY_OFF) as usize
+                    * modern.notify_off_multiplier as usize;
+                queue
+            }
+        };
+
+        let index = index as usize;
+        if self.notify_offsets.len() <= index {
+            self.notify_offsets.resize(index + 1, 0);
+        }
+        self.notify_offsets[index] = notify_offset;
+        Ok(queue)
+    }
+
+    /// Tells the device that queue `index` has new available buffers.
+    pub fn notify(&self, index: u16) {
+        let offset = self.notify_offsets[index as usize];
+        match &self.transport {
+            Transport::Legacy { io_base } => legacy_write_u16(*io_base, offset as u16, index),
+            Transport::Modern(modern) => modern.notify.write_u16(offset, index),
+        }
+    }
+
+    /// Reads and clears the ISR status, which also deasserts the interrupt.
+    pub fn read_isr(&self) -> u8 {
+        match &self.transport {
+            Transport::Legacy { io_base } => legacy_read_u8(*io_base, LEGACY_ISR_STATUS),
+            Transport::Modern(modern) => modern.isr.read_u8(0),
+        }
+    }
+
+    pub fn config_u8(&self, offset: usize) -> u8 {
+        match &self.transport {
+            Transport::Legacy { io_base } => {
+                legacy_read_u8(*io_base, LEGACY_DEVICE_CONFIG + offset as u16)
+            }
+            Transport::Modern(modern) => modern.device.read_u8(offset),
+        }
+    }
+
+    pub fn config_u16(&self, offset: usize) -> u16 {
+        match &self.transport {
+            Transport::Legacy { io_base } => {
+                legacy_read_u16(*io_base, LEGACY_DEVICE_CONFIG + offset as u16)
+            }
+            Transport::Modern(modern) => modern.device.read_u16(offset),
+        }
+    }
+
+    pub fn config_u32(&self, offset: usize) -> u32 {
+        match &self.transport {
+            Transport::Legacy { io_base } => {
+                legacy_read_u32(*io_base, LEGACY_DEVICE_CONFIG + offset as u16)
+            }
+            Transport::Modern(modern) => modern.device.read_u32(offset),
+        }
+    }
+
+    /// Reads a 64 bit field, which the device may update between the two
+    /// halves, so the low half is read until it is stable.
+    pub fn config_u64(&self, offset: usize) -> u64 {
+        loop {
+            let low = self.config_u32(offset);
+            let high = self.config_u32(offset + 4);
+            if self.config_u32(offset) == low {
+                return (high as u64) << 32 | low as u64;
+            }
+        }
+    }
+}
+
+fn write_u64(region: &MmioRegion, offset: usize, addr: PhysAddr) {
+    region.write_u32(offset, addr.as_u64() as u32);
+    region.write_u32(offset + 4, (addr.as_u64() >> 32) as u32);
+}
+
+fn legacy_read_u8(io_base: u16, offset: u16) -> u8 {
+    unsafe { Port::<u8>::new(io_base + offset).read() }
+}
+
+fn legacy_read_u16(io_base: u16, offset: u16) -> u16 {
+    unsafe { Port::<u16>::new(io_base + offset).read() }
+}
+
+fn legacy_read_u32(io_base: u16, offset: u16) -> u32 {
+    unsafe { Port::<u32>::new(io_base + offset).read() }
+}
+
+fn legacy_write_u8(io_base: u16, offset: u16, value: u8) {
+    unsafe { Port::<u8>::new(io_base + offset).write(value) }
+}
+
+fn legacy_write_u16(io_base: u16, offset: u16, value: u16) {
+    unsafe { Port::<u16>::new(io_base + offset).write(value) }
+}
+
+fn legacy_write_u32(io_base: u16, offset: u16, value: u32) {
+    unsafe { Port::<u32>::new(io_base + offset).write(value) }
+}
+
+/// Maps the register block a virtio capability at `offset` points to.
+fn map_capability(header: &Header, offset: u8) -> Option<MmioRegion> {
+    let bar = header.read_dword(offset + 4) as u8;
+    let start = header.read_dword(offset + 8);
+    let length = header.read_dword(offset + 12);
+    let base = header.memory_bar_address(bar as usize)?;
+    memory::map_mmio(PhysAddr::new(base + start as u64), length as usize).ok()
+}
+
+/// Finds the register blocks of the modern transport through the vendor
+/// specific capabilities.
+fn modern_transport(header: &Header) -> Option<ModernTransport> {
+    let mut common = None;
+    let mut notify = None;
+    let mut isr = None;
+    let mut device = None;
+
+    for capability in header.capabilities() {
+        if capability.id != PCI_CAP_ID_VENDOR {
+            continue;
+        }
+        let cfg_type = (pci_config_read_word(
+            header.bus,
+            header.device,
+            header.function,
+            capability.offset + 2,
+        ) >> 8) as u8;
+        // Devices may list a block more than once, the first one is preferred
+        match cfg_type {
+            CAP_COMMON_CFG if common.is_none() => common = Some(capability.offset),
+            CAP_NOTIFY_CFG if notify.is_none() => notify = Some(capability.offset),
+            CAP_ISR_CFG if isr.is_none() => isr = Some(capability.offset),
+            CAP_DEVICE_CFG if device.is_none() => device = Some(capability.offset),
+            _ => {}
+        }
+    }
+
+    let notify = notify?;
+    Some(ModernTransport {
+        common: map_capability(header, common?)?,
+        notify: map_capability(header, notify)?,
+        notify_off_multiplier: header.read_dword(notify + 16),
+        isr: map_capability(header, isr?)?,
+        device: map_capability(header, device?)?,
+    })
+}
+
+/// Returns the virtio devices of the given type on the PCI bus.
+pub fn find_devices(device_type: u16) -> Vec<Header> {
+    let mut devices = Vec::new();
+    for function in pci::functions() {
+        if function.vendor_id != VIRTIO_VENDOR_ID {
+            continue;
+        }
+        let Ok(header) = Header::new(function.bus, function.device, function.function) else {
+            continue;
+        };
+        let matches = if LEGACY_DEVICE_IDS.contains(&header.device_id) {
+            header
+                .rest_of_header
+                .to_standard()
+                .is_ok_and(|standard| standard.subsystem_id == device_type)
+        } else {
+            header.device_id == MODERN_DEVICE_ID_BASE + device_type
+        };
+        if matches {
+            println!(
+                "virtio: device type {} at {}:{}.{}",
+                device_type, header.bus, header.device, header.function
+            );
+            devices.push(header);
+        }
+    }
+    devices
+}
@@ -0,0 +1,278 @@
+use super::{
+    find_devices,
+    queue::{Buffer, Virtqueue},
+    VirtioError, VirtioPci, DEVICE_TYPE_NET, F_VERSION_1, ISR_QUEUE,
+};
+use crate::{
+    interrupts,
+    memory::{self, DmaBuffer},
+    networking::device::{
+        fill_checksum, ChecksumRequest, FrameFuture, MacAddress, NetCounters, NetError, NetStats,
+        NetworkDevice, MAX_FRAME_SIZE,
+    },
+    println,
+};
+use alloc::{boxed::Box, collections::BTreeMap, sync::Arc, vec::Vec};
+use core::{future::poll_fn, task::Poll};
+use futures_util::task::AtomicWaker;
+use spin::Mutex;
+
+/// The device finishes checksums the driver leaves partial.
+const F_CSUM: u64 = 1 << 0;
+/// The driver accepts received packets with partial checksums.
+const F_GUEST_CSUM: u64 = 1 << 1;
+const F_MAC: u64 = 1 << 5;
+const F_STATUS: u64 = 1 << 16;
+
+const CONFIG_MAC: usize = 0;
+const CONFIG_STATUS: usize = 6;
+const STATUS_LINK_UP: u16 = 1;
+
+const RX_QUEUE: u16 = 0;
+const TX_QUEUE: u16 = 1;
+const MAX_QUEUE_SIZE: u16 = 64;
+
+/// The header in front of every packet. The modern layout adds a buffer
+/// count at the end.
+const LEGACY_HEADER_SIZE: usize = 10;
+const MODERN_HEADER_SIZE: usize = 12;
+const HEADER_NEEDS_CSUM: u8 = 1;
+
+/// Each buffer holds one header and one full frame.
+const BUFFER_SIZE: usize = 2048;
+
+/// A ring of equally sized DMA buffers and the queue they are handed to the
+/// device through.
+struct BufferRing {
+    queue: Virtqueue,
+    memory: DmaBuffer,
+    /// Buffers owned by the device, by the head descriptor they went in as.
+    in_flight: BTreeMap<u16, usize>,
+    free: Vec<usize>,
+}
+
+impl BufferRing {
+    fn new(queue: Virtqueue) -> Result<BufferRing, VirtioError> {
+        let count = queue.size() as usize;
+        let memory = memory::alloc_dma(count * BUFFER_SIZE).ok_or(VirtioError::OutOfMemory)?;
+        Ok(BufferRing {
+            queue,
+            memory,
+            in_flight: BTreeMap::new(),
+            free: (0..count).collect(),
+        })
+    }
+
+    fn buffer(&mut self, index: usize) -> &mut [u8] {
+        &mut self.memory.as_mut_slice()[index * BUFFER_SIZE..][..BUFFER_SIZE]
+    }
+
+    fn add(&mut self, index: usize, len: usize, device_writable: bool) {
+        let addr = self.memory.phys_addr() + (index * BUFFER_SIZE) as u64;
+        let buffer = Buffer {
+            addr,
+            len: len as u32,
+            device_writable,
+        };
+        // There are as many descriptors as buffers, so this can't fail
+        let head = self.queue.add(&[buffer]).unwrap();
+        self.in_flight.insert(head, index);
+    }
+
+    /// Takes back the next buffer the device is done with.
+    fn pop_used(&mut self) -> Option<(usize, usize)> {
+        let (head, len) = self.queue.pop_used()?;
+        let index = self.in_flight.remove(&head)?;
+        Some((index, len as usize))
+    }
+}
+
+/// A virtio network card.
+pub struct VirtioNet {
+    device: VirtioPci,
+    mac: MacAddress,
+    header_size: usize,
+    features: u64,
+    rx: Mutex<BufferRing>,
+    tx: Mutex<BufferRing>,
+    rx_waker: AtomicWaker,
+    stats: NetCounters,
+}
+
+impl VirtioNet {
+    pub fn new(mut device: VirtioPci) -> Result<VirtioNet, VirtioError> {
+        let features = device.begin_init(F_CSUM | F_GUEST_CSUM | F_MAC | F_STATUS)?;
+        let mut rx = BufferRing::new(device.setup_queue(RX_QUEUE, MAX_QUEUE_SIZE)?)?;
+        let tx = BufferRing::new(device.setup_queue(TX_QUEUE, MAX_QUEUE_SIZE)?)?;
+
+        // Give the device every receive buffer up front
+        while let Some(index) = rx.free.pop() {
+            rx.add(index, BUFFER_SIZE, true);
+        }
+        device.finish_init();
+        device.notify(RX_QUEUE);
+
+        let mut mac = [0; 6];
+        if features & F_MAC != 0 {
+            for (i, byte) in mac.iter_mut().enumerate() {
+                *byte = device.config_u8(CONFIG_MAC + i);
+            }
+        } else {
+            // A locally administered address, for devices that don't have one
+            mac = [0x02, 0x00, 0x00, 0x00, 0x00, 0x01];
+        }
+
+        Ok(VirtioNet {
+            header_size: if features & F_VERSION_1 != 0 {
+                MODERN_HEADER_SIZE
+            } else {
+                LEGACY_HEADER_SIZE
+            },
+            device,
+            mac,
+            features,
+            rx: Mutex::new(rx),
+            tx: Mutex::new(tx),
+            rx_waker: AtomicWaker::new(),
+            stats: NetCounters::default(),
+        })
+    }
+
+    /// Acknowledges the interrupt and wakes the receiver, which collects
+    /// the frames itself since the rings are locked with interrupts enabled.
+    fn handle_interrupt(&self) {
+        if self.device.read_isr() & ISR_QUEUE != 0 {
+            self.rx_waker.wake();
+        }
+    }
+
+    /// Copies out the next received frame and hands its buffer back.
+    fn poll_receive(&self) -> Option<Vec<u8>> {
+        let mut rx = self.rx.lock();
+        let (index, len) = rx.pop_used()?;
+        let header_size = self.header_size;
+        let buffer = rx.buffer(index);
+        let len = len.clamp(header_size, BUFFER_SIZE);
+        let flags = buffer[0];
+        let mut frame = buffer[header_size..len].to_vec();
+
+        if flags & HEADER_NEEDS_CSUM != 0 {
+            // With F_GUEST_CSUM the device can leave the checksum for us
+            let start = u16::from_le_bytes([buffer[6], buffer[7]]) as usize;
+            let offset = u16::from_le_bytes([buffer[8], buffer[9]]) as usize;
+            fill_checksum(&mut frame, ChecksumRequest { start, offset });
+        }
+
+        rx.add(index, BUFFER_SIZE, true);
+        drop(rx);
+        self.device.notify(RX_QUEUE);
+        self.stats.received(frame.len());
+        Some(frame)
+    }
+
+    fn transmit(&self, frame: &[u8], checksum: Option<ChecksumRequest>) -> Result<(), NetError> {
+        if frame.len() > MAX_FRAME_SIZE {
+            return Err(NetError::FrameTooLarge);
+        }
+
+        let mut tx = self.tx.lock();
+        while let Some((index, _)) = tx.pop_used() {
+            tx.free.push(index);
+        }
+        let index = tx.free.pop().ok_or(NetError::QueueFull)?;
+
+        let header_size = self.header_size;
+        let buffer = tx.buffer(index);
+        buffer[..header_size].fill(0);
+        if let Some(checksum) = checksum {
+            buffer[0] = HEADER_NEEDS_CSUM;
+            buffer[6..8].copy_from_slice(&(checksum.start as u16).to_le_bytes());
+            buffer[8..10].copy_from_slice(&(checksum.offset as u16).to_le_bytes());
+        }
+        buffer[header_size..header_size + frame.len()].copy_from_slice(frame);
+
+        tx.add(index, header_size + frame.len(), false);
+        drop(tx);
+        self.device.notify(TX_QUEUE);
+        self.stats.sent(frame.len());
+        Ok(())
+    }
+}
+
+impl NetworkDevice for VirtioNet {
+    fn driver(&self) -> &'static str {
+        "virtio-net"
+    }
+
+    fn mac(&self) -> MacAddress {
+        self.mac
+    }
+
+    fn link_up(&self) -> bool {
+        // Without the status feature the link is assumed to be up
+        self.features & F_STATUS == 0 || self.device.config_u16(CONFIG_STATUS) & STATUS_LINK_UP != 0
+    }
+
+    fn stats(&self) -> NetStats {
+        self.stats.snapshot()
+    }
+
+    fn send(&self, frame: &[u8]) -> Result<(), NetError> {
+        self.transmit(frame, None)
+    }
+
+    fn send_with_checksum(&self, frame: &[u8], checksum: ChecksumRequest) -> Result<(), NetError> {
+        if self.features & F_CSUM != 0 {
+            self.transmit(frame, Some(checksum))
+        } else {
+            let mut frame = frame.to_vec();
+            fill_checksum(&mut frame, checksum);
+            self.transmit(&frame, None)
+        }
+    }
+
+    fn receive(&self) -> FrameFuture<'_> {
+        Box::pin(poll_fn(|cx| {
+            if let Some(frame) = self.poll_receive() {
+                return Poll::Ready(frame);
+            }
+            self.rx_waker.register(cx.waker());
+            match self.poll_receive() {
+                Some(frame) => Poll::Ready(frame),
+                None => Poll::Pending,
+            }
+        }))
+    }
+}
+
+/// Sets up every virtio network card on the PCI bus.
+pub fn probe() -> Vec<Arc<VirtioNet>> {
+    let mut cards = Vec::new();
+
+    for header in find_devices(DEVICE_TYPE_NET) {
+        let result = VirtioPci::new(header).and_then(|device| {
+            let irq = device.interrupt_line().ok_or(VirtioError::NoInterrupt)?;
+            let modern = device.is_modern();
+            let card = Arc::new(VirtioNet::new(device)?);
+
+            let handler = card.clone();
+            interrupts::register_irq_handler(irq, Box::new(move || handler.handle_interrupt()))
+                .map_err(|_| VirtioError::NoInterrupt)?;
+            println!(
+                "virtio-net: {:02x?} on IRQ {}, {} transport, link {}",
+                card.mac,
+                irq,
+                if modern { "modern" } else { "legacy" },
+                if card.link_up() { "up" } else { "down" }
+            );
+            Ok(card)
+        });
+
+        match result {
+            Ok(card) => cards.push(card),
+            Err(err) => println!("virtio-net: failed to set up device: {:?}", err),
+        }
+    }
+
+    cards
+}
@@ -0,0 +1,213 @@
+//! Split virtqueues: a descriptor table, a ring of descriptors made
+//! available to the device and a ring of descriptors it has used.
+
+use crate::memory::{self, DmaBuffer};
+use alloc::vec::Vec;
+use core::sync::atomic::{fence, Ordering};
+use x86_64::PhysAddr;
+
+/// Alignment of the used ring the legacy transport requires, which also
+/// satisfies the modern one.
+pub const LEGACY_ALIGN: usize = 4096;
+
+const DESC_SIZE: usize = 16;
+
+const DESC_F_NEXT: u16 = 1;
+const DESC_F_WRITE: u16 = 2;
+
+/// A buffer to hand to the device.
+#[derive(Debug, Clone, Copy)]
+pub struct Buffer {
+    pub addr: PhysAddr,
+    pub len: u32,
+    /// Whether the device writes to the buffer rather than reading it.
+    pub device_writable: bool,
+}
+
+impl Buffer {
+    pub fn readable(addr: PhysAddr, len: usize) -> Buffer {
+        Buffer {
+            addr,
+            len: len as u32,
+            device_writable: false,
+        }
+    }
+
+    pub fn writable(addr: PhysAddr, len: usize) -> Buffer {
+        Buffer {
+            addr,
+            len: len as u32,
+            device_writable: true,
+        }
+    }
+}
+
+pub struct Virtqueue {
+    index: u16,
+    size: u16,
+    memory: DmaBuffer,
+    avail_offset: usize,
+    used_offset: usize,
+    /// Head of the list of free descriptors, chained through `next`.
+    free_head: u16,
+    free_count: u16,
+    /// Our copy of the avail index, which only we write.
+    avail_idx: u16,
+    /// How far into the used ring we have read.
+    last_used_idx: u16,
+    /// Length of the chain starting at each head, to free it once used.
+    chain_lengths: Vec<u16>,
+}
+
+impl Virtqueue {
+    /// Allocates a queue with `size` descriptors, which must be a power of
+    /// two.
+    pub fn new(index: u16, size: u16) -> Option<Virtqueue> {
+        let count = size as usize;
+        let avail_offset = count * DESC_SIZE;
+        // flags, idx, the ring and used_event
+        let avail_size = 6 + 2 * count;
+        let used_offset = (avail_offset + avail_size).next_multiple_of(LEGACY_ALIGN);
+        // flags, idx, the ring of (id, len) pairs and avail_event
+        let used_size = 6 + 8 * count;
+
+        let mut queue = Virtqueue {
+            index,
+            size,
+            memory: memory::alloc_dma(used_offset + used_size)?,
+            avail_offset,
+            used_offset,
+            free_head: 0,
+            free_count: size,
+            avail_idx: 0,
+            last_used_idx: 0,
+            chain_lengths: alloc::vec![0; count],
+        };
+        for i in 0..size {
+            queue.write_desc(i, PhysAddr::new(0), 0, 0, (i + 1) % size);
+        }
+        Some(queue)
+    }
+
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    pub fn free_count(&self) -> u16 {
+        self.free_count
+    }
+
+    pub fn desc_addr(&self) -> PhysAddr {
+        self.memory.phys_addr()
+    }
+
+    pub fn avail_addr(&self) -> PhysAddr {
+        self.memory.phys_addr() + self.avail_offset as u64
+    }
+
+    pub fn used_addr(&self) -> PhysAddr {
+        self.memory.phys_addr() + self.used_offset as u64
+    }
+
+    fn ptr<T>(&self, offset: usize) -> *mut T {
+        unsafe { self.memory.as_ptr::<u8>().add(offset).cast() }
+    }
+
+    fn write_desc(&mut self, i: u16, addr: PhysAddr, len: u32, flags: u16, next: u16) {
+        let base = i as usize * DESC_SIZE;
+        unsafe {
+            self.ptr::<u64>(base).write_volatile(addr.as_u64());
+            self.ptr::<u32>(base + 8).write_volatile(len);
+            self.ptr::<u16>(base + 12).write_volatile(flags);
+            self.ptr::<u16>(base + 14).write_volatile(next);
+        }
+    }
+
+    fn desc_next(&self, i: u16) -> u16 {
+        unsafe { self.ptr::<u16>(i as usize * DESC_SIZE + 14).read_volatile() }
+    }
+
+    /// Chains `buffers` together and makes them available to the device,
+    /// returning the head descriptor that identifies them once used. The
+    /// device still has to be notified.
+    pub fn add(&mut self, buffers: &[Buffer]) -> Option<u16> {
+        if buffers.is_empty() || buffers.len() > self.free_count as usize {
+            return None;
+        }
+
+        let head = self.free_head;
+        let mut i = head;
+        for (n, buffer) in buffers.iter().enumerate() {
+            let next = self.desc_next(i);
+            let mut flags = 0;
+            if buffer.device_writable {
+                flags |= DESC_F_WRITE;
+            }
+            if n + 1 < buffers.len() {
+                flags |= DESC_F_NEXT;
+            }
+            self.write_desc(i, buffer.addr, buffer.len, flags, next);
+            if n + 1 < buffers.len() {
+                i = next;
+            } else {
+                self.free_head = next;
+            }
+        }
+        self.free_count -= buffers.len() as u16;
+        self.chain_lengths[head as usize] = buffers.len() as u16;
+
+        let slot = self.avail_idx % self.size;
+        unsafe {
+            self.ptr::<u16>(self.avail_offset + 4 + 2 * slot as usize)
+                .write_volatile(head);
+        }
+        // The ring entry has to be visible before the index that covers it
+        fence(Ordering::SeqCst);
+        self.avail_idx = self.avail_idx.wrapping_add(1);
+        unsafe {
+            self.ptr::<u16>(self.avail_offset + 2)
+                .write_volatile(self.avail_idx);
+        }
+        fence(Ordering::SeqCst);
+        Some(head)
+    }
+
+    /// Returns the next chain the device has finished with, as its head and
+    /// the number of bytes the device wrote, and frees its descriptors.
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        let used_idx = unsafe { self.ptr::<u16>(self.used_offset + 2).read_volatile() };
+        if used_idx == self.last_used_idx {
+            return None;
+        }
+        fence(Ordering::SeqCst);
+
+        let slot = (self.last_used_idx % self.size) as usize;
+        let entry = self.used_offset + 4 + 8 * slot;
+        let (head, len) = unsafe {
+            (
+                self.ptr::<u32>(entry).read_volatile() as u16,
+                self.ptr::<u32>(entry + 4).read_volatile(),
+            )
+        };
+        self.last_used_idx = self.last_used_idx.wrapping_add(1);
+
+        // Put the chain back on the front of the free list
+        let length = self.chain_lengths[head as usize];
+        let mut tail = head;
+        for _ in 1..length {
+            tail = self.desc_next(tail);
+        }
+        unsafe {
+            self.ptr::<u16>(tail as usize * DESC_SIZE + 14)
+                .write_volatile(self.free_head);
+        }
+        self.free_head = head;
+        self.free_count += length;
+
+        Some((head, len))
+    }
+}
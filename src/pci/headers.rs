@@ -1,4 +1,5 @@
 use super::pci_config_read_word;
+use alloc::vec::Vec;
 
 #[derive(Debug)]
 pub enum BAR {
@@ -98,6 +99,64 @@ impl Header {
         header.rest_of_header = HeaderType::get(bus, device, function)?;
         Ok(header)
     }
+
+    /// Reads a dword from this function's configuration space.
+    pub fn read_dword(&self, offset: u8) -> u32 {
+        let low = pci_config_read_word(self.bus, self.device, self.function, offset);
+        let high = pci_config_read_word(self.bus, self.device, self.function, offset + 2);
+        (high as u32) << 16 | low as u32
+    }
+
+    /// Walks the capability list, if the status register says there is one.
+    pub fn capabilities(&self) -> Vec<Capability> {
+        let mut capabilities = Vec::new();
+        if self.status & STATUS_CAPABILITIES_LIST == 0 {
+            return capabilities;
+        }
+        let Ok(standard) = self.rest_of_header.to_standard() else {
+            return capabilities;
+        };
+
+        // The bottom two bits are reserved
+        let mut offset = standard.capabilities_pointer & !0b11;
+        // A malformed list could loop forever, but 48 fit in the space
+        while offset != 0 && capabilities.len() < 48 {
+            let (id, next) =
+                pci_config_read_word(self.bus, self.device, self.function, offset).split();
+            capabilities.push(Capability { id, offset });
+            offset = next & !0b11;
+        }
+        capabilities
+    }
+
+    /// Returns the address of a memory BAR, combining both halves of a
+    /// 64 bit BAR.
+    pub fn memory_bar_address(&self, index: usize) -> Option<u64> {
+        let standard = self.rest_of_header.to_standard().ok()?;
+        let BAR::Memory(bar) = standard.base_address_registers.get(index)? else {
+            return None;
+        };
+        if bar._type == MEMORY_BAR_64 {
+            let high = self.read_dword(0x10 + (index as u8 + 1) * 4);
+            Some((high as u64) << 32 | bar.address as u64)
+        } else {
+            Some(bar.address as u64)
+        }
+    }
+}
+
+/// Status register bit saying the function has a capability list.
+const STATUS_CAPABILITIES_LIST: u16 = 1 << 4;
+
+/// `MemoryBar::_type` of a BAR that takes up two slots.
+pub const MEMORY_BAR_64: u8 = 0b100;
+
+/// An entry in a function's capability list.
+#[derive(Debug, Clone, Copy)]
+pub struct Capability {
+    pub id: u8,
+    /// Offset of the capability in configuration space.
+    pub offset: u8,
 }
 
 #[derive(Debug)]
@@ -110,7 +169,8 @@ pub enum HeaderType {
 
 impl HeaderType {
     fn get(bus: u8, device: u8, function: u8) -> Result<Self, HeaderError> {
-        let header_type = pci_config_read_word(bus, device, function, 0xE);
+        // Bit 7 only marks multi-function devices, the high byte is BIST
+        let header_type = pci_config_read_word(bus, device, function, 0xE) & 0x7F;
         match header_type {
             0x00 => Ok(HeaderType::Standard(StandardHeader::get_header(
                 bus, device, function,
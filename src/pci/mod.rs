@@ -3,6 +3,9 @@ pub mod headers;
 
 use x86_64::instructions::port::Port;
 
+use alloc::vec::Vec;
+use conquer_once::spin::OnceCell;
+
 use crate::println;
 
 use self::headers::Header;
@@ -29,10 +32,12 @@ pub fn pci_config_read_word(bus: u8, slot: u8, func: u8, offset: u8) -> u16 {
 
 pub fn pci_config_write_word(bus: u8, slot: u8, func: u8, offset: u8, data: u16) {
     let mut outport = Port::new(0xCF8);
+    // The word is written through the matching half of the data port, so
+    // the other half of the dword isn't touched
     let mut inport: x86_64::instructions::port::PortGeneric<
         u16,
         x86_64::instructions::port::ReadWriteAccess,
-    > = Port::new(0xCFC);
+    > = Port::new(0xCFC + (offset & 0x2) as u16);
 
     let address: u32;
     let lbus = bus as u32;
@@ -41,25 +46,12 @@ pub fn pci_config_write_word(bus: u8, slot: u8, func: u8, offset: u8, data: u16)
 
     address = (lbus << 16) | (lslot << 11) | (lfunc << 8) | (offset & 0xFC) as u32 | 0x80000000;
 
-    let mut tmp = unsafe { inport.read() };
-    tmp &= !(0xFFFF << ((offset & 0x2) * 8)); // reset the word at the offset
-    tmp |= data << ((offset & 0x2) * 8); // write the data at the offset
-
-
     unsafe {
         outport.write(address);
-        inport.write(tmp);
+        inport.write(data);
     }
 }
 
-fn get_base_class(bus: u8, slot: u8, function: u8) -> u8 {
-    (pci_config_read_word(bus, slot, function, 0x8 + 0x2) >> 8) as u8
-}
-
-fn get_sub_class(bus: u8, slot: u8, function: u8) -> u8 {
-    (pci_config_read_word(bus, slot, function, 0x8 + 0x2) & 0xFF) as u8
-}
-
 fn pci_check_vendor(bus: u8, slot: u8) -> Option<u16> {
     let vendor: u16;
     let device: u16;
@@ -97,77 +89,100 @@ pub fn get_network_card() -> Option<Header> {
     None
 }
 
-fn check_device(bus: u8, device: u8) {
-    let function: u8 = 0;
-    let vendor_id: u16;
-    vendor_id = pci_config_read_word(bus, device, function, 0);
-    if vendor_id != 0xFFFF {
-        check_function(bus, device, function);
-        let header = headers::Header::new(bus, device, function);
-        println!(
-            "PCI: Found device: bus: {}, device: {}, vendor: {}, device: {}",
-            bus,
-            device,
-            vendor_id,
-            pci_config_read_word(bus, device, function, 2)
-        );
-        println!("PCI: Header : {:?}", header);
-        let header_type: u8 = pci_config_read_word(bus, device, function, 0x0E) as u8;
-        if (header_type & 0x80) != 0 {
-            for function in 1..8 {
-                if pci_config_read_word(bus, device, function, 0) != 0xFFFF {
-                    check_function(bus, device, function);
-                    println!(
-                        "PCI: Found multi-function device: bus: {}, device: {}",
-                        bus, device
-                    );
+pub fn get_device(vendor_id: u16, device_id: u16) -> Option<Header> {
+    for bus in 0..=255 {
+        for device in 0..32 {
+            if let Ok(header) = Header::new(bus, device, 0) {
+                if header.vendor_id == vendor_id && header.device_id == device_id {
+                    return Some(header);
                 }
             }
         }
     }
+    None
 }
 
-fn get_secondary_bus(bus: u8, device: u8, function: u8) -> u8 {
-    (pci_config_read_word(bus, device, function, 0x18) >> 8) as u8
-}
-
-pub fn check_bus(bus: u8) {
-    for device in 0..32 {
-        check_device(bus, device);
-    }
+/// Where a function sits on the bus and what it is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Function {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision_id: u8,
 }
 
-fn check_function(bus: u8, device: u8, function: u8) {
-    let base_class: u8;
-    let sub_class: u8;
-    let secondary_bus: u8;
+static FUNCTIONS: OnceCell<Vec<Function>> = OnceCell::uninit();
 
-    base_class = get_base_class(bus, device, function);
-    sub_class = get_sub_class(bus, device, function);
-    if (base_class == 0x6) && (sub_class == 0x4) {
-        secondary_bus = get_secondary_bus(bus, device, function);
-        check_bus(secondary_bus);
+/// Walks the buses and logs what is on them. The kernel does this once at
+/// boot, and [`functions`] serves what was found from then on.
+pub fn scan() {
+    for function in functions() {
         println!(
-            "PCI: Found PCI-to-PCI bridge: bus: {}, device: {}, function: {}",
-            bus, device, function
-        );
-    } else {
-        println!(
-            "PCI: Found device: bus: {}, device: {}, function: {}, base class: {}, sub class: {}",
-            bus, device, function, base_class, sub_class
+            "PCI: {:02x}:{:02x}.{}: vendor {:04x}, device {:04x}, class {:02x}, subclass {:02x}",
+            function.bus,
+            function.device,
+            function.function,
+            function.vendor_id,
+            function.device_id,
+            function.class,
+            function.subclass
         );
     }
 }
 
-pub fn get_device(vendor_id: u16, device_id: u16) -> Option<Header> {
+/// Every function on every bus, including the functions of multi-function
+/// devices and bridges, which [`Header::new`] doesn't take. The buses are
+/// walked on first use only.
+pub fn functions() -> &'static [Function] {
+    FUNCTIONS.get_or_init(walk)
+}
+
+fn walk() -> Vec<Function> {
+    let mut functions = Vec::new();
     for bus in 0..=255 {
         for device in 0..32 {
-            if let Ok(header) = Header::new(bus, device, 0) {
-                if header.vendor_id == vendor_id && header.device_id == device_id {
-                    return Some(header);
+            if pci_config_read_word(bus, device, 0, 0) == 0xFFFF {
+                continue;
+            }
+            let multi_function = pci_config_read_word(bus, device, 0, 0x0E) & 0x80 != 0;
+            let count = if multi_function { 8 } else { 1 };
+
+            for function in 0..count {
+                let vendor_id = pci_config_read_word(bus, device, function, 0);
+                if vendor_id == 0xFFFF {
+                    continue;
                 }
+                let class = pci_config_read_word(bus, device, function, 0x0A);
+                let revision = pci_config_read_word(bus, device, function, 0x08);
+                functions.push(Function {
+                    bus,
+                    device,
+                    function,
+                    vendor_id,
+                    device_id: pci_config_read_word(bus, device, function, 2),
+                    class: (class >> 8) as u8,
+                    subclass: class as u8,
+                    prog_if: (revision >> 8) as u8,
+                    revision_id: revision as u8,
+                });
             }
         }
     }
-    None
+    functions
+}
+
+/// Returns every function with the given class and subclass, including the
+/// functions of multi-function devices, e.g. the IDE controller that sits
+/// next to the ISA bridge on QEMU's PIIX3.
+pub fn get_devices_by_class(class: u8, subclass: u8) -> Vec<Header> {
+    functions()
+        .iter()
+        .filter(|function| function.class == class && function.subclass == subclass)
+        .filter_map(|function| Header::new(function.bus, function.device, function.function).ok())
+        .collect()
 }
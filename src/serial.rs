@@ -4,6 +4,11 @@ use uart_16550::SerialPort;
 
 #[cfg(target_arch = "x86_64")]
 const SERIAL_PORT: u16 = 0x3F8;
+/// COM2, which carries binary data such as packet captures.
+#[cfg(target_arch = "x86_64")]
+const SERIAL_PORT_2: u16 = 0x2F8;
+/// Bytes written to COM2 with interrupts off, the size of the UART's FIFO.
+const COM2_CHUNK_SIZE: usize = 16;
 
 lazy_static! {
     pub static ref SERIAL1: Mutex<SerialPort> = {
@@ -11,6 +16,11 @@ lazy_static! {
         serial_port.init();
         Mutex::new(serial_port)
     };
+    pub static ref SERIAL2: Mutex<SerialPort> = {
+        let mut serial_port = unsafe { SerialPort::new(SERIAL_PORT_2) };
+        serial_port.init();
+        Mutex::new(serial_port)
+    };
 }
 
 #[doc(hidden)]
@@ -26,6 +36,22 @@ pub fn _print(args: ::core::fmt::Arguments) {
     });
 }
 
+/// Writes `data` to COM2 unchanged. Interrupts are only held off for a
+/// chunk at a time, since the port takes a while per byte, so COM2 must not
+/// be written from interrupt handlers or their data ends up in between.
+pub fn write_com2(data: &[u8]) {
+    use x86_64::instructions::interrupts;
+
+    for chunk in data.chunks(COM2_CHUNK_SIZE) {
+        interrupts::without_interrupts(|| {
+            let mut port = SERIAL2.lock();
+            for byte in chunk {
+                port.send_raw(*byte);
+            }
+        });
+    }
+}
+
 /// Prints to the host through the serial interface.
 #[macro_export]
 macro_rules! serial_print {
@@ -1,4 +1,4 @@
-use super::{Task, TaskId};
+use super::{Task, TaskId, TaskInfo, TASKS};
 use alloc::task::Wake;
 use alloc::{collections::BTreeMap, sync::Arc};
 use core::task::Waker;
@@ -21,6 +21,12 @@ impl Executor {
     }
     pub fn spawn(&mut self, task: Task) {
         let task_id = task.id;
+        let info = TaskInfo {
+            id: task_id.0,
+            name: task.name,
+            polls: 0,
+        };
+        TASKS.lock().insert(task_id, info);
         if self.tasks.insert(task.id, task).is_some() {
             panic!("task with same ID already in tasks");
         }
@@ -43,13 +49,21 @@ impl Executor {
                 .entry(task_id)
                 .or_insert_with(|| TaskWaker::new(task_id, task_queue.clone()));
             let mut context = Context::from_waker(waker);
-            match task.poll(&mut context) {
+            let result = task.poll(&mut context);
+            // Not locked while polling, the task might look at it
+            let mut infos = TASKS.lock();
+            match result {
                 Poll::Ready(()) => {
                     // task done -> remove it and its cached waker
                     tasks.remove(&task_id);
                     waker_cache.remove(&task_id);
+                    infos.remove(&task_id);
+                }
+                Poll::Pending => {
+                    if let Some(info) = infos.get_mut(&task_id) {
+                        info.polls += 1;
+                    }
                 }
-                Poll::Pending => {}
             }
         }
     }
@@ -73,6 +87,15 @@ impl Executor {
     }
 }
 
+impl Drop for Executor {
+    fn drop(&mut self) {
+        let mut infos = TASKS.lock();
+        for task_id in self.tasks.keys() {
+            infos.remove(task_id);
+        }
+    }
+}
+
 struct TaskWaker {
     task_id: TaskId,
     task_queue: Arc<ArrayQueue<TaskId>>,
@@ -2,19 +2,24 @@ pub mod simple_executor;
 pub mod keyboard;
 pub mod executor;
 
-use alloc::boxed::Box;
+use alloc::{boxed::Box, collections::BTreeMap, vec::Vec};
 use core::task::{Context, Poll};
 use core::{future::Future, pin::Pin};
+use spin::Mutex;
 
 pub struct Task {
     id: TaskId,
+    name: &'static str,
     future: Pin<Box<dyn Future<Output = ()>>>,
 }
 
 impl Task {
-    pub fn new(future: impl Future<Output = ()> + 'static) -> Task {
+    /// Wraps `future`, named after the async function it came from.
+    pub fn new<F: Future<Output = ()> + 'static>(future: F) -> Task {
+        let name = core::any::type_name::<F>();
         Task {
             id: TaskId::new(),
+            name: name.strip_suffix("::{{closure}}").unwrap_or(name),
             future: Box::pin(future),
         }
     }
@@ -34,3 +39,21 @@ impl TaskId {
         TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
     }
 }
+
+/// A task the [`executor::Executor`] is running, as shown by [`tasks`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TaskInfo {
+    pub id: u64,
+    pub name: &'static str,
+    /// How often the task was polled so far.
+    pub polls: u64,
+}
+
+/// Kept apart from the executor, so tasks can look at it while it runs
+/// them.
+static TASKS: Mutex<BTreeMap<TaskId, TaskInfo>> = Mutex::new(BTreeMap::new());
+
+/// The tasks spawned on an executor that haven't finished, oldest first.
+pub fn tasks() -> Vec<TaskInfo> {
+    TASKS.lock().values().copied().collect()
+}
@@ -49,3 +49,17 @@ impl SimpleExecutor {
         }
     }
 }
+
+/// Polls `future` until it completes, for blocking code that waits on a
+/// driver's async path. Nothing wakes it, so it polls in a spin loop.
+pub fn block_on<F: core::future::Future>(future: F) -> F::Output {
+    let mut future = core::pin::pin!(future);
+    let waker = dummy_waker();
+    let mut context = Context::from_waker(&waker);
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+            return output;
+        }
+        core::hint::spin_loop();
+    }
+}
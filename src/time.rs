@@ -0,0 +1,136 @@
+use alloc::vec::Vec;
+use core::{
+    future::{poll_fn, Future},
+    pin::{pin, Pin},
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll, Waker},
+};
+use futures_util::task::AtomicWaker;
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+/// Timer interrupts per second.
+pub const TICK_HZ: u64 = 1000;
+
+const PIT_FREQUENCY: u64 = 1_193_182;
+const PIT_CHANNEL_0: u16 = 0x40;
+const PIT_COMMAND: u16 = 0x43;
+/// Channel 0, low then high byte, square wave.
+const PIT_MODE: u8 = 0x36;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// The earliest tick a sleeping task wants to wake up at.
+static NEXT_DEADLINE: AtomicU64 = AtomicU64::new(u64::MAX);
+/// Wakes [`run`] when a deadline passes.
+static TIMER_WAKER: AtomicWaker = AtomicWaker::new();
+/// Wakers of sleeping tasks with the tick they want to wake up at. Only
+/// locked from tasks, so the interrupt handler never waits for it.
+static SLEEPERS: Mutex<Vec<(u64, Waker)>> = Mutex::new(Vec::new());
+
+/// Sets the PIT to interrupt [`TICK_HZ`] times a second.
+pub fn init() {
+    let divisor = (PIT_FREQUENCY / TICK_HZ) as u16;
+    let mut command = Port::<u8>::new(PIT_COMMAND);
+    let mut data = Port::<u8>::new(PIT_CHANNEL_0);
+    unsafe {
+        command.write(PIT_MODE);
+        data.write(divisor as u8);
+        data.write((divisor >> 8) as u8);
+    }
+}
+
+/// Called by the timer interrupt handler.
+pub(crate) fn tick() {
+    let now = TICKS.fetch_add(1, Ordering::Relaxed) + 1;
+    if now >= NEXT_DEADLINE.load(Ordering::Relaxed) {
+        TIMER_WAKER.wake();
+    }
+}
+
+/// Wakes sleeping tasks once their time has come. Sleeping only works while
+/// this task runs.
+pub async fn run() {
+    poll_fn(|cx| {
+        TIMER_WAKER.register(cx.waker());
+        let now = ticks();
+        let mut sleepers = SLEEPERS.lock();
+        let mut next = u64::MAX;
+        sleepers.retain(|(deadline, waker)| {
+            if *deadline <= now {
+                waker.wake_by_ref();
+                false
+            } else {
+                next = next.min(*deadline);
+                true
+            }
+        });
+        NEXT_DEADLINE.store(next, Ordering::Relaxed);
+        Poll::<()>::Pending
+    })
+    .await
+}
+
+/// Timer ticks since boot.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Milliseconds since boot, in steps of one tick.
+pub fn uptime_ms() -> u64 {
+    ticks() * 1000 / TICK_HZ
+}
+
+fn ms_to_ticks(ms: u64) -> u64 {
+    (ms * TICK_HZ).div_ceil(1000)
+}
+
+/// A future that completes once `ms` milliseconds have passed.
+pub fn sleep(ms: u64) -> Sleep {
+    Sleep {
+        deadline: ticks() + ms_to_ticks(ms),
+    }
+}
+
+pub struct Sleep {
+    deadline: u64,
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if ticks() >= self.deadline {
+            return Poll::Ready(());
+        }
+        let mut sleepers = SLEEPERS.lock();
+        let waiting = sleepers
+            .iter()
+            .any(|(deadline, waker)| *deadline == self.deadline && waker.will_wake(cx.waker()));
+        if !waiting {
+            sleepers.push((self.deadline, cx.waker().clone()));
+        }
+        NEXT_DEADLINE.fetch_min(self.deadline, Ordering::Relaxed);
+        Poll::Pending
+    }
+}
+
+/// Returned by [`timeout`] when the time ran out first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimedOut;
+
+/// Runs `future` for at most `ms` milliseconds.
+pub async fn timeout<F: Future>(ms: u64, future: F) -> Result<F::Output, TimedOut> {
+    let mut future = pin!(future);
+    let mut sleep = sleep(ms);
+    poll_fn(|cx| {
+        if let Poll::Ready(output) = future.as_mut().poll(cx) {
+            return Poll::Ready(Ok(output));
+        }
+        match Pin::new(&mut sleep).poll(cx) {
+            Poll::Ready(()) => Poll::Ready(Err(TimedOut)),
+            Poll::Pending => Poll::Pending,
+        }
+    })
+    .await
+}
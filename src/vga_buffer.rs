@@ -61,6 +61,7 @@ impl Writer {
     pub fn write_byte(&mut self, byte: u8) {
         match byte {
             b'\n' => self.new_line(), // If the byte is a newline character, call the new_line method
+            0x08 => self.backspace(),  // If the byte is a backspace, erase the previous character
             byte => {
                 if self.column_position >= BUFFER_WIDTH {
                     // If the current column position is out of bounds, call the new_line method
@@ -92,6 +93,18 @@ impl Writer {
         self.column_position = 0;
     }
 
+    fn backspace(&mut self) {
+        if self.column_position > 0 {
+            self.column_position -= 1; // Move back one column
+            let color_code = self.color_code;
+            self.buffer.chars[BUFFER_HEIGHT - 1][self.column_position].write(ScreenChar {
+                // Blank out the character that was there
+                ascii_character: b' ',
+                color_code,
+            });
+        }
+    }
+
     fn clear_row(&mut self, row: usize) {
         for col in 0..BUFFER_WIDTH {
             // Iterate over each column in the row
@@ -107,7 +120,7 @@ impl Writer {
         for byte in s.bytes() {
             // Iterate over each byte in the string
             match byte {
-                0x20..=0x7e | b'\n' => self.write_byte(byte), // If the byte is in the printable ASCII range or a newline character, write the byte
+                0x20..=0x7e | b'\n' | 0x08 => self.write_byte(byte), // If the byte is in the printable ASCII range, a newline or a backspace, write the byte
                 _ => self.write_byte(0xfe),                   // Otherwise, write a ■ character
             }
         }
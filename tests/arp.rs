@@ -0,0 +1,144 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blight_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+mod common;
+
+use blight_os::networking::{
+    self,
+    arp::{self, ArpPacket, Operation},
+    device::{self, MacAddress},
+    ethernet::{EtherType, Frame, Header, BROADCAST},
+    ip::Ipv4Addr,
+};
+use bootloader::{entry_point, BootInfo};
+use common::{interface, take_frames, FakeCard, OUR_IP, OUR_MAC, PEER_IP, PEER_MAC};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    common::init(boot_info);
+    common::add_card(FakeCard::new());
+    common::configure_ipv4();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blight_os::test_panic_handler(info)
+}
+
+/// Parses a sent frame as ARP.
+fn sent_arp(frame: &[u8]) -> (MacAddress, ArpPacket) {
+    let frame = Frame::parse(frame).unwrap();
+    assert_eq!(frame.ether_type(), EtherType::Arp);
+    (frame.dest(), ArpPacket::parse(frame.payload()).unwrap())
+}
+
+#[test_case]
+fn packet_round_trip() {
+    let packet = ArpPacket {
+        operation: Operation::Reply,
+        sender_mac: PEER_MAC,
+        sender_ip: PEER_IP,
+        target_mac: OUR_MAC,
+        target_ip: OUR_IP,
+    };
+    let bytes = packet.to_bytes();
+    assert_eq!(&bytes[..8], &[0, 1, 8, 0, 6, 4, 0, 2]);
+    assert_eq!(ArpPacket::parse(&bytes), Some(packet));
+    assert_eq!(ArpPacket::parse(&bytes[..27]), None);
+}
+
+#[test_case]
+fn announces_on_configure() {
+    let interface = device::interface(&interface().name).unwrap();
+    take_frames();
+    networking::configure(&interface, interface.ipv4());
+
+    let sent = take_frames();
+    assert_eq!(sent.len(), 1);
+    let (dest, packet) = sent_arp(&sent[0]);
+    assert_eq!(dest, BROADCAST);
+    assert_eq!(packet.operation, Operation::Request);
+    assert_eq!(packet.sender_ip, OUR_IP);
+    assert_eq!(packet.target_ip, OUR_IP);
+}
+
+#[test_case]
+fn answers_requests() {
+    take_frames();
+    let request = ArpPacket {
+        operation: Operation::Request,
+        sender_mac: PEER_MAC,
+        sender_ip: Ipv4Addr::new(10, 0, 3, 3),
+        target_mac: [0; 6],
+        target_ip: OUR_IP,
+    };
+    arp::handle(interface(), &request.to_bytes());
+
+    let sent = take_frames();
+    assert_eq!(sent.len(), 1);
+    let (dest, reply) = sent_arp(&sent[0]);
+    assert_eq!(dest, PEER_MAC);
+    assert_eq!(reply.operation, Operation::Reply);
+    assert_eq!(reply.sender_mac, OUR_MAC);
+    assert_eq!(reply.target_ip, request.sender_ip);
+    // The requester is learned as well
+    assert_eq!(arp::lookup(request.sender_ip), Some(PEER_MAC));
+}
+
+#[test_case]
+fn queues_until_resolved() {
+    let peer = Ipv4Addr::new(10, 0, 3, 4);
+    take_frames();
+    arp::send_to(interface(), peer, EtherType::Ipv4, &[1, 2, 3]).unwrap();
+    arp::send_to(interface(), peer, EtherType::Ipv4, &[4, 5, 6]).unwrap();
+
+    // One request, and nothing else until the reply
+    let sent = take_frames();
+    assert_eq!(sent.len(), 1);
+    let (_, request) = sent_arp(&sent[0]);
+    assert_eq!(request.target_ip, peer);
+    assert_eq!(arp::lookup(peer), None);
+
+    let reply = ArpPacket {
+        operation: Operation::Reply,
+        sender_mac: PEER_MAC,
+        sender_ip: peer,
+        target_mac: OUR_MAC,
+        target_ip: OUR_IP,
+    };
+    arp::handle(interface(), &reply.to_bytes());
+    assert_eq!(arp::lookup(peer), Some(PEER_MAC));
+
+    let sent = take_frames();
+    assert_eq!(sent.len(), 2);
+    for (frame, payload) in sent.iter().zip([[1, 2, 3], [4, 5, 6]]) {
+        let frame = Frame::parse(frame).unwrap();
+        assert_eq!(
+            frame.header(),
+            Header::new(PEER_MAC, OUR_MAC, EtherType::Ipv4)
+        );
+        assert_eq!(frame.payload(), &payload);
+    }
+}
+
+#[test_case]
+fn broadcasts_skip_resolution() {
+    take_frames();
+    let broadcast = Ipv4Addr::new(10, 0, 3, 255);
+    arp::send_to(interface(), broadcast, EtherType::Ipv4, &[7]).unwrap();
+
+    let sent = take_frames();
+    assert_eq!(sent.len(), 1);
+    assert_eq!(Frame::parse(&sent[0]).unwrap().dest(), BROADCAST);
+    assert_eq!(arp::lookup(broadcast), None);
+}
@@ -0,0 +1,143 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blight_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::sync::Arc;
+use blight_os::block::{cache::BufferCache, ramdisk::RamDisk, BlockDevice, BlockError};
+use bootloader::{entry_point, BootInfo};
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use blight_os::allocator;
+    use blight_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    blight_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blight_os::test_panic_handler(info)
+}
+
+/// A RAM disk that counts the requests reaching it.
+struct CountingDisk {
+    disk: RamDisk,
+    reads: AtomicUsize,
+    writes: AtomicUsize,
+}
+
+impl CountingDisk {
+    fn new(sector_count: u64) -> Arc<CountingDisk> {
+        Arc::new(CountingDisk {
+            disk: RamDisk::blank(sector_count, 512),
+            reads: AtomicUsize::new(0),
+            writes: AtomicUsize::new(0),
+        })
+    }
+}
+
+impl BlockDevice for CountingDisk {
+    fn sector_size(&self) -> usize {
+        self.disk.sector_size()
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.disk.sector_count()
+    }
+
+    fn read_sectors(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        self.reads.fetch_add(1, Ordering::Relaxed);
+        self.disk.read_sectors(lba, buf)
+    }
+
+    fn write_sectors(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        self.writes.fetch_add(1, Ordering::Relaxed);
+        self.disk.write_sectors(lba, buf)
+    }
+}
+
+#[test_case]
+fn ramdisk_round_trip() {
+    let disk = RamDisk::blank(8, 512);
+    let mut buf = [0xFF; 1024];
+    disk.read_sectors(6, &mut buf).unwrap();
+    assert!(buf.iter().all(|&b| b == 0));
+
+    disk.write_sectors(6, &[0x42; 1024]).unwrap();
+    disk.read_sectors(7, &mut buf[..512]).unwrap();
+    assert!(buf[..512].iter().all(|&b| b == 0x42));
+
+    assert_eq!(disk.read_sectors(7, &mut buf), Err(BlockError::OutOfRange));
+    assert_eq!(
+        disk.read_sectors(0, &mut buf[..100]),
+        Err(BlockError::UnalignedBuffer)
+    );
+}
+
+#[test_case]
+fn repeated_reads_hit_the_cache() {
+    let disk = CountingDisk::new(16);
+    let cache = BufferCache::new(disk.clone(), 4);
+    let mut buf = [0; 512];
+
+    cache.read_sectors(3, &mut buf).unwrap();
+    cache.read_sectors(3, &mut buf).unwrap();
+    assert_eq!(disk.reads.load(Ordering::Relaxed), 1);
+    let stats = cache.stats();
+    assert_eq!((stats.hits, stats.misses, stats.cached), (1, 1, 1));
+}
+
+#[test_case]
+fn writes_are_deferred_until_flush() {
+    let disk = CountingDisk::new(16);
+    let cache = BufferCache::new(disk.clone(), 4);
+
+    cache.write_sectors(2, &[0xAB; 1024]).unwrap();
+    assert_eq!(disk.writes.load(Ordering::Relaxed), 0);
+    assert_eq!(cache.stats().dirty, 2);
+
+    let mut buf = [0; 512];
+    cache.read_sectors(3, &mut buf).unwrap();
+    assert_eq!(buf, [0xAB; 512]);
+
+    cache.flush().unwrap();
+    assert_eq!(disk.writes.load(Ordering::Relaxed), 2);
+    assert_eq!(cache.stats().dirty, 0);
+    disk.disk.read_sectors(2, &mut buf).unwrap();
+    assert_eq!(buf, [0xAB; 512]);
+}
+
+#[test_case]
+fn eviction_writes_back_least_recently_used() {
+    let disk = CountingDisk::new(16);
+    let cache = BufferCache::new(disk.clone(), 2);
+    let mut buf = [0; 512];
+
+    cache.write_sectors(0, &[1; 512]).unwrap();
+    cache.write_sectors(1, &[2; 512]).unwrap();
+    // Sector 0 becomes the most recently used, so 1 is evicted next
+    cache.read_sectors(0, &mut buf).unwrap();
+    cache.read_sectors(5, &mut buf).unwrap();
+
+    assert_eq!(disk.writes.load(Ordering::Relaxed), 1);
+    disk.disk.read_sectors(1, &mut buf).unwrap();
+    assert_eq!(buf, [2; 512]);
+    assert_eq!(cache.stats().cached, 2);
+}
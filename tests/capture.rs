@@ -0,0 +1,109 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blight_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+mod common;
+
+use alloc::sync::Arc;
+use blight_os::networking::{
+    capture::{self, Tap},
+    device::{ChecksumRequest, NetworkDevice},
+};
+use blight_os::rtc::DateTime;
+use bootloader::{entry_point, BootInfo};
+use common::FakeCard;
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    common::init(boot_info);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blight_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn file_header() {
+    assert_eq!(
+        capture::file_header(),
+        [
+            0xD4, 0xC3, 0xB2, 0xA1, 2, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xFF, 0xFF, 0, 0, 1, 0, 0,
+            0,
+        ]
+    );
+}
+
+#[test_case]
+fn record_header() {
+    let header = capture::record_header(1_792_326_896_250_000, 60);
+    assert_eq!(header[0..4], 1_792_326_896u32.to_le_bytes());
+    assert_eq!(header[4..8], 250_000u32.to_le_bytes());
+    assert_eq!(header[8..12], 60u32.to_le_bytes());
+    assert_eq!(header[12..16], 60u32.to_le_bytes());
+}
+
+#[test_case]
+fn unix_timestamps() {
+    let epoch = DateTime {
+        year: 1970,
+        month: 1,
+        day: 1,
+        hour: 0,
+        minute: 0,
+        second: 0,
+    };
+    assert_eq!(epoch.unix_timestamp(), 0);
+    let leap_day = DateTime {
+        year: 2000,
+        month: 2,
+        day: 29,
+        hour: 12,
+        minute: 0,
+        second: 0,
+    };
+    assert_eq!(leap_day.unix_timestamp(), 951_825_600);
+    let later = DateTime {
+        year: 2026,
+        month: 10,
+        day: 18,
+        hour: 12,
+        minute: 34,
+        second: 56,
+    };
+    assert_eq!(later.unix_timestamp(), 1_792_326_896);
+}
+
+#[test_case]
+fn tap_passes_frames_on() {
+    let card = Arc::new(FakeCard::new());
+    let tap = Tap::new(card.clone());
+    assert_eq!(tap.driver(), "fake");
+    assert_eq!(tap.mac(), card.mac());
+
+    capture::start();
+    tap.send(&[1; 60]).unwrap();
+    // The software fallback fills in the checksum for the card
+    let mut frame = [0; 60];
+    frame[20] = 0xFF;
+    let checksum = ChecksumRequest {
+        start: 20,
+        offset: 2,
+    };
+    tap.send_with_checksum(&frame, checksum).unwrap();
+    capture::stop();
+
+    let sent = card.take_sent();
+    assert_eq!(sent.len(), 2);
+    assert_eq!(sent[0], [1; 60]);
+    assert_eq!(sent[1][22..24], [0x00, 0xFF]);
+}
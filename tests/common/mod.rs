@@ -0,0 +1,158 @@
+//! Setup shared by the networking tests: the kernel heap, and a fake
+//! network card registered as an interface, with helpers to look at what
+//! it was asked to send.
+//!
+//! Each test crate only uses some of it.
+#![allow(dead_code)]
+
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
+use blight_os::networking::{
+    self,
+    arp::{self, ArpPacket, Operation},
+    device::{
+        self, FrameFuture, Interface, MacAddress, NetError, NetStats, NetworkDevice, DEFAULT_MTU,
+    },
+    ethernet::{EtherType, Frame},
+    ip::{Ipv4Addr, Ipv4Config},
+};
+use bootloader::BootInfo;
+use conquer_once::spin::OnceCell;
+use spin::Mutex;
+
+pub const OUR_MAC: MacAddress = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+pub const OUR_IP: Ipv4Addr = Ipv4Addr::new(10, 0, 3, 15);
+pub const PREFIX_LEN: u8 = 24;
+pub const PEER_MAC: MacAddress = [0x52, 0x55, 0x0A, 0x00, 0x03, 0x02];
+pub const PEER_IP: Ipv4Addr = Ipv4Addr::new(10, 0, 3, 2);
+
+static CARD: OnceCell<Arc<FakeCard>> = OnceCell::uninit();
+static INTERFACE: OnceCell<Arc<Interface>> = OnceCell::uninit();
+
+/// Sets up the kernel and its heap, as the tests' `main` has to.
+pub fn init(boot_info: &'static BootInfo) {
+    use blight_os::allocator;
+    use blight_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    blight_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+}
+
+/// A network card that keeps what it is asked to send.
+pub struct FakeCard {
+    sent: Mutex<Vec<Vec<u8>>>,
+    mtu: usize,
+}
+
+impl FakeCard {
+    pub fn new() -> FakeCard {
+        FakeCard::with_mtu(DEFAULT_MTU)
+    }
+
+    pub fn with_mtu(mtu: usize) -> FakeCard {
+        FakeCard {
+            sent: Mutex::new(Vec::new()),
+            mtu,
+        }
+    }
+
+    /// The frames sent since the last call.
+    pub fn take_sent(&self) -> Vec<Vec<u8>> {
+        core::mem::take(&mut *self.sent.lock())
+    }
+}
+
+impl NetworkDevice for FakeCard {
+    fn driver(&self) -> &'static str {
+        "fake"
+    }
+
+    fn mac(&self) -> MacAddress {
+        OUR_MAC
+    }
+
+    fn mtu(&self) -> usize {
+        self.mtu
+    }
+
+    fn link_up(&self) -> bool {
+        true
+    }
+
+    fn stats(&self) -> NetStats {
+        NetStats::default()
+    }
+
+    fn send(&self, frame: &[u8]) -> Result<(), NetError> {
+        self.sent.lock().push(frame.to_vec());
+        Ok(())
+    }
+
+    fn receive(&self) -> FrameFuture<'_> {
+        Box::pin(core::future::pending())
+    }
+}
+
+/// Registers `card` as the interface the helpers below look at.
+pub fn add_card(card: FakeCard) -> &'static Arc<Interface> {
+    let card = Arc::new(card);
+    let interface = device::register(card.clone());
+    CARD.init_once(|| card);
+    INTERFACE.init_once(|| interface);
+    self::interface()
+}
+
+/// Gives the interface [`OUR_IP`], without a gateway.
+pub fn configure_ipv4() {
+    let config = Ipv4Config {
+        address: OUR_IP,
+        prefix_len: PREFIX_LEN,
+        gateway: None,
+    };
+    networking::configure(interface(), Some(config));
+}
+
+/// Has the interface learn [`PEER_MAC`] from an ARP request, so packets
+/// for [`PEER_IP`] go out right away.
+pub fn learn_peer() {
+    let request = ArpPacket {
+        operation: Operation::Request,
+        sender_mac: PEER_MAC,
+        sender_ip: PEER_IP,
+        target_mac: [0; 6],
+        target_ip: OUR_IP,
+    };
+    arp::handle(interface(), &request.to_bytes());
+}
+
+pub fn interface() -> &'static Arc<Interface> {
+    INTERFACE.get().unwrap()
+}
+
+/// The frames the card sent since the last call.
+pub fn take_frames() -> Vec<Vec<u8>> {
+    CARD.get().unwrap().take_sent()
+}
+
+/// The IPv4 packets sent since the last call.
+pub fn take_sent() -> Vec<Vec<u8>> {
+    take_frames()
+        .iter()
+        .map(|frame| Frame::parse(frame).unwrap())
+        .filter(|frame| frame.ether_type() == EtherType::Ipv4)
+        .map(|frame| frame.payload().to_vec())
+        .collect()
+}
+
+/// The IPv6 packets sent since the last call, with the MAC each went to.
+pub fn take_sent_ipv6() -> Vec<(MacAddress, Vec<u8>)> {
+    take_frames()
+        .iter()
+        .map(|frame| Frame::parse(frame).unwrap())
+        .filter(|frame| frame.ether_type() == EtherType::Ipv6)
+        .map(|frame| (frame.dest(), frame.payload().to_vec()))
+        .collect()
+}
@@ -0,0 +1,143 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blight_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+mod common;
+
+use alloc::vec::Vec;
+use blight_os::networking::{
+    self,
+    dhcp::{self, Lease, Message, MessageType},
+    ip::{self, Ipv4Addr, Ipv4Config, Ipv4Header, Protocol},
+    udp::{self, UdpHeader},
+};
+use bootloader::{entry_point, BootInfo};
+use common::{interface, take_sent, FakeCard, OUR_MAC};
+use core::{future::Future, net::SocketAddrV4, panic::PanicInfo, pin::pin, task::Context};
+use futures_util::task::noop_waker_ref;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    common::init(boot_info);
+    // Unconfigured, until the lease comes in
+    common::add_card(FakeCard::new());
+    networking::init();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blight_os::test_panic_handler(info)
+}
+
+const OFFERED_IP: Ipv4Addr = common::OUR_IP;
+/// The DHCP server, which is also the gateway and DNS server.
+const SERVER_IP: Ipv4Addr = common::PEER_IP;
+
+/// The DHCP messages sent since the last call.
+fn take_messages() -> Vec<Message> {
+    take_sent()
+        .iter()
+        .map(|packet| {
+            let header = Ipv4Header::parse(packet).unwrap();
+            assert_eq!(header.protocol, Protocol::Udp);
+            let datagram = &packet[header.header_len..];
+            let udp = UdpHeader::parse(datagram).unwrap();
+            assert_eq!(
+                (udp.src_port, udp.dst_port),
+                (dhcp::CLIENT_PORT, dhcp::SERVER_PORT)
+            );
+            Message::parse(&datagram[udp::HEADER_SIZE..]).unwrap()
+        })
+        .collect()
+}
+
+/// The server's reply to `request`, broadcast in an IPv4 packet.
+fn reply(request: &Message, message_type: MessageType) -> Vec<u8> {
+    let mut reply = Message::request(message_type, request.xid, request.chaddr);
+    reply.op = 2;
+    reply.yiaddr = OFFERED_IP;
+    reply.server_id = Some(SERVER_IP);
+    reply.subnet_mask = Some(Ipv4Addr::new(255, 255, 255, 0));
+    reply.router = Some(SERVER_IP);
+    reply.dns_servers = alloc::vec![SERVER_IP];
+    reply.lease_secs = Some(86400);
+    let datagram = udp::build(
+        SocketAddrV4::new(SERVER_IP, dhcp::SERVER_PORT).into(),
+        SocketAddrV4::new(Ipv4Addr::BROADCAST, dhcp::CLIENT_PORT).into(),
+        &reply.to_bytes(),
+    )
+    .unwrap();
+    Ipv4Header::new(SERVER_IP, Ipv4Addr::BROADCAST, Protocol::Udp).build(&datagram)
+}
+
+#[test_case]
+fn message_round_trip() {
+    let mut message = Message::request(MessageType::Request, 0x1234_5678, OUR_MAC);
+    message.requested_ip = Some(OFFERED_IP);
+    message.server_id = Some(SERVER_IP);
+    message.dns_servers = alloc::vec![SERVER_IP, Ipv4Addr::new(1, 1, 1, 1)];
+    message.lease_secs = Some(60);
+    let bytes = message.to_bytes();
+    assert!(bytes.len() >= 300);
+    assert_eq!(Message::parse(&bytes), Some(message));
+}
+
+#[test_case]
+fn lease_times() {
+    let mut ack = Message::request(MessageType::Ack, 1, OUR_MAC);
+    ack.yiaddr = OFFERED_IP;
+    ack.server_id = Some(SERVER_IP);
+    ack.subnet_mask = Some(Ipv4Addr::new(255, 255, 240, 0));
+    ack.lease_secs = Some(3600);
+
+    let lease = Lease::from_ack(&ack, 1000).unwrap();
+    assert_eq!(lease.config.prefix_len, 20);
+    assert_eq!(lease.renew_ms, Some(1000 + 1800 * 1000));
+    assert_eq!(lease.rebind_ms, Some(1000 + 3150 * 1000));
+    assert_eq!(lease.expires_ms, Some(1000 + 3600 * 1000));
+
+    ack.lease_secs = Some(u32::MAX);
+    assert_eq!(Lease::from_ack(&ack, 1000).unwrap().expires_ms, None);
+    ack.lease_secs = None;
+    assert!(Lease::from_ack(&ack, 1000).is_none());
+}
+
+#[test_case]
+fn acquires_a_lease() {
+    let interface = interface();
+    take_sent();
+    let mut client = pin!(dhcp::run(interface.clone()));
+    let mut context = Context::from_waker(noop_waker_ref());
+
+    assert!(client.as_mut().poll(&mut context).is_pending());
+    let discover = take_messages().pop().unwrap();
+    assert_eq!(discover.message_type, MessageType::Discover);
+    assert_eq!(discover.chaddr, OUR_MAC);
+
+    ip::handle(interface, &reply(&discover, MessageType::Offer));
+    assert!(client.as_mut().poll(&mut context).is_pending());
+    let request = take_messages().pop().unwrap();
+    assert_eq!(request.message_type, MessageType::Request);
+    assert_eq!(request.xid, discover.xid);
+    assert_eq!(request.requested_ip, Some(OFFERED_IP));
+    assert_eq!(request.server_id, Some(SERVER_IP));
+
+    ip::handle(interface, &reply(&request, MessageType::Ack));
+    assert!(client.as_mut().poll(&mut context).is_pending());
+    let config = Ipv4Config {
+        address: OFFERED_IP,
+        prefix_len: 24,
+        gateway: Some(SERVER_IP),
+    };
+    assert_eq!(interface.ipv4(), Some(config));
+    assert_eq!(interface.dns_servers(), [SERVER_IP]);
+    assert_eq!(dhcp::lease(&interface.name).unwrap().server, SERVER_IP);
+}
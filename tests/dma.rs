@@ -0,0 +1,81 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blight_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use blight_os::memory::{self, alloc_dma, alloc_dma32, map_mmio, phys_to_virt, DMA32_LIMIT};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::{PhysAddr, VirtAddr};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use blight_os::allocator;
+    use blight_os::memory::BootInfoFrameAllocator;
+
+    blight_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::init_kernel_memory(phys_mem_offset, mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blight_os::test_panic_handler(info)
+}
+
+fn translate(addr: VirtAddr) -> Option<PhysAddr> {
+    unsafe { memory::translate_addr(addr, phys_to_virt(PhysAddr::new(0))) }
+}
+
+#[test_case]
+fn dma_buffer_is_contiguous_and_zeroed() {
+    let mut buffer = alloc_dma(5 * 4096).expect("out of frames");
+    assert_eq!(buffer.len(), 5 * 4096);
+    assert!(buffer.phys_addr().is_aligned(4096u64));
+    assert!(buffer.as_slice().iter().all(|&b| b == 0));
+
+    for page in 0..5u64 {
+        let virt = VirtAddr::from_ptr(buffer.as_ptr::<u8>()) + page * 4096;
+        assert_eq!(translate(virt), Some(buffer.phys_addr() + page * 4096));
+    }
+
+    buffer.as_mut_slice()[4096] = 0xAB;
+    let phys = buffer.phys_addr() + 4096u64;
+    assert_eq!(unsafe { *phys_to_virt(phys).as_ptr::<u8>() }, 0xAB);
+}
+
+#[test_case]
+fn dma_buffers_do_not_overlap() {
+    let first = alloc_dma(4096).expect("out of frames");
+    let second = alloc_dma(4096).expect("out of frames");
+    assert_ne!(first.phys_addr(), second.phys_addr());
+}
+
+#[test_case]
+fn dma32_buffers_are_below_4_gib() {
+    let buffer = alloc_dma32(16 * 4096).expect("out of frames");
+    assert!(buffer.phys_addr().as_u64() + buffer.len() as u64 <= DMA32_LIMIT);
+}
+
+#[test_case]
+fn mmio_mapping_aliases_physical_memory() {
+    let mut buffer = alloc_dma(4096).expect("out of frames");
+    buffer.as_mut_slice()[0x10..0x14].copy_from_slice(&0xDEAD_BEEFu32.to_le_bytes());
+
+    // Mapping ordinary RAM lets the register accessors be checked
+    let region = map_mmio(buffer.phys_addr() + 0x10u64, 8).expect("map failed");
+    assert_eq!(region.read_u32(0), 0xDEAD_BEEF);
+    region.write_u16(4, 0x1234);
+    assert_eq!(&buffer.as_slice()[0x14..0x16], &[0x34, 0x12]);
+    assert_eq!(region.subregion(4, 4).read_u8(1), 0x12);
+}
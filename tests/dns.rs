@@ -0,0 +1,169 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blight_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+mod common;
+
+use alloc::{string::ToString, vec::Vec};
+use blight_os::networking::{
+    self,
+    dns::{self, DnsError, RecordData, RecordType},
+    ip::{self, Ipv4Addr, Ipv4Header, Protocol},
+    udp::{self, UdpHeader},
+};
+use blight_os::task::simple_executor::block_on;
+use bootloader::{entry_point, BootInfo};
+use common::{interface, take_sent, FakeCard, OUR_IP, PEER_IP};
+use core::{
+    future::Future,
+    net::SocketAddr,
+    panic::PanicInfo,
+    pin::pin,
+    task::{Context, Poll},
+};
+use futures_util::task::noop_waker_ref;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    common::init(boot_info);
+    common::add_card(FakeCard::new());
+    common::configure_ipv4();
+    networking::init();
+    common::learn_peer();
+    interface().set_dns_servers(alloc::vec![PEER_IP]);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blight_os::test_panic_handler(info)
+}
+
+/// The DNS queries sent since the last call, with the port each came from.
+fn take_queries() -> Vec<(u16, Vec<u8>)> {
+    take_sent()
+        .iter()
+        .map(|packet| {
+            let header = Ipv4Header::parse(packet).unwrap();
+            let datagram = &packet[header.header_len..];
+            let udp = UdpHeader::parse(datagram).unwrap();
+            assert_eq!(udp.dst_port, dns::PORT);
+            (udp.src_port, datagram[udp::HEADER_SIZE..].to_vec())
+        })
+        .collect()
+}
+
+/// Appends a record for the name at `name_offset` to `response`.
+fn add_record(response: &mut Vec<u8>, name_offset: u16, record_type: u16, ttl: u32, data: &[u8]) {
+    response.extend_from_slice(&(0xC000 | name_offset).to_be_bytes());
+    response.extend_from_slice(&record_type.to_be_bytes());
+    response.extend_from_slice(&[0, 1]);
+    response.extend_from_slice(&ttl.to_be_bytes());
+    response.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    response.extend_from_slice(data);
+}
+
+/// The answer to `query` for www.example.com: a CNAME to example.com, and
+/// its address.
+fn answer(query: &[u8]) -> Vec<u8> {
+    let mut response = query.to_vec();
+    response[2] |= 0x80;
+    response[7] = 2;
+    let question_name = 12;
+    let cname_target = response.len() as u16 + 12;
+    // "example" then a pointer to the "com" of the question
+    add_record(&mut response, question_name, 5, 300, b"\x07example\xC0\x18");
+    add_record(&mut response, cname_target, 1, 60, &[93, 184, 216, 34]);
+    response
+}
+
+#[test_case]
+fn builds_queries() {
+    let query = dns::query(0xABCD, "www.example.com.", RecordType::Aaaa).unwrap();
+    assert_eq!(query[..4], [0xAB, 0xCD, 0x01, 0x00]);
+    assert_eq!(&query[12..29], b"\x03www\x07example\x03com\x00");
+    assert_eq!(query[29..], [0, 28, 0, 1]);
+
+    assert_eq!(
+        dns::query(1, "a..b", RecordType::A),
+        Err(DnsError::InvalidName)
+    );
+    let long_label = "x".repeat(64);
+    assert_eq!(
+        dns::query(1, &long_label, RecordType::A),
+        Err(DnsError::InvalidName)
+    );
+}
+
+#[test_case]
+fn parses_compressed_names() {
+    let query = dns::query(7, "www.example.com", RecordType::A).unwrap();
+    let response = dns::parse_response(&answer(&query)).unwrap();
+    assert_eq!(response.id, 7);
+    assert_eq!(
+        response.question,
+        Some(("www.example.com".to_string(), RecordType::A))
+    );
+    assert_eq!(response.answers.len(), 2);
+    assert_eq!(response.answers[0].name, "www.example.com");
+    assert_eq!(
+        response.answers[0].data,
+        RecordData::Cname("example.com".to_string())
+    );
+    assert_eq!(response.answers[1].name, "example.com");
+    assert_eq!(response.answers[1].ttl, 60);
+    assert_eq!(
+        response.answers[1].data,
+        RecordData::A(Ipv4Addr::new(93, 184, 216, 34))
+    );
+}
+
+#[test_case]
+fn rejects_pointer_loops() {
+    let mut response = dns::query(7, "loop", RecordType::A).unwrap();
+    response[2] |= 0x80;
+    response[7] = 1;
+    // A record whose name points at itself
+    let offset = response.len() as u16;
+    add_record(&mut response, offset, 1, 60, &[1, 2, 3, 4]);
+    assert_eq!(dns::parse_response(&response), Err(DnsError::Malformed));
+}
+
+#[test_case]
+fn resolves_and_caches() {
+    take_sent();
+    let mut lookup = pin!(dns::lookup_ipv4("www.example.com"));
+    let mut context = Context::from_waker(noop_waker_ref());
+    assert!(lookup.as_mut().poll(&mut context).is_pending());
+
+    let (port, query) = take_queries().pop().unwrap();
+    let datagram = udp::build(
+        SocketAddr::new(PEER_IP.into(), dns::PORT),
+        SocketAddr::new(OUR_IP.into(), port),
+        &answer(&query),
+    )
+    .unwrap();
+    ip::handle(
+        interface(),
+        &Ipv4Header::new(PEER_IP, OUR_IP, Protocol::Udp).build(&datagram),
+    );
+    let Poll::Ready(addresses) = lookup.as_mut().poll(&mut context) else {
+        panic!("lookup didn't finish");
+    };
+    assert_eq!(addresses, Ok(alloc::vec![Ipv4Addr::new(93, 184, 216, 34)]));
+
+    // Answered from the cache, whatever the case and trailing dot
+    for name in ["www.example.com", "WWW.example.com."] {
+        let addresses = block_on(dns::lookup_ipv4(name)).unwrap();
+        assert_eq!(addresses, [Ipv4Addr::new(93, 184, 216, 34)]);
+    }
+    assert!(take_queries().is_empty());
+    assert_eq!(block_on(dns::lookup_host("10.0.3.2")), Ok(PEER_IP));
+}
@@ -0,0 +1,105 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blight_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use blight_os::networking::ethernet::{
+    self, EtherType, Frame, FrameError, Header, VlanTag, BROADCAST, HEADER_SIZE, MIN_FRAME_SIZE,
+};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use blight_os::allocator;
+    use blight_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    blight_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blight_os::test_panic_handler(info)
+}
+
+const SRC: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+
+#[test_case]
+fn round_trip() {
+    let header = Header::new(BROADCAST, SRC, EtherType::Arp);
+    let frame = header.build(&[1, 2, 3, 4]);
+    assert_eq!(frame.len(), HEADER_SIZE + 4);
+    assert_eq!(&frame[12..14], &[0x08, 0x06]);
+
+    let parsed = Frame::parse(&frame).unwrap();
+    assert_eq!(parsed.dest(), BROADCAST);
+    assert_eq!(parsed.src(), SRC);
+    assert_eq!(parsed.ether_type(), EtherType::Arp);
+    assert_eq!(parsed.vlan(), None);
+    assert_eq!(parsed.payload(), &[1, 2, 3, 4]);
+    assert_eq!(parsed.header(), header);
+}
+
+#[test_case]
+fn vlan_round_trip() {
+    let mut header = Header::new(BROADCAST, SRC, EtherType::Ipv6);
+    let tag = VlanTag {
+        priority: 5,
+        drop_eligible: true,
+        id: 0x123,
+    };
+    header.vlan = Some(tag);
+    let frame = header.build(&[9; 8]);
+    assert_eq!(&frame[12..18], &[0x81, 0x00, 0xB1, 0x23, 0x86, 0xDD]);
+
+    let parsed = Frame::parse(&frame).unwrap();
+    assert_eq!(parsed.vlan(), Some(tag));
+    assert_eq!(parsed.ether_type(), EtherType::Ipv6);
+    assert_eq!(parsed.payload(), &[9; 8]);
+    assert_eq!(parsed.header(), header);
+}
+
+#[test_case]
+fn unknown_ether_type() {
+    assert_eq!(EtherType::from(0x88B5), EtherType::Other(0x88B5));
+    assert_eq!(u16::from(EtherType::Other(0x88B5)), 0x88B5);
+    assert_eq!(u16::from(EtherType::from(0x0800)), 0x0800);
+}
+
+#[test_case]
+fn truncated_frames() {
+    assert_eq!(Frame::parse(&[0; 13]).unwrap_err(), FrameError::Truncated);
+
+    let mut frame = [0; 16];
+    frame[12..14].copy_from_slice(&[0x81, 0x00]);
+    assert_eq!(Frame::parse(&frame).unwrap_err(), FrameError::Truncated);
+}
+
+#[test_case]
+fn crc32_check_value() {
+    assert_eq!(ethernet::crc32(b"123456789"), 0xCBF4_3926);
+}
+
+#[test_case]
+fn fcs_round_trip() {
+    let mut frame: Vec<u8> = Header::new(BROADCAST, SRC, EtherType::Ipv4).build(&[1, 2, 3]);
+    ethernet::append_fcs(&mut frame);
+    assert_eq!(frame.len(), MIN_FRAME_SIZE + ethernet::FCS_SIZE);
+    assert!(ethernet::check_fcs(&frame));
+
+    frame[20] ^= 1;
+    assert!(!ethernet::check_fcs(&frame));
+}
@@ -0,0 +1,270 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blight_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{string::String, sync::Arc, vec, vec::Vec};
+use blight_os::block::{ramdisk::RamDisk, BlockDevice};
+use blight_os::fs::{
+    fat32::{lfn_checksum, BiosParameterBlock, Fat32},
+    vfs::{FileType, FsError, OpenOptions, SeekFrom, Vfs},
+};
+use blight_os::serial_print;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use blight_os::allocator;
+    use blight_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    blight_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blight_os::test_panic_handler(info)
+}
+
+/// Built by `build.rs` with `mkfs.fat` and `mcopy` from `tests/fixtures/fat32`.
+static IMAGE: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/fat32.img"));
+
+fn test_disk() -> Option<Arc<RamDisk>> {
+    if IMAGE.is_empty() {
+        serial_print!("[skipped: no fat32.img] ");
+        return None;
+    }
+    Some(Arc::new(RamDisk::new(IMAGE, 512)))
+}
+
+fn test_fs() -> Option<Fat32> {
+    test_disk().map(|disk| Fat32::new(disk).expect("failed to mount fat32.img"))
+}
+
+fn test_vfs() -> Option<Vfs> {
+    test_fs().map(|fs| Vfs::new(Arc::new(fs)))
+}
+
+fn read(vfs: &Vfs, path: &str) -> Vec<u8> {
+    vfs.open(path, OpenOptions::new().read(true))
+        .unwrap()
+        .read_to_end()
+        .unwrap()
+}
+
+#[test_case]
+fn checksum_of_short_name() {
+    // Reference values from the algorithm in Microsoft's FAT specification
+    assert_eq!(lfn_checksum(b"ALONGF~1TXT"), 0x02);
+    assert_eq!(lfn_checksum(b"HELLO   TXT"), 0xF1);
+}
+
+#[test_case]
+fn boot_sector() {
+    let Some(fs) = test_fs() else { return };
+    let bpb = fs.volume().bpb();
+    assert_eq!(bpb.bytes_per_sector, 512);
+    assert_eq!(bpb.fat_count, 2);
+    assert_eq!(&bpb.volume_label, b"BLIGHT     ");
+    assert!(fs.volume().fs_info().is_some());
+}
+
+/// A FAT32 boot sector for a volume of `total_sectors` 512 byte sectors,
+/// one sector per cluster.
+fn fake_boot_sector(total_sectors: u32, fat_count: u8, sectors_per_fat: u32) -> Vec<u8> {
+    let mut sector = vec![0; 512];
+    sector[11..13].copy_from_slice(&512u16.to_le_bytes());
+    sector[13] = 1;
+    sector[14..16].copy_from_slice(&32u16.to_le_bytes());
+    sector[16] = fat_count;
+    sector[32..36].copy_from_slice(&total_sectors.to_le_bytes());
+    sector[36..40].copy_from_slice(&sectors_per_fat.to_le_bytes());
+    sector[44..48].copy_from_slice(&2u32.to_le_bytes());
+    sector[510] = 0x55;
+    sector[511] = 0xAA;
+    sector
+}
+
+#[test_case]
+fn corrupt_boot_sector() {
+    assert!(BiosParameterBlock::parse(&fake_boot_sector(4096, 2, 32)).is_ok());
+    // No FATs, or FATs bigger than the volume or than a u32 can count
+    assert_eq!(
+        BiosParameterBlock::parse(&fake_boot_sector(4096, 0, 16)).err(),
+        Some(FsError::Corrupted)
+    );
+    assert_eq!(
+        BiosParameterBlock::parse(&fake_boot_sector(4096, 2, 4096)).err(),
+        Some(FsError::Corrupted)
+    );
+    assert_eq!(
+        BiosParameterBlock::parse(&fake_boot_sector(u32::MAX, 255, u32::MAX)).err(),
+        Some(FsError::Corrupted)
+    );
+    // A FAT too small to have an entry for every cluster
+    assert_eq!(
+        BiosParameterBlock::parse(&fake_boot_sector(4096, 2, 16)).err(),
+        Some(FsError::Corrupted)
+    );
+    let mut no_root = fake_boot_sector(4096, 2, 32);
+    no_root[44..48].copy_from_slice(&0u32.to_le_bytes());
+    assert_eq!(
+        BiosParameterBlock::parse(&no_root).err(),
+        Some(FsError::Corrupted)
+    );
+
+    // A volume larger than the disk it is on
+    let disk = Arc::new(RamDisk::blank(1024, 512));
+    disk.write_sectors(0, &fake_boot_sector(4096, 2, 32))
+        .unwrap();
+    assert_eq!(Fat32::new(disk).err(), Some(FsError::Corrupted));
+}
+
+#[test_case]
+fn root_directory() {
+    let Some(vfs) = test_vfs() else { return };
+    let mut names: Vec<String> = vfs
+        .read_dir("/")
+        .unwrap()
+        .into_iter()
+        .map(|entry| entry.name)
+        .collect();
+    names.sort();
+    assert_eq!(names, ["A long file name.txt", "HELLO.TXT", "docs"]);
+    assert_eq!(
+        vfs.metadata("/docs").map(|m| m.file_type),
+        Ok(FileType::Directory)
+    );
+}
+
+#[test_case]
+fn short_and_long_names() {
+    let Some(vfs) = test_vfs() else { return };
+    assert_eq!(read(&vfs, "/HELLO.TXT"), b"Hello from FAT32!\n");
+    // Short names are matched case-insensitively
+    assert_eq!(read(&vfs, "/hello.txt"), b"Hello from FAT32!\n");
+    assert_eq!(
+        read(&vfs, "/A long file name.txt"),
+        b"This file has a long file name.\n"
+    );
+    assert_eq!(vfs.metadata("/missing.txt").err(), Some(FsError::NotFound));
+}
+
+#[test_case]
+fn multi_cluster_file() {
+    let Some(vfs) = test_vfs() else { return };
+    let data = read(&vfs, "/docs/numbers.txt");
+    assert_eq!(data.len(), 3890);
+    assert!(data.starts_with(b"0\n1\n2\n"));
+    assert!(data.ends_with(b"998\n999\n"));
+
+    let mut file = vfs
+        .open("/docs/numbers.txt", OpenOptions::new().read(true))
+        .unwrap();
+    file.seek(SeekFrom::Start(510)).unwrap();
+    let mut buf = [0; 8];
+    assert_eq!(file.read(&mut buf), Ok(8));
+    assert_eq!(&buf, &data[510..518]);
+}
+
+#[test_case]
+fn create_and_write() {
+    let Some(disk) = test_disk() else { return };
+    let vfs = Vfs::new(Arc::new(Fat32::new(disk.clone()).unwrap()));
+    let data = vec![0xA5; 1500];
+
+    vfs.create_dir("/new dir").unwrap();
+    let mut file = vfs
+        .open(
+            "/new dir/a new long name.bin",
+            OpenOptions::new().write(true).create(true),
+        )
+        .unwrap();
+    assert_eq!(file.write(&data), Ok(data.len()));
+    vfs.sync().unwrap();
+
+    // Mount the same disk again so nothing can come from cached state
+    let vfs = Vfs::new(Arc::new(Fat32::new(disk).unwrap()));
+    assert_eq!(read(&vfs, "/new dir/a new long name.bin"), data);
+    assert_eq!(vfs.read_dir("/new dir").unwrap().len(), 1);
+}
+
+#[test_case]
+fn extend_and_truncate() {
+    let Some(vfs) = test_vfs() else { return };
+    let mut file = vfs
+        .open("/HELLO.TXT", OpenOptions::new().read(true).write(true))
+        .unwrap();
+
+    file.seek(SeekFrom::Start(600)).unwrap();
+    file.write(b"tail").unwrap();
+    let data = read(&vfs, "/HELLO.TXT");
+    assert_eq!(data.len(), 604);
+    assert!(data[18..600].iter().all(|&b| b == 0));
+
+    file.set_len(5).unwrap();
+    file.set_len(8).unwrap();
+    assert_eq!(read(&vfs, "/HELLO.TXT"), b"Hello\0\0\0");
+
+    // Past what a FAT32 file can hold, or what an offset can count
+    file.seek(SeekFrom::Start(u32::MAX as u64)).unwrap();
+    assert_eq!(file.write(b"x"), Err(FsError::NoSpace));
+    file.seek(SeekFrom::Start(u64::MAX)).unwrap();
+    assert_eq!(file.write(b"x"), Err(FsError::InvalidInput));
+    assert_eq!(read(&vfs, "/HELLO.TXT").len(), 8);
+}
+
+#[test_case]
+fn rename_and_remove() {
+    let Some(vfs) = test_vfs() else { return };
+    vfs.rename("/HELLO.TXT", "/Hello again.txt").unwrap();
+    assert_eq!(vfs.metadata("/HELLO.TXT").err(), Some(FsError::NotFound));
+    assert_eq!(read(&vfs, "/hello AGAIN.txt"), b"Hello from FAT32!\n");
+    assert_eq!(
+        vfs.rename("/Hello again.txt", "/docs"),
+        Err(FsError::AlreadyExists)
+    );
+
+    assert_eq!(vfs.remove("/docs"), Err(FsError::NotEmpty));
+    vfs.remove("/docs/readme.md").unwrap();
+    vfs.remove("/docs/numbers.txt").unwrap();
+    vfs.remove("/docs").unwrap();
+    assert_eq!(vfs.read_dir("/").unwrap().len(), 2);
+}
+
+#[test_case]
+fn allocation_updates_both_fats() {
+    let Some(disk) = test_disk() else { return };
+    let fs = Fat32::new(disk.clone()).unwrap();
+    let free_before = fs.volume().fs_info().unwrap().free_count;
+    let cluster = fs.volume().allocate_cluster(None).unwrap();
+    assert_eq!(fs.volume().fs_info().unwrap().free_count, free_before - 1);
+
+    let bpb = fs.volume().bpb();
+    let sector = cluster as u64 * 4 / 512;
+    let mut first = [0; 512];
+    let mut second = [0; 512];
+    disk.read_sectors(bpb.reserved_sectors as u64 + sector, &mut first)
+        .unwrap();
+    disk.read_sectors(
+        bpb.reserved_sectors as u64 + bpb.sectors_per_fat as u64 + sector,
+        &mut second,
+    )
+    .unwrap();
+    assert_eq!(first, second);
+
+    fs.volume().free_chain(cluster).unwrap();
+    assert_eq!(fs.volume().fs_info().unwrap().free_count, free_before);
+}
@@ -0,0 +1,176 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blight_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+mod common;
+
+use alloc::{string::String, vec::Vec};
+use blight_os::introspect;
+use blight_os::networking::{
+    self,
+    http::{self, HttpError, Method, Request, Response},
+    ip::Ipv4Addr,
+    tcp::TcpStream,
+};
+use blight_os::task::{self, executor::Executor, simple_executor::block_on, Task};
+use bootloader::{entry_point, BootInfo};
+use core::{net::SocketAddr, panic::PanicInfo, pin::pin};
+use futures_util::future::{select, Either};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    common::init(boot_info);
+    // Just lo, to talk to the server over
+    networking::init();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blight_os::test_panic_handler(info)
+}
+
+fn get(path: &str) -> Request {
+    Request {
+        method: Method::Get,
+        path: String::from(path),
+        query: None,
+        headers: Vec::new(),
+    }
+}
+
+#[test_case]
+fn parses_requests() {
+    let data = b"GET /pci?verbose=1 HTTP/1.1\r\nHost: localhost:8080\r\nAccept: */*\r\n\r\nbody";
+    let (request, len) = Request::parse(data).unwrap().unwrap();
+    assert_eq!(len, data.len() - 4);
+    assert_eq!(request.method, Method::Get);
+    assert_eq!(request.path, "/pci");
+    assert_eq!(request.query.as_deref(), Some("verbose=1"));
+    assert_eq!(request.header("host"), Some("localhost:8080"));
+    assert_eq!(request.header("accept"), Some("*/*"));
+
+    assert_eq!(Request::parse(b"GET / HTTP/1.1\r\nHost: x\r\n"), Ok(None));
+    assert_eq!(Request::parse(b"GET /\r\n\r\n"), Err(HttpError::BadRequest));
+    assert_eq!(
+        Request::parse(b"GET / HTTP/1.1\r\nno colon\r\n\r\n"),
+        Err(HttpError::BadRequest)
+    );
+    assert_eq!(
+        Request::parse(&[b'a'; http::MAX_HEAD_SIZE + 1]),
+        Err(HttpError::HeadTooLarge)
+    );
+}
+
+#[test_case]
+fn formats_responses() {
+    let response = Response::json(200, String::from("{}"));
+    assert_eq!(
+        response.to_bytes(true),
+        b"HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: 2\r\nConnection: close\r\n\r\n{}"
+    );
+    // HEAD gets the headers only, with the length of the body it would get
+    assert!(response
+        .to_bytes(false)
+        .ends_with(b"Content-Length: 2\r\nConnection: close\r\n\r\n"));
+    assert_eq!(Response::error(404).body, b"{\"error\":\"Not Found\"}");
+}
+
+#[test_case]
+fn escapes_strings() {
+    assert_eq!(introspect::string("eth0"), "\"eth0\"");
+    assert_eq!(
+        introspect::string("a \"b\"\\\n\u{1}"),
+        "\"a \\\"b\\\"\\\\\\n\\u0001\""
+    );
+}
+
+#[test_case]
+fn routes() {
+    assert_eq!(introspect::handle(&get("/nope")).status, 404);
+    let mut post = get("/status");
+    post.method = Method::Post;
+    assert_eq!(introspect::handle(&post).status, 405);
+
+    let response = introspect::handle(&get("/heap"));
+    assert_eq!(response.status, 200);
+    let heap = String::from_utf8(response.body).unwrap();
+    assert!(heap.starts_with("{\"start\":75059993772032,\"size\":1048576,"));
+
+    let status = String::from_utf8(introspect::handle(&get("/status")).body).unwrap();
+    assert!(status.contains("\"name\":\"lo\",\"driver\":\"loopback\""));
+    assert!(status.contains("\"ipv4\":\"127.0.0.1/8\",\"ipv6\":[\"::1/128\"]"));
+}
+
+#[test_case]
+fn lists_pci_functions() {
+    let functions = blight_os::pci::functions();
+    // QEMU's host bridge is always there
+    let host_bridge = functions
+        .iter()
+        .find(|function| function.class == 0x06 && function.subclass == 0x00)
+        .unwrap();
+    // Later calls serve the list from the boot scan instead of walking again
+    assert!(core::ptr::eq(functions, blight_os::pci::functions()));
+    let bridges = blight_os::pci::get_devices_by_class(0x06, 0x00);
+    assert!(bridges
+        .iter()
+        .any(|header| header.device_id == host_bridge.device_id));
+    let pci = introspect::pci();
+    assert!(pci.starts_with('[') && pci.ends_with(']'));
+    assert!(pci.contains(&alloc::format!(
+        "\"vendor_id\":{},\"device_id\":{}",
+        host_bridge.vendor_id,
+        host_bridge.device_id
+    )));
+}
+
+async fn idle() {}
+
+#[test_case]
+fn lists_tasks() {
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(idle()));
+    let info = task::tasks()
+        .into_iter()
+        .find(|info| info.name.ends_with("::idle"))
+        .unwrap();
+    assert_eq!(info.polls, 0);
+    assert!(introspect::tasks().contains("::idle\",\"polls\":0}"));
+
+    drop(executor);
+    assert!(task::tasks().iter().all(|other| other.id != info.id));
+}
+
+#[test_case]
+fn serves_over_tcp() {
+    let client = async {
+        let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), introspect::PORT);
+        let stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET /tasks HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        let mut buffer = [0; 512];
+        loop {
+            match stream.read(&mut buffer).await.unwrap() {
+                0 => break response,
+                len => response.extend_from_slice(&buffer[..len]),
+            }
+        }
+    };
+    let response = match block_on(select(pin!(introspect::run()), pin!(client))) {
+        Either::Left(_) => panic!("server stopped"),
+        Either::Right((response, _)) => String::from_utf8(response).unwrap(),
+    };
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n"));
+    assert!(response.ends_with("\r\n\r\n[]"));
+}
@@ -0,0 +1,114 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blight_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+mod common;
+
+use alloc::vec::Vec;
+use blight_os::networking::{
+    self, icmp,
+    ip::{self, Ipv4Addr, Ipv4Header, Protocol},
+};
+use bootloader::{entry_point, BootInfo};
+use common::{interface, take_sent, FakeCard, OUR_IP, PEER_IP};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    common::init(boot_info);
+    common::add_card(FakeCard::new());
+    common::configure_ipv4();
+    networking::init();
+    common::learn_peer();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blight_os::test_panic_handler(info)
+}
+
+/// Reserved for experimentation by RFC 3692, and never registered.
+const UNKNOWN_PROTOCOL: Protocol = Protocol::Other(253);
+
+/// An ICMP message with a correct checksum.
+fn message(kind: u8, code: u8, rest: [u8; 4], data: &[u8]) -> Vec<u8> {
+    let mut message = alloc::vec![kind, code, 0, 0];
+    message.extend_from_slice(&rest);
+    message.extend_from_slice(data);
+    let checksum = ip::checksum(&message);
+    message[2..4].copy_from_slice(&checksum.to_be_bytes());
+    message
+}
+
+#[test_case]
+fn answers_echo_requests() {
+    take_sent();
+    let request = message(8, 0, [0x12, 0x34, 0x00, 0x07], b"hello");
+    let packet = Ipv4Header::new(PEER_IP, OUR_IP, Protocol::Icmp).build(&request);
+    ip::handle(interface(), &packet);
+
+    let sent = take_sent();
+    assert_eq!(sent.len(), 1);
+    let header = Ipv4Header::parse(&sent[0]).unwrap();
+    assert_eq!((header.src, header.dst), (OUR_IP, PEER_IP));
+    assert_eq!(header.protocol, Protocol::Icmp);
+    let reply = &sent[0][header.header_len..];
+    assert_eq!(ip::checksum(reply), 0);
+    assert_eq!(reply[0], 0);
+    assert_eq!(reply[4..8], [0x12, 0x34, 0x00, 0x07]);
+    assert_eq!(&reply[icmp::HEADER_SIZE..], b"hello");
+}
+
+#[test_case]
+fn ignores_bad_checksums() {
+    take_sent();
+    let mut request = message(8, 0, [0; 4], b"hello");
+    request[8] ^= 1;
+    let packet = Ipv4Header::new(PEER_IP, OUR_IP, Protocol::Icmp).build(&request);
+    ip::handle(interface(), &packet);
+    assert!(take_sent().is_empty());
+}
+
+#[test_case]
+fn reports_unknown_protocols() {
+    take_sent();
+    let data: Vec<u8> = (0..20).collect();
+    let packet = Ipv4Header::new(PEER_IP, OUR_IP, UNKNOWN_PROTOCOL).build(&data);
+    ip::handle(interface(), &packet);
+
+    let sent = take_sent();
+    assert_eq!(sent.len(), 1);
+    let header = Ipv4Header::parse(&sent[0]).unwrap();
+    assert_eq!(header.dst, PEER_IP);
+    let error = &sent[0][header.header_len..];
+    assert_eq!(ip::checksum(error), 0);
+    assert_eq!(error[0], 3);
+    assert_eq!(error[1], icmp::CODE_PROTOCOL_UNREACHABLE);
+
+    // The offending header and the first eight bytes of its payload
+    let quote = &error[icmp::HEADER_SIZE..];
+    assert_eq!(quote.len(), ip::HEADER_SIZE + 8);
+    let quoted = Ipv4Header::parse_quoted(quote).unwrap();
+    assert_eq!(quoted.protocol, UNKNOWN_PROTOCOL);
+    assert_eq!(quoted.total_len as usize, packet.len());
+    assert_eq!(quote[ip::HEADER_SIZE..], data[..8]);
+}
+
+#[test_case]
+fn no_errors_about_broadcasts() {
+    take_sent();
+    let subnet_broadcast = Ipv4Addr::new(10, 0, 3, 255);
+    for dst in [subnet_broadcast, Ipv4Addr::BROADCAST] {
+        let packet = Ipv4Header::new(PEER_IP, dst, UNKNOWN_PROTOCOL).build(&[0; 8]);
+        ip::handle(interface(), &packet);
+    }
+    assert!(take_sent().is_empty());
+}
@@ -0,0 +1,148 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blight_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{boxed::Box, format, vec, vec::Vec};
+use blight_os::fs::initrd::{self, EntryKind, Format, Initrd, InitrdError};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use blight_os::allocator;
+    use blight_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    blight_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blight_os::test_panic_handler(info)
+}
+
+/// Appends a newc cpio record, padding the name and data to 4 bytes.
+fn push_cpio_entry(archive: &mut Vec<u8>, ino: u32, mode: u32, name: &str, data: &[u8]) {
+    let header = format!(
+        "070701{:08X}{:08X}{:08X}{:08X}{:08X}{:08X}{:08X}{:08X}{:08X}{:08X}{:08X}{:08X}{:08X}",
+        ino,
+        mode,
+        0,
+        0,
+        1,
+        0,
+        data.len(),
+        0,
+        0,
+        0,
+        0,
+        name.len() + 1,
+        0
+    );
+    archive.extend_from_slice(header.as_bytes());
+    archive.extend_from_slice(name.as_bytes());
+    archive.push(0);
+    archive.resize(archive.len().div_ceil(4) * 4, 0);
+    archive.extend_from_slice(data);
+    archive.resize(archive.len().div_ceil(4) * 4, 0);
+}
+
+fn cpio_archive() -> &'static [u8] {
+    let mut archive = Vec::new();
+    push_cpio_entry(&mut archive, 1, 0o040755, "bin", b"");
+    push_cpio_entry(
+        &mut archive,
+        2,
+        0o100644,
+        "bin/hello.txt",
+        b"Hello, initrd!\n",
+    );
+    push_cpio_entry(&mut archive, 3, 0o120777, "hello", b"bin/hello.txt");
+    push_cpio_entry(&mut archive, 0, 0, "TRAILER!!!", b"");
+    Box::leak(archive.into_boxed_slice())
+}
+
+#[test_case]
+fn embedded_archive_is_ustar() {
+    let initrd = Initrd::parse(initrd::EMBEDDED).expect("failed to parse embedded initrd");
+    assert_eq!(initrd.format(), Format::Ustar);
+    assert_eq!(initrd.read("/etc/hostname"), Ok(&b"blight\n"[..]));
+    assert!(initrd.is_dir("etc"));
+}
+
+#[test_case]
+fn newc_cpio_files() {
+    let initrd = Initrd::parse(cpio_archive()).expect("failed to parse cpio archive");
+    assert_eq!(initrd.format(), Format::NewcCpio);
+    assert_eq!(initrd.read("bin/hello.txt"), Ok(&b"Hello, initrd!\n"[..]));
+    assert_eq!(
+        initrd.get("hello").map(|entry| entry.kind),
+        Some(EntryKind::Symlink("bin/hello.txt"))
+    );
+}
+
+#[test_case]
+fn read_dir_lists_children() {
+    let initrd = Initrd::parse(cpio_archive()).unwrap();
+    let root = initrd.read_dir("/").unwrap();
+    assert_eq!(root.len(), 2);
+    assert!(root
+        .iter()
+        .any(|entry| entry.name == "bin" && entry.kind == EntryKind::Directory));
+
+    let bin = initrd.read_dir("bin").unwrap();
+    assert_eq!(bin.len(), 1);
+    assert_eq!(bin[0].name, "hello.txt");
+}
+
+#[test_case]
+fn missing_paths() {
+    let initrd = Initrd::parse(cpio_archive()).unwrap();
+    assert_eq!(initrd.read("nope"), Err(InitrdError::NotFound));
+    assert_eq!(initrd.read("bin"), Err(InitrdError::IsADirectory));
+    assert_eq!(
+        initrd.read_dir("bin/hello.txt").unwrap_err(),
+        InitrdError::NotADirectory
+    );
+}
+
+#[test_case]
+fn rejects_garbage() {
+    assert_eq!(
+        Initrd::parse(&[0xAB; 600]).unwrap_err(),
+        InitrdError::UnknownFormat
+    );
+}
+
+#[test_case]
+fn rejects_oversized_ustar_fields() {
+    // A header whose size doesn't fit in 32 bits, with a valid checksum
+    let mut header = vec![0u8; 512];
+    header[..4].copy_from_slice(b"file");
+    header[100..108].copy_from_slice(b"0000644\0");
+    header[124..136].copy_from_slice(b"77777777777\0");
+    header[156] = b'0';
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[148..156].fill(b' ');
+    let checksum: u32 = header.iter().map(|&b| b as u32).sum();
+    header[148..156].copy_from_slice(format!("{:06o}\0 ", checksum).as_bytes());
+    header.resize(2048, 0);
+
+    let archive = Box::leak(header.into_boxed_slice());
+    assert_eq!(
+        Initrd::parse(archive).unwrap_err(),
+        InitrdError::InvalidHeader
+    );
+}
@@ -0,0 +1,197 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blight_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+mod common;
+
+use alloc::{string::ToString, sync::Arc, vec::Vec};
+use blight_os::networking::{
+    device::Interface,
+    ip::{self, IpError, Ipv4Addr, Ipv4Config, Ipv4Header, Protocol, Route},
+};
+use bootloader::{entry_point, BootInfo};
+use common::{interface, take_sent, FakeCard, OUR_IP, PEER_IP};
+use core::panic::PanicInfo;
+use spin::Mutex;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    common::init(boot_info);
+    common::add_card(FakeCard::with_mtu(MTU));
+    common::configure_ipv4();
+    ip::register_protocol(TEST_PROTOCOL, record_packet);
+    common::learn_peer();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blight_os::test_panic_handler(info)
+}
+
+/// Reserved for experimentation by RFC 3692.
+const TEST_PROTOCOL: Protocol = Protocol::Other(253);
+/// Small, so packets are easy to get fragmented.
+const MTU: usize = 576;
+
+static RECEIVED: Mutex<Vec<(Ipv4Header, Vec<u8>)>> = Mutex::new(Vec::new());
+
+fn record_packet(_interface: &Arc<Interface>, header: &Ipv4Header, payload: &[u8]) {
+    RECEIVED.lock().push((*header, payload.to_vec()));
+}
+
+#[test_case]
+fn checksum_of_known_header() {
+    let mut header = [
+        0x45, 0x00, 0x00, 0x73, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11, 0xb8, 0x61, 0xc0, 0xa8, 0x00,
+        0x01, 0xc0, 0xa8, 0x00, 0xc7,
+    ];
+    assert_eq!(ip::checksum(&header), 0);
+    header[10..12].fill(0);
+    assert_eq!(ip::checksum(&header), 0xb861);
+}
+
+#[test_case]
+fn header_round_trip() {
+    let mut header = Ipv4Header::new(OUR_IP, PEER_IP, Protocol::Udp);
+    header.ident = 0x1234;
+    header.dont_fragment = true;
+    header.ttl = 7;
+    let packet = header.build(&[1, 2, 3, 4]);
+
+    let parsed = Ipv4Header::parse(&packet).unwrap();
+    assert_eq!(parsed.total_len, 24);
+    assert_eq!(parsed.ident, 0x1234);
+    assert!(parsed.dont_fragment);
+    assert!(!parsed.is_fragment());
+    assert_eq!(parsed.ttl, 7);
+    assert_eq!(parsed.protocol, Protocol::Udp);
+    assert_eq!((parsed.src, parsed.dst), (OUR_IP, PEER_IP));
+    assert_eq!(parsed.payload_len(), 4);
+}
+
+#[test_case]
+fn rejects_bad_packets() {
+    let mut packet = Ipv4Header::new(OUR_IP, PEER_IP, Protocol::Udp).build(&[0; 8]);
+    assert_eq!(
+        Ipv4Header::parse(&packet[..27]).unwrap_err(),
+        IpError::Truncated
+    );
+    packet[8] ^= 1;
+    assert_eq!(
+        Ipv4Header::parse(&packet).unwrap_err(),
+        IpError::BadChecksum
+    );
+    packet[0] = 0x65;
+    assert_eq!(
+        Ipv4Header::parse(&packet).unwrap_err(),
+        IpError::InvalidHeader
+    );
+}
+
+#[test_case]
+fn longest_prefix_wins() {
+    ip::add_route(Route {
+        destination: Ipv4Addr::new(10, 0, 3, 128),
+        prefix_len: 25,
+        gateway: Some(PEER_IP),
+        interface: interface().name.to_string(),
+        expires_ms: None,
+    });
+
+    let (_, next_hop) = ip::lookup_route(Ipv4Addr::new(10, 0, 3, 200)).unwrap();
+    assert_eq!(next_hop, PEER_IP);
+    let (_, next_hop) = ip::lookup_route(Ipv4Addr::new(10, 0, 3, 20)).unwrap();
+    assert_eq!(next_hop, Ipv4Addr::new(10, 0, 3, 20));
+    assert!(ip::lookup_route(Ipv4Addr::new(8, 8, 8, 8)).is_none());
+    assert_eq!(
+        ip::send(Ipv4Addr::new(8, 8, 8, 8), TEST_PROTOCOL, &[]).unwrap_err(),
+        IpError::NoRoute
+    );
+}
+
+#[test_case]
+fn overlong_prefixes_match_one_address() {
+    let route = Route {
+        destination: Ipv4Addr::new(192, 0, 2, 1),
+        prefix_len: 200,
+        gateway: None,
+        interface: interface().name.to_string(),
+        expires_ms: None,
+    };
+    assert!(route.matches(Ipv4Addr::new(192, 0, 2, 1)));
+    assert!(!route.matches(Ipv4Addr::new(192, 0, 2, 2)));
+    let config = Ipv4Config {
+        address: OUR_IP,
+        prefix_len: 33,
+        gateway: None,
+    };
+    assert_eq!(config.netmask(), Ipv4Addr::BROADCAST);
+}
+
+#[test_case]
+fn fragments_large_packets() {
+    take_sent();
+    let payload: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+    ip::send(PEER_IP, TEST_PROTOCOL, &payload).unwrap();
+
+    let sent = take_sent();
+    assert_eq!(sent.len(), 2);
+    let first = Ipv4Header::parse(&sent[0]).unwrap();
+    let second = Ipv4Header::parse(&sent[1]).unwrap();
+    assert!(first.more_fragments && !second.more_fragments);
+    assert_eq!(first.fragment_offset, 0);
+    assert_eq!(second.fragment_offset, first.payload_len());
+    assert_eq!(first.ident, second.ident);
+    assert_eq!(first.ttl, ip::DEFAULT_TTL);
+    assert!(first.total_len as usize <= MTU);
+    assert_eq!(first.payload_len() + second.payload_len(), 1000);
+}
+
+#[test_case]
+fn reassembles_out_of_order() {
+    RECEIVED.lock().clear();
+    let payload: Vec<u8> = (0..100).collect();
+    let mut header = Ipv4Header::new(PEER_IP, OUR_IP, TEST_PROTOCOL);
+    header.ident = 77;
+
+    let mut fragments = Vec::new();
+    for (i, chunk) in payload.chunks(40).enumerate() {
+        header.fragment_offset = i * 40;
+        header.more_fragments = i < 2;
+        fragments.push(header.build(chunk));
+    }
+    // Last fragment first, and the middle one twice
+    for index in [2, 1, 1, 0] {
+        assert!(RECEIVED.lock().is_empty());
+        ip::handle(interface(), &fragments[index]);
+    }
+
+    let received = core::mem::take(&mut *RECEIVED.lock());
+    assert_eq!(received.len(), 1);
+    let (header, data) = &received[0];
+    assert_eq!(data, &payload);
+    assert!(!header.is_fragment());
+    assert_eq!(header.src, PEER_IP);
+}
+
+#[test_case]
+fn ignores_packets_for_others() {
+    RECEIVED.lock().clear();
+    let packet = Ipv4Header::new(PEER_IP, Ipv4Addr::new(10, 0, 3, 99), TEST_PROTOCOL).build(&[1]);
+    ip::handle(interface(), &packet);
+    assert!(RECEIVED.lock().is_empty());
+
+    // Ethernet padding after the packet is cut off
+    let mut packet = Ipv4Header::new(PEER_IP, OUR_IP, TEST_PROTOCOL).build(&[1, 2]);
+    packet.extend_from_slice(&[0; 10]);
+    ip::handle(interface(), &packet);
+    assert_eq!(RECEIVED.lock()[0].1, [1, 2]);
+}
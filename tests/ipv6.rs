@@ -0,0 +1,452 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blight_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+mod common;
+
+use alloc::vec;
+use blight_os::networking::{
+    self, icmpv6,
+    ip::{IpError, Protocol},
+    ipv6::{self, AddressState, Ipv6Addr, Ipv6Address, Ipv6Header},
+    ndp::{self, Message, PrefixInfo},
+    tcp::{State, TcpHeader, TcpListener, ACK, SYN},
+    udp::{self, UdpHeader, UdpSocket},
+};
+use blight_os::{task::simple_executor::block_on, time};
+use bootloader::{entry_point, BootInfo};
+use common::{interface, take_sent_ipv6, FakeCard, OUR_MAC, PEER_MAC};
+use core::{net::SocketAddr, panic::PanicInfo};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    common::init(boot_info);
+    let interface = common::add_card(FakeCard::new());
+    networking::init();
+    ndp::start(interface);
+    // Skip duplicate address detection, which takes a second
+    interface.add_ipv6(Ipv6Address {
+        address: OUR_LINK_LOCAL,
+        prefix_len: 64,
+        state: AddressState::Preferred,
+        valid_until_ms: None,
+    });
+
+    // Learn the peer's address, so packets for it go out right away
+    let solicitation = Message::NeighborSolicitation {
+        target: OUR_LINK_LOCAL,
+        source_mac: Some(PEER_MAC),
+    };
+    receive_ndp(
+        PEER_LINK_LOCAL,
+        ipv6::solicited_node(OUR_LINK_LOCAL),
+        &solicitation,
+    );
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blight_os::test_panic_handler(info)
+}
+
+const OUR_LINK_LOCAL: Ipv6Addr = Ipv6Addr::new(0xFE80, 0, 0, 0, 0x5054, 0x00FF, 0xFE12, 0x3456);
+const PEER_LINK_LOCAL: Ipv6Addr = Ipv6Addr::new(0xFE80, 0, 0, 0, 0x5055, 0x0AFF, 0xFE00, 0x0302);
+/// From the documentation range of RFC 3849.
+const PREFIX: Ipv6Addr = Ipv6Addr::new(0x2001, 0xDB8, 1, 0, 0, 0, 0, 0);
+
+/// Has the interface receive a neighbor discovery message.
+fn receive_ndp(src: Ipv6Addr, dst: Ipv6Addr, message: &Message) {
+    let mut header = Ipv6Header::new(src, dst, Protocol::Icmpv6);
+    header.hop_limit = 255;
+    let packet = header.build(&message.to_bytes(src, dst));
+    ipv6::handle(interface(), &packet);
+}
+
+/// Parses a sent packet as a neighbor discovery message.
+fn sent_ndp(packet: &[u8]) -> (Ipv6Header, Message) {
+    let header = Ipv6Header::parse(packet).unwrap();
+    assert_eq!(header.next_header, Protocol::Icmpv6);
+    assert_eq!(header.hop_limit, 255);
+    let message = &packet[ipv6::HEADER_SIZE..];
+    let checksum = ipv6::pseudo_header(header.src, header.dst, Protocol::Icmpv6, message.len())
+        .add(message)
+        .finish();
+    assert_eq!(checksum, 0);
+    (header, Message::parse(message).unwrap())
+}
+
+#[test_case]
+fn header_round_trip() {
+    let mut header = Ipv6Header::new(PEER_LINK_LOCAL, OUR_LINK_LOCAL, Protocol::Udp);
+    header.traffic_class = 0xB8;
+    header.flow_label = 0x12345;
+    let packet = header.build(b"hello");
+    assert_eq!(packet.len(), ipv6::HEADER_SIZE + 5);
+    assert_eq!(packet[0] >> 4, 6);
+
+    header.payload_len = 5;
+    assert_eq!(Ipv6Header::parse(&packet), Ok(header));
+    assert_eq!(
+        Ipv6Header::parse(&packet[..packet.len() - 1]),
+        Err(IpError::Truncated)
+    );
+}
+
+#[test_case]
+fn addresses_from_mac() {
+    assert_eq!(
+        ipv6::interface_id(OUR_MAC),
+        [0x50, 0x54, 0x00, 0xFF, 0xFE, 0x12, 0x34, 0x56]
+    );
+    assert_eq!(ipv6::link_local(OUR_MAC), OUR_LINK_LOCAL);
+    assert_eq!(
+        ipv6::solicited_node(OUR_LINK_LOCAL),
+        Ipv6Addr::new(0xFF02, 0, 0, 0, 0, 1, 0xFF12, 0x3456)
+    );
+    assert_eq!(
+        ipv6::multicast_mac(ipv6::solicited_node(OUR_LINK_LOCAL)),
+        [0x33, 0x33, 0xFF, 0x12, 0x34, 0x56]
+    );
+}
+
+#[test_case]
+fn answers_neighbor_solicitations() {
+    take_sent_ipv6();
+    let solicitation = Message::NeighborSolicitation {
+        target: OUR_LINK_LOCAL,
+        source_mac: Some(PEER_MAC),
+    };
+    receive_ndp(
+        PEER_LINK_LOCAL,
+        ipv6::solicited_node(OUR_LINK_LOCAL),
+        &solicitation,
+    );
+
+    let sent = take_sent_ipv6();
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0].0, PEER_MAC);
+    let (header, message) = sent_ndp(&sent[0].1);
+    assert_eq!((header.src, header.dst), (OUR_LINK_LOCAL, PEER_LINK_LOCAL));
+    assert_eq!(
+        message,
+        Message::NeighborAdvertisement {
+            router: false,
+            solicited: true,
+            overrides: true,
+            target: OUR_LINK_LOCAL,
+            target_mac: Some(OUR_MAC),
+        }
+    );
+    assert_eq!(ndp::lookup(PEER_LINK_LOCAL), Some(PEER_MAC));
+}
+
+#[test_case]
+fn answers_echo_requests() {
+    take_sent_ipv6();
+    let request = icmpv6::build(
+        PEER_LINK_LOCAL,
+        OUR_LINK_LOCAL,
+        128,
+        0,
+        [0x12, 0x34, 0x00, 0x07],
+        b"hello",
+    );
+    let packet = Ipv6Header::new(PEER_LINK_LOCAL, OUR_LINK_LOCAL, Protocol::Icmpv6).build(&request);
+    ipv6::handle(interface(), &packet);
+
+    let sent = take_sent_ipv6();
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0].0, PEER_MAC);
+    let header = Ipv6Header::parse(&sent[0].1).unwrap();
+    assert_eq!((header.src, header.dst), (OUR_LINK_LOCAL, PEER_LINK_LOCAL));
+    let reply = &sent[0].1[ipv6::HEADER_SIZE..];
+    let checksum = ipv6::pseudo_header(header.src, header.dst, Protocol::Icmpv6, reply.len())
+        .add(reply)
+        .finish();
+    assert_eq!(checksum, 0);
+    assert_eq!(reply[0], 129);
+    assert_eq!(reply[4..8], [0x12, 0x34, 0x00, 0x07]);
+    assert_eq!(&reply[icmpv6::HEADER_SIZE..], b"hello");
+}
+
+#[test_case]
+fn reports_unknown_next_headers() {
+    take_sent_ipv6();
+    let packet =
+        Ipv6Header::new(PEER_LINK_LOCAL, OUR_LINK_LOCAL, Protocol::Other(253)).build(&[0; 8]);
+    ipv6::handle(interface(), &packet);
+
+    let sent = take_sent_ipv6();
+    assert_eq!(sent.len(), 1);
+    let error = &sent[0].1[ipv6::HEADER_SIZE..];
+    assert_eq!(error[..2], [4, icmpv6::CODE_UNRECOGNIZED_NEXT_HEADER]);
+    assert_eq!(error[4..8], [0, 0, 0, 6]);
+    assert_eq!(&error[icmpv6::HEADER_SIZE..], &packet[..]);
+}
+
+#[test_case]
+fn router_advertisements_configure_addresses() {
+    take_sent_ipv6();
+    let advertisement = Message::RouterAdvertisement {
+        hop_limit: 64,
+        router_lifetime_secs: 1800,
+        source_mac: Some(PEER_MAC),
+        prefixes: vec![PrefixInfo {
+            prefix: PREFIX,
+            prefix_len: 64,
+            on_link: true,
+            autonomous: true,
+            valid_secs: 86400,
+            preferred_secs: 14400,
+        }],
+    };
+    receive_ndp(PEER_LINK_LOCAL, ipv6::ALL_NODES, &advertisement);
+
+    let address = ipv6::with_interface_id(PREFIX, OUR_MAC);
+    assert_eq!(
+        address,
+        Ipv6Addr::new(0x2001, 0xDB8, 1, 0, 0x5054, 0x00FF, 0xFE12, 0x3456)
+    );
+    let configured = interface()
+        .ipv6()
+        .into_iter()
+        .find(|other| other.address == address)
+        .unwrap();
+    assert_eq!(configured.prefix_len, 64);
+    assert_eq!(configured.state, AddressState::Tentative);
+
+    // The new address is probed for first
+    let sent = take_sent_ipv6();
+    assert_eq!(sent.len(), 1);
+    let (header, message) = sent_ndp(&sent[0].1);
+    assert_eq!(header.src, Ipv6Addr::UNSPECIFIED);
+    assert_eq!(header.dst, ipv6::solicited_node(address));
+    assert_eq!(
+        message,
+        Message::NeighborSolicitation {
+            target: address,
+            source_mac: None,
+        }
+    );
+
+    let (_, next_hop) = ipv6::lookup_route(Ipv6Addr::new(0x2001, 0xDB8, 2, 0, 0, 0, 0, 1)).unwrap();
+    assert_eq!(next_hop, PEER_LINK_LOCAL);
+    let on_link = Ipv6Addr::new(0x2001, 0xDB8, 1, 0, 0, 0, 0, 1);
+    assert_eq!(ipv6::lookup_route(on_link).unwrap().1, on_link);
+}
+
+#[test_case]
+fn duplicates_are_given_up() {
+    let address = Ipv6Addr::new(0x2001, 0xDB8, 3, 0, 0, 0, 0, 1);
+    ndp::add_address(interface(), address, 64, None);
+    take_sent_ipv6();
+
+    let advertisement = Message::NeighborAdvertisement {
+        router: false,
+        solicited: false,
+        overrides: true,
+        target: address,
+        target_mac: Some(PEER_MAC),
+    };
+    receive_ndp(PEER_LINK_LOCAL, ipv6::ALL_NODES, &advertisement);
+    assert!(interface()
+        .ipv6()
+        .iter()
+        .all(|other| other.address != address));
+    assert!(take_sent_ipv6().is_empty());
+}
+
+#[test_case]
+fn overlong_prefixes_are_ignored() {
+    let prefix = Ipv6Addr::new(0x2001, 0xDB8, 4, 0, 0, 0, 0, 0);
+    let advertisement = Message::RouterAdvertisement {
+        hop_limit: 64,
+        router_lifetime_secs: 0,
+        source_mac: Some(PEER_MAC),
+        prefixes: vec![PrefixInfo {
+            prefix,
+            prefix_len: 200,
+            on_link: true,
+            autonomous: true,
+            valid_secs: 86400,
+            preferred_secs: 14400,
+        }],
+    };
+    let bytes = advertisement.to_bytes(PEER_LINK_LOCAL, ipv6::ALL_NODES);
+    let Some(Message::RouterAdvertisement { prefixes, .. }) = Message::parse(&bytes) else {
+        panic!("not parsed as a router advertisement");
+    };
+    assert!(prefixes.is_empty());
+
+    receive_ndp(PEER_LINK_LOCAL, ipv6::ALL_NODES, &advertisement);
+    assert!(ipv6::routes()
+        .iter()
+        .all(|route| route.destination != prefix));
+    assert!(interface()
+        .ipv6()
+        .iter()
+        .all(|other| other.address.segments()[..3] != [0x2001, 0xDB8, 4]));
+
+    // Routes added some other way match their destination only
+    let route = ipv6::Route {
+        destination: prefix,
+        prefix_len: 200,
+        gateway: None,
+        interface: interface().name.clone(),
+        expires_ms: None,
+    };
+    assert!(route.matches(prefix));
+    assert!(!route.matches(Ipv6Addr::new(0x2001, 0xDB8, 4, 0, 0, 0, 0, 1)));
+}
+
+#[test_case]
+fn advertisements_keep_two_hours_of_lifetime() {
+    const HOUR_SECS: u32 = 60 * 60;
+    const HOUR_MS: u64 = HOUR_SECS as u64 * 1000;
+    let prefix = Ipv6Addr::new(0x2001, 0xDB8, 5, 0, 0, 0, 0, 0);
+    let address = ipv6::with_interface_id(prefix, OUR_MAC);
+    let advertise = |valid_secs| {
+        let advertisement = Message::RouterAdvertisement {
+            hop_limit: 64,
+            router_lifetime_secs: 0,
+            source_mac: Some(PEER_MAC),
+            prefixes: vec![PrefixInfo {
+                prefix,
+                prefix_len: 64,
+                on_link: false,
+                autonomous: true,
+                valid_secs,
+                preferred_secs: 0,
+            }],
+        };
+        let before = time::uptime_ms();
+        receive_ndp(PEER_LINK_LOCAL, ipv6::ALL_NODES, &advertisement);
+        let valid_until = interface()
+            .ipv6()
+            .into_iter()
+            .find(|other| other.address == address)
+            .unwrap()
+            .valid_until_ms
+            .unwrap();
+        valid_until - before
+    };
+
+    assert!((10 * HOUR_MS..10 * HOUR_MS + 1000).contains(&advertise(10 * HOUR_SECS)));
+    // Shorter lifetimes are cut to two hours, and no further
+    assert!((2 * HOUR_MS..2 * HOUR_MS + 1000).contains(&advertise(0)));
+    assert!((2 * HOUR_MS - 1000..=2 * HOUR_MS).contains(&advertise(60)));
+    // Longer ones are always taken
+    assert!((3 * HOUR_MS..3 * HOUR_MS + 1000).contains(&advertise(3 * HOUR_SECS)));
+    take_sent_ipv6();
+}
+
+/// Parses a sent packet as a transport segment and checks its checksum.
+fn sent_transport(packet: &[u8], protocol: Protocol) -> (Ipv6Header, &[u8]) {
+    let header = Ipv6Header::parse(packet).unwrap();
+    assert_eq!(header.next_header, protocol);
+    let segment = &packet[ipv6::HEADER_SIZE..];
+    let checksum = ipv6::pseudo_header(header.src, header.dst, protocol, segment.len())
+        .add(segment)
+        .finish();
+    assert_eq!(checksum, 0);
+    (header, segment)
+}
+
+#[test_case]
+fn carries_udp() {
+    let socket = UdpSocket::bind(7100).unwrap();
+    let peer = SocketAddr::new(PEER_LINK_LOCAL.into(), 5000);
+    let datagram = udp::build(peer, SocketAddr::new(OUR_LINK_LOCAL.into(), 7100), b"ping").unwrap();
+    let packet = Ipv6Header::new(PEER_LINK_LOCAL, OUR_LINK_LOCAL, Protocol::Udp).build(&datagram);
+    ipv6::handle(interface(), &packet);
+    assert_eq!(
+        socket.try_recv_from(),
+        Some((vec![b'p', b'i', b'n', b'g'], peer))
+    );
+
+    take_sent_ipv6();
+    block_on(socket.send_to(b"pong", peer)).unwrap();
+    let sent = take_sent_ipv6();
+    assert_eq!(sent.len(), 1);
+    let (header, datagram) = sent_transport(&sent[0].1, Protocol::Udp);
+    assert_eq!((header.src, header.dst), (OUR_LINK_LOCAL, PEER_LINK_LOCAL));
+    let udp = UdpHeader::parse(datagram).unwrap();
+    assert_eq!((udp.src_port, udp.dst_port), (7100, 5000));
+    assert_eq!(&datagram[udp::HEADER_SIZE..], b"pong");
+}
+
+#[test_case]
+fn udp_needs_a_checksum() {
+    let socket = UdpSocket::bind(7101).unwrap();
+    let mut datagram = udp::build(
+        SocketAddr::new(PEER_LINK_LOCAL.into(), 5000),
+        SocketAddr::new(OUR_LINK_LOCAL.into(), 7101),
+        b"data",
+    )
+    .unwrap();
+    // Unlike over IPv4, zero doesn't mean there is none
+    datagram[6..8].copy_from_slice(&[0, 0]);
+    let packet = Ipv6Header::new(PEER_LINK_LOCAL, OUR_LINK_LOCAL, Protocol::Udp).build(&datagram);
+    ipv6::handle(interface(), &packet);
+    assert!(socket.try_recv_from().is_none());
+}
+
+#[test_case]
+fn closed_udp_ports_are_unreachable() {
+    take_sent_ipv6();
+    let datagram = udp::build(
+        SocketAddr::new(PEER_LINK_LOCAL.into(), 5000),
+        SocketAddr::new(OUR_LINK_LOCAL.into(), 7199),
+        b"data",
+    )
+    .unwrap();
+    let packet = Ipv6Header::new(PEER_LINK_LOCAL, OUR_LINK_LOCAL, Protocol::Udp).build(&datagram);
+    ipv6::handle(interface(), &packet);
+
+    let sent = take_sent_ipv6();
+    assert_eq!(sent.len(), 1);
+    let (_, error) = sent_transport(&sent[0].1, Protocol::Icmpv6);
+    assert_eq!(error[..2], [1, icmpv6::CODE_PORT_UNREACHABLE]);
+    assert_eq!(&error[icmpv6::HEADER_SIZE..], &packet[..]);
+}
+
+#[test_case]
+fn carries_tcp() {
+    let listener = TcpListener::bind(7200).unwrap();
+    let mut syn = TcpHeader::new(40000, 7200, 100, 0, SYN);
+    syn.window = 8192;
+    let segment = syn.build(PEER_LINK_LOCAL.into(), OUR_LINK_LOCAL.into(), &[]);
+    take_sent_ipv6();
+    let packet = Ipv6Header::new(PEER_LINK_LOCAL, OUR_LINK_LOCAL, Protocol::Tcp).build(&segment);
+    ipv6::handle(interface(), &packet);
+
+    let sent = take_sent_ipv6();
+    assert_eq!(sent.len(), 1);
+    let (header, segment) = sent_transport(&sent[0].1, Protocol::Tcp);
+    assert_eq!((header.src, header.dst), (OUR_LINK_LOCAL, PEER_LINK_LOCAL));
+    let syn_ack = TcpHeader::parse(segment).unwrap();
+    assert_eq!(syn_ack.flags, SYN | ACK);
+    assert_eq!(syn_ack.ack, 101);
+    // The IPv6 header is 20 bytes longer than the IPv4 one
+    assert_eq!(syn_ack.mss, Some(1440));
+
+    let ack = TcpHeader::new(40000, 7200, 101, syn_ack.seq + 1, ACK);
+    let segment = ack.build(PEER_LINK_LOCAL.into(), OUR_LINK_LOCAL.into(), &[]);
+    let packet = Ipv6Header::new(PEER_LINK_LOCAL, OUR_LINK_LOCAL, Protocol::Tcp).build(&segment);
+    ipv6::handle(interface(), &packet);
+    let (stream, peer) = block_on(listener.accept());
+    assert_eq!(peer, SocketAddr::new(PEER_LINK_LOCAL.into(), 40000));
+    assert_eq!(
+        stream.local_addr(),
+        SocketAddr::new(OUR_LINK_LOCAL.into(), 7200)
+    );
+    assert_eq!(stream.state(), State::Established);
+}
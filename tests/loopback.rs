@@ -0,0 +1,149 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blight_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+mod common;
+
+use blight_os::networking::{
+    self, device, icmp, icmpv6,
+    ip::Ipv4Addr,
+    ipv6::Ipv6Addr,
+    loopback,
+    tcp::{State, TcpError, TcpListener, TcpStream},
+    udp::{self, UdpError, UdpSocket},
+};
+use blight_os::task::simple_executor::block_on;
+use bootloader::{entry_point, BootInfo};
+use core::{net::SocketAddr, panic::PanicInfo};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    common::init(boot_info);
+    // No network card: everything below goes through lo
+    networking::init();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blight_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn registered() {
+    let interface = device::interface(loopback::NAME).unwrap();
+    let config = interface.ipv4().unwrap();
+    assert_eq!(config.address, Ipv4Addr::LOCALHOST);
+    assert_eq!(config.prefix_len, 8);
+    assert_eq!(interface.device.mtu(), loopback::MTU);
+    assert!(interface
+        .ipv6()
+        .iter()
+        .any(|address| address.address == Ipv6Addr::LOCALHOST));
+}
+
+#[test_case]
+fn udp_binding_ports() {
+    let socket = UdpSocket::bind(7000).unwrap();
+    assert_eq!(socket.local_port(), 7000);
+    assert_eq!(UdpSocket::bind(7000).unwrap_err(), UdpError::AddrInUse);
+    drop(socket);
+    assert!(UdpSocket::bind(7000).is_ok());
+
+    let first = UdpSocket::bind(0).unwrap();
+    let second = UdpSocket::bind(0).unwrap();
+    assert!(udp::EPHEMERAL_PORTS.contains(&first.local_port()));
+    assert!(udp::EPHEMERAL_PORTS.contains(&second.local_port()));
+    assert_ne!(first.local_port(), second.local_port());
+}
+
+#[test_case]
+fn udp_round_trip() {
+    let server = UdpSocket::bind(5353).unwrap();
+    let client = UdpSocket::bind(0).unwrap();
+    // Any address in 127.0.0.0/8 reaches us
+    let dst = SocketAddr::new(Ipv4Addr::new(127, 0, 0, 53).into(), 5353);
+    block_on(client.send_to(b"ping", dst)).unwrap();
+
+    let (payload, from) = server.try_recv_from().unwrap();
+    assert_eq!(payload, b"ping");
+    assert_eq!(
+        from,
+        SocketAddr::new(Ipv4Addr::LOCALHOST.into(), client.local_port())
+    );
+
+    // Datagrams queue up until they are read, in order
+    block_on(client.send_to(b"first", dst)).unwrap();
+    block_on(client.send_to(b"second", dst)).unwrap();
+    assert_eq!(block_on(server.recv_from()).0, b"first");
+    assert_eq!(server.try_recv_from().unwrap().0, b"second");
+    assert!(server.try_recv_from().is_none());
+
+    block_on(server.send_to(b"pong", from)).unwrap();
+    let (payload, from) = block_on(client.recv_from());
+    assert_eq!(payload, b"pong");
+    assert_eq!(from, SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 5353));
+}
+
+#[test_case]
+fn tcp_round_trip() {
+    let listener = TcpListener::bind(8080).unwrap();
+    let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 8080);
+    let client = block_on(TcpStream::connect(addr)).unwrap();
+    let (server, peer) = block_on(listener.accept());
+    assert_eq!(peer, client.local_addr());
+    assert_eq!(client.state(), State::Established);
+
+    let mut buffer = [0; 16];
+    block_on(client.write_all(b"hello")).unwrap();
+    assert_eq!(block_on(server.read(&mut buffer)), Ok(5));
+    assert_eq!(&buffer[..5], b"hello");
+    block_on(server.write_all(b"world")).unwrap();
+    assert_eq!(block_on(client.read(&mut buffer)), Ok(5));
+    assert_eq!(&buffer[..5], b"world");
+
+    drop(client);
+    assert_eq!(block_on(server.read(&mut buffer)), Ok(0));
+}
+
+#[test_case]
+fn tcp_closes_both_ways() {
+    let listener = TcpListener::bind(8081).unwrap();
+    assert_eq!(TcpListener::bind(8081).unwrap_err(), TcpError::AddrInUse);
+    let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 8081);
+    let client = block_on(TcpStream::connect(addr)).unwrap();
+    let (server, _) = block_on(listener.accept());
+
+    let mut buffer = [0; 16];
+    block_on(client.write_all(b"bye")).unwrap();
+    client.shutdown();
+    assert_eq!(client.state(), State::FinWait2);
+    assert_eq!(server.state(), State::CloseWait);
+    // The data before the FIN still arrives, then the end of the stream
+    assert_eq!(block_on(server.read(&mut buffer)), Ok(3));
+    assert_eq!(block_on(server.read(&mut buffer)), Ok(0));
+
+    // The other direction stays open until the server is done too
+    block_on(server.write_all(b"ok")).unwrap();
+    assert_eq!(block_on(client.read(&mut buffer)), Ok(2));
+    server.shutdown();
+    assert_eq!(server.state(), State::Closed);
+    assert_eq!(client.state(), State::TimeWait);
+    assert_eq!(block_on(client.read(&mut buffer)), Ok(0));
+}
+
+#[test_case]
+fn pings_itself() {
+    let ident = icmp::new_ident();
+    let reply = block_on(icmp::echo(Ipv4Addr::LOCALHOST, ident, 0, 1000)).unwrap();
+    assert_eq!(reply.from, Ipv4Addr::LOCALHOST);
+    let reply = block_on(icmpv6::echo(Ipv6Addr::LOCALHOST, ident, 1, 1000)).unwrap();
+    assert_eq!(reply.from, Ipv6Addr::LOCALHOST);
+}
@@ -0,0 +1,223 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blight_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+mod common;
+
+use alloc::vec::Vec;
+use blight_os::networking::{
+    self,
+    ip::{self, Ipv4Header, Protocol},
+    tcp::{self, State, TcpError, TcpHeader, TcpListener, TcpStream, ACK, FIN, PSH, RST, SYN},
+};
+use blight_os::task::simple_executor::block_on;
+use bootloader::{entry_point, BootInfo};
+use common::{interface, take_sent, FakeCard, OUR_IP, PEER_IP};
+use core::{
+    future::Future,
+    net::SocketAddr,
+    panic::PanicInfo,
+    pin::pin,
+    task::{Context, Poll},
+};
+use futures_util::task::noop_waker_ref;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    common::init(boot_info);
+    common::add_card(FakeCard::new());
+    common::configure_ipv4();
+    networking::init();
+    common::learn_peer();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blight_os::test_panic_handler(info)
+}
+
+const PEER_PORT: u16 = 40000;
+
+/// A segment from the peer, in an IPv4 packet.
+fn segment(dst_port: u16, seq: u32, ack: u32, flags: u8, data: &[u8]) -> Vec<u8> {
+    let mut header = TcpHeader::new(PEER_PORT, dst_port, seq, ack, flags);
+    header.window = 8192;
+    if flags & SYN != 0 {
+        header.mss = Some(1000);
+    }
+    let segment = header.build(PEER_IP.into(), OUR_IP.into(), data);
+    Ipv4Header::new(PEER_IP, OUR_IP, Protocol::Tcp).build(&segment)
+}
+
+/// The TCP segments sent since the last call, with their data.
+fn take_segments() -> Vec<(TcpHeader, Vec<u8>)> {
+    take_sent()
+        .iter()
+        .map(|packet| {
+            let header = Ipv4Header::parse(packet).unwrap();
+            assert_eq!(header.protocol, Protocol::Tcp);
+            let segment = &packet[header.header_len..];
+            let checksum = ip::pseudo_header(OUR_IP, PEER_IP, Protocol::Tcp, segment.len())
+                .add(segment)
+                .finish();
+            assert_eq!(checksum, 0);
+            let tcp = TcpHeader::parse(segment).unwrap();
+            (tcp, segment[tcp.header_len..].to_vec())
+        })
+        .collect()
+}
+
+/// Goes through the handshake with a listener on `port` and accepts the
+/// connection. Returns it with our initial sequence number.
+fn open(listener: &TcpListener, peer_iss: u32) -> (TcpStream, u32) {
+    let port = listener.local_port();
+    take_sent();
+    ip::handle(interface(), &segment(port, peer_iss, 0, SYN, &[]));
+    let sent = take_segments();
+    assert_eq!(sent.len(), 1);
+    let syn_ack = sent[0].0;
+    assert_eq!(syn_ack.flags, SYN | ACK);
+    assert_eq!(syn_ack.ack, peer_iss + 1);
+
+    let iss = syn_ack.seq;
+    ip::handle(interface(), &segment(port, peer_iss + 1, iss + 1, ACK, &[]));
+    let (stream, peer) = block_on(listener.accept());
+    assert_eq!(peer, SocketAddr::new(PEER_IP.into(), PEER_PORT));
+    assert_eq!(stream.state(), State::Established);
+    (stream, iss)
+}
+
+#[test_case]
+fn header_round_trip() {
+    let mut header = TcpHeader::new(1, 2, 0xDEAD_BEEF, 7, SYN | ACK);
+    header.window = 1234;
+    header.mss = Some(1460);
+    let segment = header.build(OUR_IP.into(), PEER_IP.into(), b"xy");
+
+    let parsed = TcpHeader::parse(&segment).unwrap();
+    assert_eq!(parsed.header_len, tcp::HEADER_SIZE + 4);
+    assert_eq!(
+        parsed,
+        TcpHeader {
+            header_len: 24,
+            ..header
+        }
+    );
+    assert_eq!(&segment[parsed.header_len..], b"xy");
+}
+
+#[test_case]
+fn closed_ports_reset() {
+    take_sent();
+    ip::handle(interface(), &segment(9999, 100, 0, SYN, &[]));
+    let sent = take_segments();
+    assert_eq!(sent.len(), 1);
+    let (reset, _) = sent[0];
+    assert_eq!(reset.flags, RST | ACK);
+    assert_eq!(reset.ack, 101);
+}
+
+#[test_case]
+fn exchanges_data_and_closes() {
+    let listener = TcpListener::bind(8000).unwrap();
+    assert_eq!(TcpListener::bind(8000).unwrap_err(), TcpError::AddrInUse);
+    let (stream, iss) = open(&listener, 1000);
+
+    ip::handle(
+        interface(),
+        &segment(8000, 1001, iss + 1, ACK | PSH, b"hello"),
+    );
+    let mut buffer = [0; 16];
+    assert_eq!(block_on(stream.read(&mut buffer)), Ok(5));
+    assert_eq!(&buffer[..5], b"hello");
+
+    block_on(stream.write_all(b"world")).unwrap();
+    let sent = take_segments();
+    assert_eq!(sent.len(), 1);
+    let (data, payload) = &sent[0];
+    assert_eq!(data.seq, iss + 1);
+    // The data takes the delayed acknowledgement along
+    assert_eq!(data.ack, 1006);
+    assert_eq!(payload, b"world");
+
+    stream.shutdown();
+    let (fin, _) = take_segments()[0];
+    assert_eq!(fin.flags & FIN, FIN);
+    assert_eq!(fin.seq, iss + 6);
+    assert_eq!(stream.state(), State::FinWait1);
+
+    ip::handle(interface(), &segment(8000, 1006, iss + 7, ACK | FIN, &[]));
+    let (ack, _) = take_segments()[0];
+    assert_eq!(ack.ack, 1007);
+    assert_eq!(stream.state(), State::TimeWait);
+    assert_eq!(block_on(stream.read(&mut buffer)), Ok(0));
+}
+
+#[test_case]
+fn acknowledges_unexpected_segments() {
+    let listener = TcpListener::bind(8001).unwrap();
+    let (stream, iss) = open(&listener, 5000);
+
+    // Data from the future is dropped, and the peer learns what we expect
+    ip::handle(interface(), &segment(8001, 5100, iss + 1, ACK, b"later"));
+    let sent = take_segments();
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0].0.ack, 5001);
+
+    let mut buffer = [0; 16];
+    ip::handle(interface(), &segment(8001, 5001, iss + 1, ACK, b"now"));
+    assert_eq!(block_on(stream.read(&mut buffer)), Ok(3));
+    // A retransmission overlapping what we have only adds the new part
+    ip::handle(interface(), &segment(8001, 5001, iss + 1, ACK, b"nowmore"));
+    assert_eq!(block_on(stream.read(&mut buffer)), Ok(4));
+    assert_eq!(&buffer[..4], b"more");
+}
+
+#[test_case]
+fn reset_by_peer() {
+    let listener = TcpListener::bind(8002).unwrap();
+    let (stream, _) = open(&listener, 7000);
+    ip::handle(interface(), &segment(8002, 7001, 0, RST, &[]));
+    assert_eq!(stream.state(), State::Closed);
+    let mut buffer = [0; 16];
+    assert_eq!(block_on(stream.read(&mut buffer)), Err(TcpError::Reset));
+    assert_eq!(block_on(stream.write(b"x")), Err(TcpError::Reset));
+}
+
+#[test_case]
+fn connects() {
+    take_sent();
+    let remote = SocketAddr::new(PEER_IP.into(), PEER_PORT);
+    let mut connect = pin!(TcpStream::connect(remote));
+    let mut context = Context::from_waker(noop_waker_ref());
+    assert!(connect.as_mut().poll(&mut context).is_pending());
+
+    let sent = take_segments();
+    assert_eq!(sent.len(), 1);
+    let (syn, _) = sent[0];
+    assert_eq!(syn.flags, SYN);
+    assert!(syn.mss.is_some());
+
+    let mut header = TcpHeader::new(PEER_PORT, syn.src_port, 300, syn.seq + 1, SYN | ACK);
+    header.window = 8192;
+    let syn_ack = header.build(PEER_IP.into(), OUR_IP.into(), &[]);
+    ip::handle(
+        interface(),
+        &Ipv4Header::new(PEER_IP, OUR_IP, Protocol::Tcp).build(&syn_ack),
+    );
+    let Poll::Ready(Ok(stream)) = connect.as_mut().poll(&mut context) else {
+        panic!("connect didn't finish");
+    };
+    assert_eq!(stream.peer_addr(), remote);
+    assert_eq!(stream.state(), State::Established);
+    let (ack, _) = take_segments()[0];
+    assert_eq!((ack.flags, ack.ack), (ACK, 301));
+}
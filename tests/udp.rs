@@ -0,0 +1,99 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blight_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+mod common;
+
+use alloc::vec::Vec;
+use blight_os::networking::{
+    self, icmp,
+    ip::{self, Ipv4Header, Protocol},
+    udp::{self, UdpHeader, UdpSocket},
+};
+use blight_os::task::simple_executor::block_on;
+use bootloader::{entry_point, BootInfo};
+use common::{interface, take_sent, FakeCard, OUR_IP, PEER_IP};
+use core::{net::SocketAddr, panic::PanicInfo};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    common::init(boot_info);
+    common::add_card(FakeCard::new());
+    common::configure_ipv4();
+    networking::init();
+    common::learn_peer();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blight_os::test_panic_handler(info)
+}
+
+/// A datagram from the peer to `port`, in an IPv4 packet.
+fn datagram_to(port: u16, payload: &[u8]) -> Vec<u8> {
+    let datagram = udp::build(
+        SocketAddr::new(PEER_IP.into(), 5000),
+        SocketAddr::new(OUR_IP.into(), port),
+        payload,
+    )
+    .unwrap();
+    Ipv4Header::new(PEER_IP, OUR_IP, Protocol::Udp).build(&datagram)
+}
+
+#[test_case]
+fn checks_checksums() {
+    let socket = UdpSocket::bind(7002).unwrap();
+    let mut packet = datagram_to(7002, b"data");
+    let last = packet.len() - 1;
+    packet[last] ^= 1;
+    ip::handle(interface(), &packet);
+    assert!(socket.try_recv_from().is_none());
+
+    // A zero checksum means the sender didn't compute one
+    packet[ip::HEADER_SIZE + 6..ip::HEADER_SIZE + 8].fill(0);
+    ip::handle(interface(), &packet);
+    assert!(socket.try_recv_from().is_some());
+}
+
+#[test_case]
+fn sends_datagrams() {
+    take_sent();
+    let socket = UdpSocket::bind(7003).unwrap();
+    block_on(socket.send_to(b"hello", SocketAddr::new(PEER_IP.into(), 53))).unwrap();
+
+    let sent = take_sent();
+    assert_eq!(sent.len(), 1);
+    let header = Ipv4Header::parse(&sent[0]).unwrap();
+    assert_eq!(header.protocol, Protocol::Udp);
+    let datagram = &sent[0][header.header_len..];
+    let udp = UdpHeader::parse(datagram).unwrap();
+    assert_eq!((udp.src_port, udp.dst_port), (7003, 53));
+    assert_eq!(udp.len as usize, udp::HEADER_SIZE + 5);
+    let checksum = ip::pseudo_header(OUR_IP, PEER_IP, Protocol::Udp, datagram.len())
+        .add(datagram)
+        .finish();
+    assert_eq!(checksum, 0);
+    assert_eq!(&datagram[udp::HEADER_SIZE..], b"hello");
+}
+
+#[test_case]
+fn closed_ports_are_unreachable() {
+    take_sent();
+    ip::handle(interface(), &datagram_to(7999, b"anyone?"));
+
+    let sent = take_sent();
+    assert_eq!(sent.len(), 1);
+    let header = Ipv4Header::parse(&sent[0]).unwrap();
+    assert_eq!((header.protocol, header.dst), (Protocol::Icmp, PEER_IP));
+    let error = &sent[0][header.header_len..];
+    assert_eq!(error[0], 3);
+    assert_eq!(error[1], icmp::CODE_PORT_UNREACHABLE);
+}
@@ -0,0 +1,148 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blight_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
+use blight_os::fs::{
+    initrd::{self, Initrd, InitrdFs},
+    tmpfs::TmpFs,
+    vfs::{FileType, FsError, OpenOptions, SeekFrom, Vfs},
+};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use blight_os::allocator;
+    use blight_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    blight_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blight_os::test_panic_handler(info)
+}
+
+fn test_vfs() -> Vfs {
+    let vfs = Vfs::new(Arc::new(TmpFs::new()));
+    let initrd = Initrd::parse(initrd::EMBEDDED).expect("failed to parse embedded initrd");
+    let initrd = Box::leak(Box::new(initrd));
+    vfs.create_dir("/initrd").unwrap();
+    vfs.mount("/initrd", Arc::new(InitrdFs::new(initrd)))
+        .unwrap();
+    vfs
+}
+
+#[test_case]
+fn write_then_read() {
+    let vfs = test_vfs();
+    let mut file = vfs
+        .open(
+            "/hello",
+            OpenOptions::new().read(true).write(true).create(true),
+        )
+        .unwrap();
+    assert_eq!(file.write(b"hello world"), Ok(11));
+    assert_eq!(file.seek(SeekFrom::Start(6)), Ok(6));
+
+    let mut buf = [0; 5];
+    assert_eq!(file.read(&mut buf), Ok(5));
+    assert_eq!(&buf, b"world");
+
+    assert_eq!(file.seek(SeekFrom::End(-5)), Ok(6));
+    assert_eq!(file.write(b"WORLD!"), Ok(6));
+    assert_eq!(file.metadata().size, 12);
+}
+
+#[test_case]
+fn relative_paths() {
+    let vfs = test_vfs();
+    vfs.create_dir("/a").unwrap();
+    vfs.create_dir("/a/b").unwrap();
+    vfs.set_cwd("/a/b").unwrap();
+    assert_eq!(vfs.cwd().path(), "/a/b");
+
+    vfs.set_cwd("../..").unwrap();
+    assert_eq!(vfs.cwd().path(), "/");
+    assert_eq!(vfs.resolve("a/./b/../b").unwrap().path(), "/a/b");
+    assert_eq!(vfs.resolve("/..").unwrap().path(), "/");
+}
+
+#[test_case]
+fn mounted_initrd() {
+    let vfs = test_vfs();
+    let mut file = vfs
+        .open("/initrd/etc/hostname", OpenOptions::new().read(true))
+        .unwrap();
+    assert_eq!(file.read_to_end().unwrap(), b"blight\n");
+
+    vfs.set_cwd("/initrd/etc").unwrap();
+    vfs.set_cwd("..").unwrap();
+    assert_eq!(vfs.cwd().path(), "/initrd");
+    vfs.set_cwd("..").unwrap();
+    assert_eq!(vfs.cwd().path(), "/");
+
+    assert_eq!(
+        vfs.open("/initrd/new", OpenOptions::new().write(true).create(true))
+            .err(),
+        Some(FsError::ReadOnly)
+    );
+    assert_eq!(vfs.remove("/initrd"), Err(FsError::Busy));
+}
+
+#[test_case]
+fn remove_and_rename() {
+    let vfs = test_vfs();
+    vfs.create_dir("/dir").unwrap();
+    vfs.open("/dir/file", OpenOptions::new().write(true).create(true))
+        .unwrap();
+    assert_eq!(vfs.remove("/dir"), Err(FsError::NotEmpty));
+
+    vfs.rename("/dir/file", "/dir/renamed").unwrap();
+    assert_eq!(vfs.metadata("/dir/file").err(), Some(FsError::NotFound));
+    assert_eq!(
+        vfs.metadata("/dir/renamed").map(|m| m.file_type),
+        Ok(FileType::File)
+    );
+
+    vfs.remove("/dir/renamed").unwrap();
+    vfs.remove("/dir").unwrap();
+    let names: Vec<_> = vfs
+        .read_dir("/")
+        .unwrap()
+        .into_iter()
+        .map(|entry| entry.name)
+        .collect();
+    assert_eq!(names, ["initrd"]);
+}
+
+#[test_case]
+fn writes_far_past_the_end() {
+    let vfs = test_vfs();
+    let mut file = vfs
+        .open("/sparse", OpenOptions::new().write(true).create(true))
+        .unwrap();
+    assert_eq!(file.seek(SeekFrom::Start(u64::MAX)), Ok(u64::MAX));
+    assert_eq!(file.write(b"x"), Err(FsError::InvalidInput));
+    // More than the heap holds
+    assert_eq!(file.seek(SeekFrom::Start(1 << 30)), Ok(1 << 30));
+    assert_eq!(file.write(b"x"), Err(FsError::NoSpace));
+    assert_eq!(file.seek(SeekFrom::Start(1 << 40)), Ok(1 << 40));
+    assert_eq!(file.write(b"x"), Err(FsError::NoSpace));
+    assert_eq!(file.set_len(1 << 40), Err(FsError::NoSpace));
+    assert_eq!(file.metadata().size, 0);
+}
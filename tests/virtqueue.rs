@@ -0,0 +1,119 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blight_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use blight_os::memory::{self, phys_to_virt};
+use blight_os::pci::drivers::virtio::queue::{Buffer, Virtqueue};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::PhysAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use blight_os::allocator;
+    use blight_os::memory::BootInfoFrameAllocator;
+    use x86_64::VirtAddr;
+
+    blight_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::init_kernel_memory(phys_mem_offset, mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blight_os::test_panic_handler(info)
+}
+
+fn read_u16(addr: PhysAddr) -> u16 {
+    unsafe { phys_to_virt(addr).as_ptr::<u16>().read_volatile() }
+}
+
+fn read_desc(queue: &Virtqueue, i: u16) -> (u64, u32, u16, u16) {
+    let base = phys_to_virt(queue.desc_addr() + i as u64 * 16);
+    unsafe {
+        (
+            base.as_ptr::<u64>().read_volatile(),
+            (base + 8u64).as_ptr::<u32>().read_volatile(),
+            (base + 12u64).as_ptr::<u16>().read_volatile(),
+            (base + 14u64).as_ptr::<u16>().read_volatile(),
+        )
+    }
+}
+
+/// Does what the device does when it is finished with a chain.
+fn complete(queue: &Virtqueue, head: u16, len: u32) {
+    let used = phys_to_virt(queue.used_addr());
+    unsafe {
+        let idx = (used + 2u64).as_mut_ptr::<u16>();
+        let slot = (idx.read_volatile() % queue.size()) as u64;
+        let entry = used + 4u64 + slot * 8;
+        entry.as_mut_ptr::<u32>().write_volatile(head as u32);
+        (entry + 4u64).as_mut_ptr::<u32>().write_volatile(len);
+        idx.write_volatile(idx.read_volatile().wrapping_add(1));
+    }
+}
+
+#[test_case]
+fn chains_are_linked_and_published() {
+    let mut queue = Virtqueue::new(0, 8).expect("out of frames");
+    let head = queue
+        .add(&[
+            Buffer::readable(PhysAddr::new(0x1000), 16),
+            Buffer::writable(PhysAddr::new(0x2000), 512),
+        ])
+        .unwrap();
+    assert_eq!(queue.free_count(), 6);
+
+    let (addr, len, flags, next) = read_desc(&queue, head);
+    assert_eq!((addr, len, flags), (0x1000, 16, 1));
+    let (addr, len, flags, _) = read_desc(&queue, next);
+    assert_eq!((addr, len, flags), (0x2000, 512, 2));
+
+    // The avail ring holds the head, and its index counts one chain
+    assert_eq!(read_u16(queue.avail_addr() + 2u64), 1);
+    assert_eq!(read_u16(queue.avail_addr() + 4u64), head);
+}
+
+#[test_case]
+fn used_chains_are_freed() {
+    let mut queue = Virtqueue::new(0, 4).expect("out of frames");
+    let buffer = Buffer::readable(PhysAddr::new(0x1000), 1);
+    let first = queue.add(&[buffer, buffer, buffer]).unwrap();
+    assert!(queue.add(&[buffer, buffer]).is_none());
+    let second = queue.add(&[buffer]).unwrap();
+    assert_eq!(queue.free_count(), 0);
+    assert_eq!(queue.pop_used(), None);
+
+    complete(&queue, second, 0);
+    complete(&queue, first, 42);
+    assert_eq!(queue.pop_used(), Some((second, 0)));
+    assert_eq!(queue.pop_used(), Some((first, 42)));
+    assert_eq!(queue.pop_used(), None);
+    assert_eq!(queue.free_count(), 4);
+
+    // The freed descriptors can be chained again
+    assert!(queue.add(&[buffer, buffer, buffer, buffer]).is_some());
+}
+
+#[test_case]
+fn indices_wrap_around() {
+    let mut queue = Virtqueue::new(0, 2).expect("out of frames");
+    let buffer = Buffer::writable(PhysAddr::new(0x1000), 1);
+    for _ in 0..5 {
+        let head = queue.add(&[buffer]).unwrap();
+        complete(&queue, head, 1);
+        assert_eq!(queue.pop_used(), Some((head, 1)));
+    }
+    assert_eq!(read_u16(queue.avail_addr() + 2u64), 5);
+}